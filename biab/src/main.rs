@@ -0,0 +1,111 @@
+//! Every service of the beacon in one binary.
+//!
+//! `biab <service>` runs the service as its own binary would (eg: `biab
+//! generator` as `pulse_generator`), taking the same options and settings,
+//! including its table in the config file. The services' commands are run
+//! as utilities (eg: `biab hsm-keygen` as `pulse_generator hsm-keygen`).
+use anyhow::{anyhow, Result};
+
+const ABOUT: &str = "Runs the services of a twine randomness beacon";
+
+/// The subcommands running a service, and the service each runs
+const SERVICES: &[(&str, &str, &str)] = &[
+  ("generator", "pulse_generator", pulse_generator::ABOUT),
+  ("portal", "http_portal", http_portal::ABOUT),
+  ("sync", "data_sync", data_sync::ABOUT),
+  ("rng", "rng_factory", rng_factory::ABOUT),
+];
+
+/// The services with commands, and their commands
+const UTILITIES: &[(&str, &[(&str, &str)])] = &[
+  ("pulse_generator", pulse_generator::COMMANDS),
+  ("rng_factory", rng_factory::COMMANDS),
+  ("data_sync", data_sync::COMMANDS),
+];
+
+fn usage() -> String {
+  let mut usage = format!(
+    "{}\n\nUsage: biab <service> [options]\n       biab <utility> [options]\n\nServices:\n",
+    ABOUT
+  );
+  for (name, _, about) in SERVICES {
+    usage.push_str(&format!("  {:<16}{}\n", name, about));
+  }
+  usage.push_str("\nUtilities:\n");
+  for (_, commands) in UTILITIES {
+    for (name, description) in *commands {
+      usage.push_str(&format!("  {:<16}{}\n", name, description));
+    }
+  }
+  usage.push_str("\nSee `biab <service> --help` for the options.\n");
+  usage
+}
+
+/// The service to run for `subcommand`, and its arguments
+fn resolve(
+  subcommand: &str,
+  args: Vec<String>,
+) -> Option<(&'static str, Vec<String>)> {
+  if let Some((_, service, _)) =
+    SERVICES.iter().find(|(name, ..)| *name == subcommand)
+  {
+    return Some((service, args));
+  }
+  let (service, _) = UTILITIES.iter().find(|(_, commands)| {
+    commands.iter().any(|(name, _)| *name == subcommand)
+  })?;
+  let args = std::iter::once(subcommand.to_string())
+    .chain(args)
+    .collect();
+  Some((service, args))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+  let mut args = std::env::args().skip(1);
+  let subcommand = match args.next() {
+    Some(arg) if arg == "help" || arg == "-h" || arg == "--help" => {
+      print!("{}", usage());
+      return Ok(());
+    }
+    Some(subcommand) => subcommand,
+    None => {
+      eprint!("{}", usage());
+      return Err(anyhow!("No service given"));
+    }
+  };
+  let (service, args) = resolve(&subcommand, args.collect())
+    .ok_or_else(|| anyhow!("Unknown service {}, see --help", subcommand))?;
+  biab_utils::set_invocation(&format!("biab {}", subcommand), service, args);
+  match service {
+    "pulse_generator" => pulse_generator::run().await,
+    "http_portal" => http_portal::run().await,
+    "data_sync" => data_sync::run().await,
+    "rng_factory" => rng_factory::run().await,
+    _ => unreachable!(),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_resolve() {
+    let args = vec!["--print-config".to_string()];
+    assert_eq!(
+      resolve("generator", args.clone()),
+      Some(("pulse_generator", args.clone()))
+    );
+    assert_eq!(
+      resolve("hsm-keygen", args.clone()),
+      Some((
+        "pulse_generator",
+        vec!["hsm-keygen".to_string(), "--print-config".to_string()]
+      ))
+    );
+    assert_eq!(resolve("healthcheck", vec![]).unwrap().0, "rng_factory");
+    assert_eq!(resolve("verify", vec![]).unwrap().0, "data_sync");
+    assert_eq!(resolve("pulse_generator", args), None);
+  }
+}
@@ -0,0 +1,145 @@
+//! Writing files so they're never seen half written.
+//!
+//! State the services keep in files (eg: the generator's `rng.dat`, which
+//! the next pulse can't be made without) would be lost if the service or the
+//! host stopped while writing it. Instead the contents are written to a
+//! temporary file next to it and synced to disk, then the file is renamed
+//! over the old one, which replaces it at once, and the directory synced so
+//! the rename lasts. The file being replaced can be kept as a backup
+//! (`<name>.1`, the one before it as `<name>.2`, and so on).
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AtomicWrite {
+  backups: usize,
+}
+
+impl AtomicWrite {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Keep this many of the files replaced
+  pub fn with_backups(mut self, backups: usize) -> Self {
+    self.backups = backups;
+    self
+  }
+
+  pub async fn write(
+    &self,
+    path: impl AsRef<Path>,
+    contents: impl Into<Vec<u8>>,
+  ) -> Result<()> {
+    let writer = *self;
+    let path = path.as_ref().to_path_buf();
+    let contents = contents.into();
+    tokio::task::spawn_blocking(move || writer.write_blocking(&path, &contents))
+      .await?
+  }
+
+  /// Like [AtomicWrite::write], blocking the thread
+  pub fn write_blocking(&self, path: &Path, contents: &[u8]) -> Result<()> {
+    self
+      .replace(path, contents)
+      .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))
+  }
+
+  fn replace(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let name = path.file_name().ok_or_else(|| {
+      std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file")
+    })?;
+    let dir = match path.parent() {
+      Some(dir) if !dir.as_os_str().is_empty() => dir,
+      _ => Path::new("."),
+    };
+    let temp = dir.join(format!(
+      ".{}.{}.tmp",
+      name.to_string_lossy(),
+      uuid::Uuid::new_v4().simple()
+    ));
+    let written = File::create(&temp).and_then(|mut file| {
+      file.write_all(contents)?;
+      file.sync_all()
+    });
+    if let Err(e) = written {
+      let _ = std::fs::remove_file(&temp);
+      return Err(e);
+    }
+    if self.backups > 0 && path.exists() {
+      if let Err(e) = self.back_up(path) {
+        log::warn!("Failed to back up {}: {}", path.display(), e);
+      }
+    }
+    if let Err(e) = std::fs::rename(&temp, path) {
+      let _ = std::fs::remove_file(&temp);
+      return Err(e);
+    }
+    // not every platform can sync a directory
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(())
+  }
+
+  /// Shift the backups along, the oldest dropping off, and link the file as
+  /// the newest
+  fn back_up(&self, path: &Path) -> std::io::Result<()> {
+    for n in (1..self.backups).rev() {
+      let from = backup_path(path, n);
+      if from.exists() {
+        std::fs::rename(&from, backup_path(path, n + 1))?;
+      }
+    }
+    let newest = backup_path(path, 1);
+    let _ = std::fs::remove_file(&newest);
+    // the file is renamed over right after, so the link keeps its contents
+    std::fs::hard_link(path, &newest)
+      .or_else(|_| std::fs::copy(path, &newest).map(|_| ()))
+  }
+}
+
+/// Write `contents` to `path` atomically, without backups
+pub async fn write_atomic(
+  path: impl AsRef<Path>,
+  contents: impl Into<Vec<u8>>,
+) -> Result<()> {
+  AtomicWrite::new().write(path, contents).await
+}
+
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+  let mut name = path.as_os_str().to_os_string();
+  name.push(format!(".{}", n));
+  PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_write() {
+    let dir = std::env::temp_dir().join("biab_test_atomic_write");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("state.dat");
+
+    write_atomic(&path, "one").await.unwrap();
+    let writer = AtomicWrite::new().with_backups(2);
+    for contents in ["two", "three", "four"] {
+      writer.write(&path, contents).await.unwrap();
+    }
+    let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+    assert_eq!(read(path.clone()), "four");
+    assert_eq!(read(backup_path(&path, 1)), "three");
+    assert_eq!(read(backup_path(&path, 2)), "two");
+    assert!(!backup_path(&path, 3).exists());
+    // no temporary files left behind
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+
+    assert!(write_atomic(dir.join("missing/state.dat"), "")
+      .await
+      .is_err());
+  }
+}
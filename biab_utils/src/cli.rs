@@ -0,0 +1,121 @@
+//! The command line of the services.
+//!
+//! Each service runs as a binary of its own (eg: `pulse_generator`) or as a
+//! subcommand of the `biab` binary (eg: `biab generator`), taking the same
+//! arguments either way: a command first, for services with commands (eg:
+//! `hsm-keygen`), then the options every service takes:
+//!
+//! - `--config <path>`: the config file (see [crate::config_value])
+//! - `--print-config`: print the settings and exit (see [crate::print_config])
+//! - `-h`, `--help`: print how to run the service and exit (see [help])
+use std::sync::OnceLock;
+
+struct Invocation {
+  /// How the service was run, eg: `biab generator`
+  program: String,
+  /// Names the service's table in the config file, and its logs
+  service: String,
+  /// The arguments after the program
+  args: Vec<String>,
+}
+
+static INVOCATION: OnceLock<Invocation> = OnceLock::new();
+
+fn invocation() -> &'static Invocation {
+  INVOCATION.get_or_init(|| {
+    let mut args = std::env::args();
+    let program = args
+      .next()
+      .as_deref()
+      .and_then(|path| std::path::Path::new(path).file_name())
+      .map(|name| name.to_string_lossy().into_owned())
+      .unwrap_or_default();
+    Invocation {
+      service: program.clone(),
+      program,
+      args: args.collect(),
+    }
+  })
+}
+
+/// Run as `service` (eg: `pulse_generator`), for `biab`, before anything
+/// reads the settings or logs
+pub fn set_invocation(program: &str, service: &str, args: Vec<String>) {
+  let invocation = Invocation {
+    program: program.to_string(),
+    service: service.to_string(),
+    args,
+  };
+  if INVOCATION.set(invocation).is_err() {
+    panic!("The invocation must be set before it's read");
+  }
+}
+
+/// The name of the running service
+pub(crate) fn service_name() -> String {
+  invocation().service.clone()
+}
+
+/// The arguments of the service
+pub fn args() -> &'static [String] {
+  &invocation().args
+}
+
+/// The command given to the service, if any
+pub fn command() -> Option<&'static str> {
+  args()
+    .first()
+    .map(String::as_str)
+    .filter(|arg| !arg.starts_with('-'))
+}
+
+/// With `-h` or `--help`, print what the service does and its `commands`
+/// (names and descriptions) and return true, for it to exit
+pub fn help(about: &str, commands: &[(&str, &str)]) -> bool {
+  if !args().iter().any(|arg| arg == "-h" || arg == "--help") {
+    return false;
+  }
+  print!("{}", usage(&invocation().program, about, commands));
+  true
+}
+
+fn usage(program: &str, about: &str, commands: &[(&str, &str)]) -> String {
+  let mut usage = format!("{}\n\nUsage: {}", about, program);
+  if !commands.is_empty() {
+    usage.push_str(" [command]");
+  }
+  usage.push_str(" [options]\n");
+  if !commands.is_empty() {
+    usage.push_str("\nCommands:\n");
+    for (name, description) in commands {
+      usage.push_str(&format!("  {:<16}{}\n", name, description));
+    }
+  }
+  usage.push_str(
+    "\nOptions:\n  \
+     --config <path> read settings from a config file (or CONFIG_PATH)\n  \
+     --print-config  print the settings and exit\n  \
+     -h, --help      print this and exit\n",
+  );
+  usage
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_usage() {
+    let usage = usage(
+      "biab generator",
+      "Makes pulses",
+      &[("hsm-keygen", "generate the key")],
+    );
+    assert!(usage.starts_with(
+      "Makes pulses\n\nUsage: biab generator [command] [options]\n"
+    ));
+    assert!(usage.contains("\n  hsm-keygen      generate the key\n"));
+    assert!(usage.contains("--print-config"));
+    assert!(!super::usage("x", "", &[]).contains("Commands"));
+  }
+}
@@ -0,0 +1,365 @@
+//! A lasting connection to a peer that survives it going away.
+//!
+//! A [MessengerClient] queues messages and sends them in order over one
+//! connection, which is opened again when it drops (eg: the peer
+//! restarted). Each message stays queued until the peer acknowledges it
+//! (see [Messenger::send_with_ack]). While the peer can't be reached,
+//! connecting is retried with a growing delay (see [backoff]) and messages
+//! wait in a queue of bounded size, dropping the oldest when it's full.
+//! Callbacks registered with [MessengerClient::on_change] are told when the
+//! connection opens or fails. An idle connection is pinged by
+//! [MessengerClient::heartbeat] so the peer doesn't take it for dead, and so
+//! it's noticed when the peer is gone (see [crate::heartbeat]).
+//!
+//! A client can also run in the background with [MessengerClient::spawn],
+//! for services that only hand it messages now and then. Exchanges that
+//! wait for an answer (eg: status checks) and subscriptions (see
+//! [crate::subscribe]) aren't queued, and connect with [Peer] directly.
+use crate::{backoff, LinkStatus, Message, MessageStream, Messenger, Peer};
+use crate::{heartbeat_interval, ACK_TIMEOUT};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+/// How long sending what's queued can take when a background client stops
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What happened to the connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+  Connected,
+  /// The connection dropped, or couldn't be opened
+  Disconnected {
+    /// Failed attempts to connect in a row
+    failures: u32,
+    error: String,
+  },
+}
+
+type Callback = Box<dyn Fn(&ConnectionEvent) + Send + Sync>;
+
+pub struct MessengerClient {
+  peer: Peer,
+  messenger: Messenger,
+  stream: Option<MessageStream>,
+  queue: VecDeque<Message>,
+  capacity: usize,
+  /// Failed attempts to connect in a row
+  failures: u32,
+  /// When to try connecting again
+  retry_at: Option<Instant>,
+  last_error: Option<String>,
+  /// When something was last sent on the connection
+  last_sent: Instant,
+  callbacks: Vec<Callback>,
+}
+
+impl MessengerClient {
+  /// A client for `peer`, keeping up to `capacity` messages while it can't
+  /// be reached. Nothing is connected until there's something to send.
+  pub fn new(peer: Peer, capacity: usize) -> Self {
+    Self {
+      peer,
+      messenger: Messenger::new(),
+      stream: None,
+      queue: VecDeque::with_capacity(capacity),
+      capacity: capacity.max(1),
+      failures: 0,
+      retry_at: None,
+      last_error: None,
+      last_sent: Instant::now(),
+      callbacks: Vec::new(),
+    }
+  }
+
+  /// Be told when the connection opens or fails
+  pub fn on_change<F>(&mut self, callback: F)
+  where
+    F: Fn(&ConnectionEvent) + Send + Sync + 'static,
+  {
+    self.callbacks.push(Box::new(callback));
+  }
+
+  fn changed(&self, event: ConnectionEvent) {
+    for callback in &self.callbacks {
+      callback(&event);
+    }
+  }
+
+  pub fn peer(&self) -> &Peer {
+    &self.peer
+  }
+
+  /// The messages waiting to be sent, oldest first
+  pub fn queued(&self) -> impl Iterator<Item = &Message> {
+    self.queue.iter()
+  }
+
+  /// Queue a message, dropping the oldest if the queue is full
+  pub fn push(&mut self, message: Message) {
+    while self.queue.len() >= self.capacity {
+      if let Some(dropped) = self.queue.pop_front() {
+        log::warn!(
+          "Dropped an unsent {} message to {}, the queue is full",
+          dropped.command,
+          self.peer.address()
+        );
+      }
+    }
+    self.queue.push_back(message);
+  }
+
+  /// Queue a command
+  pub fn push_text(&mut self, command: &str) {
+    self.push(self.messenger.text(command));
+  }
+
+  /// Queue a command with a payload
+  pub fn push_delivery<T: Serialize>(&mut self, command: &str, payload: &T) {
+    self.push(self.messenger.delivery(command, payload));
+  }
+
+  async fn connect(&mut self) -> Result<()> {
+    match self.peer.connect().await {
+      Ok(stream) => {
+        if self.failures > 0 {
+          log::info!("Reconnected to {}", self.peer.address());
+        }
+        self.stream = Some(stream);
+        self.last_sent = Instant::now();
+        self.failures = 0;
+        self.retry_at = None;
+        self.last_error = None;
+        self.changed(ConnectionEvent::Connected);
+        Ok(())
+      }
+      Err(e) => {
+        self.retry_at = Some(Instant::now() + backoff(self.failures));
+        self.failures += 1;
+        self.disconnected(e.to_string());
+        Err(e)
+      }
+    }
+  }
+
+  fn disconnected(&mut self, error: String) {
+    self.stream = None;
+    self.last_error = Some(error.clone());
+    self.changed(ConnectionEvent::Disconnected {
+      failures: self.failures,
+      error,
+    });
+  }
+
+  /// Send what's queued, unless still waiting to connect again
+  pub async fn flush(&mut self) -> Result<()> {
+    if self.queue.is_empty() {
+      return Ok(());
+    }
+    if self.stream.is_none() {
+      if self.retry_at.is_some_and(|at| Instant::now() < at) {
+        log::debug!(
+          "{} messages waiting to reconnect to {}",
+          self.queue.len(),
+          self.peer.address()
+        );
+        return Ok(());
+      }
+      self.connect().await?;
+    }
+    let connection = self.stream.as_mut().expect("connected");
+    while let Some(queued) = self.queue.front() {
+      // stamped when sent, so it isn't taken for a replay of an older one
+      let message = Message {
+        id: uuid::Uuid::new_v4(),
+        timestamp: chrono::Utc::now(),
+        ..queued.clone()
+      };
+      let sent = self
+        .messenger
+        .send_with_ack(connection, message, ACK_TIMEOUT)
+        .await;
+      if let Err(e) = sent {
+        // connect again, keeping the message for then
+        self.disconnected(e.to_string());
+        return Err(e);
+      }
+      self.queue.pop_front();
+      self.last_sent = Instant::now();
+    }
+    Ok(())
+  }
+
+  /// Ping the peer if nothing was sent for [heartbeat_interval], dropping
+  /// the connection if it doesn't answer
+  pub async fn heartbeat(&mut self) -> Result<()> {
+    let Some(connection) = self.stream.as_mut() else {
+      return Ok(());
+    };
+    if self.last_sent.elapsed() < heartbeat_interval() {
+      return Ok(());
+    }
+    if let Err(e) = self.messenger.ping(connection).await {
+      self.disconnected(e.to_string());
+      return Err(e);
+    }
+    self.last_sent = Instant::now();
+    Ok(())
+  }
+
+  pub fn status(&self) -> LinkStatus {
+    LinkStatus {
+      connected: self.stream.is_some(),
+      failures: self.failures,
+      buffered: self.queue.len(),
+      last_error: self.last_error.clone(),
+    }
+  }
+
+  /// Send what's queued one last time, taking up to `timeout`, and drop the
+  /// rest
+  pub async fn close(mut self, timeout: Duration) {
+    self.retry_at = None;
+    let address = self.peer.address().to_string();
+    match tokio::time::timeout(timeout, self.flush()).await {
+      Ok(Ok(())) => {}
+      Ok(Err(e)) => log::warn!("Failed to send to {}: {}", address, e),
+      Err(_) => log::warn!("Timed out sending to {}", address),
+    }
+    if !self.queue.is_empty() {
+      log::warn!("Dropped {} unsent messages", self.queue.len());
+    }
+  }
+
+  /// Run the client in the background until shut down, sending the
+  /// messages pushed to the returned handle
+  pub fn spawn(mut self, shutdown: Arc<Notify>) -> ClientHandle {
+    let (tx, mut rx) = mpsc::channel(self.capacity);
+    let handle = ClientHandle {
+      address: self.peer.address().to_string(),
+      tx,
+    };
+    tokio::spawn(async move {
+      let stopping = shutdown.notified();
+      tokio::pin!(stopping);
+      stopping.as_mut().enable();
+      let mut ticks = tokio::time::interval(heartbeat_interval());
+      loop {
+        tokio::select! {
+          _ = &mut stopping => break,
+          message = rx.recv() => match message {
+            Some(message) => self.push(message),
+            None => break,
+          },
+          // try again what's waiting to reconnect
+          _ = ticks.tick() => {
+            if let Err(e) = self.heartbeat().await {
+              log::warn!(
+                "Lost the connection to {}: {}",
+                self.peer.address(),
+                e
+              );
+            }
+          }
+        }
+        if let Err(e) = self.flush().await {
+          log::warn!("Failed to send to {}: {}", self.peer.address(), e);
+        }
+      }
+      self.close(CLOSE_TIMEOUT).await;
+    });
+    handle
+  }
+}
+
+/// Where to push messages for a [MessengerClient] running in the
+/// background
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+  address: String,
+  tx: mpsc::Sender<Message>,
+}
+
+impl ClientHandle {
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  /// Queue a message, dropping it if the client is too far behind
+  pub fn push(&self, message: Message) {
+    if let Err(e) = self.tx.try_send(message) {
+      log::warn!("Dropped a message to {}: {}", self.address, e);
+    }
+  }
+
+  /// Queue a command
+  pub fn push_text(&self, command: &str) {
+    self.push(Messenger::new().text(command));
+  }
+
+  /// Queue a command with a payload
+  pub fn push_delivery<T: Serialize>(&self, command: &str, payload: &T) {
+    self.push(Messenger::new().delivery(command, payload));
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  #[test]
+  fn test_queue() {
+    let peer = Peer::from_env("TEST_CLIENT_ADDR", "localhost:5555").unwrap();
+    let mut client = MessengerClient::new(peer, 2);
+    for command in ["a", "b", "c"] {
+      client.push_text(command);
+    }
+    let queued: Vec<_> = client.queued().map(|m| m.command.as_str()).collect();
+    assert_eq!(queued, vec!["b", "c"]);
+  }
+
+  #[tokio::test]
+  async fn test_events() {
+    // nothing listens on the port
+    let peer = Peer::from_env("TEST_CLIENT_ADDR", "127.0.0.1:9").unwrap();
+    let mut client = MessengerClient::new(peer, 2);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    client.on_change(move |event| seen.lock().unwrap().push(event.clone()));
+    client.push_text("a");
+    assert!(client.flush().await.is_err());
+    // waiting to try again
+    assert!(client.flush().await.is_ok());
+    let events = events.lock().unwrap();
+    assert!(matches!(
+      events.as_slice(),
+      [ConnectionEvent::Disconnected { failures: 1, .. }]
+    ));
+    assert_eq!(client.status().buffered, 1);
+  }
+
+  #[tokio::test]
+  async fn test_spawn() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let peer = Peer::from_env("TEST_SPAWN_ADDR", &address).unwrap();
+    let shutdown = Arc::new(Notify::new());
+    let handle = MessengerClient::new(peer, 2).spawn(shutdown.clone());
+    handle.push_text("sync");
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = MessageStream::Plain(stream);
+    let messenger = Messenger::new();
+    let message = messenger.receive(&mut stream).await.unwrap();
+    assert_eq!(message.command, "sync");
+    messenger
+      .acknowledge(&mut stream, &message, &Ok(()))
+      .await
+      .unwrap();
+    shutdown.notify_waiters();
+  }
+}
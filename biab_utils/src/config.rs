@@ -0,0 +1,608 @@
+//! Configuration of the services.
+//!
+//! Settings are named like environment variables (eg: `LEAD_TIME_SECONDS`)
+//! and looked up in layers, the first one setting them winning:
+//!
+//! 1. the `NAME` environment variable
+//! 2. the contents of the file at the path given in `NAME_FILE`
+//! 3. a file named `NAME` or `name` in one of the secrets directories,
+//!    `/run/secrets` for docker secrets or those listed in `SECRETS_DIRS`
+//!    (eg: where kubernetes secrets are mounted)
+//! 4. the config file at `CONFIG_PATH` (or given with `--config <path>`)
+//!
+//! and then the default of the setting, if it has one.
+//!
+//! Sensitive settings (passwords, keys, tokens) are read as a [Secret], so
+//! they're never printed or logged, and are best kept out of the environment
+//! and the config file with one of the files. The yaml files of the services
+//! take their secrets either inline or from a file too (see
+//! [Secret::inline_or_file]).
+//!
+//! The config file is TOML, or YAML if its name ends in `.yaml` or `.yml`.
+//! Settings at its top level apply to every service, and those in a table
+//! named after a service (eg: `[data_sync]`) to that service only. Names are
+//! case insensitive and lists are joined with commas:
+//!
+//! ```toml
+//! db_host = "db"
+//! log_level = "info"
+//!
+//! [pulse_generator]
+//! lead_time_seconds = 2
+//! anchor_services = ["ots:https://a.pool.opentimestamps.org"]
+//! ```
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+/// Where docker mounts secrets
+const DOCKER_SECRETS_DIR: &str = "/run/secrets";
+
+/// Where a setting was found
+enum Source {
+  Env,
+  File(String),
+  Secret(PathBuf),
+  ConfigFile(PathBuf),
+}
+
+impl fmt::Display for Source {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Source::Env => write!(f, "the environment"),
+      Source::File(var) => write!(f, "the file in {}", var),
+      Source::Secret(path) => write!(f, "secret {}", path.display()),
+      Source::ConfigFile(path) => write!(f, "config file {}", path.display()),
+    }
+  }
+}
+
+/// The settings of the config file that apply to this service
+struct ConfigFile {
+  path: PathBuf,
+  values: HashMap<String, String>,
+}
+
+// kept as a message, to fail every lookup with it
+static CONFIG_FILE: LazyLock<Result<Option<ConfigFile>, String>> =
+  LazyLock::new(|| {
+    let Some(path) = config_path() else {
+      return Ok(None);
+    };
+    let values = load_config_file(&path, &crate::service_name())
+      .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+    Ok(Some(ConfigFile { path, values }))
+  });
+
+/// The path given with `--config`, or in `CONFIG_PATH`
+fn config_path() -> Option<PathBuf> {
+  let mut args = crate::args().iter();
+  while let Some(arg) = args.next() {
+    if arg == "--config" {
+      return args.next().map(PathBuf::from);
+    }
+    if let Some(path) = arg.strip_prefix("--config=") {
+      return Some(PathBuf::from(path));
+    }
+  }
+  env::var_os("CONFIG_PATH").map(PathBuf::from)
+}
+
+fn load_config_file(
+  path: &Path,
+  service: &str,
+) -> Result<HashMap<String, String>> {
+  let text = std::fs::read_to_string(path)?;
+  let yaml = matches!(
+    path.extension().and_then(|ext| ext.to_str()),
+    Some("yaml" | "yml")
+  );
+  let file: Value = if yaml {
+    serde_yaml::from_str(&text)?
+  } else {
+    toml::from_str(&text)?
+  };
+  parse_config_file(file, service)
+}
+
+fn parse_config_file(
+  file: Value,
+  service: &str,
+) -> Result<HashMap<String, String>> {
+  let Value::Object(file) = file else {
+    return Err(anyhow!("expected a table of settings"));
+  };
+  let mut values = HashMap::new();
+  let mut own = None;
+  for (name, value) in file {
+    match value {
+      Value::Object(table) if name == service => own = Some(table),
+      // another service's
+      Value::Object(_) => {}
+      value => {
+        values.insert(name.to_uppercase(), setting(&name, value)?);
+      }
+    }
+  }
+  for (name, value) in own.into_iter().flatten() {
+    values.insert(name.to_uppercase(), setting(&name, value)?);
+  }
+  Ok(values)
+}
+
+/// A setting of the config file, as it would be set in the environment
+fn setting(name: &str, value: Value) -> Result<String> {
+  match value {
+    Value::String(value) => Ok(value),
+    Value::Number(value) => Ok(value.to_string()),
+    Value::Bool(value) => Ok(value.to_string()),
+    Value::Array(values) => values
+      .into_iter()
+      .map(|value| match value {
+        Value::Array(_) | Value::Object(_) | Value::Null => Err(anyhow!(
+          "{} can only list strings, numbers or booleans",
+          name
+        )),
+        value => setting(name, value),
+      })
+      .collect::<Result<Vec<_>>>()
+      .map(|values| values.join(",")),
+    Value::Null | Value::Object(_) => Err(anyhow!(
+      "{} must be a string, number, boolean or list",
+      name
+    )),
+  }
+}
+
+fn read_trimmed(path: &Path) -> std::io::Result<String> {
+  let value = std::fs::read_to_string(path)?;
+  Ok(value.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The docker secrets directory, and those in `SECRETS_DIRS` (read from the
+/// environment only, being where settings are looked up)
+fn secrets_dirs() -> Vec<PathBuf> {
+  let mut dirs = vec![PathBuf::from(DOCKER_SECRETS_DIR)];
+  if let Ok(list) = env::var("SECRETS_DIRS") {
+    dirs.extend(
+      list
+        .split(',')
+        .map(str::trim)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from),
+    );
+  }
+  dirs
+}
+
+/// The file holding a secret, named like the setting or in lowercase (the
+/// way docker secrets usually are)
+fn find_secret(dirs: &[PathBuf], name: &str) -> Option<PathBuf> {
+  dirs.iter().find_map(|dir| {
+    [name.to_string(), name.to_lowercase()]
+      .into_iter()
+      .map(|file| dir.join(file))
+      .find(|path| path.is_file())
+  })
+}
+
+fn lookup(name: &str) -> Result<Option<(String, Source)>> {
+  if let Ok(value) = env::var(name) {
+    return Ok(Some((value, Source::Env)));
+  }
+
+  let file_var = format!("{}_FILE", name);
+  if let Ok(path) = env::var(&file_var) {
+    let value = read_trimmed(Path::new(&path)).map_err(|e| {
+      anyhow!("Failed to read {} from {}: {}", file_var, path, e)
+    })?;
+    return Ok(Some((value, Source::File(file_var))));
+  }
+
+  if let Some(secret) = find_secret(&secrets_dirs(), name) {
+    let value = read_trimmed(&secret).map_err(|e| {
+      anyhow!("Failed to read secret {}: {}", secret.display(), e)
+    })?;
+    return Ok(Some((value, Source::Secret(secret))));
+  }
+
+  match &*CONFIG_FILE {
+    Ok(Some(file)) => Ok(
+      file
+        .values
+        .get(name)
+        .map(|value| (value.clone(), Source::ConfigFile(file.path.clone()))),
+    ),
+    Ok(None) => Ok(None),
+    Err(e) => Err(anyhow!("{}", e)),
+  }
+}
+
+/// Look up a configuration value by name (see the layers above)
+pub fn config_value(name: &str) -> Result<Option<String>> {
+  Ok(lookup(name)?.map(|(value, _)| value))
+}
+
+fn not_set(name: &str) -> anyhow::Error {
+  anyhow!(
+    "{} is not set. Provide it as an environment variable, a file path in {}_FILE, a docker secret named {} (or a file in SECRETS_DIRS), or in the config file",
+    name,
+    name,
+    name.to_lowercase()
+  )
+}
+
+/// Like [config_value] but fails with a helpful message if it isn't set
+pub fn required_config_value(name: &str) -> Result<String> {
+  config_value(name)?.ok_or_else(|| not_set(name))
+}
+
+/// A setting parsed as a `T`, failing with its value and where it was set if
+/// it's invalid
+pub fn config_parse<T>(name: &str) -> Result<Option<T>>
+where
+  T: FromStr,
+  T::Err: fmt::Display,
+{
+  match lookup(name)? {
+    Some((value, source)) => value.trim().parse().map(Some).map_err(|e| {
+      anyhow!("Invalid {} {:?} (from {}): {}", name, value, source, e)
+    }),
+    None => Ok(None),
+  }
+}
+
+/// Like [config_parse], with a default
+pub fn config_or<T>(name: &str, default: T) -> Result<T>
+where
+  T: FromStr,
+  T::Err: fmt::Display,
+{
+  Ok(config_parse(name)?.unwrap_or(default))
+}
+
+/// Like [config_parse], failing if it isn't set
+pub fn required_config<T>(name: &str) -> Result<T>
+where
+  T: FromStr,
+  T::Err: fmt::Display,
+{
+  config_parse(name)?.ok_or_else(|| not_set(name))
+}
+
+/// A setting turning something on (`true`, `1`, `yes` or `on`), off by
+/// default
+pub fn config_flag(name: &str) -> Result<bool> {
+  match lookup(name)? {
+    Some((value, source)) => match value.trim().to_lowercase().as_str() {
+      "true" | "1" | "yes" | "on" => Ok(true),
+      "false" | "0" | "no" | "off" | "" => Ok(false),
+      _ => Err(anyhow!(
+        "Invalid {} {:?} (from {}): expected true or false",
+        name,
+        value,
+        source
+      )),
+    },
+    None => Ok(false),
+  }
+}
+
+/// A comma separated setting, empty if it isn't set
+pub fn config_list(name: &str) -> Result<Vec<String>> {
+  Ok(
+    config_value(name)?
+      .map(|value| {
+        value
+          .split(',')
+          .map(str::trim)
+          .filter(|item| !item.is_empty())
+          .map(str::to_string)
+          .collect()
+      })
+      .unwrap_or_default(),
+  )
+}
+
+/// A sensitive setting, hidden when printed or logged
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+  pub fn expose(&self) -> &str {
+    &self.0
+  }
+
+  /// A secret of a yaml config file, given either inline (eg: `api_key`) or
+  /// as the path of a file holding it (eg: `api_key_file`)
+  pub fn inline_or_file(
+    field: &str,
+    inline: Option<Secret>,
+    path: Option<&Path>,
+  ) -> Result<Option<Secret>> {
+    match (inline, path) {
+      (Some(_), Some(_)) => {
+        Err(anyhow!("Set either {} or {}_file, not both", field, field))
+      }
+      (Some(secret), None) => Ok(Some(secret)),
+      (None, Some(path)) => {
+        let secret = read_trimmed(path).map_err(|e| {
+          anyhow!("Failed to read {}_file {}: {}", field, path.display(), e)
+        })?;
+        Ok(Some(Secret(secret)))
+      }
+      (None, None) => Ok(None),
+    }
+  }
+}
+
+impl From<String> for Secret {
+  fn from(secret: String) -> Self {
+    Secret(secret)
+  }
+}
+
+impl fmt::Debug for Secret {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "***")
+  }
+}
+
+impl Serialize for Secret {
+  fn serialize<S: serde::Serializer>(
+    &self,
+    serializer: S,
+  ) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str("***")
+  }
+}
+
+/// Like [config_value], for a sensitive setting
+pub fn config_secret(name: &str) -> Result<Option<Secret>> {
+  Ok(config_value(name)?.map(Secret))
+}
+
+/// The settings every service reads the same way, printed under `shared`
+/// along with its own
+#[derive(Debug, Serialize)]
+struct SharedConfig {
+  /// With the password hidden, for the services using the database
+  #[serde(skip_serializing_if = "Option::is_none")]
+  database_url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  db_connect_attempts: Option<u32>,
+  log_level: String,
+  log_format: String,
+  otel_exporter_otlp_endpoint: Option<String>,
+  metrics_addr: Option<std::net::SocketAddr>,
+}
+
+impl SharedConfig {
+  fn load(uses_database: bool) -> Result<Self> {
+    let (database_url, db_connect_attempts) = if uses_database {
+      let url = redact_url(&database_url()?);
+      (Some(url), Some(crate::store::connect_attempts()?))
+    } else {
+      (None, None)
+    };
+    Ok(Self {
+      database_url,
+      db_connect_attempts,
+      log_level: config_value("LOG_LEVEL")?.unwrap_or_else(|| "info".into()),
+      log_format: config_value("LOG_FORMAT")?.unwrap_or_else(|| "text".into()),
+      otel_exporter_otlp_endpoint: config_value("OTEL_EXPORTER_OTLP_ENDPOINT")?,
+      metrics_addr: crate::metrics_address()?,
+    })
+  }
+}
+
+#[derive(Serialize)]
+struct PrintedConfig<'a, T> {
+  #[serde(flatten)]
+  config: &'a T,
+  shared: SharedConfig,
+}
+
+/// With `--print-config`, print the settings of a service (as YAML, with
+/// secrets hidden), along with the shared ones (the database's if
+/// `uses_database`, logging and metrics), and return true, for it to exit
+pub fn print_config<T: Serialize>(
+  config: &T,
+  uses_database: bool,
+) -> Result<bool> {
+  if !crate::args().iter().any(|arg| arg == "--print-config") {
+    return Ok(false);
+  }
+  let printed = PrintedConfig {
+    config,
+    shared: SharedConfig::load(uses_database)?,
+  };
+  print!("{}", serde_yaml::to_string(&printed)?);
+  Ok(true)
+}
+
+/// The database connection url.
+///
+/// Either `DATABASE_URL` is used directly, or the url is assembled from
+/// `DB_HOST`, `DB_PORT`, `DB_USER`, `DB_PASSWORD` and `DB_NAME`.
+pub fn database_url() -> Result<String> {
+  if let Some(url) = config_secret("DATABASE_URL")? {
+    return Ok(url.expose().to_string());
+  }
+
+  let password = match config_secret("DB_PASSWORD")? {
+    Some(password) => password,
+    None => {
+      return Err(anyhow!(
+        "Database is not configured. Set DATABASE_URL, or DB_PASSWORD (along with DB_HOST, DB_USER and DB_NAME if the defaults don't apply)"
+      ))
+    }
+  };
+  let host = config_value("DB_HOST")?.unwrap_or("db".into());
+  let port = config_value("DB_PORT")?;
+  let user = config_value("DB_USER")?.unwrap_or("root".into());
+  let name = config_value("DB_NAME")?.unwrap_or("twine".into());
+
+  Ok(build_database_url(
+    &host,
+    port.as_deref(),
+    &user,
+    password.expose(),
+    &name,
+  ))
+}
+
+fn build_database_url(
+  host: &str,
+  port: Option<&str>,
+  user: &str,
+  password: &str,
+  name: &str,
+) -> String {
+  let host = match port {
+    Some(port) => format!("{}:{}", host, port),
+    None => host.to_string(),
+  };
+  format!(
+    "mysql://{}:{}@{}/{}",
+    percent_encode(user),
+    percent_encode(password),
+    host,
+    name
+  )
+}
+
+fn percent_encode(s: &str) -> String {
+  s.bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        (b as char).to_string()
+      }
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+/// Hide the password of a url so it can be logged
+pub fn redact_url(url: &str) -> String {
+  let (scheme, rest) = match url.split_once("://") {
+    Some(parts) => parts,
+    None => return url.to_string(),
+  };
+  match rest.rsplit_once('@') {
+    Some((userinfo, host)) => match userinfo.split_once(':') {
+      Some((user, _)) => format!("{}://{}:***@{}", scheme, user, host),
+      None => url.to_string(),
+    },
+    None => url.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_database_url() {
+    let url = build_database_url("db", None, "root", "p@ss:word", "twine");
+    assert_eq!(url, "mysql://root:p%40ss%3Aword@db/twine");
+    assert_eq!(redact_url(&url), "mysql://root:***@db/twine");
+
+    let url = build_database_url("10.0.0.1", Some("3307"), "beacon", "pw", "t");
+    assert_eq!(url, "mysql://beacon:pw@10.0.0.1:3307/t");
+  }
+
+  #[test]
+  fn test_parse_config_file() {
+    let file: Value = toml::from_str(
+      r#"
+      db_host = "db"
+      lead_time_seconds = 5
+
+      [pulse_generator]
+      lead_time_seconds = 2
+      anchor_services = [
+        "ots:https://a.pool.opentimestamps.org",
+        "rfc3161:https://freetsa.org/tsr",
+      ]
+
+      [data_sync]
+      sync_period_seconds = 30
+      "#,
+    )
+    .unwrap();
+    let values = parse_config_file(file, "pulse_generator").unwrap();
+    assert_eq!(values["DB_HOST"], "db");
+    assert_eq!(values["LEAD_TIME_SECONDS"], "2");
+    assert_eq!(
+      values["ANCHOR_SERVICES"],
+      "ots:https://a.pool.opentimestamps.org,rfc3161:https://freetsa.org/tsr"
+    );
+    assert!(!values.contains_key("SYNC_PERIOD_SECONDS"));
+
+    let file: Value =
+      serde_yaml::from_str("pulse_generator: {tls: {cert: a}}").unwrap();
+    assert!(parse_config_file(file, "pulse_generator").is_err());
+  }
+
+  #[test]
+  fn test_secrets() {
+    let dir = std::env::temp_dir().join("biab_test_secrets");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("db_password"), "hunter2\n").unwrap();
+    std::fs::write(dir.join("WRITE_API_KEY"), "key").unwrap();
+    let dirs = [PathBuf::from("/nonexistent"), dir.clone()];
+    assert_eq!(
+      find_secret(&dirs, "DB_PASSWORD"),
+      Some(dir.join("db_password"))
+    );
+    assert_eq!(
+      find_secret(&dirs, "WRITE_API_KEY"),
+      Some(dir.join("WRITE_API_KEY"))
+    );
+    assert_eq!(find_secret(&dirs, "HSM_PASSWORD"), None);
+
+    let path = dir.join("db_password");
+    let secret = Secret::inline_or_file("api_key", None, Some(&path));
+    assert_eq!(secret.unwrap().unwrap().expose(), "hunter2");
+    let inline = Some(Secret::from("key".to_string()));
+    assert!(Secret::inline_or_file("api_key", inline, Some(&path)).is_err());
+    assert_eq!(format!("{:?}", Secret::from("key".to_string())), "***");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_printed_config() {
+    #[derive(Serialize)]
+    struct Config {
+      lead_time_seconds: u64,
+    }
+    let printed = PrintedConfig {
+      config: &Config {
+        lead_time_seconds: 2,
+      },
+      shared: SharedConfig {
+        database_url: None,
+        db_connect_attempts: None,
+        log_level: "info".into(),
+        log_format: "json".into(),
+        otel_exporter_otlp_endpoint: None,
+        metrics_addr: Some("0.0.0.0:9100".parse().unwrap()),
+      },
+    };
+    let yaml: serde_yaml::Value =
+      serde_yaml::from_str(&serde_yaml::to_string(&printed).unwrap()).unwrap();
+    assert_eq!(yaml["lead_time_seconds"], 2);
+    assert_eq!(yaml["shared"]["log_format"], "json");
+    assert_eq!(yaml["shared"]["metrics_addr"], "0.0.0.0:9100");
+    assert!(yaml["shared"].get("database_url").is_none());
+  }
+}
@@ -0,0 +1,324 @@
+//! Co-signing pulses, so that no single operator can sign them alone.
+//!
+//! Twine pulses carry a single signature, that of the strand's key. With
+//! [Cosigners], each operator also signs every pulse with their own key, in
+//! turn, until `threshold` of them have, and the [Cosignature]s are recorded
+//! in the payload (under `cosignatures`). The pulse isn't made if fewer than
+//! `threshold` operators sign it.
+//!
+//! The operators' public keys and the threshold make up the strand's
+//! [CosignPolicy], which the chain audit and `verify` check the pulses
+//! against: a pulse signed with the strand's key alone, or by fewer than
+//! `threshold` of the operators, fails. Each operator signs the
+//! [cosigned_message] of the pulse (its strand, index and payload, without
+//! the co-signatures), so a co-signature can't be moved to another pulse.
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_builder::Signer;
+use twine_protocol::twine_lib::crypto::{PublicKey, SignatureAlgorithm};
+use twine_protocol::twine_lib::{serde_ipld_dagcbor, Bytes};
+
+/// The payload field the co-signatures are recorded in
+const COSIGNATURES_FIELD: &str = "cosignatures";
+
+/// An operator's signature of a pulse
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cosignature {
+  /// The operator's name in the [CosignPolicy]
+  pub signer: String,
+  pub signature: Bytes,
+}
+
+/// What the operators sign for the pulse at `index` of `strand`, given its
+/// payload with or without the co-signatures
+pub fn cosigned_message(
+  strand: &Cid,
+  index: u64,
+  payload: &Ipld,
+) -> Result<Vec<u8>> {
+  #[derive(Serialize)]
+  struct Message<'a> {
+    strand: &'a Cid,
+    index: u64,
+    payload: Ipld,
+  }
+
+  let mut payload = payload.clone();
+  if let Ipld::Map(map) = &mut payload {
+    map.remove(COSIGNATURES_FIELD);
+  }
+  Ok(serde_ipld_dagcbor::to_vec(&Message {
+    strand,
+    index,
+    payload,
+  })?)
+}
+
+pub struct Cosigners<S> {
+  /// The operators' signers, by name, in the order they're asked to sign
+  signers: Vec<(String, S)>,
+  threshold: usize,
+}
+
+impl<S: Signer<Key = PublicKey>> Cosigners<S> {
+  /// `threshold` of the signers, each with a key of their own, must sign
+  /// each pulse
+  pub fn try_new(signers: Vec<(String, S)>, threshold: usize) -> Result<Self> {
+    if threshold == 0 || threshold > signers.len() {
+      return Err(anyhow!(
+        "The threshold must be between 1 and the number of cosigners ({}), \
+         not {}",
+        signers.len(),
+        threshold
+      ));
+    }
+    let mut names = HashSet::new();
+    let mut keys = HashSet::new();
+    for (name, signer) in &signers {
+      if !names.insert(name.as_str()) {
+        return Err(anyhow!("Cosigner {} is given twice", name));
+      }
+      if !keys.insert(signer.public_key().key.0) {
+        return Err(anyhow!(
+          "Cosigner {} has the key of another, each must have their own",
+          name
+        ));
+      }
+    }
+    Ok(Self { signers, threshold })
+  }
+
+  /// Ask the signers in turn to sign the message, checking each signature
+  /// with the signer's key, until `threshold` of them have
+  pub fn cosign(&self, message: &[u8]) -> Result<Vec<Cosignature>> {
+    let mut cosignatures = vec![];
+    let mut failed = vec![];
+    for (name, signer) in &self.signers {
+      if cosignatures.len() == self.threshold {
+        break;
+      }
+      let signed = signer
+        .sign(message)
+        .map_err(|e| anyhow!("{}", e.0))
+        .and_then(|signature| {
+          signer.public_key().verify(signature.clone(), message)?;
+          Ok(signature)
+        });
+      match signed {
+        Ok(signature) => cosignatures.push(Cosignature {
+          signer: name.clone(),
+          signature,
+        }),
+        Err(e) => {
+          log::warn!("Cosigner {} didn't sign: {}", name, e);
+          failed.push(name.as_str());
+        }
+      }
+    }
+    if cosignatures.len() < self.threshold {
+      return Err(anyhow!(
+        "Only {} of the {} cosignatures needed (failed: {})",
+        cosignatures.len(),
+        self.threshold,
+        failed.join(", ")
+      ));
+    }
+    Ok(cosignatures)
+  }
+
+  /// The policy the pulses of `strand` are checked against
+  pub fn policy(&self, strand: &Cid) -> CosignPolicy {
+    CosignPolicy {
+      strand: strand.to_string(),
+      threshold: self.threshold,
+      cosigners: self
+        .signers
+        .iter()
+        .map(|(name, signer)| {
+          let key = signer.public_key();
+          Cosigner {
+            name: name.clone(),
+            algorithm: key.alg,
+            key: hex::encode(key.key.0),
+          }
+        })
+        .collect(),
+    }
+  }
+}
+
+/// Expected yaml structure, as printed by the generator's `cosign-policy`:
+/// ```yaml
+/// strand: bafyrei...
+/// threshold: 2
+/// cosigners:
+///   - name: alice
+///     algorithm: ED25519
+///     key: 3b6a27bc...
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CosignPolicy {
+  /// The strand whose pulses are co-signed
+  pub strand: String,
+  pub threshold: usize,
+  pub cosigners: Vec<Cosigner>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cosigner {
+  pub name: String,
+  pub algorithm: SignatureAlgorithm,
+  /// The public key, in hex
+  pub key: String,
+}
+
+impl CosignPolicy {
+  pub fn load(path: &str) -> Result<Self> {
+    let file = std::fs::File::open(path)
+      .map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+    let policy: Self =
+      serde_yaml::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| anyhow!("Invalid cosign policy {}: {}", path, e))?;
+    if policy.threshold == 0 || policy.threshold > policy.cosigners.len() {
+      return Err(anyhow!(
+        "Invalid cosign policy {}: the threshold must be between 1 and the \
+         number of cosigners",
+        path
+      ));
+    }
+    Ok(policy)
+  }
+
+  pub fn applies_to(&self, strand: &Cid) -> bool {
+    self.strand == strand.to_string()
+  }
+
+  /// Check the pulse has valid co-signatures of at least `threshold` of the
+  /// cosigners
+  pub fn check(&self, twine: &Twine) -> Result<()> {
+    #[derive(Deserialize)]
+    struct Cosigned {
+      #[serde(default)]
+      cosignatures: Vec<Cosignature>,
+    }
+
+    let cosigned = twine
+      .extract_payload::<Cosigned>()
+      .map_err(|e| anyhow!("Can't read the cosignatures: {}", e))?;
+    let message =
+      cosigned_message(&twine.strand_cid(), twine.index(), twine.payload())?;
+    let mut signed = HashSet::new();
+    let mut invalid = vec![];
+    for cosignature in cosigned.cosignatures {
+      let valid = self
+        .cosigners
+        .iter()
+        .find(|cosigner| cosigner.name == cosignature.signer)
+        .is_some_and(|cosigner| {
+          cosigner.verify(cosignature.signature, &message).is_ok()
+        });
+      if valid {
+        signed.insert(cosignature.signer);
+      } else {
+        invalid.push(cosignature.signer);
+      }
+    }
+    if !invalid.is_empty() {
+      return Err(anyhow!("invalid cosignatures of {}", invalid.join(", ")));
+    }
+    if signed.len() < self.threshold {
+      return Err(anyhow!(
+        "cosigned by {} of the {} operators required",
+        signed.len(),
+        self.threshold
+      ));
+    }
+    Ok(())
+  }
+}
+
+impl Cosigner {
+  fn verify(&self, signature: Bytes, message: &[u8]) -> Result<()> {
+    let key =
+      PublicKey::new(self.algorithm.clone(), hex::decode(&self.key)?.into());
+    key.verify(signature, message)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_signer::TestSigner;
+  use std::collections::BTreeMap;
+
+  fn cosigners(available: &[bool]) -> Vec<(String, TestSigner)> {
+    available
+      .iter()
+      .enumerate()
+      .map(|(i, available)| {
+        let signer = TestSigner::new();
+        let signer = if *available {
+          signer
+        } else {
+          signer.unavailable()
+        };
+        (format!("signer{}", i), signer)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_try_new() {
+    assert!(Cosigners::try_new(cosigners(&[true, true]), 2).is_ok());
+    assert!(Cosigners::try_new(cosigners(&[true, true]), 0).is_err());
+    assert!(Cosigners::try_new(cosigners(&[true, true]), 3).is_err());
+    let mut signers = cosigners(&[true, true]);
+    signers[1].1 = TestSigner::new().claiming(&signers[0].1);
+    assert!(Cosigners::try_new(signers, 1).is_err());
+  }
+
+  #[test]
+  fn test_cosign() {
+    let message = cosigned_message(
+      &Cid::default(),
+      1,
+      &Ipld::Map(BTreeMap::from([("value".to_string(), Ipld::Integer(1))])),
+    )
+    .unwrap();
+
+    let signers = Cosigners::try_new(cosigners(&[false, true, true]), 2);
+    let cosignatures = signers.unwrap().cosign(&message).unwrap();
+    let names: Vec<_> = cosignatures.iter().map(|c| &c.signer).collect();
+    assert_eq!(names, ["signer1", "signer2"]);
+
+    // no single signer can sign alone
+    let signers = Cosigners::try_new(cosigners(&[true, false, false]), 2);
+    assert!(signers.unwrap().cosign(&message).is_err());
+  }
+
+  #[test]
+  fn test_cosigned_message() {
+    let payload = |cosigned: bool| {
+      let mut map = BTreeMap::from([("value".to_string(), Ipld::Integer(1))]);
+      if cosigned {
+        map.insert(COSIGNATURES_FIELD.to_string(), Ipld::List(vec![]));
+      }
+      Ipld::Map(map)
+    };
+    let strand = Cid::default();
+    let message = cosigned_message(&strand, 1, &payload(false)).unwrap();
+    assert_eq!(
+      message,
+      cosigned_message(&strand, 1, &payload(true)).unwrap()
+    );
+    assert_ne!(
+      message,
+      cosigned_message(&strand, 2, &payload(false)).unwrap()
+    );
+  }
+}
@@ -0,0 +1,132 @@
+//! Telling repeated or replayed messages from new ones.
+//!
+//! A [crate::Messenger] remembers the ids of the messages it received from
+//! each peer in the last `MESSAGE_DEDUP_SECS` (default 300), and ignores
+//! another message with one of those ids. Messages stamped before then can't
+//! be told from replays anymore, so they're ignored too. Peers are told apart
+//! by the name in their TLS certificate, or else their IP address, so one
+//! peer's messages don't get in the way of another's.
+use crate::Message;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::LazyLock;
+use uuid::Uuid;
+
+const DEFAULT_WINDOW_SECS: u64 = 300;
+/// The most ids remembered for a peer, so a chatty one can't exhaust memory
+const MAX_IDS: usize = 100_000;
+
+static WINDOW_SECS: LazyLock<u64> = LazyLock::new(|| {
+  crate::config_or("MESSAGE_DEDUP_SECS", DEFAULT_WINDOW_SECS).unwrap_or_else(
+    |e| {
+      log::warn!("{}, using {} seconds", e, DEFAULT_WINDOW_SECS);
+      DEFAULT_WINDOW_SECS
+    },
+  )
+});
+
+/// The ids of the messages received from each peer lately
+#[derive(Debug)]
+pub(crate) struct RecentMessages {
+  window: chrono::Duration,
+  peers: HashMap<String, Recent>,
+}
+
+#[derive(Debug, Default)]
+struct Recent {
+  ids: HashSet<Uuid>,
+  /// When each id was received, oldest first
+  received: VecDeque<(DateTime<Utc>, Uuid)>,
+}
+
+impl Recent {
+  fn forget_before(&mut self, cutoff: DateTime<Utc>) {
+    while let Some((received, id)) = self.received.front() {
+      if *received >= cutoff && self.received.len() <= MAX_IDS {
+        break;
+      }
+      self.ids.remove(id);
+      self.received.pop_front();
+    }
+  }
+}
+
+impl RecentMessages {
+  /// Remembering messages for `MESSAGE_DEDUP_SECS`
+  pub(crate) fn from_env() -> Self {
+    Self::new(std::time::Duration::from_secs(*WINDOW_SECS))
+  }
+
+  pub(crate) fn new(window: std::time::Duration) -> Self {
+    Self {
+      window: chrono::Duration::from_std(window)
+        .unwrap_or(chrono::Duration::MAX),
+      peers: HashMap::new(),
+    }
+  }
+
+  /// Remember a message from `peer`, failing if it's been received before
+  /// or is too old to tell
+  pub(crate) fn check(
+    &mut self,
+    peer: &str,
+    message: &Message,
+    now: DateTime<Utc>,
+  ) -> Result<()> {
+    let cutoff = now
+      .checked_sub_signed(self.window)
+      .unwrap_or(DateTime::<Utc>::MIN_UTC);
+    self.peers.retain(|_, recent| {
+      recent.forget_before(cutoff);
+      !recent.received.is_empty()
+    });
+    if message.timestamp < cutoff {
+      return Err(anyhow!(
+        "{} was sent at {}, too long ago",
+        message.id,
+        message.timestamp
+      ));
+    }
+    let recent = self.peers.entry(peer.to_string()).or_default();
+    if !recent.ids.insert(message.id) {
+      return Err(anyhow!("{} was received before", message.id));
+    }
+    recent.received.push_back((now, message.id));
+    recent.forget_before(cutoff);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::Messenger;
+  use std::time::Duration;
+
+  #[test]
+  fn test_recent_messages() {
+    let mut recent = RecentMessages::new(Duration::from_secs(60));
+    let now = Utc::now();
+    let first = Messenger::new().text("a");
+    let second = Messenger::new().text("b");
+    assert!(recent.check("rng_factory", &first, now).is_ok());
+    assert!(recent.check("rng_factory", &second, now).is_ok());
+    // older than the latest, but still a repeat
+    assert!(recent.check("rng_factory", &first, now).is_err());
+    // another peer's ids are its own
+    assert!(recent.check("generator", &first, now).is_ok());
+    // forgotten once too old, when it's refused for its timestamp instead
+    let later = now + chrono::Duration::seconds(61);
+    assert!(recent.check("rng_factory", &first, later).is_err());
+    assert!(recent.peers.is_empty());
+  }
+
+  #[test]
+  fn test_huge_window() {
+    let mut recent = RecentMessages::new(Duration::from_secs(u64::MAX));
+    let message = Messenger::new().text("a");
+    assert!(recent.check("rng_factory", &message, Utc::now()).is_ok());
+    assert!(recent.check("rng_factory", &message, Utc::now()).is_err());
+  }
+}
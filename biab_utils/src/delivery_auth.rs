@@ -0,0 +1,229 @@
+//! Authenticating randomness deliveries.
+//!
+//! So nothing else on the network can pass off its own "randomness" as the
+//! rng factory's, deliveries can be authenticated with either:
+//!
+//! - a key shared by the rng factory and the generator, `DELIVERY_HMAC_KEY`
+//!   (at least 32 bytes), with HMAC-SHA256
+//! - an Ed25519 signature, with the PEM encoded private key (PKCS#8) at
+//!   `DELIVERY_SIGNING_KEY_PATH` on the rng factory, and the hex encoded
+//!   public key in `DELIVERY_VERIFYING_KEY` on the generator
+//!
+//! Keys can be files or docker secrets too (see [config_value]). The tag
+//! covers the delivery's id, its randomness, sources and their attestation,
+//! the request it answers and when it was issued (see [authenticated_data]),
+//! and once a key is configured the generator rejects deliveries that aren't
+//! authenticated with it.
+use crate::{config_secret, config_value, Authentication, RandomnessDelivery};
+use anyhow::{anyhow, Result};
+use ring::hmac;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+/// Keeps tags from being valid for anything but a delivery
+const DOMAIN: &[u8] = b"biab-randomness-delivery-v1";
+/// The shortest shared key accepted
+const MIN_HMAC_KEY_LEN: usize = 32;
+
+const HMAC_METHOD: &str = "hmac-sha256";
+const ED25519_METHOD: &str = "ed25519";
+
+pub enum DeliveryAuth {
+  Hmac(hmac::Key),
+  Sign(Ed25519KeyPair),
+  Verify(UnparsedPublicKey<Vec<u8>>),
+}
+
+fn field(data: &mut Vec<u8>, bytes: &[u8]) {
+  data.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+  data.extend_from_slice(bytes);
+}
+
+/// The parts of a delivery that are authenticated, length prefixed
+pub fn authenticated_data(delivery: &RandomnessDelivery) -> Vec<u8> {
+  let mut data = DOMAIN.to_vec();
+  let id = delivery.id.map(|id| id.as_bytes().to_vec());
+  field(&mut data, &id.unwrap_or_default());
+  field(&mut data, &delivery.bytes);
+  field(&mut data, delivery.source.as_bytes());
+  field(&mut data, delivery.sources.join("\n").as_bytes());
+  let request_id = delivery.request_id.map(|id| id.as_bytes().to_vec());
+  field(&mut data, &request_id.unwrap_or_default());
+  let issued_at = delivery.issued_at.map(|at| at.to_rfc3339());
+  field(&mut data, issued_at.unwrap_or_default().as_bytes());
+  let attestation = delivery.attestation.as_ref().map(|a| a.digest());
+  field(&mut data, &attestation.unwrap_or_default());
+  data
+}
+
+fn hmac_from_env() -> Result<Option<DeliveryAuth>> {
+  config_secret("DELIVERY_HMAC_KEY")?
+    .map(|key| DeliveryAuth::hmac(key.expose().as_bytes()))
+    .transpose()
+}
+
+impl DeliveryAuth {
+  /// Authentication with a shared key
+  pub fn hmac(key: &[u8]) -> Result<Self> {
+    if key.len() < MIN_HMAC_KEY_LEN {
+      return Err(anyhow!(
+        "DELIVERY_HMAC_KEY must be at least {} bytes",
+        MIN_HMAC_KEY_LEN
+      ));
+    }
+    Ok(DeliveryAuth::Hmac(hmac::Key::new(hmac::HMAC_SHA256, key)))
+  }
+
+  /// How the rng factory authenticates deliveries, if it does
+  pub fn signer_from_env() -> Result<Option<Self>> {
+    let hmac = hmac_from_env()?;
+    let key_path = match config_value("DELIVERY_SIGNING_KEY_PATH")? {
+      Some(_) if hmac.is_some() => {
+        return Err(anyhow!(
+          "Set either DELIVERY_HMAC_KEY or DELIVERY_SIGNING_KEY_PATH"
+        ))
+      }
+      Some(key_path) => key_path,
+      None => return Ok(hmac),
+    };
+    let pem = std::fs::read(&key_path)
+      .map_err(|e| anyhow!("Failed to read {}: {}", key_path, e))?;
+    let der = rustls_pemfile::private_key(&mut pem.as_slice())
+      .map_err(|e| anyhow!("Invalid signing key {}: {}", key_path, e))?
+      .ok_or_else(|| anyhow!("No private key in {}", key_path))?;
+    let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der.secret_der())
+      .map_err(|e| anyhow!("{} is not an Ed25519 key: {}", key_path, e))?;
+    log::info!(
+      "Signing deliveries with {}",
+      hex::encode(key.public_key().as_ref())
+    );
+    Ok(Some(DeliveryAuth::Sign(key)))
+  }
+
+  /// How the generator checks deliveries, if it does
+  pub fn verifier_from_env() -> Result<Option<Self>> {
+    let hmac = hmac_from_env()?;
+    match config_value("DELIVERY_VERIFYING_KEY")? {
+      Some(_) if hmac.is_some() => Err(anyhow!(
+        "Set either DELIVERY_HMAC_KEY or DELIVERY_VERIFYING_KEY"
+      )),
+      Some(key) => {
+        let key = hex::decode(key.trim())
+          .ok()
+          .filter(|key| key.len() == 32)
+          .ok_or_else(|| {
+            anyhow!("DELIVERY_VERIFYING_KEY must be a hex Ed25519 public key")
+          })?;
+        Ok(Some(DeliveryAuth::Verify(UnparsedPublicKey::new(
+          &ED25519, key,
+        ))))
+      }
+      None => Ok(hmac),
+    }
+  }
+
+  /// Tag a delivery as from the rng factory
+  pub fn authenticate(&self, delivery: &mut RandomnessDelivery) -> Result<()> {
+    let data = authenticated_data(delivery);
+    let (method, tag) = match self {
+      DeliveryAuth::Hmac(key) => {
+        (HMAC_METHOD, hmac::sign(key, &data).as_ref().to_vec())
+      }
+      DeliveryAuth::Sign(key) => {
+        (ED25519_METHOD, key.sign(&data).as_ref().to_vec())
+      }
+      DeliveryAuth::Verify(_) => {
+        return Err(anyhow!("A public key can't authenticate deliveries"))
+      }
+    };
+    delivery.authentication = Some(Authentication {
+      method: method.to_string(),
+      tag,
+    });
+    Ok(())
+  }
+
+  /// Check a delivery is authenticated with the configured key
+  pub fn verify(&self, delivery: &RandomnessDelivery) -> Result<()> {
+    let authentication = delivery
+      .authentication
+      .as_ref()
+      .ok_or_else(|| anyhow!("The delivery isn't authenticated"))?;
+    let data = authenticated_data(delivery);
+    let tag = &authentication.tag;
+    let valid = match (self, authentication.method.as_str()) {
+      (DeliveryAuth::Hmac(key), HMAC_METHOD) => {
+        hmac::verify(key, &data, tag).is_ok()
+      }
+      (DeliveryAuth::Sign(key), ED25519_METHOD) => {
+        UnparsedPublicKey::new(&ED25519, key.public_key().as_ref())
+          .verify(&data, tag)
+          .is_ok()
+      }
+      (DeliveryAuth::Verify(key), ED25519_METHOD) => {
+        key.verify(&data, tag).is_ok()
+      }
+      (_, method) => {
+        return Err(anyhow!("The delivery is authenticated with {}", method))
+      }
+    };
+    if !valid {
+      return Err(anyhow!(
+        "The delivery's {} tag is invalid",
+        authentication.method
+      ));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use ring::rand::SystemRandom;
+
+  fn delivery() -> RandomnessDelivery {
+    RandomnessDelivery {
+      id: Some(uuid::Uuid::new_v4()),
+      bytes: vec![7; 64],
+      source: "os".to_string(),
+      sources: vec!["os".to_string()],
+      conditioning: None,
+      request_id: Some(uuid::Uuid::new_v4()),
+      issued_at: Some(chrono::Utc::now()),
+      attestation: None,
+      authentication: None,
+    }
+  }
+
+  #[test]
+  fn test_hmac() {
+    assert!(DeliveryAuth::hmac(&[1; 16]).is_err());
+    let auth = DeliveryAuth::hmac(&[1; 32]).unwrap();
+    let mut delivery = delivery();
+    assert!(auth.verify(&delivery).is_err());
+    auth.authenticate(&mut delivery).unwrap();
+    assert!(auth.verify(&delivery).is_ok());
+
+    let other = DeliveryAuth::hmac(&[2; 32]).unwrap();
+    assert!(other.verify(&delivery).is_err());
+    delivery.bytes[0] ^= 1;
+    assert!(auth.verify(&delivery).is_err());
+  }
+
+  #[test]
+  fn test_ed25519() {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let public_key = key.public_key().as_ref().to_vec();
+    let signer = DeliveryAuth::Sign(key);
+    let verifier =
+      DeliveryAuth::Verify(UnparsedPublicKey::new(&ED25519, public_key));
+    let mut delivery = delivery();
+    signer.authenticate(&mut delivery).unwrap();
+    assert!(verifier.verify(&delivery).is_ok());
+    assert!(verifier.authenticate(&mut delivery.clone()).is_err());
+
+    delivery.request_id = None;
+    assert!(verifier.verify(&delivery).is_err());
+  }
+}
@@ -0,0 +1,136 @@
+//! Where services publish events and subscribe to them.
+//!
+//! By default events go over the services' own connections (see
+//! [crate::pubsub]): a service publishing them serves them at
+//! `PUBLISH_ADDR`, and the others subscribe to it at `PULSE_EVENTS_ADDR`.
+//!
+//! Larger deployments can set `EVENT_BUS=nats` to go through a NATS server
+//! at `NATS_URL` (default `nats://nats:4222`) instead, in services built with
+//! the `biab_utils/nats` feature. Each topic is published on the subject
+//! `<NATS_SUBJECT_PREFIX>.<topic>` (default prefix `biab`), so the server
+//! fans events out to any number of subscribers. Either way, subscribers
+//! miss the events published while they aren't connected.
+use crate::{config_value, subscribe, Message, Messenger, Peer};
+use crate::{start_tcp_server_with_topics, Topics};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Notify};
+
+/// How events are published and subscribed to
+#[async_trait]
+pub trait EventBus: Send + Sync {
+  /// Send an event to the subscribers of its topic (its command)
+  async fn publish(&self, event: Message) -> Result<()>;
+
+  /// The events published on `topics` from now on, until shut down
+  async fn subscribe(&self, topics: &[&str])
+    -> Result<mpsc::Receiver<Message>>;
+}
+
+/// Events over the services' own connections
+pub struct TcpEvents {
+  /// Served at `PUBLISH_ADDR`, if set
+  topics: Option<Topics>,
+  /// `PULSE_EVENTS_ADDR`, if set
+  source: Option<Peer>,
+  shutdown: Arc<Notify>,
+}
+
+impl TcpEvents {
+  /// Serving events at `PUBLISH_ADDR` and subscribing at
+  /// `PULSE_EVENTS_ADDR`, unless neither is set
+  pub fn from_env(shutdown: Arc<Notify>) -> Result<Option<Self>> {
+    let source = match config_value("PULSE_EVENTS_ADDR")? {
+      Some(_) => Some(Peer::from_env("PULSE_EVENTS_ADDR", "")?),
+      None => None,
+    };
+    let topics = match config_value("PUBLISH_ADDR")? {
+      Some(address) => Some(serve(address, shutdown.clone())),
+      None => None,
+    };
+    if topics.is_none() && source.is_none() {
+      return Ok(None);
+    }
+    Ok(Some(Self {
+      topics,
+      source,
+      shutdown,
+    }))
+  }
+}
+
+fn serve(address: String, shutdown: Arc<Notify>) -> Topics {
+  let topics = Topics::new();
+  let mut messages =
+    start_tcp_server_with_topics(address, shutdown, topics.clone());
+  // nothing but subscriptions is expected there
+  tokio::spawn(async move {
+    while let Some(message) = messages.recv().await {
+      log::warn!("Ignoring {} message on PUBLISH_ADDR", message.command);
+    }
+  });
+  topics
+}
+
+#[async_trait]
+impl EventBus for TcpEvents {
+  async fn publish(&self, event: Message) -> Result<()> {
+    if let Some(topics) = &self.topics {
+      let topic = event.command.clone();
+      let sent = topics.publish_message(event);
+      log::debug!("Published {} to {} subscribers", topic, sent);
+    }
+    Ok(())
+  }
+
+  async fn subscribe(
+    &self,
+    topics: &[&str],
+  ) -> Result<mpsc::Receiver<Message>> {
+    let source = self
+      .source
+      .clone()
+      .ok_or_else(|| anyhow!("PULSE_EVENTS_ADDR isn't set"))?;
+    Ok(subscribe(source, topics, self.shutdown.clone()))
+  }
+}
+
+/// The event bus configured by `EVENT_BUS`, if events are published or
+/// subscribed to at all
+pub async fn event_bus(
+  shutdown: Arc<Notify>,
+) -> Result<Option<Arc<dyn EventBus>>> {
+  match config_value("EVENT_BUS")?.as_deref() {
+    None | Some("tcp") => Ok(
+      TcpEvents::from_env(shutdown)?
+        .map(|events| Arc::new(events) as Arc<dyn EventBus>),
+    ),
+    Some("nats") => Ok(Some(nats(shutdown).await?)),
+    Some(other) => Err(anyhow!("Unknown EVENT_BUS {}", other)),
+  }
+}
+
+#[cfg(feature = "nats")]
+async fn nats(shutdown: Arc<Notify>) -> Result<Arc<dyn EventBus>> {
+  Ok(Arc::new(crate::nats::NatsEvents::from_env(shutdown).await?))
+}
+
+#[cfg(not(feature = "nats"))]
+async fn nats(_shutdown: Arc<Notify>) -> Result<Arc<dyn EventBus>> {
+  Err(anyhow!(
+    "EVENT_BUS=nats needs a build with the biab_utils/nats feature"
+  ))
+}
+
+/// Publish an event with `payload` on `topic`, logging if it fails
+pub async fn publish_event<T: serde::Serialize>(
+  bus: &dyn EventBus,
+  topic: &str,
+  payload: &T,
+) {
+  let event = Messenger::new().delivery(topic, payload);
+  if let Err(e) = bus.publish(event).await {
+    log::error!("Failed to publish {}: {}", topic, e);
+  }
+}
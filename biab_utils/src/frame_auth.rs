@@ -0,0 +1,108 @@
+//! Authenticating the frames messages are sent in.
+//!
+//! A lighter alternative to TLS (see [crate::Listener]) for keeping other
+//! containers on the network from messaging the services: with a key shared
+//! by all of them in `MESSAGE_HMAC_KEY` (at least 32 bytes, or a file or
+//! docker secret, see [crate::config_value]), every frame is followed by an
+//! HMAC-SHA256 tag over its length and message. Frames without a valid tag
+//! are dropped, logged as alerts and counted in the
+//! `biab_rejected_frames_total` metric. Messages aren't encrypted.
+//!
+//! All services must have the same key, as a frame without a tag is
+//! rejected by a service with one, and one with a tag can't be read by a
+//! service without.
+use crate::int_counter;
+use crate::{config_secret, Secret};
+use anyhow::{anyhow, Result};
+use prometheus::IntCounter;
+use ring::hmac;
+use std::sync::LazyLock;
+
+/// Keeps tags from being valid for anything but a frame
+const DOMAIN: &[u8] = b"biab-message-frame-v1";
+/// The shortest shared key accepted
+const MIN_KEY_LEN: usize = 32;
+/// The length of a tag
+pub(crate) const TAG_LEN: usize = 32;
+
+static KEY: LazyLock<Result<Option<hmac::Key>, String>> = LazyLock::new(|| {
+  let key = config_secret("MESSAGE_HMAC_KEY").map_err(|e| e.to_string())?;
+  match key.as_ref().map(Secret::expose) {
+    Some(key) if key.len() < MIN_KEY_LEN => Err(format!(
+      "MESSAGE_HMAC_KEY must be at least {} bytes",
+      MIN_KEY_LEN
+    )),
+    Some(key) => Ok(Some(hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()))),
+    None => Ok(None),
+  }
+});
+
+static REJECTED_FRAMES: LazyLock<IntCounter> = LazyLock::new(|| {
+  int_counter(
+    "biab_rejected_frames_total",
+    "Received message frames without a valid tag",
+  )
+});
+
+/// The key frames are authenticated with, if `MESSAGE_HMAC_KEY` is set
+pub fn frame_key() -> Result<Option<&'static hmac::Key>> {
+  match &*KEY {
+    Ok(key) => Ok(key.as_ref()),
+    Err(e) => Err(anyhow!("{}", e)),
+  }
+}
+
+fn data(message: &[u8]) -> Vec<u8> {
+  let mut data = DOMAIN.to_vec();
+  data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+  data.extend_from_slice(message);
+  data
+}
+
+/// The tag of a frame's message
+pub(crate) fn frame_tag(key: &hmac::Key, message: &[u8]) -> Vec<u8> {
+  hmac::sign(key, &data(message)).as_ref().to_vec()
+}
+
+/// Check a frame's tag, counting and alerting if it isn't valid
+pub(crate) fn verify_frame(
+  key: &hmac::Key,
+  message: &[u8],
+  tag: &[u8],
+) -> bool {
+  let valid = hmac::verify(key, &data(message), tag).is_ok();
+  if !valid {
+    reject_frame("its tag isn't valid");
+  }
+  valid
+}
+
+/// Count a frame that couldn't be authenticated
+pub(crate) fn reject_frame(reason: &str) {
+  REJECTED_FRAMES.inc();
+  log::error!("ALERT: rejected a message frame, {}", reason);
+}
+
+/// How many frames were rejected since the service started
+pub fn rejected_frames() -> u64 {
+  REJECTED_FRAMES.get()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_frame_tag() {
+    // the counter is shared with other tests, which may reject frames too
+    let rejected = rejected_frames();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &[1; 32]);
+    let tag = frame_tag(&key, b"message");
+    assert_eq!(tag.len(), TAG_LEN);
+    assert!(verify_frame(&key, b"message", &tag));
+    assert!(!verify_frame(&key, b"massage", &tag));
+    let other = hmac::Key::new(hmac::HMAC_SHA256, &[2; 32]);
+    assert!(!verify_frame(&other, b"message", &tag));
+    assert!(rejected_frames() >= rejected + 2);
+  }
+}
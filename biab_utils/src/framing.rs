@@ -0,0 +1,178 @@
+//! How messages are framed on the wire.
+//!
+//! Each frame starts with a magic byte and the protocol version, then the
+//! length of the message (a big endian u32), the message itself and its tag
+//! if frames are authenticated (see [crate::frame_key]). A frame longer than
+//! `MESSAGE_MAX_BYTES` (default 1 MiB) is refused without reading it, as is
+//! one of another protocol or an unknown version, and the connection is
+//! closed.
+//!
+//! The version is the [WireFormat] of the message and its payload:
+//!
+//! - 1: MessagePack, with struct fields by position, so both ends need the
+//!   same struct layouts
+//! - 2: CBOR, with struct fields by name, so fields can be added without
+//!   breaking services that don't know them yet
+//!
+//! Both are always read. Messages are sent in `MESSAGE_FORMAT` (`cbor` by
+//! default, or `msgpack` while some services can't read CBOR yet), and
+//! replies (eg: acknowledgments) in the format of what they answer, so a
+//! service can be upgraded before or after the services it talks to.
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::LazyLock;
+
+/// Starts every frame, so anything else talking to the port is noticed
+const MAGIC: u8 = 0xb1;
+pub(crate) const HEADER_LEN: usize = 6;
+const DEFAULT_MAX_BYTES: usize = 1 << 20;
+
+static MAX_BYTES: LazyLock<usize> = LazyLock::new(|| {
+  crate::config_or("MESSAGE_MAX_BYTES", DEFAULT_MAX_BYTES).unwrap_or_else(|e| {
+    log::warn!("{}, using {} bytes", e, DEFAULT_MAX_BYTES);
+    DEFAULT_MAX_BYTES
+  })
+});
+
+static FORMAT: LazyLock<WireFormat> =
+  LazyLock::new(|| match crate::config_value("MESSAGE_FORMAT") {
+    Ok(format) => match format.as_deref() {
+      None | Some("cbor") => WireFormat::Cbor,
+      Some("msgpack") => WireFormat::MessagePack,
+      Some(other) => {
+        log::warn!("Invalid MESSAGE_FORMAT {}, using cbor", other);
+        WireFormat::Cbor
+      }
+    },
+    Err(e) => {
+      log::warn!("{}, using cbor", e);
+      WireFormat::Cbor
+    }
+  });
+
+/// How a message and its payload are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum WireFormat {
+  MessagePack,
+  #[default]
+  Cbor,
+}
+
+impl WireFormat {
+  pub(crate) fn version(self) -> u8 {
+    match self {
+      Self::MessagePack => 1,
+      Self::Cbor => 2,
+    }
+  }
+
+  pub(crate) fn from_version(version: u8) -> Option<Self> {
+    match version {
+      1 => Some(Self::MessagePack),
+      2 => Some(Self::Cbor),
+      _ => None,
+    }
+  }
+
+  pub fn encode<T: Serialize>(self, data: &T) -> Result<Vec<u8>> {
+    match self {
+      Self::MessagePack => Ok(rmp_serde::to_vec(data)?),
+      Self::Cbor => {
+        let mut encoded = Vec::new();
+        ciborium::into_writer(data, &mut encoded)?;
+        Ok(encoded)
+      }
+    }
+  }
+
+  pub fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T> {
+    match self {
+      Self::MessagePack => Ok(rmp_serde::from_slice(data)?),
+      Self::Cbor => Ok(ciborium::from_reader(data)?),
+    }
+  }
+}
+
+/// The format messages are sent in, `MESSAGE_FORMAT`
+pub fn wire_format() -> WireFormat {
+  *FORMAT
+}
+
+/// The longest message accepted, `MESSAGE_MAX_BYTES`
+pub fn max_frame_bytes() -> usize {
+  *MAX_BYTES
+}
+
+/// The header of a frame for a message of `len` bytes
+pub(crate) fn header(
+  format: WireFormat,
+  len: usize,
+) -> Result<[u8; HEADER_LEN]> {
+  if len > max_frame_bytes() {
+    return Err(anyhow!(
+      "The message is {} bytes, over MESSAGE_MAX_BYTES ({})",
+      len,
+      max_frame_bytes()
+    ));
+  }
+  let mut header = [MAGIC, format.version(), 0, 0, 0, 0];
+  header[2..].copy_from_slice(&(len as u32).to_be_bytes());
+  Ok(header)
+}
+
+/// The format and length of the message a frame header is for
+pub(crate) fn message_len(
+  header: &[u8; HEADER_LEN],
+  max: usize,
+) -> Result<(WireFormat, usize)> {
+  if header[0] != MAGIC {
+    return Err(anyhow!("Not a message frame"));
+  }
+  let format = WireFormat::from_version(header[1])
+    .ok_or_else(|| anyhow!("Unsupported protocol version {}", header[1]))?;
+  let len = u32::from_be_bytes(header[2..].try_into().expect("4 bytes"));
+  let len = len as usize;
+  if len > max {
+    return Err(anyhow!("The message is {} bytes, over {}", len, max));
+  }
+  Ok((format, len))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde::Deserialize;
+
+  #[test]
+  fn test_message_len() {
+    let header = header(WireFormat::Cbor, 300).unwrap();
+    assert_eq!(message_len(&header, 1024).unwrap(), (WireFormat::Cbor, 300));
+    assert!(message_len(&header, 100).is_err());
+    assert!(message_len(&[0, 0, 0, 0, 1, 44], 1024).is_err());
+    assert!(message_len(&[MAGIC, 9, 0, 0, 1, 44], 1024).is_err());
+  }
+
+  #[test]
+  fn test_added_fields() {
+    #[derive(Serialize)]
+    struct Newer {
+      bytes: Vec<u8>,
+      added: String,
+    }
+    #[derive(Deserialize)]
+    struct Older {
+      bytes: Vec<u8>,
+    }
+    let newer = Newer {
+      bytes: vec![1, 2],
+      added: "new".to_string(),
+    };
+    let encoded = WireFormat::Cbor.encode(&newer).unwrap();
+    let older: Older = WireFormat::Cbor.decode(&encoded).unwrap();
+    assert_eq!(older.bytes, vec![1, 2]);
+    // but not by position
+    let encoded = WireFormat::MessagePack.encode(&newer).unwrap();
+    assert!(WireFormat::MessagePack.decode::<Older>(&encoded).is_err());
+  }
+}
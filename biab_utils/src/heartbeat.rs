@@ -0,0 +1,103 @@
+//! Noticing connections that went dead without being closed.
+//!
+//! When a container is killed, connections to it can stay open at the other
+//! end with nothing ever arriving on them again. So connections that are
+//! kept open are pinged when nothing else was sent on them for
+//! `MESSAGE_HEARTBEAT_SECS` (default 15): a `ping` message the other end
+//! acknowledges as soon as it's received (see [Messenger::receive]), and
+//! that is dropped (and opened again) if it doesn't. Services close the
+//! connections nothing arrived on for three times that long.
+use crate::{Message, MessageStream, Messenger, ACK_TIMEOUT};
+use anyhow::Result;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// The command connections are pinged with
+pub const PING_COMMAND: &str = "ping";
+
+const DEFAULT_INTERVAL_SECS: u64 = 15;
+
+static INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+  let secs = match crate::config_parse::<u64>("MESSAGE_HEARTBEAT_SECS") {
+    Ok(Some(secs)) if secs > 0 => secs,
+    Ok(Some(_)) => {
+      log::warn!(
+        "MESSAGE_HEARTBEAT_SECS must be positive, using {} seconds",
+        DEFAULT_INTERVAL_SECS
+      );
+      DEFAULT_INTERVAL_SECS
+    }
+    Ok(None) => DEFAULT_INTERVAL_SECS,
+    Err(e) => {
+      log::warn!("{}, using {} seconds", e, DEFAULT_INTERVAL_SECS);
+      DEFAULT_INTERVAL_SECS
+    }
+  };
+  Duration::from_secs(secs)
+});
+
+/// How long a connection kept open can go without sending anything,
+/// `MESSAGE_HEARTBEAT_SECS`
+pub fn heartbeat_interval() -> Duration {
+  *INTERVAL
+}
+
+/// How long a connection can go without receiving anything before it's
+/// taken for dead
+pub fn liveness_timeout() -> Duration {
+  heartbeat_interval() * 3
+}
+
+impl Messenger {
+  /// Like [Messenger::receive], but closing the connection if nothing
+  /// arrives for the [liveness_timeout]
+  pub async fn receive_live(
+    &self,
+    stream: &mut MessageStream,
+  ) -> Option<Message> {
+    let timeout = liveness_timeout();
+    match tokio::time::timeout(timeout, self.receive(stream)).await {
+      Ok(message) => message,
+      Err(_) => {
+        log::warn!(
+          "Closing the connection from {}, nothing arrived for {:?}",
+          stream
+            .remote_addr()
+            .map_or("an unknown peer".to_string(), |a| a.to_string()),
+          timeout
+        );
+        None
+      }
+    }
+  }
+
+  /// Check the other end is still there, failing unless it answers in time
+  pub async fn ping(&self, stream: &mut MessageStream) -> Result<()> {
+    let ping = self.text(PING_COMMAND);
+    self.send_with_ack(stream, ping, ACK_TIMEOUT).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use tokio::net::{TcpListener, TcpStream};
+
+  #[tokio::test]
+  async fn test_ping() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let other_end = tokio::spawn(async move {
+      let (stream, _) = listener.accept().await.unwrap();
+      let mut stream = MessageStream::Plain(stream);
+      // pings are answered, not returned
+      Messenger::new().receive(&mut stream).await
+    });
+    let stream = TcpStream::connect(address).await.unwrap();
+    let mut stream = MessageStream::Plain(stream);
+    let messenger = Messenger::new();
+    messenger.ping(&mut stream).await.unwrap();
+    drop(stream);
+    assert!(other_end.await.unwrap().is_none());
+  }
+}
@@ -0,0 +1,169 @@
+//! Provisioning the beacon's signing key in a YubiHSM2, and attesting it.
+//!
+//! The key is generated inside the HSM, able to sign (PKCS#1 v1.5, see
+//! [crate::HsmSigner]) and nothing else: it can't be exported, even wrapped.
+//! To show it never left the hardware, the HSM signs an attestation
+//! certificate for it with the device's attestation key, whose own
+//! certificate is issued by Yubico and kept in the HSM. With both, anyone
+//! can check the key was generated in a genuine YubiHSM2 (see
+//! [KeyAttestation]).
+use crate::AtomicWrite;
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use std::path::Path;
+use yubihsm::asymmetric::Algorithm;
+use yubihsm::object::{self, Type};
+use yubihsm::{Capability, Client, Domain};
+
+/// Where the HSM keeps the certificate of its attestation key, issued by
+/// Yubico
+const DEVICE_CERTIFICATE_ID: object::Id = 0;
+const KEY_LABEL: &str = "twine beacon signing key";
+
+/// A key algorithm by name. Only `rsa2048` for now, as the signer refuses
+/// larger keys (see [crate::HsmSigner])
+pub fn parse_key_algorithm(name: &str) -> Result<Algorithm> {
+  match name.trim().to_lowercase().as_str() {
+    "rsa2048" => Ok(Algorithm::Rsa2048),
+    other => Err(anyhow!(
+      "Unsupported key algorithm {}, expected rsa2048 (the only RSA keys \
+       signed with SHA-256, which twine_lib verifies)",
+      other
+    )),
+  }
+}
+
+/// Domains by number (1 to 16), comma separated
+pub fn parse_domains(list: &str) -> Result<Domain> {
+  let mut bits = 0u16;
+  for domain in list.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+    let number = domain
+      .parse::<u16>()
+      .ok()
+      .filter(|n| (1..=16).contains(n))
+      .ok_or_else(|| anyhow!("Invalid domain {}, expected 1 to 16", domain))?;
+    bits |= 1 << (number - 1);
+  }
+  Domain::from_bits(bits)
+    .filter(|domains| !domains.is_empty())
+    .ok_or_else(|| anyhow!("No domains in {:?}", list))
+}
+
+/// Generate the signing key at `key_id`, failing if there's a key there
+/// already
+pub fn generate_signing_key(
+  client: &Client,
+  key_id: u16,
+  algorithm: Algorithm,
+  domains: Domain,
+) -> Result<()> {
+  if client.get_object_info(key_id, Type::AsymmetricKey).is_ok() {
+    return Err(anyhow!(
+      "There is a key at {:#06x} already, delete it first to replace it",
+      key_id
+    ));
+  }
+  client.generate_asymmetric_key(
+    key_id,
+    object::Label::from(KEY_LABEL),
+    domains,
+    Capability::SIGN_PKCS,
+    algorithm,
+  )?;
+  log::info!("Generated {:?} key {:#06x} in the HSM", algorithm, key_id);
+  Ok(())
+}
+
+/// The certificates showing a key was generated in a YubiHSM2.
+///
+/// The key's certificate is signed by the device's attestation key, whose
+/// certificate chains up to the Yubico YubiHSM attestation CA, so it can be
+/// checked with eg:
+///
+/// ```sh
+/// openssl verify -CAfile yubihsm-attestation-ca.pem \
+///   -untrusted device.pem key.pem
+/// ```
+pub struct KeyAttestation {
+  /// DER of the certificate of the key
+  pub key_certificate: Vec<u8>,
+  /// DER of the certificate of the device's attestation key
+  pub device_certificate: Vec<u8>,
+}
+
+impl KeyAttestation {
+  /// Have the HSM attest the key at `key_id`
+  pub fn fetch(client: &Client, key_id: u16) -> Result<Self> {
+    let key_certificate = client
+      .sign_attestation_certificate(key_id, None)
+      .map_err(|e| anyhow!("Failed to attest key {:#06x}: {}", key_id, e))?;
+    let device_certificate =
+      client.get_opaque(DEVICE_CERTIFICATE_ID).map_err(|e| {
+        anyhow!("Failed to read the device attestation certificate: {}", e)
+      })?;
+    Ok(Self {
+      key_certificate: key_certificate.as_ref().to_vec(),
+      device_certificate,
+    })
+  }
+
+  /// The key's certificate then the device's, as PEM
+  pub fn to_pem(&self) -> String {
+    [&self.key_certificate, &self.device_certificate]
+      .into_iter()
+      .map(|der| pem_certificate(der))
+      .collect()
+  }
+
+  /// Save as PEM, keeping the attestation replaced (eg: of an older key)
+  pub fn save(&self, path: &Path) -> Result<()> {
+    AtomicWrite::new()
+      .with_backups(1)
+      .write_blocking(path, self.to_pem().as_bytes())
+  }
+}
+
+fn pem_certificate(der: &[u8]) -> String {
+  let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+  let mut pem = "-----BEGIN CERTIFICATE-----\n".to_string();
+  for line in encoded.as_bytes().chunks(64) {
+    pem.push_str(&String::from_utf8_lossy(line));
+    pem.push('\n');
+  }
+  pem.push_str("-----END CERTIFICATE-----\n");
+  pem
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(parse_domains("1").unwrap(), Domain::DOM1);
+    assert_eq!(
+      parse_domains("1, 16").unwrap(),
+      Domain::DOM1 | Domain::DOM16
+    );
+    assert!(parse_domains("17").is_err());
+    assert!(parse_domains("").is_err());
+    assert_eq!(parse_key_algorithm("RSA2048").unwrap(), Algorithm::Rsa2048);
+    assert!(parse_key_algorithm("rsa4096").is_err());
+    assert!(parse_key_algorithm("ecp256").is_err());
+  }
+
+  #[test]
+  fn test_pem() {
+    let attestation = KeyAttestation {
+      key_certificate: vec![1; 60],
+      device_certificate: vec![2; 3],
+    };
+    let pem = attestation.to_pem();
+    let certs = rustls_pemfile::certs(&mut pem.as_bytes())
+      .collect::<std::result::Result<Vec<_>, _>>()
+      .unwrap();
+    assert_eq!(certs.len(), 2);
+    assert_eq!(certs[0].as_ref(), &[1; 60][..]);
+    assert_eq!(certs[1].as_ref(), &[2; 3][..]);
+  }
+}
@@ -1,14 +1,135 @@
+//! Signing with a key kept in a YubiHSM2.
+//!
+//! The HSM closes sessions that are idle for 30 seconds, and the connector
+//! can drop its connection, so the signer keeps its session alive with a
+//! ping every so often (see [HsmSigner::keep_alive]), and opens a new one
+//! (authenticating again) when a ping or a signature fails. Signing is
+//! retried on a new session a few times before the pulse is given up on, so
+//! it can block for a while: the generator signs on a blocking thread. Errors
+//! the HSM answers with that a new session won't fix (eg: no key with the
+//! id) fail right away.
+//!
+//! Keys are 2048 bit RSA keys, signing with PKCS#1 v1.5 padding over a
+//! SHA-256 digest. twine_lib verifies SHA-256 signatures of 2048 bit keys
+//! only, so larger keys are refused:
+//!
+//! - 3072 bit keys would need SHA-384 signatures, which twine_lib verifies,
+//!   but yubihsm 0.42 only signs PKCS#1 v1.5 over SHA-256, so they wait on
+//!   an upstream release that can sign SHA-384 digests.
+//! - twine_lib can't verify signatures of 4096 bit keys at all.
+//!
+//! The HSM could also sign with PSS padding, but twine signatures can't say
+//! they are (there's no PSS [SignatureAlgorithm]), so verifiers would reject
+//! them.
+use crate::{retry_blocking, RetryPolicy};
 use rsa::pkcs1::EncodeRsaPublicKey;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
 use twine_protocol::prelude::*;
-use twine_protocol::twine_lib::crypto::Signature;
+use twine_protocol::twine_lib::crypto::{Signature, SignatureAlgorithm};
 use twine_protocol::{twine_builder::Signer, twine_lib::crypto::PublicKey};
+use yubihsm::client::ErrorKind;
 use yubihsm::object::Type;
 use yubihsm::{asymmetric::Algorithm, Client};
 
+/// Opens a client with a new session, authenticated with the credentials
+pub type HsmConnect =
+  Box<dyn Fn() -> Result<Client, anyhow::Error> + Send + Sync>;
+
+/// How long to wait before signing again on a new session, doubling after
+/// each failure
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Whether the error is of the session or the connection to the HSM, rather
+/// than of the request (eg: a wrong key id), so worth retrying on a new one
+fn is_session_error(e: &yubihsm::client::Error) -> bool {
+  use yubihsm::device::ErrorKind::*;
+  match e.kind() {
+    ErrorKind::DeviceError { kind } => matches!(
+      kind,
+      InvalidSession | SessionsFull | SessionFailed | AuthenticationFailed
+    ),
+    _ => true,
+  }
+}
+
+struct Session {
+  connect: HsmConnect,
+  client: Mutex<Client>,
+}
+
+impl Session {
+  fn client(&self) -> MutexGuard<'_, Client> {
+    self.client.lock().expect("hsm client lock")
+  }
+
+  fn reopen(&self) -> Result<(), anyhow::Error> {
+    let client = (self.connect)()?;
+    *self.client() = client;
+    log::info!("Opened a new HSM session");
+    Ok(())
+  }
+
+  /// Call `f` with the client, on a new session after each session error, as
+  /// many times as `retry` allows
+  fn call<T, F>(
+    &self,
+    retry: &RetryPolicy,
+    operation: &str,
+    f: F,
+  ) -> Result<T, anyhow::Error>
+  where
+    F: Fn(&Client) -> Result<T, yubihsm::client::Error>,
+  {
+    let result =
+      retry_blocking(retry, operation, is_session_error, |attempt| {
+        if attempt > 1 {
+          if let Err(e) = self.reopen() {
+            log::warn!("Failed to reopen the HSM session: {}", e);
+          }
+        }
+        f(&self.client())
+      });
+    Ok(result?)
+  }
+
+  /// Ping the HSM, opening a new session if it doesn't answer
+  fn check(&self) -> Result<(), anyhow::Error> {
+    // not holding the client while reopening
+    let ping = self.client().ping();
+    if let Err(e) = ping {
+      log::warn!("HSM session lost ({}), reopening it", e);
+      self.reopen()?;
+    }
+    Ok(())
+  }
+}
+
 pub struct HsmSigner {
-  client: Client,
+  session: Arc<Session>,
   public_key: PublicKey,
   key_id: u16,
+  retry: RetryPolicy,
+}
+
+/// How twine signatures made with a key of the HSM are described
+fn signature_algorithm(
+  alg: Algorithm,
+) -> Result<SignatureAlgorithm, anyhow::Error> {
+  match alg {
+    Algorithm::Rsa2048 => Ok(SignatureAlgorithm::Sha256Rsa(2048)),
+    Algorithm::Rsa3072 => Err(anyhow::anyhow!(
+      "Unsupported key type {:?}: twine_lib only verifies SHA-384 signatures \
+       of 3072 bit RSA keys, which yubihsm can't make yet",
+      alg
+    )),
+    Algorithm::Rsa4096 => Err(anyhow::anyhow!(
+      "Unsupported key type {:?}: twine_lib can't verify signatures of 4096 \
+       bit RSA keys",
+      alg
+    )),
+    _ => Err(anyhow::anyhow!("Unsupported key type. Found: {:?}", alg)),
+  }
 }
 
 fn get_public_key(
@@ -23,14 +144,7 @@ fn get_public_key(
     "Only Asymmetric RSA supported. Found: {:?}",
     info.algorithm
   ))?;
-  let signing_alg = match alg {
-    Algorithm::Rsa2048 => {
-      twine_protocol::twine_lib::crypto::SignatureAlgorithm::Sha256Rsa(2048)
-    }
-    _ => {
-      return Err(anyhow::anyhow!("Unsupported key type. Found: {:?}", alg));
-    }
-  };
+  let signing_alg = signature_algorithm(alg)?;
 
   let n = rsa::BigUint::from_bytes_be(n);
   let e = rsa::BigUint::from_bytes_be(&vec![0x01, 0x00, 0x01]);
@@ -42,14 +156,54 @@ fn get_public_key(
 }
 
 impl HsmSigner {
-  pub fn try_new(client: Client, key_id: u16) -> Result<Self, anyhow::Error> {
+  /// A signer with the key `key_id`, on a session opened with `connect`
+  pub fn try_new(
+    connect: HsmConnect,
+    key_id: u16,
+  ) -> Result<Self, anyhow::Error> {
+    let client = connect()?;
     let public_key = get_public_key(&client, key_id)?;
     Ok(HsmSigner {
-      client,
+      session: Arc::new(Session {
+        connect,
+        client: Mutex::new(client),
+      }),
       public_key,
       key_id,
+      retry: RetryPolicy::new(3, RETRY_DELAY),
     })
   }
+
+  /// Try signing this many times, on a new session after each failure
+  pub fn with_sign_attempts(mut self, attempts: u32) -> Self {
+    self.retry.attempts = attempts.max(1);
+    self
+  }
+
+  /// Check the session is still open (reopening it if not)
+  pub fn check(&self) -> Result<(), anyhow::Error> {
+    self.session.check()
+  }
+
+  /// Ping the HSM every `interval` while the signer is in use, so its
+  /// session doesn't time out between pulses
+  pub fn keep_alive(&self, interval: Duration) {
+    let session = Arc::downgrade(&self.session);
+    std::thread::spawn(move || keep_alive(session, interval));
+  }
+}
+
+fn keep_alive(session: Weak<Session>, interval: Duration) {
+  loop {
+    std::thread::sleep(interval);
+    // the signer is gone
+    let Some(session) = session.upgrade() else {
+      return;
+    };
+    if let Err(e) = session.check() {
+      log::error!("ALERT: Failed to reopen the HSM session: {}", e);
+    }
+  }
 }
 
 impl Signer for HsmSigner {
@@ -60,11 +214,100 @@ impl Signer for HsmSigner {
   }
 
   fn sign<T: AsRef<[u8]>>(&self, data: T) -> Result<Signature, SigningError> {
-    let sig = self
-      .client
-      .sign_rsa_pkcs1v15_sha256(self.key_id, data.as_ref())
+    let signature = self
+      .session
+      .call(&self.retry, "HSM signing", |client| {
+        client.sign_rsa_pkcs1v15_sha256(self.key_id, data.as_ref())
+      })
       .map_err(|e| SigningError(e.to_string()))?;
+    Ok(signature.as_ref().into())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::atomic::{AtomicU32, Ordering};
+  use yubihsm::{Capability, Connector, Credentials, Domain};
+
+  const KEY_ID: u16 = 100;
+
+  /// A session on a mock HSM holding an Ed25519 key, counting the sessions
+  /// opened
+  fn mock_session() -> (Session, Connector, Arc<AtomicU32>) {
+    let connector = Connector::mockhsm();
+    let opened = Arc::new(AtomicU32::new(0));
+    let connect: HsmConnect = {
+      let connector = connector.clone();
+      let opened = opened.clone();
+      Box::new(move || {
+        opened.fetch_add(1, Ordering::SeqCst);
+        Ok(Client::open(
+          connector.clone(),
+          Credentials::default(),
+          false,
+        )?)
+      })
+    };
+    let client = connect().unwrap();
+    client
+      .generate_asymmetric_key(
+        KEY_ID,
+        "test".into(),
+        Domain::DOM1,
+        Capability::SIGN_EDDSA,
+        Algorithm::Ed25519,
+      )
+      .unwrap();
+    let session = Session {
+      connect,
+      client: Mutex::new(client),
+    };
+    (session, connector, opened)
+  }
+
+  /// Lose the session, as if it timed out
+  fn lose(session: &Session, connector: &Connector) {
+    let key = yubihsm::authentication::Key::derive_from_password(b"wrong");
+    let credentials = Credentials::new(1, key);
+    *session.client() = Client::create(connector.clone(), credentials).unwrap();
+  }
+
+  #[test]
+  fn test_check() {
+    let (session, connector, opened) = mock_session();
+    session.check().unwrap();
+    assert_eq!(opened.load(Ordering::SeqCst), 1);
+    lose(&session, &connector);
+    session.check().unwrap();
+    assert_eq!(opened.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn test_call() {
+    let (session, connector, opened) = mock_session();
+    let retry = RetryPolicy::new(3, Duration::from_millis(1));
+    let sign = |client: &Client| client.sign_ed25519(KEY_ID, b"pulse");
+    session.call(&retry, "signing", sign).unwrap();
+    assert_eq!(opened.load(Ordering::SeqCst), 1);
+    // signed on a new session
+    lose(&session, &connector);
+    session.call(&retry, "signing", sign).unwrap();
+    assert_eq!(opened.load(Ordering::SeqCst), 2);
+    // no key to sign with, which a new session wouldn't fix
+    let missing = |client: &Client| client.sign_ed25519(KEY_ID + 1, b"pulse");
+    assert!(session.call(&retry, "signing", missing).is_err());
+    assert_eq!(opened.load(Ordering::SeqCst), 2);
+  }
 
-    Ok(sig.as_ref().into())
+  #[test]
+  fn test_signature_algorithm() {
+    assert!(matches!(
+      signature_algorithm(Algorithm::Rsa2048),
+      Ok(SignatureAlgorithm::Sha256Rsa(2048))
+    ));
+    assert!(signature_algorithm(Algorithm::Rsa3072).is_err());
+    assert!(signature_algorithm(Algorithm::Rsa4096).is_err());
+    assert!(signature_algorithm(Algorithm::EcP256).is_err());
   }
 }
@@ -8,9 +8,76 @@ pub use tcp_server::*;
 mod messages;
 pub use messages::*;
 
+mod delivery_auth;
+pub use delivery_auth::*;
+
+mod dedup;
+
+mod heartbeat;
+pub use heartbeat::*;
+
+mod framing;
+pub use framing::{max_frame_bytes, wire_format, WireFormat};
+
+mod frame_auth;
+pub use frame_auth::{frame_key, rejected_frames};
+
 mod hsm_signer;
 pub use hsm_signer::*;
 
+mod hsm_keys;
+pub use hsm_keys::*;
+
+mod cosigning;
+pub use cosigning::*;
+
+mod signer_check;
+pub use signer_check::*;
+
+#[cfg(test)]
+mod test_signer;
+
+mod config;
+pub use config::*;
+
+mod cli;
+pub use cli::*;
+
+mod peers;
+pub use peers::*;
+
+mod client;
+pub use client::*;
+
+mod pubsub;
+pub use pubsub::*;
+
+mod events;
+pub use events::*;
+
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "nats")]
+pub use nats::NatsEvents;
+
+mod tls;
+pub use tls::*;
+
+mod retry;
+pub use retry::*;
+
+mod atomic_write;
+pub use atomic_write::*;
+
+mod store;
+pub use store::*;
+
+mod logging;
+pub use logging::*;
+
+mod metrics;
+pub use metrics::*;
+
 pub async fn handle_shutdown_signal(shutdown: Arc<Notify>) {
   use tokio::signal::{
     ctrl_c,
@@ -28,18 +95,6 @@ pub async fn handle_shutdown_signal(shutdown: Arc<Notify>) {
       shutdown.notify_waiters();
     }
   };
-}
-
-pub fn init_logger() {
-  use simple_logger::SimpleLogger;
-  let level = match std::env::var("LOG_LEVEL") {
-    Ok(level) => level,
-    Err(_) => "info".to_string(),
-  };
-
-  SimpleLogger::new()
-    .with_level(level.parse().unwrap())
-    .with_module_level("biab_utils", level.parse().unwrap())
-    .init()
-    .unwrap();
+  // the spans so far, in case the service exits before they're exported
+  let _ = tokio::task::spawn_blocking(flush_traces).await;
 }
@@ -0,0 +1,314 @@
+//! Log output and tracing for the services.
+//!
+//! Services log with [tracing], inside spans for the work they're doing (eg:
+//! a pulse being assembled, an HTTP request), and records logged with `log`
+//! are passed on to it. What's logged is chosen by `LOG_LEVEL`, a level
+//! (default `info`) or a list of filter directives (eg:
+//! `info,data_sync=debug`, see [EnvFilter]).
+//!
+//! Logs are written to stdout as text, after the spans they were logged in.
+//! With `LOG_FORMAT=json` each record is instead one line of JSON, for log
+//! collectors like Loki or Elasticsearch to ingest:
+//!
+//! ```json
+//! {"timestamp":"2025-03-01T12:00:00.123Z","level":"INFO","service":"pulse_generator","module":"pulse_generator","message":"Pulse (3) published: ...","spans":["pulse"],"fields":{"stage":"publish","index":3}}
+//! ```
+//!
+//! `fields` holds the fields of the record and of the spans it's in.
+//!
+//! Services built with the `biab_utils/otlp` feature also export their spans
+//! to the OpenTelemetry collector at `OTEL_EXPORTER_OTLP_ENDPOINT`, if set.
+use serde_json::{Map, Value};
+use std::io::Write;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+/// How log records are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+  #[default]
+  Text,
+  Json,
+}
+
+/// The format set by `LOG_FORMAT` (`text` or `json`)
+pub fn log_format() -> LogFormat {
+  let format = crate::config_value("LOG_FORMAT").unwrap_or_else(|e| {
+    eprintln!("{}, logging text", e);
+    None
+  });
+  match format.as_deref() {
+    None | Some("text") => LogFormat::Text,
+    Some("json") => LogFormat::Json,
+    Some(other) => {
+      eprintln!("Unknown LOG_FORMAT {}, logging text", other);
+      LogFormat::Text
+    }
+  }
+}
+
+/// What's logged, as set by `LOG_LEVEL`
+pub fn log_filter() -> EnvFilter {
+  let directives = crate::config_value("LOG_LEVEL")
+    .unwrap_or_else(|e| {
+      eprintln!("{}, logging info", e);
+      None
+    })
+    .unwrap_or_else(|| "info".to_string());
+  EnvFilter::try_new(&directives).unwrap_or_else(|e| {
+    eprintln!("Invalid LOG_LEVEL {}, logging info: {}", directives, e);
+    EnvFilter::new("info")
+  })
+}
+
+pub fn init_logger() {
+  init_logger_with(tracing_subscriber::layer::Identity::new());
+}
+
+/// Like [init_logger], with another layer keeping track of spans (filtered
+/// on its own, it sees spans whatever the `LOG_LEVEL`)
+pub fn init_logger_with<L>(layer: L)
+where
+  L: Layer<Registry> + Send + Sync + 'static,
+{
+  tracing_subscriber::registry()
+    .with(layer)
+    .with(output().with_filter(log_filter()))
+    .with(otlp().map(|otlp| otlp.with_filter(log_filter())))
+    .init();
+}
+
+fn output<S>() -> Box<dyn Layer<S> + Send + Sync>
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  match log_format() {
+    LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+    LogFormat::Json => JsonLayer::new(std::io::stdout).boxed(),
+  }
+}
+
+#[cfg(feature = "otlp")]
+static TRACER_PROVIDER: std::sync::OnceLock<
+  opentelemetry_sdk::trace::SdkTracerProvider,
+> = std::sync::OnceLock::new();
+
+/// Exporting spans to `OTEL_EXPORTER_OTLP_ENDPOINT`, if set
+#[cfg(feature = "otlp")]
+fn otlp<S>() -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+  S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+  use opentelemetry::trace::TracerProvider;
+  use opentelemetry_otlp::WithExportConfig;
+
+  let endpoint = match crate::config_value("OTEL_EXPORTER_OTLP_ENDPOINT") {
+    Ok(Some(endpoint)) => endpoint,
+    Ok(None) => return None,
+    Err(e) => {
+      eprintln!("Not exporting spans: {}", e);
+      return None;
+    }
+  };
+  let exporter = match opentelemetry_otlp::SpanExporter::builder()
+    .with_tonic()
+    .with_endpoint(endpoint)
+    .build()
+  {
+    Ok(exporter) => exporter,
+    Err(e) => {
+      eprintln!("Not exporting spans: {}", e);
+      return None;
+    }
+  };
+  let resource = opentelemetry_sdk::Resource::builder()
+    .with_service_name(crate::service_name())
+    .build();
+  let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+    .with_batch_exporter(exporter)
+    .with_resource(resource)
+    .build();
+  let tracer = provider.tracer("biab");
+  let _ = TRACER_PROVIDER.set(provider);
+  Some(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp<S>() -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+  S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+  if let Ok(Some(_)) = crate::config_value("OTEL_EXPORTER_OTLP_ENDPOINT") {
+    eprintln!(
+      "Not exporting spans: OTEL_EXPORTER_OTLP_ENDPOINT needs a build with \
+       the biab_utils/otlp feature"
+    );
+  }
+  None
+}
+
+/// Send the spans that haven't been exported yet, if they are
+pub fn flush_traces() {
+  #[cfg(feature = "otlp")]
+  if let Some(provider) = TRACER_PROVIDER.get() {
+    if let Err(e) = provider.force_flush() {
+      log::warn!("Failed to export spans: {}", e);
+    }
+  }
+}
+
+/// Writes each record as a line of JSON
+pub struct JsonLayer<W> {
+  service: String,
+  writer: W,
+}
+
+impl<W: for<'a> MakeWriter<'a>> JsonLayer<W> {
+  pub fn new(writer: W) -> Self {
+    Self {
+      service: crate::service_name(),
+      writer,
+    }
+  }
+}
+
+/// The fields of a span or record
+#[derive(Default)]
+struct JsonFields(Map<String, Value>);
+
+impl JsonFields {
+  fn insert(&mut self, field: &Field, value: Value) {
+    // added by `log` records, and part of the line already
+    if !field.name().starts_with("log.") {
+      self.0.insert(field.name().to_string(), value);
+    }
+  }
+}
+
+impl Visit for JsonFields {
+  fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+    self.insert(field, format!("{:?}", value).into());
+  }
+
+  fn record_str(&mut self, field: &Field, value: &str) {
+    self.insert(field, value.into());
+  }
+
+  fn record_i64(&mut self, field: &Field, value: i64) {
+    self.insert(field, value.into());
+  }
+
+  fn record_u64(&mut self, field: &Field, value: u64) {
+    self.insert(field, value.into());
+  }
+
+  fn record_f64(&mut self, field: &Field, value: f64) {
+    self.insert(field, value.into());
+  }
+
+  fn record_bool(&mut self, field: &Field, value: bool) {
+    self.insert(field, value.into());
+  }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+  W: for<'a> MakeWriter<'a> + 'static,
+{
+  fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+    if let Some(span) = ctx.span(id) {
+      let mut fields = JsonFields::default();
+      attrs.record(&mut fields);
+      span.extensions_mut().insert(fields);
+    }
+  }
+
+  fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+    if let Some(span) = ctx.span(id) {
+      if let Some(fields) = span.extensions_mut().get_mut::<JsonFields>() {
+        values.record(fields);
+      }
+    }
+  }
+
+  fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+    // where records logged with `log` come from
+    let normalized = event.normalized_metadata();
+    let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+    let mut spans = Vec::new();
+    let mut fields = Map::new();
+    if let Some(scope) = ctx.event_scope(event) {
+      for span in scope.from_root() {
+        spans.push(span.name());
+        if let Some(span_fields) = span.extensions().get::<JsonFields>() {
+          fields.extend(span_fields.0.clone());
+        }
+      }
+    }
+    let mut event_fields = JsonFields::default();
+    event.record(&mut event_fields);
+    let message = event_fields.0.remove("message").unwrap_or_default();
+    fields.extend(event_fields.0);
+    let line = serde_json::json!({
+      "timestamp": chrono::Utc::now()
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+      "level": metadata.level().as_str(),
+      "service": self.service,
+      "module": metadata.module_path().unwrap_or(metadata.target()),
+      "message": message,
+      "spans": spans,
+      "fields": fields,
+    });
+    let _ = writeln!(self.writer.make_writer(), "{}", line);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  #[derive(Clone, Default)]
+  struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_json_line() {
+    let buffer = Buffer::default();
+    let output = buffer.clone();
+    let layer = JsonLayer::new(move || output.clone());
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+      let span = tracing::info_span!("pulse", index = 3u64);
+      let _entered = span.enter();
+      tracing::warn!(strand = "abc", late = true, "Pulse {} is late", 3);
+    });
+    let written = buffer.0.lock().unwrap().clone();
+    let line: Value = serde_json::from_slice(&written).unwrap();
+    assert_eq!(line["level"], "WARN");
+    assert_eq!(line["module"], module_path!());
+    assert_eq!(line["message"], "Pulse 3 is late");
+    assert_eq!(line["spans"], serde_json::json!(["pulse"]));
+    assert_eq!(line["fields"]["index"], 3);
+    assert_eq!(line["fields"]["strand"], "abc");
+    assert_eq!(line["fields"]["late"], true);
+    assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+  }
+}
@@ -1,19 +1,15 @@
-use anyhow::Result;
+use crate::dedup::RecentMessages;
+use crate::frame_auth::{frame_tag, reject_frame, verify_frame, TAG_LEN};
+use crate::framing::{header, message_len, HEADER_LEN};
+use crate::{frame_key, max_frame_bytes, MessageStream, PING_COMMAND};
+use crate::{wire_format, WireFormat};
+use anyhow::{anyhow, Result};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::io::Read;
 use std::sync::Arc;
-use std::sync::RwLock;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::TcpStream;
-
-fn encode<T: Serialize>(data: &T) -> Result<Vec<u8>> {
-  rmp_serde::to_vec(data).map_err(|e| e.into())
-}
-
-fn decode<T: DeserializeOwned, R: Read>(data: R) -> Result<T> {
-  rmp_serde::decode::from_read(data).map_err(|e| e.into())
-}
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 
 #[derive(Debug, Clone, Hash, Serialize, Deserialize)]
 pub struct Message {
@@ -21,6 +17,18 @@ pub struct Message {
   pub timestamp: chrono::DateTime<chrono::Utc>,
   pub command: String,
   pub payload: Option<Vec<u8>>,
+  /// Whether the sender waits for an [Ack]. Only sent when it does, so
+  /// other messages can still be read by older services.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub ack: bool,
+  /// Who sent it, if they presented a TLS certificate (see
+  /// [crate::MessageStream::peer]). Noted on receipt, never sent.
+  #[serde(skip)]
+  pub peer: Option<String>,
+  /// How it and its payload are encoded (see [WireFormat]). Sent in the
+  /// frame header, not in the message.
+  #[serde(skip)]
+  pub format: WireFormat,
 }
 
 impl Message {
@@ -28,11 +36,219 @@ impl Message {
     self
       .payload
       .as_ref()
-      .map(|p| decode(p.as_slice()))
+      .map(|p| self.format.decode(p.as_slice()))
       .transpose()
   }
 }
 
+/// The command messages are acknowledged with (see [Messenger::send_with_ack])
+pub const ACK_COMMAND: &str = "ack";
+
+/// How long a peer has to acknowledge a message
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A message was taken by the service it was sent to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ack {
+  /// The id of the message acknowledged
+  pub id: uuid::Uuid,
+  /// Why it couldn't be handled, if it couldn't
+  pub error: Option<String>,
+}
+
+/// The command randomness is delivered to the generator with
+pub const RANDOMNESS_COMMAND: &str = "randomness";
+/// The command the generator asks the rng factory for randomness with
+pub const NEED_ENTROPY_COMMAND: &str = "need-entropy";
+
+/// The command the generator advertises its pulse period with
+pub const STRAND_PERIOD_COMMAND: &str = "strand-period";
+
+/// The generator's pulse period, so deliveries can keep pace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StrandPeriod {
+  pub millis: u64,
+}
+
+/// The command the generator acknowledges taking randomness with
+pub const CONSUMED_COMMAND: &str = "randomness-consumed";
+
+/// The generator took randomness for a pulse, so deliveries should go on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Consumed {
+  /// The delivery it took, or none if there wasn't one to take
+  pub id: Option<uuid::Uuid>,
+}
+
+/// The topic the generator publishes each new pulse on (see [crate::Topics])
+pub const PULSE_PUBLISHED_TOPIC: &str = "pulse_published";
+
+/// A pulse the generator published
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PulsePublished {
+  /// The cid of its strand
+  pub strand: String,
+  pub index: u64,
+  /// The cid of the pulse
+  pub cid: String,
+}
+
+/// The command the rng factory's pool status is asked for and answered with
+pub const POOL_STATUS_COMMAND: &str = "pool-status";
+
+/// How full the rng factory's pool is, and how fresh
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PoolStatus {
+  /// Bytes in the pool
+  pub level: usize,
+  pub capacity: usize,
+  /// The level it's topped up at
+  pub low_watermark: usize,
+  /// How long the oldest and newest bytes have been in the pool
+  pub oldest_secs: Option<f64>,
+  pub newest_secs: Option<f64>,
+  /// Bytes discarded for being too old
+  pub expired_bytes: u64,
+  /// Deliveries that waited for the pool to be filled
+  pub starved_deliveries: u64,
+}
+
+/// The command the rng factory's status is asked for and answered with
+pub const RNG_STATUS_COMMAND: &str = "rng-status";
+
+/// How an entropy source of the rng factory is doing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceStatus {
+  pub name: String,
+  /// Whether its last read succeeded
+  pub healthy: bool,
+  pub health: SourceHealth,
+  /// The share of reads that passed the health tests, once there are some
+  pub pass_rate: Option<f64>,
+  pub last_error: Option<String>,
+}
+
+/// The rng factory's connection to the generator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkStatus {
+  pub connected: bool,
+  /// Failed attempts to connect in a row
+  pub failures: u32,
+  /// Deliveries waiting to be sent
+  pub buffered: usize,
+  pub last_error: Option<String>,
+}
+
+/// How the rng factory is doing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RngStatus {
+  /// Whether any of its sources can be read
+  pub healthy: bool,
+  pub sources: Vec<SourceStatus>,
+  /// When randomness last reached the generator
+  pub last_delivery: Option<chrono::DateTime<chrono::Utc>>,
+  /// When deliveries are pushed to the generator
+  pub generator: Option<LinkStatus>,
+  /// Whether deliveries are held back as the generator isn't taking them
+  #[serde(default)]
+  pub paused: bool,
+  pub pool: PoolStatus,
+}
+
+/// A request for fresh randomness, answered with a [RandomnessDelivery]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntropyRequest {
+  pub bytes: usize,
+}
+
+/// How raw entropy was conditioned into a delivery
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conditioning {
+  /// eg: `sha3-512` or `hmac-drbg-sha512`
+  pub function: String,
+  /// Raw bytes of entropy per output (or seed)
+  pub input_bytes: usize,
+  /// Outputs between reseeds, for DRBGs
+  pub reseed_interval: Option<u64>,
+}
+
+/// The health test results of an entropy source, since it started
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceHealth {
+  pub samples: u64,
+  pub repetition_failures: u64,
+  pub proportion_failures: u64,
+}
+
+/// What's known about an entropy source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceAttestation {
+  pub name: String,
+  /// eg: the manufacturer and product of a USB TRNG
+  pub device: Option<String>,
+  pub serial: Option<String>,
+  pub firmware: Option<String>,
+  pub health: SourceHealth,
+  /// Whether it contributed to the delivery
+  pub contributed: bool,
+}
+
+/// Where a delivery's randomness came from, for auditing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attestation {
+  /// The assessed min-entropy per byte of raw output
+  pub min_entropy: f64,
+  pub sources: Vec<SourceAttestation>,
+}
+
+impl Attestation {
+  /// The JSON its digest is of
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self).expect("Failed to serialize attestation")
+  }
+
+  /// The SHA-256 of its JSON
+  pub fn digest(&self) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, self.to_json().as_bytes())
+      .as_ref()
+      .to_vec()
+  }
+}
+
+/// Proof a delivery came from the rng factory (see [DeliveryAuth])
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Authentication {
+  /// `hmac-sha256` or `ed25519`
+  pub method: String,
+  pub tag: Vec<u8>,
+}
+
+/// Randomness sent by the rng factory to the generator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RandomnessDelivery {
+  /// Identifies the delivery, eg: in the rng factory's archive
+  #[serde(default)]
+  pub id: Option<uuid::Uuid>,
+  pub bytes: Vec<u8>,
+  /// The entropy source it came from, or how several were combined
+  pub source: String,
+  /// The sources that contributed to it
+  #[serde(default)]
+  pub sources: Vec<String>,
+  #[serde(default)]
+  pub conditioning: Option<Conditioning>,
+  /// The id of the request message it answers, if requested
+  #[serde(default)]
+  pub request_id: Option<uuid::Uuid>,
+  /// When the rng factory produced it
+  #[serde(default)]
+  pub issued_at: Option<chrono::DateTime<chrono::Utc>>,
+  #[serde(default)]
+  pub attestation: Option<Attestation>,
+  #[serde(default)]
+  pub authentication: Option<Authentication>,
+}
+
 impl AsRef<Message> for Message {
   fn as_ref(&self) -> &Message {
     self
@@ -41,20 +257,17 @@ impl AsRef<Message> for Message {
 
 #[derive(Debug, Clone)]
 pub struct Messenger {
-  latest: Arc<RwLock<Option<Message>>>,
+  /// Messages received lately, to ignore repeats (see [crate::dedup])
+  recent: Arc<Mutex<RecentMessages>>,
 }
 
 impl Messenger {
   pub fn new() -> Self {
     Self {
-      latest: Arc::new(RwLock::new(None)),
+      recent: Arc::new(Mutex::new(RecentMessages::from_env())),
     }
   }
 
-  pub fn latest(&self) -> Option<Message> {
-    self.latest.read().expect("Failed to acquire lock").clone()
-  }
-
   pub fn text(&self, command: &str) -> Message {
     self.prepare(command, None::<&()>)
   }
@@ -63,83 +276,225 @@ impl Messenger {
     self.prepare(command, Some(payload))
   }
 
-  fn prepare<T: Serialize>(&self, command: &str, payload: Option<&T>) -> Message {
+  /// A reply to `request`, in the format it was sent in so older peers can
+  /// read it
+  pub fn answer<T: Serialize>(
+    &self,
+    request: &Message,
+    command: &str,
+    payload: &T,
+  ) -> Message {
+    self.prepare_as(request.format, command, Some(payload))
+  }
+
+  fn prepare<T: Serialize>(
+    &self,
+    command: &str,
+    payload: Option<&T>,
+  ) -> Message {
+    self.prepare_as(wire_format(), command, payload)
+  }
+
+  fn prepare_as<T: Serialize>(
+    &self,
+    format: WireFormat,
+    command: &str,
+    payload: Option<&T>,
+  ) -> Message {
     let id = uuid::Uuid::new_v4();
     let timestamp = chrono::Utc::now();
-    let payload = payload.map(|p| encode(p).expect("Failed to serialize payload"));
+    let payload =
+      payload.map(|p| format.encode(p).expect("Failed to serialize payload"));
     let message = Message {
       id,
       timestamp,
       command: command.to_string(),
       payload,
+      ack: false,
+      peer: None,
+      format,
     };
     message
   }
 
-  pub async fn send_text(&self, stream: &mut TcpStream, command: &str) -> tokio::io::Result<()> {
+  pub async fn send_text<S: AsyncWrite + Unpin>(
+    &self,
+    stream: &mut S,
+    command: &str,
+  ) -> tokio::io::Result<()> {
     self.send(stream, self.text(command)).await
   }
 
-  pub async fn send_delivery<T: Serialize>(
+  pub async fn send_delivery<T: Serialize, S: AsyncWrite + Unpin>(
     &self,
-    stream: &mut TcpStream,
+    stream: &mut S,
     command: &str,
     payload: &T,
   ) -> tokio::io::Result<()> {
     self.send(stream, self.delivery(command, payload)).await
   }
 
-  /// Asynchronously send a message over a TCP stream
-  async fn send<M: AsRef<Message>>(
+  /// Asynchronously send a message over a stream (see [crate::MessageStream])
+  pub async fn send<M: AsRef<Message>, S: AsyncWrite + Unpin>(
     &self,
-    stream: &mut TcpStream,
+    stream: &mut S,
     message: M,
   ) -> tokio::io::Result<()> {
-    let serialized = encode(message.as_ref()).expect("Failed to serialize message");
-    let len = serialized.len() as u32;
+    let message = message.as_ref();
+    let serialized = message
+      .format
+      .encode(message)
+      .expect("Failed to serialize message");
+    let header = header(message.format, serialized.len()).map_err(|e| {
+      tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, e.to_string())
+    })?;
+    let key =
+      frame_key().map_err(|e| tokio::io::Error::other(e.to_string()))?;
 
     let mut writer = BufWriter::new(stream);
-    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(&header).await?;
     writer.write_all(&serialized).await?;
+    // authenticate the frame if there's a key (see [crate::frame_key])
+    if let Some(key) = key {
+      writer.write_all(&frame_tag(key, &serialized)).await?;
+    }
     writer.flush().await?;
     Ok(())
   }
 
-  /// Asynchronously receive a message from a TCP stream
-  pub async fn receive(&self, stream: &mut TcpStream) -> Option<Message> {
-    let mut reader = BufReader::new(stream);
+  /// Send a message and wait up to `timeout` for the peer to acknowledge
+  /// it, failing if it doesn't or couldn't handle it
+  pub async fn send_with_ack(
+    &self,
+    stream: &mut MessageStream,
+    mut message: Message,
+    timeout: Duration,
+  ) -> Result<()> {
+    message.ack = true;
+    self.send(stream, &message).await?;
+    let response = tokio::time::timeout(timeout, self.receive(stream))
+      .await
+      .map_err(|_| anyhow!("{} wasn't acknowledged in time", message.command))?
+      .ok_or_else(|| anyhow!("{} wasn't acknowledged", message.command))?;
+    if response.command != ACK_COMMAND {
+      return Err(anyhow!("Expected an ack, got {}", response.command));
+    }
+    let ack: Ack = response
+      .extract_payload()?
+      .ok_or_else(|| anyhow!("Received an ack without a payload"))?;
+    if ack.id != message.id {
+      return Err(anyhow!("Received an ack for another message"));
+    }
+    match ack.error {
+      Some(e) => Err(anyhow!("{} failed: {}", message.command, e)),
+      None => Ok(()),
+    }
+  }
 
-    let mut len_buf = [0; 4];
-    reader.read_exact(&mut len_buf).await.ok()?;
-    let len = u32::from_be_bytes(len_buf) as usize;
+  /// Acknowledge a message with how handling it went, if the sender asked
+  pub async fn acknowledge<S: AsyncWrite + Unpin>(
+    &self,
+    stream: &mut S,
+    message: &Message,
+    handled: &Result<()>,
+  ) -> tokio::io::Result<()> {
+    if !message.ack {
+      return Ok(());
+    }
+    let ack = Ack {
+      id: message.id,
+      error: handled.as_ref().err().map(|e| e.to_string()),
+    };
+    self
+      .send(stream, self.answer(message, ACK_COMMAND, &ack))
+      .await
+  }
 
-    let mut data_buf = vec![0; len];
-    reader.read_exact(&mut data_buf).await.ok()?;
+  /// Asynchronously receive a message from a stream, noting who sent it and
+  /// skipping any received before. Pings are acknowledged rather than
+  /// returned (see [crate::heartbeat]).
+  pub async fn receive(&self, stream: &mut MessageStream) -> Option<Message> {
+    let sender = match (stream.peer(), stream.remote_addr()) {
+      (Some(name), _) => name,
+      (None, Some(address)) => address.ip().to_string(),
+      (None, None) => "unknown".to_string(),
+    };
+    loop {
+      let message = self.read_message(stream).await?;
+      let checked = self.recent.lock().expect("Failed to acquire lock").check(
+        &sender,
+        &message,
+        chrono::Utc::now(),
+      );
+      match checked {
+        Ok(()) if message.command == PING_COMMAND => {
+          let answered = self.acknowledge(stream, &message, &Ok(())).await;
+          if let Err(e) = answered {
+            log::warn!("Failed to answer a ping from {}: {}", sender, e);
+            return None;
+          }
+        }
+        Ok(()) => return Some(message),
+        Err(e) => log::warn!("Ignoring a message from {}: {}", sender, e),
+      }
+    }
+  }
 
-    let message: Message = match decode(data_buf.as_slice()) {
-      Ok(message) => message,
+  async fn read_message(&self, stream: &mut MessageStream) -> Option<Message> {
+    let peer = stream.peer();
+    // read unbuffered, so nothing of the next frame is lost
+    let mut header = [0; HEADER_LEN];
+    if let Err(e) = stream.read_exact(&mut header).await {
+      // the peer closing the connection between messages is how it ends
+      if e.kind() != std::io::ErrorKind::UnexpectedEof {
+        log::warn!("Failed to read a message: {}", e);
+      }
+      return None;
+    }
+    // the frame isn't read any further, so the caller closes the connection
+    let (format, len) = match message_len(&header, max_frame_bytes()) {
+      Ok(frame) => frame,
       Err(e) => {
-        log::error!("Failed to deserialize message: {}", e);
+        log::warn!("Rejected a frame: {}", e);
         return None;
       }
     };
 
-    match &*self.latest.read().expect("Failed to acquire lock") {
-      Some(latest) => {
-        if message.timestamp < latest.timestamp {
-          log::warn!("Received message with older timestamp");
-          return None;
-        }
-        if message.id == latest.id {
-          log::warn!("Received duplicate message. Ignoring.");
-          return None;
-        }
+    let mut data_buf = vec![0; len];
+    if let Err(e) = stream.read_exact(&mut data_buf).await {
+      log::warn!("Failed to read a message: {}", e);
+      return None;
+    }
+
+    let key = match frame_key() {
+      Ok(key) => key,
+      Err(e) => {
+        log::error!("{}", e);
+        return None;
       }
-      None => {}
     };
+    if let Some(key) = key {
+      let mut tag = [0; TAG_LEN];
+      if stream.read_exact(&mut tag).await.is_err() {
+        reject_frame("it has no tag");
+        return None;
+      }
+      if !verify_frame(key, &data_buf, &tag) {
+        return None;
+      }
+    }
 
-    // Store the latest received message
-    *self.latest.write().expect("Failed to acquire lock") = Some(message.clone());
+    let message: Message = match format.decode(data_buf.as_slice()) {
+      Ok(message) => Message {
+        peer,
+        format,
+        ..message
+      },
+      Err(e) => {
+        log::error!("Failed to deserialize message: {}", e);
+        return None;
+      }
+    };
 
     Some(message)
   }
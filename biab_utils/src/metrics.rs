@@ -0,0 +1,156 @@
+//! Prometheus metrics, the same way in every service.
+//!
+//! Metrics are registered with the default registry by the helpers here,
+//! named after the service recording them (eg: `data_sync_syncs_total`) or
+//! `biab_` for the ones recorded by this crate in any service (eg:
+//! `biab_open_connections`), with their unit last (`_seconds`, `_bytes`, or
+//! `_total` for counters).
+//!
+//! Services serve them at `GET /metrics` on `METRICS_ADDR` (eg:
+//! `0.0.0.0:9100`), if set, with [start_metrics_server]. The HTTP portal
+//! serves them with its API instead.
+use anyhow::{anyhow, Result};
+use prometheus::{
+  Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+  IntGauge, IntGaugeVec, Opts, TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(
+  name: &str,
+  metric: prometheus::Result<M>,
+) -> M {
+  let metric = metric.unwrap_or_else(|e| panic!("Invalid {}: {}", name, e));
+  prometheus::register(Box::new(metric.clone()))
+    .unwrap_or_else(|e| panic!("Failed to register {}: {}", name, e));
+  metric
+}
+
+pub fn int_counter(name: &str, help: &str) -> IntCounter {
+  register(name, IntCounter::new(name, help))
+}
+
+pub fn int_counter_vec(
+  name: &str,
+  help: &str,
+  labels: &[&str],
+) -> IntCounterVec {
+  register(name, IntCounterVec::new(Opts::new(name, help), labels))
+}
+
+pub fn int_gauge(name: &str, help: &str) -> IntGauge {
+  register(name, IntGauge::new(name, help))
+}
+
+pub fn int_gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+  register(name, IntGaugeVec::new(Opts::new(name, help), labels))
+}
+
+pub fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> GaugeVec {
+  register(name, GaugeVec::new(Opts::new(name, help), labels))
+}
+
+/// A histogram with the default buckets (5ms to 10s), unless given others
+pub fn histogram_vec(
+  name: &str,
+  help: &str,
+  labels: &[&str],
+  buckets: Option<Vec<f64>>,
+) -> HistogramVec {
+  let mut opts = HistogramOpts::new(name, help);
+  if let Some(buckets) = buckets {
+    opts = opts.buckets(buckets);
+  }
+  register(name, HistogramVec::new(opts, labels))
+}
+
+/// Every metric registered, in the text exposition format
+pub fn encode_metrics() -> Result<Vec<u8>> {
+  let mut buffer = Vec::new();
+  TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+  Ok(buffer)
+}
+
+/// A response with every metric registered
+pub fn metrics_response() -> warp::reply::Response {
+  match encode_metrics() {
+    Ok(buffer) => {
+      warp::reply::with_header(buffer, "content-type", prometheus::TEXT_FORMAT)
+        .into_response()
+    }
+    Err(e) => {
+      log::error!("Failed to encode metrics: {}", e);
+      warp::reply::with_status(
+        "failed to encode metrics",
+        StatusCode::INTERNAL_SERVER_ERROR,
+      )
+      .into_response()
+    }
+  }
+}
+
+/// `METRICS_ADDR`, if set
+pub fn metrics_address() -> Result<Option<SocketAddr>> {
+  match crate::config_value("METRICS_ADDR")? {
+    Some(addr) => Ok(Some(
+      addr
+        .parse()
+        .map_err(|e| anyhow!("Invalid METRICS_ADDR: {}", e))?,
+    )),
+    None => Ok(None),
+  }
+}
+
+/// Serve the metrics at `GET /metrics` on `METRICS_ADDR` until shutdown, if
+/// set
+pub fn start_metrics_server(shutdown: Arc<Notify>) -> Result<()> {
+  let nothing_else = warp::any()
+    .and_then(|| async { Err::<String, _>(warp::reject::not_found()) });
+  start_metrics_server_with(shutdown, nothing_else)
+}
+
+/// Like [start_metrics_server], also serving `routes` (eg: a status page)
+pub fn start_metrics_server_with<F, R>(
+  shutdown: Arc<Notify>,
+  routes: F,
+) -> Result<()>
+where
+  F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+  R: Reply,
+{
+  let Some(addr) = metrics_address()? else {
+    return Ok(());
+  };
+  let routes = warp::get()
+    .and(warp::path!("metrics").map(metrics_response))
+    .or(routes);
+  let (addr, server) = warp::serve(routes)
+    .try_bind_with_graceful_shutdown(addr, async move {
+      shutdown.notified().await
+    })?;
+  log::info!("Serving metrics at http://{}/metrics", addr);
+  tokio::spawn(server);
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_encode_metrics() {
+    let counter = int_counter_vec(
+      "biab_test_events_total",
+      "Events counted by the test",
+      &["kind"],
+    );
+    counter.with_label_values(&["a"]).inc_by(2);
+    let text = String::from_utf8(encode_metrics().unwrap()).unwrap();
+    assert!(text.contains("# HELP biab_test_events_total"));
+    assert!(text.contains("biab_test_events_total{kind=\"a\"} 2"));
+  }
+}
@@ -0,0 +1,146 @@
+//! Events through a NATS server (see [crate::events]).
+//!
+//! Each event is sent as its message, encoded as it would be in a frame,
+//! with the frame version in a `Biab-Message-Version` header. NATS
+//! authenticates and encrypts the connection itself (eg: credentials and a
+//! `tls://` scheme in `NATS_URL`), so frames aren't tagged with
+//! `MESSAGE_HMAC_KEY`.
+use crate::{config_value, redact_url, EventBus, Message, WireFormat};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Notify};
+
+const VERSION_HEADER: &str = "Biab-Message-Version";
+/// Events received but not yet taken before the subscription waits
+const SUBSCRIBER_CAPACITY: usize = 32;
+
+pub struct NatsEvents {
+  client: async_nats::Client,
+  prefix: String,
+  shutdown: Arc<Notify>,
+}
+
+impl NatsEvents {
+  /// Connected to `NATS_URL`, with subjects under `NATS_SUBJECT_PREFIX`
+  pub async fn from_env(shutdown: Arc<Notify>) -> Result<Self> {
+    let url = config_value("NATS_URL")?
+      .unwrap_or_else(|| "nats://nats:4222".to_string());
+    let prefix =
+      config_value("NATS_SUBJECT_PREFIX")?.unwrap_or_else(|| "biab".into());
+    let client = async_nats::connect(url.as_str()).await.map_err(|e| {
+      anyhow!("Failed to connect to {}: {}", redact_url(&url), e)
+    })?;
+    log::info!("Connected to NATS at {}", redact_url(&url));
+    Ok(Self {
+      client,
+      prefix,
+      shutdown,
+    })
+  }
+
+  fn subject(&self, topic: &str) -> String {
+    format!("{}.{}", self.prefix, topic)
+  }
+}
+
+fn decode(message: &async_nats::Message) -> Result<Message> {
+  let version = message
+    .headers
+    .as_ref()
+    .and_then(|headers| headers.get(VERSION_HEADER))
+    .ok_or_else(|| anyhow!("The event has no {} header", VERSION_HEADER))?
+    .as_str()
+    .parse::<u8>()?;
+  let format = WireFormat::from_version(version)
+    .ok_or_else(|| anyhow!("Unsupported protocol version {}", version))?;
+  let event: Message = format.decode(&message.payload)?;
+  Ok(Message { format, ..event })
+}
+
+#[async_trait]
+impl EventBus for NatsEvents {
+  async fn publish(&self, event: Message) -> Result<()> {
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(VERSION_HEADER, event.format.version().to_string());
+    let payload = event.format.encode(&event)?;
+    self
+      .client
+      .publish_with_headers(
+        self.subject(&event.command),
+        headers,
+        payload.into(),
+      )
+      .await?;
+    Ok(())
+  }
+
+  async fn subscribe(
+    &self,
+    topics: &[&str],
+  ) -> Result<mpsc::Receiver<Message>> {
+    let mut subscriptions = Vec::new();
+    for topic in topics {
+      subscriptions.push(self.client.subscribe(self.subject(topic)).await?);
+    }
+    let mut events = futures::stream::select_all(subscriptions);
+    let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+    let shutdown = self.shutdown.clone();
+    tokio::spawn(async move {
+      let stopping = shutdown.notified();
+      tokio::pin!(stopping);
+      stopping.as_mut().enable();
+      loop {
+        let message = tokio::select! {
+          _ = &mut stopping => break,
+          message = events.next() => match message {
+            Some(message) => message,
+            None => break,
+          }
+        };
+        match decode(&message) {
+          Ok(event) => {
+            if tx.send(event).await.is_err() {
+              break;
+            }
+          }
+          Err(e) => {
+            log::warn!("Ignoring an event on {}: {}", message.subject, e)
+          }
+        }
+      }
+    });
+    Ok(rx)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::Messenger;
+
+  #[test]
+  fn test_decode() {
+    let event = Messenger::new().delivery("pulse_published", &7u64);
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(VERSION_HEADER, event.format.version().to_string());
+    let message = async_nats::Message {
+      subject: "biab.pulse_published".into(),
+      reply: None,
+      payload: event.format.encode(&event).unwrap().into(),
+      headers: Some(headers),
+      status: None,
+      description: None,
+      length: 0,
+    };
+    let decoded = decode(&message).unwrap();
+    assert_eq!(decoded.id, event.id);
+    assert_eq!(decoded.extract_payload::<u64>().unwrap(), Some(7));
+    let message = async_nats::Message {
+      headers: None,
+      ..message
+    };
+    assert!(decode(&message).is_err());
+  }
+}
@@ -0,0 +1,173 @@
+//! Where the services find each other.
+//!
+//! The services message each other over TCP (see [Messenger]). Their
+//! addresses default to the docker compose service names but can be set
+//! with `<SERVICE>_ADDR` (eg: `DATA_SYNC_ADDR=sync.internal:5555`), as
+//! environment variables, files or docker secrets (see [config_value]), so
+//! they can run outside of compose.
+//!
+//! Host names are resolved again on every connection, so a peer that moves
+//! (eg: a restarted container with a new ip) is still found, and every
+//! address a name resolves to is tried. Failed connections are retried
+//! `PEER_CONNECT_ATTEMPTS` times (default 3) with a growing delay. Peers can
+//! be connected to with TLS (see [ClientTls]).
+use crate::{config_or, config_value, frame_key, ClientTls, MessageStream};
+use crate::{retry_with_backoff, Messenger, RetryPolicy};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+
+/// The address services listen for messages on, `LISTEN_ADDR`
+pub fn listen_address() -> Result<String> {
+  let address =
+    config_value("LISTEN_ADDR")?.unwrap_or_else(|| "0.0.0.0:5555".into());
+  validate(&address).map_err(|e| anyhow!("Invalid LISTEN_ADDR: {}", e))?;
+  Ok(address)
+}
+
+/// Check an address is a host (or ip) and port
+fn validate(address: &str) -> Result<()> {
+  let (host, port) = address
+    .rsplit_once(':')
+    .ok_or_else(|| anyhow!("{} has no port", address))?;
+  if host.is_empty() {
+    return Err(anyhow!("{} has no host", address));
+  }
+  port
+    .parse::<u16>()
+    .map_err(|_| anyhow!("{} has an invalid port", address))?;
+  Ok(())
+}
+
+/// Connections are retried after 250ms, then twice as long each time up to
+/// 16s
+const CONNECT_RETRY: RetryPolicy =
+  RetryPolicy::new(1, Duration::from_millis(250))
+    .with_max_delay(Duration::from_secs(16))
+    .with_log_level(log::Level::Debug);
+
+/// How long to wait before another attempt to connect
+pub fn backoff(attempt: u32) -> Duration {
+  CONNECT_RETRY
+    .without_jitter()
+    .delay(attempt.saturating_add(1))
+}
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+  address: String,
+  retry: RetryPolicy,
+  tls: Option<ClientTls>,
+}
+
+impl Peer {
+  /// The peer at the address in `var`, or else `default`
+  pub fn from_env(var: &str, default: &str) -> Result<Self> {
+    let address = config_value(var)?.unwrap_or_else(|| default.to_string());
+    validate(&address).map_err(|e| anyhow!("Invalid {}: {}", var, e))?;
+    let attempts = config_or("PEER_CONNECT_ATTEMPTS", 3u32)?.max(1);
+    let tls = ClientTls::from_env(var, &address)?;
+    frame_key()?;
+    Ok(Self {
+      address,
+      retry: RetryPolicy {
+        attempts,
+        ..CONNECT_RETRY
+      },
+      tls,
+    })
+  }
+
+  /// The data sync service, `DATA_SYNC_ADDR`
+  pub fn data_sync() -> Result<Self> {
+    Self::from_env("DATA_SYNC_ADDR", "data_sync:5555")
+  }
+
+  /// The pulse generator, `GENERATOR_ADDR`
+  pub fn generator() -> Result<Self> {
+    Self::from_env("GENERATOR_ADDR", "generator:5555")
+  }
+
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  async fn try_connect(&self) -> Result<MessageStream> {
+    let mut last_error = None;
+    let addrs = lookup_host(&self.address)
+      .await
+      .map_err(|e| anyhow!("{}: {}", self.address, e))?;
+    for addr in addrs {
+      match TcpStream::connect(addr).await {
+        Ok(stream) => return self.secure(stream).await,
+        Err(e) => {
+          last_error = Some(anyhow!("{} ({}): {}", self.address, addr, e))
+        }
+      }
+    }
+    Err(last_error.unwrap_or_else(|| {
+      anyhow!("{} did not resolve to any address", self.address)
+    }))
+  }
+
+  /// Start TLS on a connection to the peer, if configured
+  async fn secure(&self, stream: TcpStream) -> Result<MessageStream> {
+    match &self.tls {
+      Some(tls) => tls
+        .connect(stream)
+        .await
+        .map_err(|e| anyhow!("{}: {}", self.address, e)),
+      None => Ok(MessageStream::Plain(stream)),
+    }
+  }
+
+  /// Connect, resolving the address again and retrying a few times
+  pub async fn connect(&self) -> Result<MessageStream> {
+    retry_with_backoff(&self.retry, "Connecting", |_| self.try_connect())
+      .await
+      .map_err(|e| anyhow!("Failed to connect to {}", e))
+  }
+
+  /// Send a command to the peer
+  pub async fn send_text(&self, command: &str) -> Result<()> {
+    let mut stream = self.connect().await?;
+    Messenger::new().send_text(&mut stream, command).await?;
+    Ok(())
+  }
+
+  /// Send a command with a payload to the peer
+  pub async fn send_delivery<T: Serialize>(
+    &self,
+    command: &str,
+    payload: &T,
+  ) -> Result<()> {
+    let mut stream = self.connect().await?;
+    Messenger::new()
+      .send_delivery(&mut stream, command, payload)
+      .await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_validate() {
+    assert!(validate("data_sync:5555").is_ok());
+    assert!(validate("10.0.0.2:80").is_ok());
+    assert!(validate("[::1]:5555").is_ok());
+    assert!(validate("data_sync").is_err());
+    assert!(validate(":5555").is_err());
+    assert!(validate("data_sync:port").is_err());
+  }
+
+  #[test]
+  fn test_backoff() {
+    assert_eq!(backoff(0), Duration::from_millis(250));
+    assert_eq!(backoff(2), Duration::from_secs(1));
+    assert_eq!(backoff(100), Duration::from_secs(16));
+  }
+}
@@ -0,0 +1,240 @@
+//! Events published once on a topic and received by every service
+//! subscribed to it.
+//!
+//! A service serves its [Topics] with [start_tcp_server_with_topics]. Other
+//! services [subscribe] by connecting to it and sending a `subscribe`
+//! message naming the topics they want (see [Subscribe]); that connection is
+//! then only used to send them each event published on those topics, as a
+//! message whose command is the topic. Subscribers connect again when the
+//! connection drops, missing what was published meanwhile, so events are
+//! for waking services up early (eg: to sync a new pulse), not for anything
+//! they can't do without.
+use crate::{backoff, start_tcp_server_with, Message, Messenger, Peer};
+use crate::{heartbeat_interval, MessageStream, ACK_TIMEOUT};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+
+/// The command topics are subscribed to with
+pub const SUBSCRIBE_COMMAND: &str = "subscribe";
+
+/// The events published on topics that aren't taken yet by a slow
+/// subscriber, before it starts missing them
+const SUBSCRIBER_CAPACITY: usize = 32;
+
+/// The topics a connection subscribes to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscribe {
+  pub topics: Vec<String>,
+}
+
+struct Subscriber {
+  topics: HashSet<String>,
+  events: mpsc::Sender<Message>,
+}
+
+/// The subscribers to a service's topics
+#[derive(Clone, Default)]
+pub struct Topics {
+  subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Topics {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// The events published on `topics` from now on
+  pub fn subscribe(&self, topics: &[String]) -> mpsc::Receiver<Message> {
+    let (events, received) = mpsc::channel(SUBSCRIBER_CAPACITY);
+    let subscriber = Subscriber {
+      topics: topics.iter().cloned().collect(),
+      events,
+    };
+    self
+      .subscribers
+      .lock()
+      .expect("subscribers lock")
+      .push(subscriber);
+    received
+  }
+
+  /// How many subscribers there are to `topic`
+  pub fn subscribers(&self, topic: &str) -> usize {
+    let subscribers = self.subscribers.lock().expect("subscribers lock");
+    subscribers
+      .iter()
+      .filter(|subscriber| subscriber.topics.contains(topic))
+      .count()
+  }
+
+  /// Send an event to every subscriber to `topic`, returning how many it
+  /// was sent to
+  pub fn publish<T: Serialize>(&self, topic: &str, payload: &T) -> usize {
+    self.publish_message(Messenger::new().delivery(topic, payload))
+  }
+
+  /// Send an event to every subscriber to its topic (its command),
+  /// returning how many it was sent to
+  pub fn publish_message(&self, event: Message) -> usize {
+    let topic = event.command.as_str();
+    let mut subscribers = self.subscribers.lock().expect("subscribers lock");
+    // forget the subscribers that disconnected
+    subscribers.retain(|subscriber| !subscriber.events.is_closed());
+    let mut sent = 0;
+    for subscriber in subscribers.iter() {
+      if !subscriber.topics.contains(topic) {
+        continue;
+      }
+      match subscriber.events.try_send(event.clone()) {
+        Ok(()) => sent += 1,
+        Err(e) => log::warn!("Dropped a {} event: {}", topic, e),
+      }
+    }
+    sent
+  }
+}
+
+/// TCP Server to listen for messages, also serving `topics` to the
+/// connections that subscribe to them
+pub fn start_tcp_server_with_topics(
+  addr: String,
+  shutdown: Arc<Notify>,
+  topics: Topics,
+) -> mpsc::Receiver<Message> {
+  start_tcp_server_with(addr, shutdown, Some(topics))
+}
+
+/// The events a `subscribe` message asks for
+pub(crate) fn subscription(
+  topics: Option<&Topics>,
+  message: &Message,
+) -> Result<mpsc::Receiver<Message>> {
+  let topics = topics.ok_or_else(|| anyhow!("Nothing is published here"))?;
+  let subscribe: Subscribe = message
+    .extract_payload()?
+    .ok_or_else(|| anyhow!("The subscription has no topics"))?;
+  Ok(topics.subscribe(&subscribe.topics))
+}
+
+/// Send the events a connection subscribed to until it drops or the service
+/// shuts down
+pub(crate) async fn serve_subscriber(
+  messenger: &Messenger,
+  stream: &mut MessageStream,
+  mut events: mpsc::Receiver<Message>,
+  shutdown: &Notify,
+) -> Result<()> {
+  let stopping = shutdown.notified();
+  tokio::pin!(stopping);
+  stopping.as_mut().enable();
+  // pinged while there are no events, so a dead subscriber is noticed
+  let mut heartbeats = tokio::time::interval(heartbeat_interval());
+  heartbeats.reset();
+  loop {
+    let event = tokio::select! {
+      _ = &mut stopping => return Ok(()),
+      _ = heartbeats.tick() => {
+        messenger.ping(stream).await?;
+        continue;
+      }
+      event = events.recv() => match event {
+        Some(event) => event,
+        None => return Ok(()),
+      }
+    };
+    messenger.send(stream, event).await?;
+    heartbeats.reset();
+  }
+}
+
+/// The events published on `topics` by `peer`, subscribing again whenever
+/// the connection drops, until shut down
+pub fn subscribe(
+  peer: Peer,
+  topics: &[&str],
+  shutdown: Arc<Notify>,
+) -> mpsc::Receiver<Message> {
+  let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+  let subscribe = Subscribe {
+    topics: topics.iter().map(|topic| topic.to_string()).collect(),
+  };
+  tokio::spawn(async move {
+    let stopping = shutdown.notified();
+    tokio::pin!(stopping);
+    stopping.as_mut().enable();
+    let mut failures = 0;
+    loop {
+      tokio::select! {
+        _ = &mut stopping => break,
+        result = receive_events(&peer, &subscribe, &tx, &mut failures) => {
+          if tx.is_closed() {
+            break;
+          }
+          if let Err(e) = result {
+            log::warn!(
+              "Lost the subscription to {}: {}",
+              peer.address(),
+              e
+            );
+          }
+        }
+      }
+      tokio::select! {
+        _ = &mut stopping => break,
+        _ = tokio::time::sleep(backoff(failures)) => {}
+      }
+      failures += 1;
+    }
+  });
+  rx
+}
+
+async fn receive_events(
+  peer: &Peer,
+  subscribe: &Subscribe,
+  tx: &mpsc::Sender<Message>,
+  failures: &mut u32,
+) -> Result<()> {
+  let messenger = Messenger::new();
+  let mut stream = peer.connect().await?;
+  let request = messenger.delivery(SUBSCRIBE_COMMAND, subscribe);
+  messenger
+    .send_with_ack(&mut stream, request, ACK_TIMEOUT)
+    .await?;
+  log::info!(
+    "Subscribed to {} from {}",
+    subscribe.topics.join(", "),
+    peer.address()
+  );
+  *failures = 0;
+  // pings from the publisher keep the connection from going silent
+  while let Some(event) = messenger.receive_live(&mut stream).await {
+    if tx.send(event).await.is_err() {
+      return Ok(());
+    }
+  }
+  Err(anyhow!("The connection closed"))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_publish() {
+    let topics = Topics::new();
+    let mut pulses = topics.subscribe(&["pulse_published".to_string()]);
+    let others = topics.subscribe(&["other".to_string()]);
+    assert_eq!(topics.subscribers("pulse_published"), 1);
+    assert_eq!(topics.publish("pulse_published", &42u64), 1);
+    let event = pulses.recv().await.unwrap();
+    assert_eq!(event.command, "pulse_published");
+    assert_eq!(event.extract_payload::<u64>().unwrap(), Some(42));
+    drop(others);
+    assert_eq!(topics.publish("other", &()), 0);
+    assert_eq!(topics.subscribers("other"), 0);
+  }
+}
@@ -0,0 +1,217 @@
+//! Retrying operations that can fail for a while (eg: a database still
+//! starting, a dropped connection, an HSM session that timed out).
+//!
+//! A [RetryPolicy] says how many times to try, and how long to wait after
+//! each failure: the delay doubles each time, up to a maximum, and with
+//! jitter is cut to somewhere between half and all of it, so services
+//! failing together don't retry in lockstep. [retry_with_backoff] runs an
+//! async operation under a policy, [retry_if] only retries the errors worth
+//! retrying, and [retry_blocking] does the same for blocking operations.
+use rand::Rng;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+  /// How many times to try, at least once
+  pub attempts: u32,
+  /// The delay after the first failure
+  pub base_delay: Duration,
+  pub max_delay: Duration,
+  pub jitter: bool,
+  /// The level retries are logged at
+  pub log_level: log::Level,
+}
+
+impl RetryPolicy {
+  pub const fn new(attempts: u32, base_delay: Duration) -> Self {
+    Self {
+      attempts,
+      base_delay,
+      max_delay: Duration::MAX,
+      jitter: true,
+      log_level: log::Level::Warn,
+    }
+  }
+
+  pub const fn with_max_delay(mut self, max_delay: Duration) -> Self {
+    self.max_delay = max_delay;
+    self
+  }
+
+  pub const fn without_jitter(mut self) -> Self {
+    self.jitter = false;
+    self
+  }
+
+  pub const fn with_log_level(mut self, log_level: log::Level) -> Self {
+    self.log_level = log_level;
+    self
+  }
+
+  /// How long to wait after `failures` failures in a row
+  pub fn delay(&self, failures: u32) -> Duration {
+    let delay = self
+      .base_delay
+      .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+      .min(self.max_delay);
+    if self.jitter {
+      delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    } else {
+      delay
+    }
+  }
+
+  /// Log a failed attempt, returning how long to wait before the next one,
+  /// or None if it was the last one
+  fn failed<E: Display>(
+    &self,
+    operation: &str,
+    attempt: u32,
+    e: &E,
+  ) -> Option<Duration> {
+    if attempt >= self.attempts {
+      return None;
+    }
+    let delay = self.delay(attempt);
+    log::log!(
+      self.log_level,
+      "{} failed (attempt {}/{}), retrying in {:?}: {}",
+      operation,
+      attempt,
+      self.attempts,
+      delay,
+      e
+    );
+    Some(delay)
+  }
+}
+
+/// Call `f` with the number of the attempt (from 1) until it succeeds or
+/// the policy runs out of attempts, returning the last error
+pub async fn retry_with_backoff<T, E, F, Fut>(
+  policy: &RetryPolicy,
+  operation: &str,
+  f: F,
+) -> Result<T, E>
+where
+  E: Display,
+  F: FnMut(u32) -> Fut,
+  Fut: Future<Output = Result<T, E>>,
+{
+  retry_if(policy, operation, |_| true, f).await
+}
+
+/// Like [retry_with_backoff], returning errors that aren't `is_retryable`
+/// straight away
+pub async fn retry_if<T, E, F, Fut>(
+  policy: &RetryPolicy,
+  operation: &str,
+  is_retryable: impl Fn(&E) -> bool,
+  mut f: F,
+) -> Result<T, E>
+where
+  E: Display,
+  F: FnMut(u32) -> Fut,
+  Fut: Future<Output = Result<T, E>>,
+{
+  let mut attempt = 1;
+  loop {
+    match f(attempt).await {
+      Err(e) if is_retryable(&e) => match policy.failed(operation, attempt, &e)
+      {
+        Some(delay) => tokio::time::sleep(delay).await,
+        None => return Err(e),
+      },
+      res => return res,
+    }
+    attempt += 1;
+  }
+}
+
+/// Like [retry_if], for blocking operations (eg: HSM calls), sleeping the
+/// thread between attempts
+pub fn retry_blocking<T, E, F>(
+  policy: &RetryPolicy,
+  operation: &str,
+  is_retryable: impl Fn(&E) -> bool,
+  mut f: F,
+) -> Result<T, E>
+where
+  E: Display,
+  F: FnMut(u32) -> Result<T, E>,
+{
+  let mut attempt = 1;
+  loop {
+    match f(attempt) {
+      Err(e) if is_retryable(&e) => match policy.failed(operation, attempt, &e)
+      {
+        Some(delay) => std::thread::sleep(delay),
+        None => return Err(e),
+      },
+      res => return res,
+    }
+    attempt += 1;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_delay() {
+    let policy = RetryPolicy::new(5, Duration::from_secs(1))
+      .with_max_delay(Duration::from_secs(5))
+      .without_jitter();
+    assert_eq!(policy.delay(1), Duration::from_secs(1));
+    assert_eq!(policy.delay(3), Duration::from_secs(4));
+    assert_eq!(policy.delay(100), Duration::from_secs(5));
+
+    let policy = RetryPolicy::new(5, Duration::from_secs(10));
+    for _ in 0..10 {
+      let delay = policy.delay(2);
+      assert!(delay >= Duration::from_secs(10));
+      assert!(delay <= Duration::from_secs(20));
+    }
+  }
+
+  #[tokio::test]
+  async fn test_retry() {
+    let policy = RetryPolicy::new(3, Duration::from_millis(1));
+    let mut calls = 0;
+    let res: Result<u32, String> =
+      retry_with_backoff(&policy, "test", |attempt| {
+        calls += 1;
+        async move {
+          if attempt < 3 {
+            Err("not yet".to_string())
+          } else {
+            Ok(attempt)
+          }
+        }
+      })
+      .await;
+    assert_eq!(res, Ok(3));
+    assert_eq!(calls, 3);
+
+    let res: Result<(), String> =
+      retry_with_backoff(&policy, "test", |_| async { Err("no".to_string()) })
+        .await;
+    assert!(res.is_err());
+
+    let mut calls = 0;
+    let res: Result<(), &str> = retry_blocking(
+      &policy,
+      "test",
+      |e| *e != "fatal",
+      |_| {
+        calls += 1;
+        Err("fatal")
+      },
+    );
+    assert_eq!(res, Err("fatal"));
+    assert_eq!(calls, 1);
+  }
+}
@@ -0,0 +1,78 @@
+//! Checking a signer works before relying on it.
+//!
+//! A signer given the wrong key (eg: an HSM key id that isn't the strand's
+//! key) would sign pulses nobody can verify, and a slow one (eg: a remote HSM)
+//! would miss the time pulses are due. [self_test] signs a known message a
+//! few times, checks the signatures with the signer's public key and times
+//! them, for services to check at startup.
+use anyhow::{anyhow, Result};
+use std::time::{Duration, Instant};
+use twine_protocol::twine_builder::Signer;
+use twine_protocol::twine_lib::crypto::PublicKey;
+use twine_protocol::twine_lib::errors::VerificationError;
+
+const MESSAGE: &[u8] = b"twine beacon signer self-test";
+
+#[derive(Debug, Clone)]
+pub struct SelfTest {
+  /// The slowest signature
+  pub max_latency: Duration,
+  pub mean_latency: Duration,
+  /// Whether the signatures were checked, which twine_lib can't do for some
+  /// keys (eg: 4096 bit RSA keys), so that pulses signed with them
+  /// can't be verified either
+  pub verified: bool,
+}
+
+/// Sign the known message `rounds` times (at least once), failing if any
+/// signature fails or doesn't verify
+pub fn self_test<S: Signer<Key = PublicKey>>(
+  signer: &S,
+  rounds: u32,
+) -> Result<SelfTest> {
+  let public_key = signer.public_key();
+  let rounds = rounds.max(1);
+  let mut total = Duration::ZERO;
+  let mut max_latency = Duration::ZERO;
+  let mut verified = true;
+  for _ in 0..rounds {
+    let start = Instant::now();
+    let signature = signer
+      .sign(MESSAGE)
+      .map_err(|e| anyhow!("Self-test signing failed: {}", e.0))?;
+    let latency = start.elapsed();
+    total += latency;
+    max_latency = max_latency.max(latency);
+    match public_key.verify(signature, MESSAGE) {
+      Ok(()) => {}
+      Err(VerificationError::UnsupportedKeyAlgorithm) => verified = false,
+      Err(e) => {
+        return Err(anyhow!(
+          "Self-test signature doesn't verify with the signer's public key: {}",
+          e
+        ))
+      }
+    }
+  }
+  Ok(SelfTest {
+    max_latency,
+    mean_latency: total / rounds,
+    verified,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_signer::TestSigner;
+
+  #[test]
+  fn test_self_test() {
+    let result = self_test(&TestSigner::new(), 3).unwrap();
+    assert!(result.verified);
+    assert!(result.mean_latency <= result.max_latency);
+
+    let other = TestSigner::new();
+    assert!(self_test(&TestSigner::new().claiming(&other), 1).is_err());
+  }
+}
@@ -0,0 +1,381 @@
+use crate::{database_url, redact_url};
+use crate::{retry_if, retry_with_backoff, RetryPolicy};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::Stream;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use twine_protocol::twine_http_store::v2::HttpStore;
+use twine_protocol::twine_lib::{
+  as_cid::AsCid,
+  errors::{ResolutionError, StoreError},
+  resolver::{
+    unchecked_base::{BaseResolver, TwineStream},
+    AbsoluteRange, MaybeSend, Resolver,
+  },
+  store::Store,
+  twine::{AnyTwine, Strand, Tixel},
+  Cid,
+};
+use twine_sql_store::sqlx::MySqlPool;
+use twine_sql_store::SqlStore;
+
+/// The store type used by the services
+pub type DbStore = RetryingStore<SqlStore>;
+
+/// Open the SQL store using the configured database url.
+///
+/// The database may not be accepting connections yet (eg: when all
+/// containers start at once) so connecting is retried with exponential
+/// backoff up to `DB_CONNECT_ATTEMPTS` times (default 10).
+pub async fn open_store() -> Result<DbStore> {
+  open_store_with_retries(&database_url()?).await
+}
+
+/// How many times connecting to the database is tried,
+/// `DB_CONNECT_ATTEMPTS`
+pub(crate) fn connect_attempts() -> Result<u32> {
+  crate::config_or("DB_CONNECT_ATTEMPTS", 10u32)
+}
+
+/// How to retry connecting to a database that may still be starting
+fn connect_policy() -> Result<RetryPolicy> {
+  let attempts = connect_attempts()?;
+  Ok(
+    RetryPolicy::new(attempts, Duration::from_secs(1))
+      .with_max_delay(Duration::from_secs(30)),
+  )
+}
+
+/// Like [open_store], but for the database at `url`
+pub async fn open_store_with_retries(url: &str) -> Result<DbStore> {
+  let policy = connect_policy()?;
+  let store = retry_with_backoff(&policy, "Connecting to the database", |_| {
+    open_store_at(url)
+  })
+  .await?;
+
+  Ok(RetryingStore::new(store))
+}
+
+/// A connection pool to the configured database, for tables the store
+/// doesn't cover. Connecting is retried the same way as for [open_store].
+pub async fn open_pool() -> Result<MySqlPool> {
+  let url = database_url()?;
+  let policy = connect_policy()?;
+  retry_with_backoff(&policy, "Connecting to the database", |_| {
+    MySqlPool::connect(&url)
+  })
+  .await
+  .map_err(|e| {
+    anyhow::anyhow!(
+      "Failed to connect to database at {}: {}",
+      redact_url(&url),
+      e
+    )
+  })
+}
+
+pub async fn open_store_at(url: &str) -> Result<SqlStore> {
+  if !url.starts_with("mysql:") {
+    return Err(anyhow::anyhow!(
+      "Unsupported database url {}. Only mysql:// urls are supported",
+      redact_url(url)
+    ));
+  }
+
+  log::debug!("Connecting to database at {}", redact_url(url));
+  SqlStore::open(url).await.map_err(|e| {
+    anyhow::anyhow!(
+      "Failed to connect to database at {}: {}",
+      redact_url(url),
+      e
+    )
+  })
+}
+
+/// An HTTP client sending `api_key` as `Authorization: ApiKey <key>` with
+/// every request, if not empty
+pub fn http_client(
+  api_key: &str,
+) -> Result<twine_protocol::twine_http_store::reqwest::Client> {
+  use twine_protocol::twine_http_store::reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Client,
+  };
+  let mut headers = HeaderMap::new();
+  if !api_key.is_empty() {
+    let value = HeaderValue::from_str(&format!("ApiKey {}", api_key))
+      .map_err(|e| anyhow::anyhow!("Invalid API key: {}", e))?;
+    headers.insert(AUTHORIZATION, value);
+  }
+  Ok(Client::builder().default_headers(headers).build()?)
+}
+
+/// A remote twine HTTP store, authenticating with `api_key` if not empty
+pub fn open_http_store(url: &str, api_key: &str) -> Result<HttpStore> {
+  Ok(HttpStore::new(http_client(api_key)?).with_url(url))
+}
+
+fn is_transient_resolution(e: &ResolutionError) -> bool {
+  matches!(e, ResolutionError::Fetch(_))
+}
+
+fn is_transient_store(e: &StoreError) -> bool {
+  match e {
+    StoreError::Saving(_) => true,
+    StoreError::Fetching(e) => is_transient_resolution(e),
+    _ => false,
+  }
+}
+
+/// Called with the name of each store operation, how long it took
+/// (including retries) and whether it succeeded
+pub type StoreObserver = fn(&str, Duration, bool);
+
+/// Wraps a store and retries operations that fail with transient errors
+/// (eg: a dropped database connection) a few times before giving up.
+///
+/// Streams are not retried once they have been started.
+#[derive(Debug, Clone)]
+pub struct RetryingStore<R> {
+  inner: R,
+  policy: RetryPolicy,
+  observer: Option<StoreObserver>,
+}
+
+impl<R> RetryingStore<R> {
+  pub fn new(inner: R) -> Self {
+    Self {
+      inner,
+      policy: RetryPolicy::new(3, Duration::from_millis(250)),
+      observer: None,
+    }
+  }
+
+  pub fn with_attempts(mut self, attempts: u32) -> Self {
+    self.policy.attempts = attempts.max(1);
+    self
+  }
+
+  /// Report the duration of every operation, eg: for metrics
+  pub fn with_observer(mut self, observer: StoreObserver) -> Self {
+    self.observer = Some(observer);
+    self
+  }
+
+  pub fn inner(&self) -> &R {
+    &self.inner
+  }
+
+  async fn retry<T, E, F, Fut>(
+    &self,
+    operation: &str,
+    is_transient: fn(&E) -> bool,
+    mut f: F,
+  ) -> Result<T, E>
+  where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+  {
+    let start = Instant::now();
+    let res = retry_if(
+      &self.policy,
+      &format!("Store {}", operation),
+      is_transient,
+      |_| f(),
+    )
+    .await;
+    if let Some(observer) = self.observer {
+      observer(operation, start.elapsed(), res.is_ok());
+    }
+    res
+  }
+}
+
+impl RetryingStore<SqlStore> {
+  /// Save the first tixel of a strand mirrored from partway through, which
+  /// [Store::save] refuses without the tixel before it. The strand is saved
+  /// first and the tixel must verify against it. `pool` is a connection to
+  /// the store's database, as the store has no way to save it.
+  pub async fn save_first_tixel(
+    &self,
+    pool: &MySqlPool,
+    strand: &Strand,
+    tixel: &Tixel,
+  ) -> Result<(), StoreError> {
+    strand
+      .verify_tixel(tixel)
+      .map_err(|e| StoreError::Saving(e.to_string()))?;
+    self.save(strand.clone()).await?;
+    self
+      .retry("save_first_tixel", is_transient_store, || async move {
+        twine_sql_store::sqlx::query(
+          "INSERT IGNORE INTO Tixels (cid, data, strand, idx) \
+           SELECT ?, ?, s.id, ? FROM Strands s WHERE s.cid = ?",
+        )
+        .bind(tixel.cid().to_bytes())
+        .bind(tixel.bytes().to_vec())
+        .bind(tixel.index())
+        .bind(strand.cid().to_bytes())
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| StoreError::Saving(e.to_string()))
+      })
+      .await
+  }
+}
+
+#[async_trait]
+impl<R: BaseResolver> BaseResolver for RetryingStore<R> {
+  async fn has_index(
+    &self,
+    strand: &Cid,
+    index: u64,
+  ) -> Result<bool, ResolutionError> {
+    self
+      .retry("has_index", is_transient_resolution, || {
+        self.inner.has_index(strand, index)
+      })
+      .await
+  }
+
+  async fn has_twine(
+    &self,
+    strand: &Cid,
+    cid: &Cid,
+  ) -> Result<bool, ResolutionError> {
+    self
+      .retry("has_twine", is_transient_resolution, || {
+        self.inner.has_twine(strand, cid)
+      })
+      .await
+  }
+
+  async fn has_strand(&self, cid: &Cid) -> Result<bool, ResolutionError> {
+    self
+      .retry("has_strand", is_transient_resolution, || {
+        self.inner.has_strand(cid)
+      })
+      .await
+  }
+
+  async fn fetch_latest(&self, strand: &Cid) -> Result<Tixel, ResolutionError> {
+    self
+      .retry("fetch_latest", is_transient_resolution, || {
+        self.inner.fetch_latest(strand)
+      })
+      .await
+  }
+
+  async fn fetch_index(
+    &self,
+    strand: &Cid,
+    index: u64,
+  ) -> Result<Tixel, ResolutionError> {
+    self
+      .retry("fetch_index", is_transient_resolution, || {
+        self.inner.fetch_index(strand, index)
+      })
+      .await
+  }
+
+  async fn fetch_tixel(
+    &self,
+    strand: &Cid,
+    tixel: &Cid,
+  ) -> Result<Tixel, ResolutionError> {
+    self
+      .retry("fetch_tixel", is_transient_resolution, || {
+        self.inner.fetch_tixel(strand, tixel)
+      })
+      .await
+  }
+
+  async fn fetch_strand(
+    &self,
+    strand: &Cid,
+  ) -> Result<Strand, ResolutionError> {
+    self
+      .retry("fetch_strand", is_transient_resolution, || {
+        self.inner.fetch_strand(strand)
+      })
+      .await
+  }
+
+  async fn range_stream<'a>(
+    &'a self,
+    range: AbsoluteRange,
+  ) -> Result<TwineStream<'a, Tixel>, ResolutionError> {
+    self
+      .retry("range_stream", is_transient_resolution, || {
+        self.inner.range_stream(range)
+      })
+      .await
+  }
+
+  async fn fetch_strands<'a>(
+    &'a self,
+  ) -> Result<TwineStream<'a, Strand>, ResolutionError> {
+    self
+      .retry("fetch_strands", is_transient_resolution, || {
+        self.inner.fetch_strands()
+      })
+      .await
+  }
+}
+
+impl<R: BaseResolver> Resolver for RetryingStore<R> {}
+
+#[async_trait]
+impl<R: Store> Store for RetryingStore<R> {
+  async fn save<T: Into<AnyTwine> + MaybeSend>(
+    &self,
+    twine: T,
+  ) -> Result<(), StoreError> {
+    let twine: AnyTwine = twine.into();
+    self
+      .retry("save", is_transient_store, || {
+        self.inner.save(twine.clone())
+      })
+      .await
+  }
+
+  async fn save_many<
+    I: Into<AnyTwine> + MaybeSend,
+    S: Iterator<Item = I> + MaybeSend,
+    T: IntoIterator<Item = I, IntoIter = S> + MaybeSend,
+  >(
+    &self,
+    twines: T,
+  ) -> Result<(), StoreError> {
+    let twines: Vec<AnyTwine> = twines.into_iter().map(|t| t.into()).collect();
+    self
+      .retry("save_many", is_transient_store, || {
+        self.inner.save_many(twines.clone())
+      })
+      .await
+  }
+
+  async fn save_stream<
+    I: Into<AnyTwine> + MaybeSend,
+    T: Stream<Item = I> + MaybeSend + Unpin,
+  >(
+    &self,
+    twines: T,
+  ) -> Result<(), StoreError> {
+    self.inner.save_stream(twines).await
+  }
+
+  async fn delete<C: AsCid + MaybeSend>(
+    &self,
+    cid: C,
+  ) -> Result<(), StoreError> {
+    let cid = *cid.as_cid();
+    self
+      .retry("delete", is_transient_store, || self.inner.delete(cid))
+      .await
+  }
+}
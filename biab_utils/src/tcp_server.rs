@@ -1,22 +1,67 @@
-use crate::{Message, Messenger};
+use crate::pubsub::{serve_subscriber, subscription};
+use crate::SUBSCRIBE_COMMAND;
+use crate::{int_gauge, Incoming, Listener, Message, Messenger, Topics};
+use prometheus::IntGauge;
+use std::sync::LazyLock;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::TcpListener, sync::Notify};
+use tokio::sync::Notify;
 
-// TCP Server to listen for messages
+static OPEN_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+  int_gauge(
+    "biab_open_connections",
+    "Connections from peers being served",
+  )
+});
+
+/// A connection from a peer, counted in the `biab_open_connections` metric
+/// until it's dropped
+pub struct OpenConnection {
+  peer: SocketAddr,
+}
+
+impl OpenConnection {
+  pub fn new(peer: SocketAddr) -> Self {
+    OPEN_CONNECTIONS.inc();
+    log::debug!("[{}] Connected", peer);
+    Self { peer }
+  }
+}
+
+impl Drop for OpenConnection {
+  fn drop(&mut self) {
+    OPEN_CONNECTIONS.dec();
+    log::debug!("[{}] Disconnected", self.peer);
+  }
+}
+
+/// How many connections from peers are being served
+pub fn open_connections() -> i64 {
+  OPEN_CONNECTIONS.get()
+}
+
+// TCP Server to listen for messages, with TLS if configured (see [Listener])
 pub fn start_tcp_server(
   addr: String,
   shutdown: Arc<Notify>,
+) -> tokio::sync::mpsc::Receiver<Message> {
+  start_tcp_server_with(addr, shutdown, None)
+}
+
+pub(crate) fn start_tcp_server_with(
+  addr: String,
+  shutdown: Arc<Notify>,
+  topics: Option<Topics>,
 ) -> tokio::sync::mpsc::Receiver<Message> {
   let (tx, rx) = tokio::sync::mpsc::channel(32);
 
   let messenger = Messenger::new();
 
   tokio::spawn(async move {
-    let listener = match TcpListener::bind(&addr).await {
+    let listener = match Listener::bind(&addr).await {
       Ok(listener) => listener,
       Err(e) => {
-        log::error!("Failed to bind to {}: {}", addr, e);
-        panic!("Failed to bind to address");
+        log::error!("{}", e);
+        panic!("Failed to listen for messages");
       }
     };
 
@@ -30,9 +75,16 @@ pub fn start_tcp_server(
         }
         result = listener.accept() => {
           match result {
-            Ok((stream, peer)) => {
-              log::debug!("New connection from {}", peer);
-              tokio::spawn(handle_client(messenger.clone(), stream, peer, tx.clone()));
+            Ok((incoming, peer)) => {
+              let client = handle_client(
+                messenger.clone(),
+                incoming,
+                peer,
+                tx.clone(),
+                topics.clone(),
+                shutdown.clone(),
+              );
+              tokio::spawn(client);
             }
             Err(e) => {
               log::error!("Failed to accept connection: {}", e);
@@ -48,17 +100,81 @@ pub fn start_tcp_server(
 
 async fn handle_client(
   messenger: Messenger,
-  mut stream: tokio::net::TcpStream,
+  incoming: Incoming,
   peer: SocketAddr,
   tx: tokio::sync::mpsc::Sender<Message>,
+  topics: Option<Topics>,
+  shutdown: Arc<Notify>,
 ) {
+  // listen for shutdown from now on, so it isn't missed between messages
+  let stopping = shutdown.notified();
+  tokio::pin!(stopping);
+  stopping.as_mut().enable();
+
+  let mut stream = match incoming.stream().await {
+    Ok(stream) => stream,
+    Err(e) => {
+      log::warn!("[{}] {}", peer, e);
+      return;
+    }
+  };
+  let _connection = OpenConnection::new(peer);
+  // until the peer disconnects (it reconnects for its next message)
   loop {
-    if let Some(message) = messenger.receive(&mut stream).await {
-      log::debug!("[{}] Received message: {:?}", peer, message);
+    let message = tokio::select! {
+      _ = &mut stopping => {
+        log::debug!("[{}] Closing, shutting down", peer);
+        break;
+      }
+      message = messenger.receive_live(&mut stream) => match message {
+        Some(message) => message,
+        // closed, silent for too long or sent something that isn't a message
+        None => break,
+      }
+    };
+    log::debug!("[{}] Received message: {:?}", peer, message);
+
+    // the connection only carries events from now on (see [crate::pubsub])
+    if message.command == SUBSCRIBE_COMMAND {
+      let events = subscription(topics.as_ref(), &message);
+      let handled = events
+        .as_ref()
+        .map(|_| ())
+        .map_err(|e| anyhow::anyhow!("{}", e));
+      if let Err(e) =
+        messenger.acknowledge(&mut stream, &message, &handled).await
+      {
+        log::warn!("[{}] Failed to acknowledge {}: {}", peer, message.id, e);
+        break;
+      }
+      match events {
+        Ok(events) => {
+          log::info!("[{}] Subscribed", peer);
+          let served =
+            serve_subscriber(&messenger, &mut stream, events, &shutdown);
+          if let Err(e) = served.await {
+            log::debug!("[{}] Stopped sending events: {}", peer, e);
+          }
+        }
+        Err(e) => log::warn!("[{}] Refused a subscription: {}", peer, e),
+      }
+      break;
+    }
 
-      if let Err(e) = tx.send(message).await {
+    // acknowledged once the service has taken it, if the sender asked
+    let taken = match tx.send(message.clone()).await {
+      Ok(()) => Ok(()),
+      Err(e) => {
         log::error!("[{}] Failed to broadcast recieved message: {}", peer, e);
+        Err(anyhow::anyhow!("The service is stopping"))
       }
+    };
+    if let Err(e) = messenger.acknowledge(&mut stream, &message, &taken).await {
+      log::warn!("[{}] Failed to acknowledge {}: {}", peer, message.id, e);
+      break;
+    }
+    if taken.is_err() {
+      break;
     }
   }
 }
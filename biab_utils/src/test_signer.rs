@@ -0,0 +1,57 @@
+//! An Ed25519 signer for the tests of the signing helpers.
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use twine_protocol::prelude::*;
+use twine_protocol::twine_builder::Signer;
+use twine_protocol::twine_lib::crypto::{
+  PublicKey, Signature, SignatureAlgorithm,
+};
+
+pub(crate) struct TestSigner {
+  /// None for a signer that can't be reached
+  key: Option<Ed25519KeyPair>,
+  public_key: PublicKey,
+}
+
+impl TestSigner {
+  /// A signer with a new key
+  pub fn new() -> Self {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let public_key = PublicKey::new(
+      SignatureAlgorithm::Ed25519,
+      key.public_key().as_ref().into(),
+    );
+    Self {
+      key: Some(key),
+      public_key,
+    }
+  }
+
+  /// Claiming the public key of `other`, which its signatures don't match
+  pub fn claiming(mut self, other: &TestSigner) -> Self {
+    self.public_key = other.public_key.clone();
+    self
+  }
+
+  /// Failing to sign
+  pub fn unavailable(mut self) -> Self {
+    self.key = None;
+    self
+  }
+}
+
+impl Signer for TestSigner {
+  type Key = PublicKey;
+
+  fn public_key(&self) -> Self::Key {
+    self.public_key.clone()
+  }
+
+  fn sign<T: AsRef<[u8]>>(&self, data: T) -> Result<Signature, SigningError> {
+    match &self.key {
+      Some(key) => Ok(key.sign(data.as_ref()).as_ref().into()),
+      None => Err(SigningError("unavailable".to_string())),
+    }
+  }
+}
@@ -0,0 +1,375 @@
+//! Optional TLS for the messages between services.
+//!
+//! Messages (see [crate::Messenger]) are plain TCP by default, which is
+//! fine on a private container network. TLS is turned on per endpoint:
+//!
+//! - a service's listener on `LISTEN_ADDR` requires TLS once its certificate
+//!   and key are in `LISTEN_TLS_CERT` and `LISTEN_TLS_KEY`, and then also
+//!   requires clients to present a certificate signed by one of the CAs in
+//!   `LISTEN_TLS_CLIENT_CA`, if set. With `LISTEN_TLS_ALLOWED_PEERS` (eg:
+//!   `generator,rng_factory`) only clients whose certificate is for one of
+//!   those names are accepted, eg: so only the rng factory can deliver
+//!   randomness to the generator.
+//! - a peer (eg: at `GENERATOR_ADDR`) is connected to with TLS once the CA
+//!   certificates to verify it with are in `<PEER>_TLS_CA` (eg:
+//!   `GENERATOR_TLS_CA`). Its certificate must be for the host in its
+//!   address, or else for `<PEER>_TLS_SERVER_NAME`. If the peer requires a
+//!   client certificate, it's presented from `<PEER>_TLS_CERT` and
+//!   `<PEER>_TLS_KEY`.
+//!
+//! The certificates are PEM files, read on startup. Messages received from
+//! a client that presented a certificate carry its name (see
+//! [MessageStream::peer]).
+use crate::frame_key;
+use anyhow::{anyhow, Result};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection messages are sent and received on, with or without TLS
+pub enum MessageStream {
+  Plain(TcpStream),
+  Tls(Box<tokio_rustls::TlsStream<TcpStream>>),
+}
+
+impl MessageStream {
+  /// The names in the certificate the other end presented, if any
+  pub fn peer_names(&self) -> Vec<String> {
+    let certs = match self {
+      Self::Plain(_) => None,
+      Self::Tls(stream) => stream.get_ref().1.peer_certificates(),
+    };
+    certs
+      .and_then(|certs| certs.first())
+      .and_then(|cert| webpki::EndEntityCert::try_from(cert).ok())
+      .map(|cert| cert.valid_dns_names().map(String::from).collect())
+      .unwrap_or_default()
+  }
+
+  /// Who's at the other end: the first name in its certificate
+  pub fn peer(&self) -> Option<String> {
+    self.peer_names().into_iter().next()
+  }
+
+  /// The address of the other end
+  pub fn remote_addr(&self) -> Option<SocketAddr> {
+    match self {
+      Self::Plain(stream) => stream.peer_addr().ok(),
+      Self::Tls(stream) => stream.get_ref().0.peer_addr().ok(),
+    }
+  }
+}
+
+impl AsyncRead for MessageStream {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+      Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+    }
+  }
+}
+
+impl AsyncWrite for MessageStream {
+  fn poll_write(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    match self.get_mut() {
+      Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+      Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+    }
+  }
+
+  fn poll_flush(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+      Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+    }
+  }
+
+  fn poll_shutdown(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+      Self::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+    }
+  }
+}
+
+/// Listens for messages, with TLS if `LISTEN_TLS_CERT` is set
+pub struct Listener {
+  tcp: TcpListener,
+  tls: Option<TlsAcceptor>,
+  /// The peers accepted, or anyone if empty
+  allowed: Arc<Vec<String>>,
+}
+
+impl Listener {
+  pub async fn bind(address: &str) -> Result<Self> {
+    // fail now rather than on every message if the frame key is invalid
+    frame_key()?;
+    let tls = server_config()?.map(TlsAcceptor::from);
+    let allowed = allowed_peers()?;
+    if !allowed.is_empty() && tls.is_none() {
+      return Err(anyhow!(
+        "LISTEN_TLS_ALLOWED_PEERS needs LISTEN_TLS_CERT and LISTEN_TLS_KEY"
+      ));
+    }
+    let tcp = TcpListener::bind(address)
+      .await
+      .map_err(|e| anyhow!("Failed to bind to {}: {}", address, e))?;
+    if tls.is_some() {
+      log::info!("Requiring TLS on {}", address);
+    }
+    if !allowed.is_empty() {
+      log::info!("Only accepting {} on {}", allowed.join(", "), address);
+    }
+    Ok(Self {
+      tcp,
+      tls,
+      allowed: Arc::new(allowed),
+    })
+  }
+
+  /// The next connection. Its TLS handshake is left to [Incoming::stream],
+  /// so a slow client doesn't hold up the others.
+  pub async fn accept(&self) -> io::Result<(Incoming, SocketAddr)> {
+    let (tcp, peer) = self.tcp.accept().await?;
+    let incoming = Incoming {
+      tcp,
+      tls: self.tls.clone(),
+      allowed: self.allowed.clone(),
+    };
+    Ok((incoming, peer))
+  }
+}
+
+/// A connection accepted by a [Listener]
+pub struct Incoming {
+  tcp: TcpStream,
+  tls: Option<TlsAcceptor>,
+  allowed: Arc<Vec<String>>,
+}
+
+impl Incoming {
+  /// The stream to receive messages on, once any TLS handshake is done and
+  /// the peer is found to be allowed
+  pub async fn stream(self) -> Result<MessageStream> {
+    let acceptor = match self.tls {
+      Some(acceptor) => acceptor,
+      None => return Ok(MessageStream::Plain(self.tcp)),
+    };
+    let stream =
+      tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(self.tcp))
+        .await
+        .map_err(|_| anyhow!("The TLS handshake timed out"))?
+        .map_err(|e| anyhow!("The TLS handshake failed: {}", e))?;
+    let stream = MessageStream::Tls(Box::new(stream.into()));
+    if self.allowed.is_empty() {
+      return Ok(stream);
+    }
+    let names = stream.peer_names();
+    if !names.iter().any(|name| self.allowed.contains(name)) {
+      log::error!(
+        "ALERT: refused a connection from a peer that isn't allowed ({})",
+        names.join(", ")
+      );
+      return Err(anyhow!("The peer isn't allowed"));
+    }
+    Ok(stream)
+  }
+}
+
+/// How to connect to a peer with TLS
+#[derive(Clone)]
+pub struct ClientTls {
+  connector: TlsConnector,
+  server_name: ServerName<'static>,
+}
+
+impl std::fmt::Debug for ClientTls {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("ClientTls")
+      .field("server_name", &self.server_name)
+      .finish()
+  }
+}
+
+impl ClientTls {
+  /// TLS for the peer at `address`, from `var` (eg: `GENERATOR_ADDR`), if
+  /// `<PEER>_TLS_CA` is set
+  pub fn from_env(var: &str, address: &str) -> Result<Option<Self>> {
+    let prefix = format!("{}_TLS", var.strip_suffix("_ADDR").unwrap_or(var));
+    let ca = match crate::config_value(&format!("{}_CA", prefix))? {
+      Some(ca) => ca,
+      None => return Ok(None),
+    };
+    let builder = ClientConfig::builder_with_provider(provider())
+      .with_safe_default_protocol_versions()?
+      .with_root_certificates(roots(&ca)?);
+    let config = match identity(&prefix)? {
+      Some((certs, key)) => builder.with_client_auth_cert(certs, key)?,
+      None => builder.with_no_client_auth(),
+    };
+    let name = crate::config_value(&format!("{}_SERVER_NAME", prefix))?
+      .unwrap_or_else(|| host(address).to_string());
+    let server_name = ServerName::try_from(name.clone())
+      .map_err(|_| anyhow!("Invalid {}_SERVER_NAME: {}", prefix, name))?;
+    Ok(Some(Self {
+      connector: TlsConnector::from(Arc::new(config)),
+      server_name,
+    }))
+  }
+
+  pub async fn connect(&self, tcp: TcpStream) -> Result<MessageStream> {
+    let connecting = self.connector.connect(self.server_name.clone(), tcp);
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, connecting)
+      .await
+      .map_err(|_| anyhow!("The TLS handshake timed out"))?
+      .map_err(|e| anyhow!("The TLS handshake failed: {}", e))?;
+    Ok(MessageStream::Tls(Box::new(stream.into())))
+  }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+  Arc::new(ring::default_provider())
+}
+
+/// The listener's TLS config, if `LISTEN_TLS_CERT` is set
+fn server_config() -> Result<Option<Arc<ServerConfig>>> {
+  let (certs, key) = match identity("LISTEN_TLS")? {
+    Some(identity) => identity,
+    None => return Ok(None),
+  };
+  let builder = ServerConfig::builder_with_provider(provider())
+    .with_safe_default_protocol_versions()?;
+  let builder = match crate::config_value("LISTEN_TLS_CLIENT_CA")? {
+    Some(ca) => {
+      let verifier = WebPkiClientVerifier::builder_with_provider(
+        Arc::new(roots(&ca)?),
+        provider(),
+      )
+      .build()?;
+      builder.with_client_cert_verifier(verifier)
+    }
+    None => builder.with_no_client_auth(),
+  };
+  Ok(Some(Arc::new(builder.with_single_cert(certs, key)?)))
+}
+
+/// The names in `LISTEN_TLS_ALLOWED_PEERS`, which need clients to present
+/// a certificate
+fn allowed_peers() -> Result<Vec<String>> {
+  let allowed = match crate::config_value("LISTEN_TLS_ALLOWED_PEERS")? {
+    Some(allowed) => parse_names(&allowed),
+    None => return Ok(Vec::new()),
+  };
+  if crate::config_value("LISTEN_TLS_CLIENT_CA")?.is_none() {
+    return Err(anyhow!(
+      "LISTEN_TLS_ALLOWED_PEERS needs LISTEN_TLS_CLIENT_CA to verify them"
+    ));
+  }
+  Ok(allowed)
+}
+
+fn parse_names(names: &str) -> Vec<String> {
+  names
+    .split(',')
+    .map(|name| name.trim().to_string())
+    .filter(|name| !name.is_empty())
+    .collect()
+}
+
+/// The host of a `host:port` address
+fn host(address: &str) -> &str {
+  let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+  host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+  std::fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path, e))
+}
+
+fn certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+  let certs = rustls_pemfile::certs(&mut read(path)?.as_slice())
+    .collect::<io::Result<Vec<_>>>()
+    .map_err(|e| anyhow!("Invalid certificate {}: {}", path, e))?;
+  if certs.is_empty() {
+    return Err(anyhow!("No certificates in {}", path));
+  }
+  Ok(certs)
+}
+
+fn roots(path: &str) -> Result<RootCertStore> {
+  let mut roots = RootCertStore::empty();
+  for cert in certs(path)? {
+    roots
+      .add(cert)
+      .map_err(|e| anyhow!("Invalid CA certificate in {}: {}", path, e))?;
+  }
+  Ok(roots)
+}
+
+/// The certificate and key in `<PREFIX>_CERT` and `<PREFIX>_KEY`, if set
+fn identity(
+  prefix: &str,
+) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>> {
+  let cert_var = format!("{}_CERT", prefix);
+  let key_var = format!("{}_KEY", prefix);
+  let (cert, key) = match (
+    crate::config_value(&cert_var)?,
+    crate::config_value(&key_var)?,
+  ) {
+    (Some(cert), Some(key)) => (cert, key),
+    (None, None) => return Ok(None),
+    _ => {
+      return Err(anyhow!("{} and {} must be set together", cert_var, key_var))
+    }
+  };
+  let private_key = rustls_pemfile::private_key(&mut read(&key)?.as_slice())
+    .map_err(|e| anyhow!("Invalid key {}: {}", key, e))?
+    .ok_or_else(|| anyhow!("No private key in {}", key))?;
+  Ok(Some((certs(&cert)?, private_key)))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_host() {
+    assert_eq!(host("generator:5555"), "generator");
+    assert_eq!(host("10.0.0.2:5555"), "10.0.0.2");
+    assert_eq!(host("[::1]:5555"), "::1");
+  }
+
+  #[test]
+  fn test_parse_names() {
+    assert_eq!(
+      parse_names(" generator, rng_factory,,"),
+      vec!["generator".to_string(), "rng_factory".to_string()]
+    );
+  }
+}
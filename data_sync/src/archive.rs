@@ -0,0 +1,317 @@
+//! Rolling CAR archives on the local filesystem as a sync target.
+//!
+//! Tixels are appended to CAR files under `<path>/<strand cid>/`, starting
+//! a new file every day of pulse timestamps (`2025-01-31.car`) or every
+//! `pulses_per_file` tixels (named by their first index, zero padded). Every file starts
+//! with the strand, so each one can be imported on its own (in order), eg:
+//! by pushing it to a portal.
+//!
+//! Next to each archive is an index sidecar (`.car.index`), with a line of
+//! JSON for every tixel giving its index, cid, and the offset and length of
+//! its block in the archive. The sidecar is written after the archive, so
+//! if writing is cut short (eg: by a crash) whatever the archive has past
+//! the sidecar's last tixel is cut off before appending again. Archiving
+//! carries on after the last index in any of the sidecars, so one left
+//! empty by a file's first write failing doesn't start the strand over.
+use crate::target::Target;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::car::to_car_stream;
+use twine_spec_rng::RandomnessPayload;
+
+/// The `archive` section of a remote
+#[derive(Debug, Deserialize)]
+pub struct ArchiveConfig {
+  path: PathBuf,
+  /// Start a new file after this many tixels, instead of every day
+  pulses_per_file: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+  index: u64,
+  cid: String,
+  offset: u64,
+  length: u64,
+}
+
+pub struct ArchiveTarget {
+  path: PathBuf,
+  pulses_per_file: Option<u64>,
+}
+
+/// The archive file (without extension) a tixel goes in
+fn file_stem(pulses_per_file: Option<u64>, index: u64, date: &str) -> String {
+  match pulses_per_file {
+    Some(n) => format!("{:020}", index - index % n.max(1)),
+    None => date.to_string(),
+  }
+}
+
+/// The day of a tixel's pulse timestamp, or of today for tixels without one
+fn tixel_date(tixel: &Twine) -> String {
+  tixel
+    .extract_payload::<RandomnessPayload>()
+    .map(|payload| payload.timestamp())
+    .unwrap_or_else(|_| chrono::Utc::now())
+    .format("%Y-%m-%d")
+    .to_string()
+}
+
+/// The length of the complete lines at the start of a sidecar, and where
+/// the block of the last of them ends in the archive
+fn intact(sidecar: &str) -> Result<(usize, Option<u64>)> {
+  let mut kept = 0;
+  let mut end = None;
+  for line in sidecar.split_inclusive('\n') {
+    let entry = match line.strip_suffix('\n') {
+      Some(line) => serde_json::from_str::<IndexEntry>(line),
+      None => break,
+    };
+    let entry = match entry {
+      Ok(entry) => entry,
+      Err(_) => break,
+    };
+    let entry_end = entry
+      .offset
+      .checked_add(entry.length)
+      .ok_or_else(|| anyhow!("Invalid sidecar entry for {}", entry.index))?;
+    kept += line.len();
+    end = Some(entry_end);
+  }
+  Ok((kept, end))
+}
+
+impl ArchiveTarget {
+  pub fn new(config: ArchiveConfig) -> Self {
+    Self {
+      path: config.path,
+      pulses_per_file: config.pulses_per_file,
+    }
+  }
+
+  /// The name to call remotes without one
+  pub fn default_name(config: &ArchiveConfig) -> String {
+    format!("file://{}", config.path.display())
+  }
+
+  fn strand_dir(&self, strand: &Cid) -> PathBuf {
+    self.path.join(strand.to_string())
+  }
+
+  /// The last index in any of the strand's sidecars
+  async fn last_archived(&self, dir: &Path) -> Result<Option<u64>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let mut last = None;
+    while let Some(entry) = entries.next_entry().await? {
+      let path = entry.path();
+      if !path.to_string_lossy().ends_with(".car.index") {
+        continue;
+      }
+      let sidecar = tokio::fs::read_to_string(path).await?;
+      let sidecar_last = sidecar
+        .lines()
+        .filter_map(|line| serde_json::from_str::<IndexEntry>(line).ok())
+        .map(|entry| entry.index)
+        .max();
+      last = last.max(sidecar_last);
+    }
+    Ok(last)
+  }
+
+  /// Append tixels to one archive file, starting it if needed
+  async fn append(
+    &self,
+    strand: &Strand,
+    stem: &str,
+    tixels: Vec<Twine>,
+  ) -> Result<()> {
+    let dir = self.strand_dir(&strand.cid());
+    tokio::fs::create_dir_all(&dir).await?;
+    let car_path = dir.join(format!("{}.car", stem));
+    let sidecar_path = dir.join(format!("{}.car.index", stem));
+    let mut car = tokio::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&car_path)
+      .await?;
+    let mut sidecar = tokio::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&sidecar_path)
+      .await?;
+
+    // cut off what an earlier write left unfinished, without any tixels in
+    // the sidecar the file is started over
+    let (kept, end) = intact(&tokio::fs::read_to_string(&sidecar_path).await?)?;
+    let mut offset = end.unwrap_or(0);
+    let len = car.metadata().await?.len();
+    if len < offset {
+      return Err(anyhow!(
+        "Archive {} is shorter than its sidecar",
+        car_path.display()
+      ));
+    }
+    if len > offset {
+      log::warn!(
+        "Truncating {} from {} to {} bytes after an unfinished write",
+        car_path.display(),
+        len,
+        offset
+      );
+      car.set_len(offset).await?;
+    }
+    if sidecar.metadata().await?.len() > kept as u64 {
+      log::warn!("Truncating {} to {} bytes", sidecar_path.display(), kept);
+      sidecar.set_len(kept as u64).await?;
+    }
+
+    // a new file gets the header and the strand first
+    let starting = offset == 0;
+    let blocks = starting
+      .then(|| AnyTwine::from(strand.clone()))
+      .into_iter()
+      .chain(tixels.iter().cloned().map(AnyTwine::from));
+    let mut encoded =
+      to_car_stream(futures::stream::iter(blocks), vec![strand.cid()]);
+    let header = encoded.next().await.unwrap_or_default();
+    if starting {
+      let block = encoded.next().await.unwrap_or_default();
+      car.write_all(&header).await?;
+      car.write_all(&block).await?;
+      offset += (header.len() + block.len()) as u64;
+    }
+
+    let mut lines = String::new();
+    for tixel in &tixels {
+      let block = encoded.next().await.ok_or_else(|| {
+        anyhow!("No block encoded for tixel {}", tixel.index())
+      })?;
+      car.write_all(&block).await?;
+      let entry = IndexEntry {
+        index: tixel.index(),
+        cid: tixel.cid().to_string(),
+        offset,
+        length: block.len() as u64,
+      };
+      lines.push_str(&serde_json::to_string(&entry)?);
+      lines.push('\n');
+      offset += block.len() as u64;
+    }
+    car.sync_all().await?;
+    sidecar.write_all(lines.as_bytes()).await?;
+    sidecar.sync_all().await?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Target for ArchiveTarget {
+  async fn next_index(&self, strand: &Strand) -> Result<u64> {
+    let last = self.last_archived(&self.strand_dir(&strand.cid())).await?;
+    Ok(last.map(|index| index + 1).unwrap_or(0))
+  }
+
+  /// Strands are written at the start of each archive file instead
+  async fn save_strand(&self, _strand: &Strand) -> Result<()> {
+    Ok(())
+  }
+
+  async fn save_tixels(
+    &self,
+    strand: &Strand,
+    tixels: Vec<Twine>,
+  ) -> Result<()> {
+    // split the tixels up by the file they go in
+    let mut batch: Vec<Twine> = Vec::new();
+    let mut batch_stem = None;
+    for tixel in tixels {
+      let stem =
+        file_stem(self.pulses_per_file, tixel.index(), &tixel_date(&tixel));
+      if batch_stem.as_ref().is_some_and(|s| *s != stem) {
+        let full = std::mem::take(&mut batch);
+        self
+          .append(strand, batch_stem.as_deref().unwrap(), full)
+          .await?;
+      }
+      batch_stem = Some(stem);
+      batch.push(tixel);
+    }
+    if let Some(stem) = batch_stem {
+      self.append(strand, &stem, batch).await?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_file_stem() {
+    assert_eq!(file_stem(None, 12, "2025-01-31"), "2025-01-31");
+    assert_eq!(file_stem(Some(1000), 999, ""), format!("{:020}", 0));
+    assert_eq!(file_stem(Some(1000), 1000, ""), format!("{:020}", 1000));
+    assert_eq!(file_stem(Some(1000), 2345, ""), format!("{:020}", 2000));
+  }
+
+  #[test]
+  fn test_intact() {
+    let line = |index, offset| {
+      let entry = IndexEntry {
+        index,
+        cid: String::new(),
+        offset,
+        length: 10,
+      };
+      format!("{}\n", serde_json::to_string(&entry).unwrap())
+    };
+    assert_eq!(intact("").unwrap(), (0, None));
+    let whole = format!("{}{}", line(0, 100), line(1, 110));
+    assert_eq!(intact(&whole).unwrap(), (whole.len(), Some(120)));
+    // a line cut short is dropped
+    let torn = format!("{}{{\"index\":2", whole);
+    assert_eq!(intact(&torn).unwrap(), (whole.len(), Some(120)));
+    let torn = &whole[..whole.len() - 1];
+    assert_eq!(intact(torn).unwrap(), (line(0, 100).len(), Some(110)));
+  }
+
+  #[tokio::test]
+  async fn test_last_archived() {
+    let dir = std::env::temp_dir()
+      .join(format!("archive-target-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let target = ArchiveTarget::new(ArchiveConfig {
+      path: dir.clone(),
+      pulses_per_file: None,
+    });
+    assert_eq!(target.last_archived(&dir).await.unwrap(), None);
+
+    let lines: String = (0..3)
+      .map(|index| {
+        let entry = IndexEntry {
+          index,
+          cid: String::new(),
+          offset: index * 10,
+          length: 10,
+        };
+        format!("{}\n", serde_json::to_string(&entry).unwrap())
+      })
+      .collect();
+    std::fs::write(dir.join("2025-01-30.car.index"), lines).unwrap();
+    // the next day's file failed before its first tixel was written
+    std::fs::write(dir.join("2025-01-31.car.index"), "").unwrap();
+    assert_eq!(target.last_archived(&dir).await.unwrap(), Some(2));
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
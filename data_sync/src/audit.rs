@@ -0,0 +1,101 @@
+//! Finding tixels missing from remotes.
+//!
+//! Regular syncing only sends what comes after a remote's cursor, so tixels
+//! missing from the middle of a strand (eg: after a partial restore of the
+//! remote) would never be sent again. Every `AUDIT_PERIOD_SECONDS` (or on an
+//! `audit` message) each remote is asked for its tixels below the cursor, a
+//! window at a time, and any missing ranges are queued to be repaired by the
+//! next sync. An audit checks at most `AUDIT_MAX_TIXELS` per remote and the
+//! next one continues from there, so long strands are walked over several
+//! audits.
+use crate::remotes::Remote;
+use anyhow::Result;
+use biab_utils::config_or;
+use std::collections::BTreeSet;
+use twine_protocol::prelude::*;
+
+/// How many tixels to ask a remote for at once
+const WINDOW: u64 = 1000;
+
+pub fn max_tixels_from_env() -> Result<u64> {
+  Ok(config_or("AUDIT_MAX_TIXELS", 100_000u64)?.max(1))
+}
+
+/// The missing runs of indices from `start` to `end` (inclusive)
+fn gaps(start: u64, end: u64, present: &BTreeSet<u64>) -> Vec<(u64, u64)> {
+  let mut gaps = Vec::new();
+  let mut next = start;
+  for index in present.range(start..=end) {
+    if *index > next {
+      gaps.push((next, index - 1));
+    }
+    next = index + 1;
+  }
+  if next <= end {
+    gaps.push((next, end));
+  }
+  gaps
+}
+
+/// Check the next stretch of every strand synced to this remote, queueing
+/// repairs for any gaps. Returns the number of tixels found missing.
+pub async fn audit_remote(remote: &mut Remote, max_tixels: u64) -> Result<u64> {
+  let mut budget = max_tixels;
+  let mut missing = 0;
+  let strands: Vec<(Cid, u64)> =
+    remote.cursors.iter().map(|(s, next)| (*s, *next)).collect();
+  for (strand, next) in strands {
+    if budget == 0 {
+      break;
+    }
+    // everything below the cursor should be there
+    if next == 0 {
+      continue;
+    }
+    let mut start = match remote.audit_positions.get(&strand) {
+      Some(position) if *position < next => *position,
+      _ => 0,
+    };
+    while start < next && budget > 0 {
+      let end = (start + WINDOW.min(budget)).min(next) - 1;
+      let range = AbsoluteRange::new(strand, start, end);
+      let present = match remote.target.present(range).await? {
+        Some(present) => present,
+        None => {
+          log::debug!("Remote {} can't be audited", remote.name);
+          return Ok(missing);
+        }
+      };
+      for (from, to) in gaps(start, end, &present) {
+        log::warn!(
+          "Remote {} is missing tixels {} to {} of strand {}",
+          remote.name,
+          from,
+          to,
+          strand
+        );
+        missing += to - from + 1;
+        remote.repairs.push(AbsoluteRange::new(strand, from, to));
+      }
+      budget -= end - start + 1;
+      start = end + 1;
+    }
+    remote.audit_positions.insert(strand, start);
+  }
+  Ok(missing)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_gaps() {
+    let present: BTreeSet<u64> = [0, 1, 2, 5, 6, 9].into_iter().collect();
+    assert_eq!(gaps(0, 9, &present), vec![(3, 4), (7, 8)]);
+    assert_eq!(gaps(0, 11, &present), vec![(3, 4), (7, 8), (10, 11)]);
+    assert_eq!(gaps(3, 4, &present), vec![(3, 4)]);
+    assert_eq!(gaps(5, 6, &present), vec![]);
+    assert_eq!(gaps(0, 2, &BTreeSet::new()), vec![(0, 2)]);
+  }
+}
@@ -0,0 +1,229 @@
+//! Retry timing for failing remotes.
+//!
+//! After a failure a remote is retried with exponential backoff (with
+//! jitter, so remotes failing together don't retry in lockstep). After
+//! `BREAKER_THRESHOLD` failures in a row the circuit breaker opens and the
+//! remote is left out of syncing for `BREAKER_COOLDOWN_SECS`. It then gets a
+//! single trial sync (half open), which either closes the breaker again or
+//! reopens it for another cooldown. Remotes that say how long to wait (see
+//! [crate::pushback]) are left for at least that long, and it still counts
+//! as a failure, so one that keeps asking to wait opens the breaker too.
+use crate::metrics;
+use crate::pushback::Pushback;
+use anyhow::Result;
+use biab_utils::{config_or, RetryPolicy};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+  pub retry_base: Duration,
+  pub retry_max: Duration,
+  pub threshold: u32,
+  pub cooldown: Duration,
+}
+
+impl BreakerConfig {
+  pub fn from_env() -> Result<Self> {
+    Ok(Self {
+      retry_base: Duration::from_secs(config_or("SYNC_RETRY_BASE_SECS", 5)?),
+      retry_max: Duration::from_secs(config_or("SYNC_RETRY_MAX_SECS", 300)?),
+      threshold: config_or("BREAKER_THRESHOLD", 5u32)?.max(1),
+      cooldown: Duration::from_secs(config_or("BREAKER_COOLDOWN_SECS", 600)?),
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+  Closed,
+  Open,
+  HalfOpen,
+}
+
+impl State {
+  pub fn name(&self) -> &'static str {
+    match self {
+      State::Closed => "closed",
+      State::Open => "open",
+      State::HalfOpen => "half_open",
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Breaker {
+  config: BreakerConfig,
+  state: State,
+  failures: u32,
+  retry_at: Option<Instant>,
+}
+
+impl Breaker {
+  /// A closed breaker for the remote or upstream called `name`
+  pub fn new(name: &str, config: BreakerConfig) -> Self {
+    metrics::observe_breaker(name, State::Closed, false);
+    Self {
+      config,
+      state: State::Closed,
+      failures: 0,
+      retry_at: None,
+    }
+  }
+
+  /// When the remote may be synced again, if it's waiting
+  pub fn retry_at(&self) -> Option<Instant> {
+    self.retry_at
+  }
+
+  /// Whether the remote may be synced now. An open breaker whose cooldown
+  /// has passed goes half open, which is returned as a state change.
+  pub fn allow(&mut self, now: Instant) -> (bool, Option<State>) {
+    if self.retry_at.is_some_and(|at| now < at) {
+      return (false, None);
+    }
+    if self.state == State::Open {
+      self.state = State::HalfOpen;
+      return (true, Some(State::HalfOpen));
+    }
+    (true, None)
+  }
+
+  /// Record a successful sync, returning the state change if any
+  pub fn succeeded(&mut self) -> Option<State> {
+    self.failures = 0;
+    self.retry_at = None;
+    self.transition(State::Closed)
+  }
+
+  /// Record a failed sync, waiting at least `retry_after` if the remote
+  /// asked for it, returning the state change if any
+  pub fn failed(
+    &mut self,
+    now: Instant,
+    retry_after: Option<Duration>,
+  ) -> Option<State> {
+    self.failures += 1;
+    let (wait, change) = if self.state == State::HalfOpen
+      || self.failures >= self.config.threshold
+    {
+      (self.config.cooldown, self.transition(State::Open))
+    } else {
+      (self.backoff(), None)
+    };
+    self.retry_at = Some(now + wait.max(retry_after.unwrap_or_default()));
+    change
+  }
+
+  /// The exponential delay for the current failure count, with jitter
+  fn backoff(&self) -> Duration {
+    RetryPolicy::new(self.config.threshold, self.config.retry_base)
+      .with_max_delay(self.config.retry_max)
+      .delay(self.failures)
+  }
+
+  /// Like [Breaker::allow] for now, logging any state change of `name`
+  pub fn ready(&mut self, name: &str) -> bool {
+    let (ready, change) = self.allow(Instant::now());
+    if let Some(state) = change {
+      log::info!("Circuit breaker for {} is half open, retrying", name);
+      metrics::observe_breaker(name, state, true);
+    }
+    ready
+  }
+
+  /// Like [Breaker::succeeded], logging any state change of `name`
+  pub fn record_success(&mut self, name: &str) {
+    let failures = self.failures;
+    metrics::observe_sync(name, true);
+    if let Some(state) = self.succeeded() {
+      log::info!("Circuit breaker for {} closed", name);
+      metrics::observe_breaker(name, state, true);
+    }
+    if failures > 0 {
+      log::info!("Syncing with {} recovered", name);
+    }
+  }
+
+  /// Like [Breaker::failed], logging the error and any state change of `name`
+  pub fn record_failure(&mut self, name: &str, e: anyhow::Error) {
+    metrics::observe_sync(name, false);
+    let retry_after = e
+      .downcast_ref::<Pushback>()
+      .and_then(|pushback| pushback.retry_after);
+    let change = self.failed(Instant::now(), retry_after);
+    let wait = self
+      .retry_at
+      .map(|at| at.saturating_duration_since(Instant::now()))
+      .unwrap_or_default();
+    log::error!(
+      "Error syncing with {} (failure {}, retrying in {:?}): {}",
+      name,
+      self.failures,
+      wait,
+      e
+    );
+    if let Some(state) = change {
+      log::warn!(
+        "Circuit breaker for {} opened, leaving it out for {:?}",
+        name,
+        wait
+      );
+      metrics::observe_breaker(name, state, true);
+    }
+  }
+
+  fn transition(&mut self, state: State) -> Option<State> {
+    if self.state == state {
+      return None;
+    }
+    self.state = state;
+    Some(state)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_breaker() {
+    let config = BreakerConfig {
+      retry_base: Duration::from_secs(10),
+      retry_max: Duration::from_secs(30),
+      threshold: 3,
+      cooldown: Duration::from_secs(600),
+    };
+    let mut breaker = Breaker::new("test", config);
+    let now = Instant::now();
+    assert_eq!(breaker.allow(now), (true, None));
+
+    assert_eq!(breaker.failed(now, None), None);
+    assert_eq!(breaker.allow(now), (false, None));
+    let wait = breaker.retry_at().unwrap() - now;
+    assert!(wait >= Duration::from_secs(5) && wait <= Duration::from_secs(10));
+
+    assert_eq!(breaker.failed(now, None), None);
+    assert!(breaker.retry_at().unwrap() - now <= Duration::from_secs(20));
+    assert_eq!(breaker.failed(now, None), Some(State::Open));
+    assert_eq!(breaker.retry_at(), Some(now + config.cooldown));
+
+    let later = now + config.cooldown;
+    assert_eq!(breaker.allow(later), (true, Some(State::HalfOpen)));
+    assert_eq!(breaker.failed(later, None), Some(State::Open));
+    assert_eq!(breaker.allow(later), (false, None));
+
+    let later = later + config.cooldown;
+    assert_eq!(breaker.allow(later), (true, Some(State::HalfOpen)));
+    assert_eq!(breaker.succeeded(), Some(State::Closed));
+    assert_eq!(breaker.failures, 0);
+    assert_eq!(breaker.allow(later), (true, None));
+
+    // asking to wait counts as a failure, and is waited out in full
+    let minute = Duration::from_secs(60);
+    assert_eq!(breaker.failed(later, Some(minute)), None);
+    assert_eq!(breaker.retry_at(), Some(later + minute));
+    assert_eq!(breaker.failed(later, Some(minute)), None);
+    assert_eq!(breaker.failed(later, Some(minute)), Some(State::Open));
+    assert_eq!(breaker.retry_at(), Some(later + config.cooldown));
+  }
+}
@@ -0,0 +1,427 @@
+//! Full audits of the local chain.
+//!
+//! Every `CHAIN_AUDIT_PERIOD_HOURS` (eg: 168 for weekly), or on a
+//! `chain-audit` message, every randomness strand is walked from the start
+//! (or the first tixel stored, for mirrors of part of a strand) checking
+//! each pulse the way a client would: its signature, its link to the
+//! previous pulse, the randomness precommitment made by the previous pulse
+//! and its timestamp, a whole number of periods after the previous one's.
+//! With `COSIGN_POLICY_PATH` set (see [biab_utils::CosignPolicy]), the
+//! pulses of its strand must also be co-signed by enough of its operators.
+//! Strands of other kinds are left out.
+//! Each remote is then asked how far it has the strand and, where it
+//! can be read back, whether its latest tixel matches ours.
+//!
+//! The result is written as JSON to `chain-audit-<time>.json` in
+//! `CHAIN_AUDIT_REPORT_DIR` (default `audit_reports`). A report is valid if
+//! no pulse has an issue and no remote has a different tixel at its head.
+//! Remotes that are merely behind are reported but don't make it invalid.
+use crate::remotes::Remote;
+use crate::upstreams;
+use anyhow::{anyhow, Result};
+use biab_utils::{config_value, CosignPolicy, DbStore};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use futures::TryStreamExt;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use twine_protocol::prelude::*;
+use twine_spec_rng::{RandomnessPayload, RngStrandDetails};
+
+const BATCH_SIZE: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum IssueKind {
+  /// The pulse could not be read from the store
+  Missing,
+  Signature,
+  /// The pulse does not link to the one before it
+  Continuity,
+  /// The salt doesn't match the previous pulse's precommitment
+  Randomness,
+  /// The pulse isn't a whole number of periods after the one before it
+  Timestamp,
+  /// Too few of the operators co-signed the pulse
+  Cosignature,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Issue {
+  pub index: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cid: Option<String>,
+  pub kind: IssueKind,
+  pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RemoteReport {
+  remote: String,
+  /// How many tixels of the strand the remote has
+  #[serde(skip_serializing_if = "Option::is_none")]
+  count: Option<u64>,
+  /// How many fewer tixels than the local chain
+  #[serde(skip_serializing_if = "Option::is_none")]
+  behind: Option<u64>,
+  /// Whether the remote's latest tixel is the same as ours, if known
+  #[serde(skip_serializing_if = "Option::is_none")]
+  head_matches: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct StrandReport {
+  pub strand: String,
+  pub latest_index: Option<u64>,
+  pub checked: u64,
+  pub issues: Vec<Issue>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  remotes: Vec<RemoteReport>,
+}
+
+impl StrandReport {
+  pub fn new(strand: Cid, latest_index: Option<u64>) -> Self {
+    Self {
+      strand: strand.to_string(),
+      latest_index,
+      checked: 0,
+      issues: vec![],
+      remotes: vec![],
+    }
+  }
+
+  fn issue(
+    &mut self,
+    index: u64,
+    cid: Option<Cid>,
+    kind: IssueKind,
+    message: impl ToString,
+  ) {
+    self.issues.push(Issue {
+      index,
+      cid: cid.map(|c| c.to_string()),
+      kind,
+      message: message.to_string(),
+    });
+  }
+}
+
+#[derive(Debug, Serialize)]
+struct ChainReport {
+  started_at: String,
+  finished_at: String,
+  valid: bool,
+  strands: Vec<StrandReport>,
+}
+
+fn report_dir_from_env() -> Result<PathBuf> {
+  Ok(
+    config_value("CHAIN_AUDIT_REPORT_DIR")?
+      .unwrap_or_else(|| "audit_reports".to_string())
+      .into(),
+  )
+}
+
+fn report_path(dir: &Path, at: DateTime<Utc>) -> PathBuf {
+  dir.join(format!("chain-audit-{}.json", at.format("%Y%m%dT%H%M%SZ")))
+}
+
+/// Compare a remote holding `remote_count` tixels with the local chain of
+/// `local_count`, given both tixels at the remote's head if they were read
+fn compare(
+  remote: &str,
+  remote_count: u64,
+  local_count: u64,
+  heads: Option<(Cid, Option<Cid>)>,
+) -> RemoteReport {
+  let head_matches = if remote_count > local_count {
+    // the remote has tixels we don't
+    Some(false)
+  } else {
+    heads.map(|(local, remote)| remote == Some(local))
+  };
+  RemoteReport {
+    remote: remote.to_string(),
+    count: Some(remote_count),
+    behind: Some(local_count.saturating_sub(remote_count)),
+    head_matches,
+    error: None,
+  }
+}
+
+/// Check `timestamp` comes a whole number of periods after `prev` (more
+/// than one if pulses were skipped, eg: while the generator was down), and
+/// isn't more than a period ahead of `now`
+fn check_timestamp(
+  prev: DateTime<Utc>,
+  timestamp: DateTime<Utc>,
+  period: TimeDelta,
+  now: DateTime<Utc>,
+) -> Result<(), String> {
+  let gap = (timestamp - prev).num_milliseconds();
+  if gap <= 0 {
+    return Err(format!(
+      "timestamp {} isn't after the previous pulse's {}",
+      timestamp, prev
+    ));
+  }
+  let period_ms = period.num_milliseconds();
+  if period_ms > 0 && gap % period_ms != 0 {
+    return Err(format!(
+      "timestamp {} is {}ms after the previous pulse's, not a whole number \
+       of periods of {}ms",
+      timestamp, gap, period_ms
+    ));
+  }
+  if timestamp > now + period {
+    return Err(format!("timestamp {} is in the future", timestamp));
+  }
+  Ok(())
+}
+
+fn check_pulse(
+  report: &mut StrandReport,
+  twine: &Twine,
+  prev: Option<&Twine>,
+  policy: Option<&CosignPolicy>,
+) {
+  let index = twine.index();
+  let cid = Some(twine.cid());
+  if let Err(e) = twine.strand().verify_tixel(twine) {
+    report.issue(index, cid, IssueKind::Signature, e);
+  }
+  if let Some(Err(e)) = policy.map(|policy| policy.check(twine)) {
+    report.issue(index, cid, IssueKind::Cosignature, e);
+  }
+  // without the previous pulse there is nothing to compare against
+  let prev = match prev {
+    Some(prev) => prev,
+    None => return,
+  };
+  match twine.previous() {
+    Some(stitch) if stitch.tixel == prev.cid() => {}
+    Some(stitch) => report.issue(
+      index,
+      cid,
+      IssueKind::Continuity,
+      format!("links to {} instead of {}", stitch.tixel, prev.cid()),
+    ),
+    None => report.issue(
+      index,
+      cid,
+      IssueKind::Continuity,
+      "has no link to the previous pulse",
+    ),
+  }
+  let payload = match twine.extract_payload::<RandomnessPayload>() {
+    Ok(payload) => payload,
+    Err(e) => {
+      report.issue(index, cid, IssueKind::Randomness, e);
+      return;
+    }
+  };
+  if let Err(e) = payload.validate_randomness(prev) {
+    report.issue(index, cid, IssueKind::Randomness, e);
+  }
+  let period = twine
+    .strand()
+    .extract_details::<RngStrandDetails>()
+    .map(|details| details.period);
+  let prev_timestamp = prev
+    .extract_payload::<RandomnessPayload>()
+    .map(|payload| payload.timestamp());
+  if let (Ok(period), Ok(prev_timestamp)) = (period, prev_timestamp) {
+    let checked =
+      check_timestamp(prev_timestamp, payload.timestamp(), period, Utc::now());
+    if let Err(e) = checked {
+      report.issue(index, cid, IssueKind::Timestamp, e);
+    }
+  }
+}
+
+/// Walk a strand from `from` up to `latest`, checking the co-signatures
+/// of the pulses if the policy is the strand's
+pub(crate) async fn walk<R: Resolver>(
+  store: &R,
+  report: &mut StrandReport,
+  strand: Cid,
+  from: u64,
+  latest: u64,
+  policy: Option<&CosignPolicy>,
+) {
+  let policy = policy.filter(|policy| policy.applies_to(&strand));
+  let mut prev: Option<Twine> = None;
+  for batch in AbsoluteRange::new(strand, from, latest).batches(BATCH_SIZE) {
+    let twines: Result<Vec<Twine>, _> = match store.resolve_range(batch).await {
+      Ok(stream) => stream.try_collect().await,
+      Err(e) => Err(e),
+    };
+    let twines = match twines {
+      Ok(twines) => twines,
+      Err(e) => {
+        report.issue(
+          batch.lower(),
+          None,
+          IssueKind::Missing,
+          format!("pulses {} could not be read: {}", batch, e),
+        );
+        prev = None;
+        continue;
+      }
+    };
+    for twine in twines {
+      let expected = prev.as_ref().map(|p| p.index() + 1);
+      if expected.is_some_and(|expected| twine.index() != expected) {
+        report.issue(
+          twine.index(),
+          Some(twine.cid()),
+          IssueKind::Missing,
+          format!("expected index {}", expected.unwrap_or_default()),
+        );
+      }
+      check_pulse(report, &twine, prev.as_ref(), policy);
+      report.checked += 1;
+      prev = Some(twine);
+    }
+  }
+}
+
+/// How a remote's copy of a strand compares to the local one
+async fn check_remote(
+  store: &DbStore,
+  remote: &Remote,
+  strand: &Strand,
+  local_count: u64,
+) -> Result<RemoteReport> {
+  let remote_count = remote.target.next_index(strand).await?;
+  let heads = match remote_count.checked_sub(1) {
+    Some(head)
+      if remote.target.can_read_back() && remote_count <= local_count =>
+    {
+      let local = store.resolve_index(strand, head).await?.cid();
+      let remote = remote.target.stored_cid(strand, head).await?;
+      Some((local, remote))
+    }
+    _ => None,
+  };
+  Ok(compare(&remote.name, remote_count, local_count, heads))
+}
+
+async fn audit_chain(
+  store: &DbStore,
+  remotes: &[Remote],
+) -> Result<ChainReport> {
+  let started_at = Utc::now();
+  let policy = config_value("COSIGN_POLICY_PATH")?
+    .map(|path| CosignPolicy::load(&path))
+    .transpose()?;
+  let strands: Vec<Strand> = store.strands().await?.try_collect().await?;
+  let mut reports = Vec::new();
+  for strand in strands {
+    let cid = strand.cid();
+    if strand.extract_details::<RngStrandDetails>().is_err() {
+      log::info!("Not auditing strand {}, it isn't a randomness strand", cid);
+      continue;
+    }
+    log::info!("Auditing the chain of strand {}...", cid);
+    let latest = match store.resolve_latest(&strand).await {
+      Ok(latest) => Some(latest.index()),
+      Err(ResolutionError::NotFound) => None,
+      Err(e) => return Err(e.into()),
+    };
+    let mut report = StrandReport::new(cid, latest);
+    if let Some(latest) = latest {
+      let first = upstreams::first_index(store, &cid, latest).await?;
+      walk(store, &mut report, cid, first, latest, policy.as_ref()).await;
+    }
+    let local_count = latest.map(|latest| latest + 1).unwrap_or(0);
+    for remote in remotes {
+      let remote_report = check_remote(store, remote, &strand, local_count)
+        .await
+        .unwrap_or_else(|e| RemoteReport {
+          remote: remote.name.clone(),
+          error: Some(e.to_string()),
+          ..RemoteReport::default()
+        });
+      report.remotes.push(remote_report);
+    }
+    reports.push(report);
+  }
+  let valid = reports.iter().all(|report| {
+    report.issues.is_empty()
+      && report.remotes.iter().all(|r| r.head_matches != Some(false))
+  });
+  Ok(ChainReport {
+    started_at: started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+    finished_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    valid,
+    strands: reports,
+  })
+}
+
+/// Audit the whole chain and write the report
+pub async fn run(store: &DbStore, remotes: &[Remote]) -> Result<PathBuf> {
+  let report = audit_chain(store, remotes).await?;
+  let dir = report_dir_from_env()?;
+  tokio::fs::create_dir_all(&dir)
+    .await
+    .map_err(|e| anyhow!("Failed to create {}: {}", dir.display(), e))?;
+  let path = report_path(&dir, Utc::now());
+  biab_utils::write_atomic(&path, serde_json::to_vec_pretty(&report)?).await?;
+  if !report.valid {
+    log::error!("ALERT: chain audit found problems, see {}", path.display());
+  }
+  Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_compare() {
+    let cid = Cid::default();
+    let report = compare("primary", 90, 100, None);
+    assert_eq!(report.behind, Some(10));
+    assert_eq!(report.head_matches, None);
+    let report = compare("primary", 100, 100, Some((cid, Some(cid))));
+    assert_eq!(report.behind, Some(0));
+    assert_eq!(report.head_matches, Some(true));
+    // the remote claims a tixel it doesn't have
+    let report = compare("primary", 100, 100, Some((cid, None)));
+    assert_eq!(report.head_matches, Some(false));
+    // or has more than we do
+    let report = compare("primary", 101, 100, None);
+    assert_eq!(report.behind, Some(0));
+    assert_eq!(report.head_matches, Some(false));
+  }
+
+  #[test]
+  fn test_check_timestamp() {
+    let period = TimeDelta::seconds(60);
+    let prev = DateTime::parse_from_rfc3339("2025-03-01T12:00:00Z")
+      .unwrap()
+      .with_timezone(&Utc);
+    let now = prev + TimeDelta::hours(1);
+    assert!(check_timestamp(prev, prev + period, period, now).is_ok());
+    // pulses skipped
+    assert!(check_timestamp(prev, prev + period * 3, period, now).is_ok());
+    assert!(check_timestamp(prev, prev, period, now).is_err());
+    assert!(check_timestamp(prev, prev - period, period, now).is_err());
+    let late = prev + period + TimeDelta::milliseconds(1);
+    assert!(check_timestamp(prev, late, period, now).is_err());
+    assert!(check_timestamp(prev, now + period * 2, period, now).is_err());
+  }
+
+  #[test]
+  fn test_report_path() {
+    let at = DateTime::parse_from_rfc3339("2025-03-01T12:00:05Z")
+      .unwrap()
+      .with_timezone(&Utc);
+    assert_eq!(
+      report_path(Path::new("reports"), at),
+      Path::new("reports/chain-audit-20250301T120005Z.json")
+    );
+  }
+}
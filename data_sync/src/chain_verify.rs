@@ -0,0 +1,231 @@
+//! The `verify` command, checking a strand as a third party would.
+//!
+//! `verify <source> <strand>` reads the strand from a twine HTTP store (eg:
+//! a beacon's portal, given as a URL) or from a CAR file (eg: one of the
+//! archives written by data sync), and walks it the way the chain audit
+//! walks the local chain: every pulse's signature, its link to the previous
+//! pulse, the randomness precommitment of the previous pulse and its
+//! timestamp. `--from <index>` starts from that pulse rather than the first
+//! (eg: for an archive holding part of the strand), `--cosign-policy <file>`
+//! also checks the co-signatures of the pulses against the strand's policy
+//! (see [biab_utils::CosignPolicy]), and `--json` prints the report as JSON.
+//! The command fails if any pulse has an issue, or if there were no pulses
+//! to check (eg: `--from` past the end of an archive).
+use crate::chain_audit::{self, StrandReport};
+use anyhow::{anyhow, Result};
+use biab_utils::CosignPolicy;
+use std::path::Path;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::car::from_car_bytes;
+use twine_protocol::twine_lib::store::MemoryStore;
+use twine_spec_rng::RngStrandDetails;
+
+struct Options {
+  source: String,
+  strand: Cid,
+  from: u64,
+  /// The path of the cosign policy
+  policy: Option<String>,
+  json: bool,
+}
+
+impl Options {
+  /// From the arguments after the command
+  fn parse(args: &[String]) -> Result<Self> {
+    let usage = "Usage: verify <store URL or CAR file> <strand CID> \
+                 [--from <index>] [--cosign-policy <file>] [--json]";
+    let mut positional = vec![];
+    let mut from = 0;
+    let mut policy = None;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--from" => {
+          from = args
+            .next()
+            .and_then(|index| index.parse().ok())
+            .ok_or_else(|| anyhow!("--from needs a pulse index"))?;
+        }
+        "--cosign-policy" => {
+          policy = Some(
+            args
+              .next()
+              .ok_or_else(|| anyhow!("--cosign-policy needs a file"))?
+              .clone(),
+          );
+        }
+        "--json" => json = true,
+        arg if arg.starts_with('-') => {
+          return Err(anyhow!("Unknown option {}. {}", arg, usage))
+        }
+        arg => positional.push(arg),
+      }
+    }
+    let (source, strand) = match positional[..] {
+      [source, strand] => (source, strand),
+      _ => return Err(anyhow!("{}", usage)),
+    };
+    let strand = Cid::try_from(strand)
+      .map_err(|e| anyhow!("Invalid strand {}: {}", strand, e))?;
+    Ok(Self {
+      source: source.to_string(),
+      strand,
+      from,
+      policy,
+      json,
+    })
+  }
+}
+
+/// Read every strand and tixel of a CAR file into memory
+fn load_car(path: &Path) -> Result<MemoryStore> {
+  let bytes = std::fs::read(path)
+    .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+  let twines = from_car_bytes(&mut bytes.as_slice())
+    .map_err(|e| anyhow!("Invalid CAR file {}: {}", path.display(), e))?;
+  // tixels can only be saved once their strand is
+  let (strands, tixels): (Vec<_>, Vec<_>) = twines
+    .into_iter()
+    .partition(|twine| matches!(twine, AnyTwine::Strand(_)));
+  let store = MemoryStore::new();
+  for twine in strands.into_iter().chain(tixels) {
+    store.save_sync(twine)?;
+  }
+  Ok(store)
+}
+
+async fn verify<R: Resolver>(
+  store: &R,
+  strand: Cid,
+  from: u64,
+  policy: Option<&CosignPolicy>,
+) -> Result<StrandReport> {
+  let strand = store
+    .resolve_strand(strand)
+    .await
+    .map_err(|e| anyhow!("Failed to read strand {}: {}", strand, e))?
+    .unpack();
+  strand.extract_details::<RngStrandDetails>().map_err(|e| {
+    anyhow!("Strand {} isn't a randomness beacon: {}", strand.cid(), e)
+  })?;
+  let latest = match store.resolve_latest(&strand).await {
+    Ok(latest) => Some(latest.index()),
+    Err(ResolutionError::NotFound) => None,
+    Err(e) => return Err(anyhow!("Failed to read the latest pulse: {}", e)),
+  };
+  if policy.is_some_and(|policy| !policy.applies_to(&strand.cid())) {
+    return Err(anyhow!(
+      "The cosign policy isn't that of strand {}",
+      strand.cid()
+    ));
+  }
+  let mut report = StrandReport::new(strand.cid(), latest);
+  if let Some(latest) = latest.filter(|latest| *latest >= from) {
+    chain_audit::walk(store, &mut report, strand.cid(), from, latest, policy)
+      .await;
+  }
+  Ok(report)
+}
+
+fn print_report(options: &Options, report: &StrandReport) {
+  println!("Strand {} from {}", report.strand, options.source);
+  match report.latest_index {
+    Some(latest) if report.checked > 0 => println!(
+      "Checked {} pulses, {} to {}",
+      report.checked, options.from, latest
+    ),
+    _ => println!("No pulses to check"),
+  }
+  for issue in &report.issues {
+    let kind = format!("{:?}", issue.kind).to_lowercase();
+    match &issue.cid {
+      Some(cid) => println!(
+        "  pulse {} ({}): {}: {}",
+        issue.index, cid, kind, issue.message
+      ),
+      None => println!("  pulse {}: {}: {}", issue.index, kind, issue.message),
+    }
+  }
+  if !report.issues.is_empty() {
+    println!("The chain has {} issues", report.issues.len());
+  } else if report.checked > 0 {
+    println!("The chain is valid");
+  }
+}
+
+/// Verify the strand given in the arguments, failing if it isn't valid
+pub async fn run(args: &[String]) -> Result<()> {
+  let options = Options::parse(args)?;
+  let policy = options
+    .policy
+    .as_deref()
+    .map(CosignPolicy::load)
+    .transpose()?;
+  let policy = policy.as_ref();
+  let report = if options.source.starts_with("http://")
+    || options.source.starts_with("https://")
+  {
+    let store = biab_utils::open_http_store(&options.source, "")?;
+    verify(&store, options.strand, options.from, policy).await?
+  } else {
+    let store = load_car(Path::new(&options.source))?;
+    verify(&store, options.strand, options.from, policy).await?
+  };
+  if options.json {
+    println!("{}", serde_json::to_string_pretty(&report)?);
+  } else {
+    print_report(&options, &report);
+  }
+  if !report.issues.is_empty() {
+    return Err(anyhow!(
+      "Strand {} failed verification with {} issues",
+      report.strand,
+      report.issues.len()
+    ));
+  }
+  if report.checked == 0 {
+    return Err(anyhow!(
+      "No pulses of strand {} to check from {}",
+      report.strand,
+      options.from
+    ));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  #[test]
+  fn test_parse() {
+    let strand = Cid::default().to_string();
+    let options = Options::parse(&args(&[
+      "archive.car",
+      &strand,
+      "--from",
+      "1000",
+      "--cosign-policy",
+      "policy.yaml",
+      "--json",
+    ]))
+    .unwrap();
+    assert_eq!(options.source, "archive.car");
+    assert_eq!(options.strand, Cid::default());
+    assert_eq!(options.from, 1000);
+    assert_eq!(options.policy.as_deref(), Some("policy.yaml"));
+    assert!(options.json);
+
+    assert!(Options::parse(&args(&["archive.car"])).is_err());
+    assert!(Options::parse(&args(&["archive.car", "not a cid"])).is_err());
+    assert!(Options::parse(&args(&["a", &strand, "--from"])).is_err());
+    let no_policy = args(&["a", &strand, "--cosign-policy"]);
+    assert!(Options::parse(&no_policy).is_err());
+    assert!(Options::parse(&args(&["a", &strand, "--verbose"])).is_err());
+  }
+}
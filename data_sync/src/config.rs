@@ -0,0 +1,46 @@
+//! The sync service's settings, read once and checked at startup (see
+//! [biab_utils::config_value] for where they're read from).
+//!
+//! The remotes, upstreams and the other parts of syncing read their own
+//! settings when they're created, at startup too (and the remotes and
+//! upstreams again when they're reloaded).
+use anyhow::Result;
+use biab_utils::{config_flag, config_or, config_parse};
+use serde::Serialize;
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+pub struct Config {
+  pub sync_period_seconds: u64,
+  pub audit_period_seconds: Option<u64>,
+  pub chain_audit_period_hours: Option<u64>,
+  /// Report what would be synced, and exit
+  pub dry_run: bool,
+}
+
+impl Config {
+  pub fn load() -> Result<Self> {
+    Ok(Self {
+      sync_period_seconds: config_or("SYNC_PERIOD_SECONDS", 30)?,
+      audit_period_seconds: config_parse("AUDIT_PERIOD_SECONDS")?,
+      chain_audit_period_hours: config_parse("CHAIN_AUDIT_PERIOD_HOURS")?,
+      dry_run: config_flag("SYNC_DRY_RUN")?,
+    })
+  }
+
+  pub fn sync_period(&self) -> Duration {
+    Duration::from_secs(self.sync_period_seconds)
+  }
+
+  /// How often to audit the remotes, if they're audited periodically
+  pub fn audit_period(&self) -> Option<Duration> {
+    self.audit_period_seconds.map(Duration::from_secs)
+  }
+
+  /// How often to audit the chain, if it's audited periodically
+  pub fn chain_audit_period(&self) -> Option<Duration> {
+    self
+      .chain_audit_period_hours
+      .map(|hours| Duration::from_secs(hours * 3600))
+  }
+}
@@ -0,0 +1,73 @@
+//! Sync progress, persisted in the `SyncCursors` table.
+//!
+//! For every (remote, strand) pair the cursor is the next index to send. It
+//! is advanced after each chunk the remote accepts, so an interrupted sync
+//! resumes where it stopped instead of asking the remote how far along it is.
+//! Cursors only move forward: a save behind the stored cursor (eg: from a
+//! sync still running from before a reload) is ignored.
+use anyhow::Result;
+use std::collections::HashMap;
+use twine_protocol::prelude::Cid;
+use twine_sql_store::sqlx::MySqlPool;
+
+pub struct SyncCursors {
+  pool: MySqlPool,
+}
+
+impl SyncCursors {
+  pub fn new(pool: MySqlPool) -> Self {
+    Self { pool }
+  }
+
+  /// The cursors of every strand synced to this remote
+  pub async fn load(&self, remote: &str) -> Result<HashMap<Cid, u64>> {
+    let rows: Vec<(Vec<u8>, u64)> = twine_sql_store::sqlx::query_as(
+      "SELECT strand, next_index FROM SyncCursors WHERE remote = ?",
+    )
+    .bind(remote)
+    .fetch_all(&self.pool)
+    .await?;
+    rows
+      .into_iter()
+      .map(|(strand, next)| Ok((Cid::try_from(strand)?, next)))
+      .collect()
+  }
+
+  pub async fn save(
+    &self,
+    remote: &str,
+    strand: &Cid,
+    next: u64,
+  ) -> Result<()> {
+    let mut tx = self.pool.begin().await?;
+    let stored: Option<(u64,)> = twine_sql_store::sqlx::query_as(
+      "SELECT next_index FROM SyncCursors WHERE remote = ? AND strand = ? \
+       FOR UPDATE",
+    )
+    .bind(remote)
+    .bind(strand.to_bytes())
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some((stored,)) = stored.filter(|(stored,)| *stored > next) {
+      log::warn!(
+        "Not moving the cursor of {} on {} back from {} to {}",
+        strand,
+        remote,
+        stored,
+        next
+      );
+      return Ok(());
+    }
+    twine_sql_store::sqlx::query(
+      "INSERT INTO SyncCursors (remote, strand, next_index) VALUES (?, ?, ?) \
+       ON DUPLICATE KEY UPDATE next_index = VALUES(next_index)",
+    )
+    .bind(remote)
+    .bind(strand.to_bytes())
+    .bind(next)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+  }
+}
@@ -0,0 +1,103 @@
+//! A twine HTTP store as a sync target.
+//!
+//! Reads go through the store's client, but strands and tixels are pushed
+//! as CAR files directly (`POST /` and `POST /<strand cid>`, as the portal
+//! accepts them), so responses asking for less can be seen and honored (see
+//! [crate::pushback]).
+use crate::pushback;
+use crate::target::{self, Target};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::collections::BTreeSet;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_http_store::reqwest::Client;
+use twine_protocol::twine_http_store::v2::HttpStore;
+use twine_protocol::twine_lib::resolver::unchecked_base::BaseResolver;
+
+pub struct HttpTarget {
+  store: HttpStore,
+  client: Client,
+  address: String,
+}
+
+impl HttpTarget {
+  pub fn new(address: &str, api_key: &str) -> Result<Self> {
+    Ok(Self {
+      store: biab_utils::open_http_store(address, api_key)?,
+      client: biab_utils::http_client(api_key)?,
+      address: address.trim_end_matches('/').to_string(),
+    })
+  }
+
+  async fn push(&self, path: &str, car: Vec<u8>) -> Result<()> {
+    let response = self
+      .client
+      .post(format!("{}/{}", self.address, path))
+      .header("content-type", "application/vnd.ipld.car")
+      .body(car)
+      .send()
+      .await?;
+    pushback::check(response).await?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Target for HttpTarget {
+  async fn next_index(&self, strand: &Strand) -> Result<u64> {
+    match self.store.resolve_latest(strand).await {
+      Ok(latest) => Ok(latest.index() + 1),
+      Err(ResolutionError::NotFound) => Ok(0),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn save_strand(&self, strand: &Strand) -> Result<()> {
+    let car = target::car([AnyTwine::from(strand.clone())], strand.cid()).await;
+    self.push("", car).await
+  }
+
+  async fn save_tixels(
+    &self,
+    strand: &Strand,
+    tixels: Vec<Twine>,
+  ) -> Result<()> {
+    let root = match tixels.last() {
+      Some(last) => last.cid(),
+      None => return Ok(()),
+    };
+    let car = target::car(tixels.into_iter().map(AnyTwine::from), root).await;
+    self.push(&strand.cid().to_string(), car).await
+  }
+
+  fn can_read_back(&self) -> bool {
+    true
+  }
+
+  async fn stored_cid(
+    &self,
+    strand: &Strand,
+    index: u64,
+  ) -> Result<Option<Cid>> {
+    match self.store.resolve_index(strand, index).await {
+      Ok(tixel) => Ok(Some(tixel.cid())),
+      Err(ResolutionError::NotFound) => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn present(
+    &self,
+    range: AbsoluteRange,
+  ) -> Result<Option<BTreeSet<u64>>> {
+    let present = self
+      .store
+      .range_stream(range)
+      .await?
+      .filter_map(|tixel| async move { tixel.ok().map(|t| t.index()) })
+      .collect()
+      .await;
+    Ok(Some(present))
+  }
+}
@@ -0,0 +1,430 @@
+//! IPFS as a sync target.
+//!
+//! Blocks are imported into a Kubo node (over its RPC API) as CAR files, so
+//! the node provides them to the IPFS network. Each strand is pinned, and
+//! so is its latest tixel. Tixels link back to the ones before them, so that
+//! one recursive pin keeps the whole strand, and it is moved along with
+//! `pin/update` as new tixels arrive. On the first pin of a strand after a
+//! restart, recursive pins of its tixels left by earlier runs are found on
+//! the node and moved or removed, so that old pins don't pile up.
+//!
+//! Optionally the latest tixel of each strand is also pinned with a remote
+//! pinning service (see the IPFS Pinning Service API), which then fetches
+//! the blocks from the network, with the Kubo node as an origin.
+//!
+//! A failed import or pin fails the sync like any other remote error, so it
+//! is retried with backoff from the last synced tixel.
+use crate::pushback;
+use crate::target::{self, Target};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use biab_utils::Secret;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_http_store::reqwest::{
+  Client, RequestBuilder, Response,
+};
+use twine_protocol::twine_lib::twine::TwineBlock;
+
+const BOUNDARY: &str = "data-sync-car-boundary";
+
+/// The `ipfs` section of a remote
+#[derive(Debug, Deserialize)]
+pub struct IpfsConfig {
+  /// The Kubo RPC API, eg: `http://kubo:5001`
+  api: String,
+  pinning_service: Option<PinningServiceConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PinningServiceConfig {
+  /// eg: `https://api.pinata.cloud/psa`
+  endpoint: String,
+  access_token: Option<Secret>,
+  /// Or a file holding it
+  access_token_file: Option<PathBuf>,
+  /// Multiaddrs of the Kubo node, for the service to fetch blocks from
+  #[serde(default)]
+  origins: Vec<String>,
+}
+
+struct PinningService {
+  endpoint: String,
+  access_token: Secret,
+  origins: Vec<String>,
+}
+
+impl PinningService {
+  fn new(config: PinningServiceConfig) -> Result<Self> {
+    let access_token = Secret::inline_or_file(
+      "access_token",
+      config.access_token,
+      config.access_token_file.as_deref(),
+    )?
+    .ok_or_else(|| {
+      anyhow!(
+        "The pinning service {} needs an access_token",
+        config.endpoint
+      )
+    })?;
+    Ok(Self {
+      endpoint: config.endpoint,
+      access_token,
+      origins: config.origins,
+    })
+  }
+}
+
+#[derive(Debug, Serialize)]
+struct Pin<'a> {
+  cid: String,
+  name: String,
+  #[serde(skip_serializing_if = "<[String]>::is_empty")]
+  origins: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct PinStatus {
+  requestid: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PinResults {
+  results: Vec<PinStatus>,
+}
+
+/// The answer of Kubo's `pin/ls`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PinList {
+  #[serde(default)]
+  keys: HashMap<String, serde::de::IgnoredAny>,
+}
+
+impl PinList {
+  fn cids(&self) -> Result<Vec<Cid>> {
+    self
+      .keys
+      .keys()
+      .map(|cid| {
+        Cid::try_from(cid.as_str())
+          .map_err(|e| anyhow!("Invalid pin {}: {}", cid, e))
+      })
+      .collect()
+  }
+}
+
+/// Whether a block is a tixel of `strand` (rather than eg: the strand)
+fn on_strand(cid: Cid, bytes: &[u8], strand: &Cid) -> bool {
+  Tixel::from_block(cid, bytes).is_ok_and(|tixel| tixel.strand_cid() == *strand)
+}
+
+pub struct IpfsTarget {
+  client: Client,
+  api: String,
+  pinning_service: Option<PinningService>,
+  /// The latest tixel pinned on the node, by strand
+  pinned: Mutex<HashMap<Cid, Cid>>,
+  /// Pinning service request ids, by strand
+  requests: Mutex<HashMap<Cid, String>>,
+}
+
+impl IpfsTarget {
+  pub fn new(config: IpfsConfig) -> Result<Self> {
+    Ok(Self {
+      client: Client::new(),
+      api: config.api.trim_end_matches('/').to_string(),
+      pinning_service: config
+        .pinning_service
+        .map(PinningService::new)
+        .transpose()?,
+      pinned: Mutex::new(HashMap::new()),
+      requests: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// The name to call remotes without one
+  pub fn default_name(config: &IpfsConfig) -> String {
+    format!("ipfs+{}", config.api)
+  }
+
+  /// Call a Kubo RPC method
+  async fn rpc(&self, method: &str, args: &[(&str, String)]) -> Result<()> {
+    self
+      .send(
+        self
+          .client
+          .post(format!("{}/api/v0/{}", self.api, method))
+          .query(args),
+      )
+      .await
+  }
+
+  async fn send(&self, request: RequestBuilder) -> Result<()> {
+    self.call(request).await?;
+    Ok(())
+  }
+
+  async fn call(&self, request: RequestBuilder) -> Result<Response> {
+    pushback::check(request.send().await?).await
+  }
+
+  /// Call a Kubo RPC method, returning what it answers with
+  async fn rpc_bytes(
+    &self,
+    method: &str,
+    args: &[(&str, String)],
+  ) -> Result<Vec<u8>> {
+    let request = self
+      .client
+      .post(format!("{}/api/v0/{}", self.api, method))
+      .query(args);
+    Ok(self.call(request).await?.bytes().await?.to_vec())
+  }
+
+  async fn rpc_json<T: DeserializeOwned>(
+    &self,
+    method: &str,
+    args: &[(&str, String)],
+  ) -> Result<T> {
+    Ok(serde_json::from_slice(
+      &self.rpc_bytes(method, args).await?,
+    )?)
+  }
+
+  /// Recursive pins of the strand's tixels on the node, eg: from before a
+  /// restart
+  async fn earlier_pins(&self, strand: &Cid) -> Result<Vec<Cid>> {
+    let pins: PinList = self
+      .rpc_json("pin/ls", &[("type", "recursive".to_string())])
+      .await?;
+    let mut earlier = vec![];
+    for cid in pins.cids()? {
+      let block = self.rpc_bytes("block/get", &[("arg", cid.to_string())]);
+      if on_strand(cid, &block.await?, strand) {
+        earlier.push(cid);
+      }
+    }
+    Ok(earlier)
+  }
+
+  /// Import the blocks of a CAR file, pinning its root if asked to
+  async fn import(&self, car: Vec<u8>, pin_root: bool) -> Result<()> {
+    let mut body = format!(
+      "--{}\r\nContent-Disposition: form-data; name=\"file\"; \
+       filename=\"blocks.car\"\r\n\
+       Content-Type: application/vnd.ipld.car\r\n\r\n",
+      BOUNDARY
+    )
+    .into_bytes();
+    body.extend_from_slice(&car);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+    self
+      .send(
+        self
+          .client
+          .post(format!("{}/api/v0/dag/import", self.api))
+          .query(&[("pin-roots", pin_root.to_string())])
+          .header(
+            "content-type",
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+          )
+          .body(body),
+      )
+      .await
+  }
+
+  /// Move the recursive pin of the strand to its new latest tixel
+  async fn pin_latest(&self, strand: &Cid, latest: &Cid) -> Result<()> {
+    let previous = self
+      .pinned
+      .lock()
+      .expect("pinned lock")
+      .get(strand)
+      .cloned();
+    match previous {
+      Some(previous) if previous == *latest => {}
+      Some(previous) => {
+        self
+          .rpc(
+            "pin/update",
+            &[
+              ("arg", previous.to_string()),
+              ("arg", latest.to_string()),
+              ("unpin", "true".to_string()),
+            ],
+          )
+          .await?
+      }
+      None => {
+        let mut earlier = self.earlier_pins(strand).await?;
+        earlier.retain(|cid| cid != latest);
+        match earlier.pop() {
+          Some(previous) => {
+            self
+              .rpc(
+                "pin/update",
+                &[
+                  ("arg", previous.to_string()),
+                  ("arg", latest.to_string()),
+                  ("unpin", "true".to_string()),
+                ],
+              )
+              .await?
+          }
+          None => {
+            self
+              .rpc(
+                "pin/add",
+                &[
+                  ("arg", latest.to_string()),
+                  ("recursive", "true".to_string()),
+                ],
+              )
+              .await?
+          }
+        }
+        // any more are superseded by the new pin
+        for cid in earlier {
+          log::info!("Unpinning superseded tixel {} of {}", cid, strand);
+          self.rpc("pin/rm", &[("arg", cid.to_string())]).await?;
+        }
+      }
+    }
+    self
+      .pinned
+      .lock()
+      .expect("pinned lock")
+      .insert(*strand, *latest);
+    Ok(())
+  }
+
+  /// Ask the pinning service to pin the latest tixel instead of the last one
+  async fn pin_remotely(&self, strand: &Cid, latest: &Cid) -> Result<()> {
+    let service = match &self.pinning_service {
+      Some(service) => service,
+      None => return Ok(()),
+    };
+    let endpoint = service.endpoint.trim_end_matches('/');
+    let pin = Pin {
+      cid: latest.to_string(),
+      name: format!("{}:latest", strand),
+      origins: &service.origins,
+    };
+    let request_id = match self.request_id(strand) {
+      Some(id) => Some(id),
+      None => {
+        self
+          .find_request(endpoint, service.access_token.expose(), &pin.name)
+          .await?
+      }
+    };
+    let url = match request_id {
+      // replaces the pin
+      Some(id) => format!("{}/pins/{}", endpoint, id),
+      None => format!("{}/pins", endpoint),
+    };
+    let response = self
+      .client
+      .post(url)
+      .bearer_auth(service.access_token.expose())
+      .header("content-type", "application/json")
+      .body(serde_json::to_vec(&pin)?)
+      .send()
+      .await?;
+    let response = pushback::check(response).await?;
+    let pin: PinStatus = serde_json::from_slice(&response.bytes().await?)?;
+    self
+      .requests
+      .lock()
+      .expect("requests lock")
+      .insert(*strand, pin.requestid);
+    Ok(())
+  }
+
+  fn request_id(&self, strand: &Cid) -> Option<String> {
+    self
+      .requests
+      .lock()
+      .expect("requests lock")
+      .get(strand)
+      .cloned()
+  }
+
+  /// The id of an earlier pin request for the strand, eg: before a restart
+  async fn find_request(
+    &self,
+    endpoint: &str,
+    access_token: &str,
+    name: &str,
+  ) -> Result<Option<String>> {
+    let response = self
+      .client
+      .get(format!("{}/pins", endpoint))
+      .bearer_auth(access_token)
+      .query(&[("name", name), ("limit", "1")])
+      .send()
+      .await?;
+    let response = pushback::check(response).await?;
+    let results: PinResults = serde_json::from_slice(&response.bytes().await?)?;
+    Ok(results.results.into_iter().next().map(|pin| pin.requestid))
+  }
+}
+
+#[async_trait]
+impl Target for IpfsTarget {
+  /// The node can't tell how far a strand got, so without a sync cursor
+  /// everything is imported again (which changes nothing that's there)
+  async fn next_index(&self, _strand: &Strand) -> Result<u64> {
+    Ok(0)
+  }
+
+  async fn save_strand(&self, strand: &Strand) -> Result<()> {
+    let car = target::car([AnyTwine::from(strand.clone())], strand.cid()).await;
+    self.import(car, true).await
+  }
+
+  async fn save_tixels(
+    &self,
+    strand: &Strand,
+    tixels: Vec<Twine>,
+  ) -> Result<()> {
+    let latest = match tixels.last() {
+      Some(latest) => latest.cid(),
+      None => return Ok(()),
+    };
+    let car = target::car(tixels.into_iter().map(AnyTwine::from), latest).await;
+    self.import(car, false).await?;
+    self.pin_latest(&strand.cid(), &latest).await?;
+    self.pin_remotely(&strand.cid(), &latest).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_pin_list() {
+    let cid = Cid::default();
+    let json = format!(r#"{{"Keys":{{"{}":{{"Type":"recursive"}}}}}}"#, cid);
+    let pins: PinList = serde_json::from_str(&json).unwrap();
+    assert_eq!(pins.cids().unwrap(), vec![cid]);
+    // kubo leaves out the keys when nothing is pinned
+    let pins: PinList = serde_json::from_str("{}").unwrap();
+    assert!(pins.cids().unwrap().is_empty());
+    let pins: PinList =
+      serde_json::from_str(r#"{"Keys":{"nope":{}}}"#).unwrap();
+    assert!(pins.cids().is_err());
+  }
+
+  #[test]
+  fn test_on_strand() {
+    // other content pinned on the node isn't mistaken for a tixel
+    assert!(!on_strand(Cid::default(), b"not a tixel", &Cid::default()));
+  }
+}
@@ -0,0 +1,189 @@
+//! Alarms for remotes falling behind.
+//!
+//! After every sync the remotes' cursors are compared with the local latest
+//! tixel of each strand. A remote is lagging on a strand when it's more than
+//! `LAG_ALERT_PULSES` tixels behind, or hasn't been caught up for more than
+//! `LAG_ALERT_MINUTES`. Either can be set, or both. A remote without a
+//! cursor for a strand hasn't been sent any of it, so it's behind by the
+//! whole strand rather than caught up. Lagging remotes are logged as `ALERT`
+//! warnings and shown in the metrics, and when `LAG_ALERT_WEBHOOK_URL` is set
+//! (eg: a Slack incoming webhook) a message is posted there when a remote
+//! starts and stops lagging.
+use crate::metrics;
+use crate::remotes::Remote;
+use anyhow::Result;
+use biab_utils::{config_parse, config_value, DbStore};
+use futures::TryStreamExt;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use twine_protocol::prelude::*;
+use twine_protocol::twine_http_store::reqwest::Client;
+
+#[derive(Debug)]
+struct StrandLag {
+  /// When the remote last had every tixel (or was first seen)
+  caught_up_at: Instant,
+  lagging: bool,
+}
+
+pub struct LagAlarm {
+  max_pulses: Option<u64>,
+  max_behind: Option<Duration>,
+  webhook: Option<String>,
+  client: Client,
+  strands: HashMap<(String, Cid), StrandLag>,
+}
+
+/// How many tixels up to `latest` a remote is missing, given the next index
+/// it's due, if any
+fn tixels_behind(next: Option<u64>, latest: u64) -> u64 {
+  (latest + 1).saturating_sub(next.unwrap_or(0))
+}
+
+/// Whether `lag` tixels, behind for `behind`, is too far
+fn is_lagging(
+  lag: u64,
+  behind: Duration,
+  max_pulses: Option<u64>,
+  max_behind: Option<Duration>,
+) -> bool {
+  lag > 0
+    && (max_pulses.is_some_and(|max| lag > max)
+      || max_behind.is_some_and(|max| behind > max))
+}
+
+impl LagAlarm {
+  /// The alarm, if a threshold is set
+  pub fn from_env() -> Result<Option<Self>> {
+    let max_pulses = config_parse("LAG_ALERT_PULSES")?;
+    let max_behind = config_parse::<u64>("LAG_ALERT_MINUTES")?
+      .map(|m| Duration::from_secs(m * 60));
+    if max_pulses.is_none() && max_behind.is_none() {
+      return Ok(None);
+    }
+    Ok(Some(Self {
+      max_pulses,
+      max_behind,
+      webhook: config_value("LAG_ALERT_WEBHOOK_URL")?,
+      client: Client::new(),
+      strands: HashMap::new(),
+    }))
+  }
+
+  /// Check every remote against the local latest tixels
+  pub async fn check(&mut self, store: &DbStore, remotes: &[Remote]) {
+    if let Err(e) = self.check_strands(store, remotes).await {
+      log::error!("Error checking remote lag: {}", e);
+    }
+  }
+
+  async fn check_strands(
+    &mut self,
+    store: &DbStore,
+    remotes: &[Remote],
+  ) -> Result<()> {
+    let strands: Vec<Strand> = store.strands().await?.try_collect().await?;
+    let now = Instant::now();
+    for strand in strands {
+      let latest = match store.resolve_latest(&strand).await {
+        Ok(latest) => latest.index(),
+        Err(ResolutionError::NotFound) => continue,
+        Err(e) => return Err(e.into()),
+      };
+      for remote in remotes {
+        let next = remote.cursors.get(&strand.cid()).copied();
+        let lag = tixels_behind(next, latest);
+        metrics::observe_lag(&remote.name, &strand.cid(), lag);
+        let state = self
+          .strands
+          .entry((remote.name.clone(), strand.cid()))
+          .or_insert(StrandLag {
+            caught_up_at: now,
+            lagging: false,
+          });
+        if lag == 0 {
+          state.caught_up_at = now;
+        }
+        let lagging = is_lagging(
+          lag,
+          now - state.caught_up_at,
+          self.max_pulses,
+          self.max_behind,
+        );
+        metrics::observe_lagging(&remote.name, &strand.cid(), lagging);
+        if lagging == state.lagging {
+          continue;
+        }
+        state.lagging = lagging;
+        let message = if lagging && next.is_none() {
+          format!(
+            "{} has none of strand {} yet (latest {})",
+            remote.name,
+            strand.cid(),
+            latest
+          )
+        } else if lagging {
+          format!(
+            "{} is {} pulses behind on strand {} (latest {})",
+            remote.name,
+            lag,
+            strand.cid(),
+            latest
+          )
+        } else {
+          format!("{} caught up on strand {}", remote.name, strand.cid())
+        };
+        if lagging {
+          log::warn!("ALERT: {}", message);
+        } else {
+          log::info!("{}", message);
+        }
+        self.notify(&message).await;
+      }
+    }
+    Ok(())
+  }
+
+  async fn notify(&self, message: &str) {
+    let url = match &self.webhook {
+      Some(url) => url,
+      None => return,
+    };
+    let body = serde_json::json!({ "text": message }).to_string();
+    let result = self
+      .client
+      .post(url)
+      .header("content-type", "application/json")
+      .body(body)
+      .send()
+      .await
+      .and_then(|response| response.error_for_status());
+    if let Err(e) = result {
+      log::error!("Failed to send lag alert: {}", e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_tixels_behind() {
+    assert_eq!(tixels_behind(None, 9), 10);
+    assert_eq!(tixels_behind(Some(0), 9), 10);
+    assert_eq!(tixels_behind(Some(8), 9), 2);
+    assert_eq!(tixels_behind(Some(10), 9), 0);
+  }
+
+  #[test]
+  fn test_is_lagging() {
+    let minute = Duration::from_secs(60);
+    assert!(!is_lagging(0, minute * 60, Some(0), Some(minute)));
+    assert!(!is_lagging(5, minute * 60, None, None));
+    assert!(!is_lagging(5, minute * 60, Some(5), None));
+    assert!(is_lagging(6, Duration::ZERO, Some(5), None));
+    assert!(!is_lagging(1, minute, None, Some(minute)));
+    assert!(is_lagging(1, minute * 2, Some(5), Some(minute)));
+  }
+}
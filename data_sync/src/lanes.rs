@@ -0,0 +1,136 @@
+//! Sending fresh pulses ahead of a backfill.
+//!
+//! When a remote is far behind, sending in order means it shows a stale
+//! latest pulse until the whole backlog is through. Remotes that don't need
+//! tixels in order (object stores) instead get the newest
+//! `SYNC_HEAD_TIXELS` (default 10) first, the "head" lane, and the backlog
+//! is filled in behind them, at most `SYNC_BACKFILL_CHUNKS` (default 10)
+//! chunks per sync, so new pulses keep going out promptly while it runs.
+//!
+//! The sync cursor only ever covers tixels sent without gaps. What was sent
+//! ahead of it is kept in memory, and once the backfill reaches it the
+//! cursor skips over it. After a restart it's sent again, which is harmless.
+use anyhow::Result;
+use biab_utils::config_or;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Lanes {
+  /// How many of the newest tixels go first, 0 to always send in order
+  pub head: u64,
+  /// At most this many backfill chunks per sync while a head is out
+  pub backfill_chunks: usize,
+}
+
+impl Lanes {
+  pub fn from_env() -> Result<Self> {
+    Ok(Self {
+      head: config_or("SYNC_HEAD_TIXELS", 10)?,
+      backfill_chunks: config_or("SYNC_BACKFILL_CHUNKS", 10usize)?.max(1),
+    })
+  }
+}
+
+/// What to send of a strand, as inclusive ranges of indices
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+  /// Sent first
+  pub head: Option<(u64, u64)>,
+  /// What will have been sent ahead of the cursor after the head
+  pub ahead: Option<(u64, u64)>,
+  /// Sent from the cursor, in order
+  pub backfill: Option<(u64, u64)>,
+}
+
+/// Plan sending up to `latest`, with `next` the cursor and `ahead` what
+/// was already sent past it
+pub fn plan(
+  next: u64,
+  latest: u64,
+  ahead: Option<(u64, u64)>,
+  head: u64,
+) -> Plan {
+  if latest < next {
+    return Plan::default();
+  }
+  let (head_range, ahead) = match ahead {
+    // keep extending what's ahead while it's close to the latest
+    Some((start, end)) if latest.saturating_sub(end) <= head => (
+      (end < latest).then_some((end + 1, latest)),
+      Some((start, latest)),
+    ),
+    // far enough behind to send the newest first
+    _ if head > 0 && latest + 1 - next > head => {
+      let range = (latest + 1 - head, latest);
+      (Some(range), Some(range))
+    }
+    _ => (None, None),
+  };
+  let backfill_end = ahead.map(|(start, _)| start - 1).unwrap_or(latest);
+  Plan {
+    head: head_range,
+    ahead,
+    backfill: (next <= backfill_end).then_some((next, backfill_end)),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_plan() {
+    // caught up
+    assert_eq!(plan(10, 9, None, 10), Plan::default());
+    // a small backlog goes in order
+    assert_eq!(
+      plan(5, 9, None, 10),
+      Plan {
+        backfill: Some((5, 9)),
+        ..Plan::default()
+      }
+    );
+    assert_eq!(
+      plan(0, 99, None, 0),
+      Plan {
+        backfill: Some((0, 99)),
+        ..Plan::default()
+      }
+    );
+    // a big one sends the newest first
+    assert_eq!(
+      plan(0, 99, None, 10),
+      Plan {
+        head: Some((90, 99)),
+        ahead: Some((90, 99)),
+        backfill: Some((0, 89)),
+      }
+    );
+    // new tixels extend what's ahead
+    assert_eq!(
+      plan(20, 102, Some((90, 99)), 10),
+      Plan {
+        head: Some((100, 102)),
+        ahead: Some((90, 102)),
+        backfill: Some((20, 89)),
+      }
+    );
+    // nothing new
+    assert_eq!(
+      plan(20, 99, Some((90, 99)), 10),
+      Plan {
+        head: None,
+        ahead: Some((90, 99)),
+        backfill: Some((20, 89)),
+      }
+    );
+    // too far past what's ahead starts a new head
+    assert_eq!(
+      plan(20, 200, Some((90, 99)), 10),
+      Plan {
+        head: Some((191, 200)),
+        ahead: Some((191, 200)),
+        backfill: Some((20, 190)),
+      }
+    );
+  }
+}
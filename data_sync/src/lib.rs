@@ -0,0 +1,658 @@
+use anyhow::{anyhow, Result};
+use biab_utils::{config_or, handle_shutdown_signal, init_logger};
+use biab_utils::{DbStore, PulsePublished};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Notify;
+use tokio::time::{sleep, sleep_until};
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::resolver::unchecked_base::BaseResolver;
+use twine_sql_store::sqlx::MySqlPool;
+
+mod archive;
+mod audit;
+mod breaker;
+mod chain_audit;
+mod chain_verify;
+mod config;
+mod cursors;
+mod http_target;
+mod ipfs;
+mod lag;
+mod lanes;
+mod metrics;
+mod plan;
+mod pushback;
+mod receipts;
+mod remotes;
+mod s3;
+mod status;
+mod target;
+mod throttle;
+mod upstreams;
+mod verify;
+mod webhooks;
+use config::Config;
+use cursors::SyncCursors;
+use lag::LagAlarm;
+use lanes::Lanes;
+use receipts::{Delivery, Receipts};
+use remotes::Remote;
+use throttle::Throttle;
+use upstreams::Upstream;
+use verify::Verify;
+use webhooks::Webhooks;
+
+/// How remotes are synced
+struct SyncOptions {
+  concurrency: usize,
+  verify: Verify,
+  throttle: Throttle,
+  lanes: Lanes,
+  /// How many chunks to read ahead of the one being sent
+  pipeline_depth: usize,
+  webhooks: Webhooks,
+  receipts: Option<Receipts>,
+}
+
+impl SyncOptions {
+  async fn from_env() -> Result<Self> {
+    Ok(Self {
+      concurrency: config_or("SYNC_CONCURRENCY", 2usize)?.max(1),
+      verify: Verify::from_env()?,
+      throttle: Throttle::from_env()?,
+      lanes: Lanes::from_env()?,
+      pipeline_depth: config_or("SYNC_PIPELINE_DEPTH", 2usize)?.max(1),
+      webhooks: Webhooks::from_env()?,
+      receipts: Receipts::from_env().await?,
+    })
+  }
+}
+
+#[derive(Debug, Clone)]
+struct Signals {
+  pub shutdown: Arc<Notify>,
+  pub start_sync: Arc<Notify>,
+  pub start_audit: Arc<Notify>,
+  pub start_chain_audit: Arc<Notify>,
+  pub reload: Arc<Notify>,
+}
+
+/// What data sync does, for `--help`
+pub const ABOUT: &str =
+  "Pushes the beacon's strands to remote stores, and pulls those of upstream beacons";
+
+/// Data sync's commands, for `--help`
+pub const COMMANDS: &[(&str, &str)] = &[(
+  "verify",
+  "verify a strand's chain: verify <store URL or CAR file> <strand CID> \
+   [--from <index>] [--json]",
+)];
+
+/// Run data sync, or one of its commands
+pub async fn run() -> Result<()> {
+  if biab_utils::help(ABOUT, COMMANDS) {
+    return Ok(());
+  }
+  // the report is printed without the logs
+  if biab_utils::command() == Some("verify") {
+    return chain_verify::run(&biab_utils::args()[1..]).await;
+  }
+  init_logger();
+  if let Some(command) = biab_utils::command() {
+    return Err(anyhow!("Unknown command {}, see --help", command));
+  }
+  let config = Config::load()?;
+  if biab_utils::print_config(&config, true)? {
+    return Ok(());
+  }
+
+  // Setup graceful shutdown
+  let shutdown = Arc::new(Notify::new());
+  tokio::spawn(handle_shutdown_signal(shutdown.clone()));
+
+  let signals = Signals {
+    shutdown,
+    start_sync: Arc::new(Notify::new()),
+    start_audit: Arc::new(Notify::new()),
+    start_chain_audit: Arc::new(Notify::new()),
+    reload: Arc::new(Notify::new()),
+  };
+
+  init_sync_scheduler(signals.clone(), config.sync_period());
+  init_audit_scheduler(signals.clone(), config.audit_period());
+  init_tcp_listener(signals.clone());
+  init_pulse_events(signals.clone()).await?;
+  init_reload_signal(signals.clone());
+  metrics::init_metrics_server(signals.shutdown.clone())?;
+
+  let store = biab_utils::open_store().await?;
+
+  let pool = biab_utils::open_pool().await?;
+  let cursors = SyncCursors::new(pool.clone());
+  let options = SyncOptions::from_env().await?;
+  let mut remotes = remotes::from_env(options.throttle.chunk_size)?;
+  for remote in remotes.iter_mut() {
+    remote.load_cursors(&cursors).await?;
+  }
+  let upstreams = upstreams::from_env()?;
+  if remotes.is_empty() && upstreams.is_empty() {
+    return Err(anyhow!(
+      "Nothing to sync. Set REMOTES_PATH, REMOTE_STORE_ADDRESS or \
+       UPSTREAMS_PATH"
+    ));
+  }
+  if config.dry_run {
+    return plan::dry_run(&store, &remotes).await;
+  }
+
+  init_chain_audit(
+    signals.clone(),
+    config.chain_audit_period(),
+    options.throttle.chunk_size,
+  );
+  // Start the worker and sync immediately
+  signals.start_sync.notify_one();
+  worker(signals, store, pool, cursors, options, remotes, upstreams).await
+}
+
+fn init_tcp_listener(signals: Signals) {
+  let addr = biab_utils::listen_address().expect("Invalid LISTEN_ADDR");
+  // Start TCP server
+  let mut messages =
+    biab_utils::start_tcp_server(addr, signals.shutdown.clone());
+
+  // listen for messages from the TCP server
+  tokio::spawn(async move {
+    while let Some(message) = messages.recv().await {
+      log::trace!("Received message: {:?}", message);
+      match message.command.as_str() {
+        "sync" => signals.start_sync.notify_one(),
+        "audit" => signals.start_audit.notify_one(),
+        "chain-audit" => signals.start_chain_audit.notify_one(),
+        "reload" => signals.reload.notify_one(),
+        _ => {}
+      }
+    }
+  });
+}
+
+/// Sync as soon as the generator publishes a pulse, if subscribed to its
+/// events (see [biab_utils::event_bus])
+async fn init_pulse_events(signals: Signals) -> Result<()> {
+  let Some(bus) = biab_utils::event_bus(signals.shutdown.clone()).await? else {
+    return Ok(());
+  };
+  let mut events = bus.subscribe(&[biab_utils::PULSE_PUBLISHED_TOPIC]).await?;
+  tokio::spawn(async move {
+    while let Some(event) = events.recv().await {
+      log::trace!("Received event: {:?}", event);
+      if let Ok(Some(pulse)) = event.extract_payload::<PulsePublished>() {
+        tracing::debug!(
+          strand = %pulse.strand,
+          index = pulse.index,
+          "Pulse published, syncing"
+        );
+      }
+      signals.start_sync.notify_one();
+    }
+  });
+  Ok(())
+}
+
+fn init_reload_signal(signals: Signals) {
+  use tokio::signal::unix::{signal, SignalKind};
+  // Reload the remotes and upstreams on SIGHUP
+  let mut hangup = signal(SignalKind::hangup()).expect("SIGHUP handler");
+  tokio::spawn(async move {
+    loop {
+      tokio::select! {
+        _ = hangup.recv() => {
+          signals.reload.notify_one();
+        }
+        _ = signals.shutdown.notified() => {
+          break;
+        }
+      }
+    }
+  });
+}
+
+fn init_sync_scheduler(signals: Signals, period: std::time::Duration) {
+  // Send a start sync signal every period
+  tokio::spawn(async move {
+    loop {
+      tokio::select! {
+        _ = sleep(period) => {
+          signals.start_sync.notify_one();
+        }
+        _ = signals.shutdown.notified() => {
+          break;
+        }
+      }
+    }
+  });
+}
+
+fn init_audit_scheduler(signals: Signals, period: Option<std::time::Duration>) {
+  // Audits only run periodically if configured
+  let Some(period) = period else {
+    return;
+  };
+
+  tokio::spawn(async move {
+    loop {
+      tokio::select! {
+        _ = sleep(period) => {
+          signals.start_audit.notify_one();
+        }
+        _ = signals.shutdown.notified() => {
+          break;
+        }
+      }
+    }
+  });
+}
+
+fn init_chain_audit(
+  signals: Signals,
+  period: Option<std::time::Duration>,
+  chunk_size: usize,
+) {
+  tokio::spawn(async move {
+    loop {
+      tokio::select! {
+        _ = async {
+          match period {
+            Some(period) => sleep(period).await,
+            None => std::future::pending().await,
+          }
+        } => {}
+        _ = signals.start_chain_audit.notified() => {}
+        _ = signals.shutdown.notified() => {
+          break;
+        }
+      }
+      log::info!("Starting chain audit...");
+      // the remotes as currently configured, apart from the syncing ones
+      let result = async {
+        let store = biab_utils::open_store().await?;
+        let remotes = remotes::from_env(chunk_size)?;
+        chain_audit::run(&store, &remotes).await
+      };
+      tokio::select! {
+        result = result => match result {
+          Ok(path) => {
+            log::info!("Chain audit written to {}", path.display())
+          }
+          Err(e) => log::error!("Chain audit failed: {}", e),
+        },
+        _ = signals.shutdown.notified() => {
+          break;
+        }
+      }
+    }
+  });
+}
+
+async fn worker(
+  signals: Signals,
+  store: DbStore,
+  pool: MySqlPool,
+  cursors: SyncCursors,
+  options: SyncOptions,
+  mut remotes: Vec<Remote>,
+  mut upstreams: Vec<Upstream>,
+) -> Result<()> {
+  let audit_max_tixels = audit::max_tixels_from_env()?;
+  let mut lag_alarm = LagAlarm::from_env()?;
+
+  let worker = tokio::spawn(async move {
+    let mut retry: Option<tokio::task::JoinHandle<()>> = None;
+    loop {
+      tokio::select! {
+        _ = signals.shutdown.notified() => {
+          log::info!("Stopping tasks...");
+          break;
+        }
+        _ = signals.start_sync.notified() => {
+          log::debug!("Starting sync...");
+        }
+        _ = signals.start_audit.notified() => {
+          log::info!("Starting audit...");
+          tokio::select! {
+            _ = signals.shutdown.notified() => {
+              log::info!("Stopping tasks...");
+              break;
+            }
+            _ = start_audit(
+              &mut remotes,
+              options.concurrency,
+              audit_max_tixels,
+            ) => {}
+          }
+          // then sync, which repairs whatever was found missing
+        }
+        _ = signals.reload.notified() => {
+          log::info!("Reloading remotes and upstreams...");
+          let chunk_size = options.throttle.chunk_size;
+          if let Err(e) =
+            remotes::reload(&mut remotes, chunk_size, &cursors).await
+          {
+            log::error!("Failed to reload remotes, keeping them: {}", e);
+          }
+          match upstreams::from_env() {
+            Ok(reloaded) => upstreams = reloaded,
+            Err(e) => {
+              log::error!("Failed to reload upstreams, keeping them: {}", e)
+            }
+          }
+          // then sync with whatever changed
+        }
+      }
+
+      tokio::select! {
+        _ = signals.shutdown.notified() => {
+          log::info!("Stopping tasks...");
+          break;
+        }
+        _ = async {
+          // pull first, so new upstream tixels go on to remotes right away
+          start_pull(&store, &pool, &mut upstreams, &options).await;
+          start_sync(&store, &cursors, &mut remotes, &options).await;
+          if let Some(lag_alarm) = lag_alarm.as_mut() {
+            lag_alarm.check(&store, &remotes).await;
+          }
+        } => {}
+      }
+
+      // come back for remotes and upstreams that are backing off
+      if let Some(retry) = retry.take() {
+        retry.abort();
+      }
+      let now = Instant::now();
+      let next_retry = remotes
+        .iter()
+        .filter_map(Remote::retry_at)
+        .chain(upstreams.iter().filter_map(Upstream::retry_at))
+        .filter(|at| *at > now)
+        .min();
+      // keep filling in behind tixels sent ahead
+      if remotes.iter().any(|remote| {
+        !remote.ahead.is_empty() && remote.retry_at().is_none_or(|at| at <= now)
+      }) {
+        signals.start_sync.notify_one();
+      }
+      if let Some(at) = next_retry {
+        let start_sync = signals.start_sync.clone();
+        retry = Some(tokio::spawn(async move {
+          sleep_until(at.into()).await;
+          start_sync.notify_one();
+        }));
+      }
+    }
+  });
+
+  worker.await?;
+  Ok(())
+}
+
+#[tracing::instrument(name = "pull", skip_all)]
+async fn start_pull(
+  store: &DbStore,
+  pool: &MySqlPool,
+  upstreams: &mut [Upstream],
+  options: &SyncOptions,
+) {
+  use futures::StreamExt;
+  futures::stream::iter(upstreams.iter_mut())
+    .for_each_concurrent(options.concurrency, |upstream| {
+      upstream.pull(store, pool, options.throttle.chunk_size)
+    })
+    .await;
+}
+
+#[tracing::instrument(name = "sync", skip_all)]
+async fn start_sync(
+  store: &DbStore,
+  cursors: &SyncCursors,
+  remotes: &mut [Remote],
+  options: &SyncOptions,
+) {
+  use futures::StreamExt;
+  log::debug!("Beginning sync...");
+  futures::stream::iter(remotes.iter_mut())
+    .for_each_concurrent(options.concurrency, |remote| async move {
+      if !remote.ready() {
+        log::debug!("Skipping {} after recent failures", remote.name);
+        return;
+      }
+      match sync_remote(store, cursors, remote, options).await {
+        Ok(()) => remote.succeeded(),
+        Err(e) => remote.failed(e),
+      }
+    })
+    .await;
+  log::debug!("Sync complete");
+}
+
+async fn start_audit(
+  remotes: &mut [Remote],
+  concurrency: usize,
+  max_tixels: u64,
+) {
+  use futures::StreamExt;
+  let now = Instant::now();
+  futures::stream::iter(remotes.iter_mut())
+    .for_each_concurrent(concurrency, |remote| async move {
+      // leave remotes that are failing alone
+      if remote.retry_at().is_some_and(|at| at > now) {
+        return;
+      }
+      match audit::audit_remote(remote, max_tixels).await {
+        Ok(0) => log::debug!("Audit of {} found no gaps", remote.name),
+        Ok(missing) => log::warn!(
+          "Audit of {} found {} missing tixels, repairing",
+          remote.name,
+          missing
+        ),
+        Err(e) => log::error!("Error auditing {}: {}", remote.name, e),
+      }
+    })
+    .await;
+}
+
+#[tracing::instrument(
+  name = "sync_remote",
+  skip_all,
+  fields(remote = %remote.name)
+)]
+async fn sync_remote(
+  store: &DbStore,
+  cursors: &SyncCursors,
+  remote: &mut Remote,
+  options: &SyncOptions,
+) -> Result<()> {
+  use futures::TryStreamExt;
+  log::debug!("Syncing to {}...", remote.name);
+  // fill in gaps found by audits first
+  while let Some(range) = remote.repairs.first().copied() {
+    log::info!("Repairing {} on {}", range, remote.name);
+    let strand = store.resolve_strand(range.strand).await?.unpack();
+    let tixels: Vec<Twine> =
+      store.resolve_range(range).await?.try_collect().await?;
+    let count = tixels.len();
+    remote.target.save_tixels(&strand, tixels).await?;
+    metrics::observe_tixels(&remote.name, count);
+    remote.repairs.remove(0);
+  }
+
+  let strands: Vec<Strand> = store.strands().await?.try_collect().await?;
+  for strand in strands {
+    let cid = strand.cid();
+    let latest = match store.resolve_latest(&strand).await {
+      Ok(latest) => latest.index(),
+      Err(ResolutionError::NotFound) => {
+        log::error!("No latest tixel for strand: {}", cid);
+        continue;
+      }
+      Err(e) => {
+        log::error!("Error resolving latest tixel: {}", e);
+        continue;
+      }
+    };
+    let starting_index = match remote.cursors.get(&cid) {
+      Some(next) => *next,
+      // nothing synced yet, so ask the remote where to start
+      None => {
+        let next = remote.target.next_index(&strand).await.map_err(|e| {
+          anyhow!("Error resolving latest tixel of strand {}: {}", cid, e)
+        })?;
+        // a mirrored strand may start after 0
+        let next = next.max(upstreams::first_index(store, &cid, latest).await?);
+        cursors.save(&remote.name, &cid, next).await?;
+        remote.cursors.insert(cid, next);
+        next
+      }
+    };
+
+    status::observe_strand(&remote.name, &cid, starting_index, latest);
+    let head = if remote.target.in_order() {
+      0
+    } else {
+      options.lanes.head
+    };
+    let ahead = remote.ahead.get(&cid).copied();
+    let plan = lanes::plan(starting_index, latest, ahead, head);
+    if plan == lanes::Plan::default() {
+      log::debug!(
+        "No new tixels to sync to {} for strand: {}",
+        remote.name,
+        cid
+      );
+      continue;
+    }
+
+    // if we're starting at the first tixel, save the strand first
+    if ahead.is_none()
+      && (starting_index == 0
+        || !store.has_index(&cid, starting_index - 1).await?)
+    {
+      remote.target.save_strand(&strand).await?;
+    }
+    if let Some((start, end)) = plan.head {
+      let range = AbsoluteRange::new(cid, start, end);
+      log::debug!("Syncing newest tixels to {} first: {}", remote.name, range);
+      send_range(store, None, remote, &strand, range, options, usize::MAX)
+        .await?;
+    }
+    if let Some(ahead) = plan.ahead {
+      remote.ahead.insert(cid, ahead);
+    }
+    if let Some((start, end)) = plan.backfill {
+      let range = AbsoluteRange::new(cid, start, end);
+      log::debug!("Syncing range to {}: {}", remote.name, range);
+      // a little at a time while newer tixels are waiting
+      let max_chunks = match plan.ahead {
+        Some(_) => options.lanes.backfill_chunks,
+        None => usize::MAX,
+      };
+      send_range(
+        store,
+        Some(cursors),
+        remote,
+        &strand,
+        range,
+        options,
+        max_chunks,
+      )
+      .await?;
+    }
+    // once the backfill reaches what was sent ahead, skip over it
+    if let Some((start, end)) = plan.ahead {
+      if remote.cursors.get(&cid).is_some_and(|next| *next >= start) {
+        cursors.save(&remote.name, &cid, end + 1).await?;
+        remote.cursors.insert(cid, end + 1);
+        remote.ahead.remove(&cid);
+      }
+    }
+    let next = remote.cursors.get(&cid).copied().unwrap_or(starting_index);
+    status::observe_strand(&remote.name, &cid, next, latest);
+    // only when this sync got the latest out, not while backfilling behind it
+    let sent_latest =
+      plan.head.is_some() || (plan.ahead.is_none() && next == latest + 1);
+    if sent_latest {
+      options.webhooks.notify(&remote.name, &cid, latest);
+    }
+  }
+  Ok(())
+}
+
+/// Send a range of tixels a chunk at a time, up to `max_chunks`, moving the
+/// cursor after each one when given the cursors. The next chunks are read
+/// from the local store while one is being sent, up to `SYNC_PIPELINE_DEPTH`
+/// of them.
+async fn send_range(
+  store: &DbStore,
+  cursors: Option<&SyncCursors>,
+  remote: &mut Remote,
+  strand: &Strand,
+  range: AbsoluteRange,
+  options: &SyncOptions,
+  max_chunks: usize,
+) -> Result<()> {
+  use futures::{StreamExt, TryStreamExt};
+  let stream = store.resolve_range(range).await?;
+  let (tx, mut rx) = tokio::sync::mpsc::channel(options.pipeline_depth);
+  let chunk_size = remote.chunk_size;
+  let read = async move {
+    let mut chunks = stream.try_chunks(chunk_size).take(max_chunks);
+    while let Some(chunk) = chunks.try_next().await.map_err(|e| anyhow!(e))? {
+      if tx.send(chunk).await.is_err() {
+        break;
+      }
+    }
+    Ok::<_, anyhow::Error>(())
+  };
+  let mut delivery = options
+    .receipts
+    .as_ref()
+    .map(|_| Delivery::new(&remote.name, range.strand));
+  let send = async {
+    let mut first = true;
+    while let Some(chunk) = rx.recv().await {
+      let next = match chunk.last() {
+        Some(last) => last.index() + 1,
+        None => continue,
+      };
+      let bytes = chunk.iter().map(|t| t.bytes().len() as u64).sum();
+      options.throttle.wait(bytes, first).await;
+      first = false;
+      let count = chunk.len();
+      log::debug!("Saving chunk of {} tixels to {}", count, remote.name);
+      let expected = options.verify.expected(&chunk);
+      let delivered = delivery.as_ref().map(|d| d.with(&chunk));
+      remote.target.save_tixels(strand, chunk).await?;
+      if delivered.is_some() {
+        delivery = delivered;
+      }
+      remote.chunk_accepted();
+      metrics::observe_tixels(&remote.name, count);
+      if let Some(cursors) = cursors {
+        cursors.save(&remote.name, &range.strand, next).await?;
+        remote.cursors.insert(range.strand, next);
+      }
+      verify::verify(remote, strand, &expected).await?;
+    }
+    Ok::<_, anyhow::Error>(())
+  };
+  let result = futures::try_join!(read, send);
+  // what got through before any error is still receipted
+  if let (Some(receipts), Some(delivery)) = (&options.receipts, delivery) {
+    if let Err(e) = receipts.record(delivery).await {
+      log::error!("Failed to record sync receipt: {}", e);
+    }
+  }
+  result?;
+  Ok(())
+}
@@ -0,0 +1,136 @@
+//! Prometheus metrics at `GET /metrics` on `METRICS_ADDR`, if set.
+//!
+//! Sync attempts are counted per remote or upstream, along with the tixels
+//! sent to remotes, the tixels pulled from upstreams and circuit breaker
+//! state changes. The current breaker state of each is a gauge (0 closed, 1
+//! half open, 2 open). Tixels read back from remotes are counted by result
+//! (see [crate::verify]). When lag alerts are on, how far behind each
+//! remote is on each strand is a gauge too (see [crate::lag]).
+use crate::breaker::State;
+use crate::status;
+use biab_utils::{int_counter_vec, int_gauge_vec};
+use prometheus::{IntCounterVec, IntGaugeVec};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::Notify;
+use twine_protocol::prelude::*;
+use warp::Filter;
+
+static SYNCS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "data_sync_syncs_total",
+    "Sync attempts by remote or upstream and result (ok or error)",
+    &["remote", "result"],
+  )
+});
+
+static TIXELS_SYNCED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "data_sync_tixels_synced_total",
+    "Tixels accepted by each remote",
+    &["remote"],
+  )
+});
+
+static TIXELS_PULLED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "data_sync_tixels_pulled_total",
+    "Tixels pulled from each upstream",
+    &["upstream"],
+  )
+});
+
+static LAG: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+  int_gauge_vec(
+    "data_sync_lag_tixels",
+    "Tixels each remote is behind the local latest, by strand",
+    &["remote", "strand"],
+  )
+});
+
+static LAGGING: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+  int_gauge_vec(
+    "data_sync_lagging",
+    "Whether each remote is past the lag alert threshold, by strand",
+    &["remote", "strand"],
+  )
+});
+
+static BREAKER_STATE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+  int_gauge_vec(
+    "data_sync_breaker_state",
+    "Circuit breaker state by remote or upstream (0 closed, 1 half open, \
+     2 open)",
+    &["remote"],
+  )
+});
+
+static BREAKER_TRANSITIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "data_sync_breaker_transitions_total",
+    "Circuit breaker state changes by remote or upstream and new state",
+    &["remote", "state"],
+  )
+});
+
+static TIXELS_VERIFIED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "data_sync_tixels_verified_total",
+    "Tixels read back from each remote by result (ok, mismatch or missing)",
+    &["remote", "result"],
+  )
+});
+
+pub fn observe_sync(remote: &str, ok: bool) {
+  SYNCS
+    .with_label_values(&[remote, if ok { "ok" } else { "error" }])
+    .inc();
+}
+
+pub fn observe_tixels(remote: &str, count: usize) {
+  TIXELS_SYNCED
+    .with_label_values(&[remote])
+    .inc_by(count as u64);
+}
+
+pub fn observe_pulled(upstream: &str, count: usize) {
+  TIXELS_PULLED
+    .with_label_values(&[upstream])
+    .inc_by(count as u64);
+}
+
+pub fn observe_verified(remote: &str, result: &str) {
+  TIXELS_VERIFIED.with_label_values(&[remote, result]).inc();
+}
+
+pub fn observe_lag(remote: &str, strand: &Cid, lag: u64) {
+  LAG
+    .with_label_values(&[remote, &strand.to_string()])
+    .set(lag as i64);
+}
+
+pub fn observe_lagging(remote: &str, strand: &Cid, lagging: bool) {
+  LAGGING
+    .with_label_values(&[remote, &strand.to_string()])
+    .set(lagging as i64);
+}
+
+pub fn observe_breaker(remote: &str, state: State, changed: bool) {
+  let value = match state {
+    State::Closed => 0,
+    State::HalfOpen => 1,
+    State::Open => 2,
+  };
+  BREAKER_STATE.with_label_values(&[remote]).set(value);
+  if changed {
+    BREAKER_TRANSITIONS
+      .with_label_values(&[remote, state.name()])
+      .inc();
+  }
+}
+
+/// Serve the metrics and sync status until shutdown, if `METRICS_ADDR` is
+/// set
+pub fn init_metrics_server(shutdown: Arc<Notify>) -> anyhow::Result<()> {
+  let status = warp::get().and(warp::path!("status").map(status::render));
+  biab_utils::start_metrics_server_with(shutdown, status)
+}
@@ -0,0 +1,96 @@
+//! Dry runs, showing what a sync would send without sending it.
+//!
+//! With `SYNC_DRY_RUN=true` the service works out, for every remote and
+//! strand, the range of tixels the next sync would send (from the saved
+//! cursors, or asking the remote where it is), how many objects that is and
+//! roughly how many bytes, prints the plan and exits. Nothing is written to
+//! remotes, cursors aren't saved and upstreams aren't pulled.
+use crate::remotes::Remote;
+use anyhow::Result;
+use biab_utils::DbStore;
+use futures::TryStreamExt;
+use twine_protocol::prelude::*;
+
+/// A byte count for people
+fn human_bytes(bytes: u64) -> String {
+  const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+  let mut value = bytes as f64;
+  let mut unit = 0;
+  while value >= 1000.0 && unit < UNITS.len() - 1 {
+    value /= 1000.0;
+    unit += 1;
+  }
+  if unit == 0 {
+    format!("{} B", bytes)
+  } else {
+    format!("{:.1} {}", value, UNITS[unit])
+  }
+}
+
+/// Print what the next sync would send to each remote
+pub async fn dry_run(store: &DbStore, remotes: &[Remote]) -> Result<()> {
+  let strands: Vec<Strand> = store.strands().await?.try_collect().await?;
+  for remote in remotes {
+    println!("{}:", remote.name);
+    let mut total_objects = 0;
+    let mut total_bytes = 0;
+    for strand in &strands {
+      let cid = strand.cid();
+      let latest = match store.resolve_latest(strand).await {
+        Ok(latest) => latest,
+        Err(ResolutionError::NotFound) => continue,
+        Err(e) => return Err(e.into()),
+      };
+      let next = match remote.cursors.get(&cid) {
+        Some(next) => *next,
+        None => match remote.target.next_index(strand).await {
+          Ok(next) => next,
+          Err(e) => {
+            println!("  {}: can't tell where the remote is: {}", cid, e);
+            continue;
+          }
+        },
+      };
+      if latest.index() < next {
+        println!("  {}: up to date at {}", cid, latest.index());
+        continue;
+      }
+      let tixels = latest.index() + 1 - next;
+      // tixels of a strand are about the same size
+      let mut bytes = tixels * latest.bytes().len() as u64;
+      let mut objects = tixels;
+      if next == 0 {
+        bytes += strand.bytes().len() as u64;
+        objects += 1;
+      }
+      println!(
+        "  {}: {} to {} ({} objects, ~{})",
+        cid,
+        next,
+        latest.index(),
+        objects,
+        human_bytes(bytes)
+      );
+      total_objects += objects;
+      total_bytes += bytes;
+    }
+    println!(
+      "  total: {} objects, ~{}",
+      total_objects,
+      human_bytes(total_bytes)
+    );
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_human_bytes() {
+    assert_eq!(human_bytes(999), "999 B");
+    assert_eq!(human_bytes(1500), "1.5 KB");
+    assert_eq!(human_bytes(2_340_000_000), "2.3 GB");
+  }
+}
@@ -0,0 +1,121 @@
+//! Remotes asking for less.
+//!
+//! A remote that rate limits (429), is overloaded or briefly down (5xx) or
+//! refuses a request as too large (413) is pushing back, which is treated
+//! differently to other errors: its `Retry-After` header is honored when
+//! it's longer than the usual backoff (the failure still counts towards its
+//! circuit breaker), and the chunks sent to it get smaller. They grow back a
+//! little with every chunk it accepts.
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use twine_protocol::twine_http_store::reqwest::{header, Response, StatusCode};
+
+#[derive(Debug)]
+pub struct Pushback {
+  pub status: StatusCode,
+  /// How long the remote asked to be left alone for
+  pub retry_after: Option<Duration>,
+  message: String,
+}
+
+impl std::fmt::Display for Pushback {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{}", self.message)?;
+    if let Some(retry_after) = self.retry_after {
+      write!(f, " (retry after {:?})", retry_after)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for Pushback {}
+
+fn is_pushback(status: StatusCode) -> bool {
+  status == StatusCode::TOO_MANY_REQUESTS
+    || status == StatusCode::PAYLOAD_TOO_LARGE
+    || status.is_server_error()
+}
+
+/// Parse a `Retry-After` header, either seconds or an HTTP date
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+  if let Ok(seconds) = value.trim().parse::<u64>() {
+    return Some(Duration::from_secs(seconds));
+  }
+  let at = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+  Some((at.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+}
+
+/// The chunk size after a chunk was accepted or pushed back on
+pub fn next_chunk_size(current: usize, max: usize, pushed_back: bool) -> usize {
+  if pushed_back {
+    (current / 2).max(1)
+  } else {
+    (current + (max / 10).max(1)).min(max)
+  }
+}
+
+/// The response if it succeeded, or else an error describing it, which is a
+/// [Pushback] if the remote is pushing back
+pub async fn check(response: Response) -> anyhow::Result<Response> {
+  let status = response.status();
+  if status.is_success() {
+    return Ok(response);
+  }
+  let retry_after = response
+    .headers()
+    .get(header::RETRY_AFTER)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| parse_retry_after(value, Utc::now()));
+  let message = format!(
+    "{} responded {}: {}",
+    response.url(),
+    status,
+    response.text().await.unwrap_or_default()
+  );
+  if is_pushback(status) {
+    return Err(
+      Pushback {
+        status,
+        retry_after,
+        message,
+      }
+      .into(),
+    );
+  }
+  Err(anyhow!(message))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_retry_after() {
+    let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+      .unwrap()
+      .with_timezone(&Utc);
+    assert_eq!(
+      parse_retry_after("120", now),
+      Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+      parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+      Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+      parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+      Some(Duration::ZERO)
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
+  }
+
+  #[test]
+  fn test_next_chunk_size() {
+    assert_eq!(next_chunk_size(1000, 1000, true), 500);
+    assert_eq!(next_chunk_size(1, 1000, true), 1);
+    assert_eq!(next_chunk_size(500, 1000, false), 600);
+    assert_eq!(next_chunk_size(950, 1000, false), 1000);
+    assert_eq!(next_chunk_size(3, 5, false), 4);
+  }
+}
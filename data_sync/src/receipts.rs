@@ -0,0 +1,212 @@
+//! Signed receipts of what was sent to which remote, and when.
+//!
+//! If `SYNC_RECEIPTS_PATH` is set, every range of tixels delivered to a
+//! remote is recorded as a line of JSON appended to that file:
+//!
+//! ```json
+//! {"remote": "primary", "strand": "bafyrei...", "start": 100, "end": 199,
+//!  "timestamp": "2025-03-01T12:00:00Z", "content_hash": "sha-256:...",
+//!  "key_id": "...", "signature": "..."}
+//! ```
+//!
+//! The content hash is the SHA-256 of the tixels' bytes, in order. Receipts
+//! are signed with the PEM encoded Ed25519 private key (PKCS#8) at
+//! `SYNC_RECEIPT_KEY_PATH`, which is required with `SYNC_RECEIPTS_PATH`. The
+//! signature (hex) is over the lines of [Receipt::message]. The key id
+//! defaults to the hex encoded public key and can be set with
+//! `SYNC_RECEIPT_KEY_ID`.
+use anyhow::{anyhow, Result};
+use biab_utils::config_value;
+use chrono::{DateTime, SecondsFormat, Utc};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use twine_protocol::prelude::*;
+
+#[derive(Debug, Serialize)]
+struct Receipt {
+  remote: String,
+  strand: String,
+  start: u64,
+  end: u64,
+  timestamp: String,
+  content_hash: String,
+  key_id: String,
+  signature: String,
+}
+
+impl Receipt {
+  /// What is signed, one field per line
+  fn message(&self) -> String {
+    format!(
+      "{}\n{}\n{}\n{}\n{}\n{}",
+      self.remote,
+      self.strand,
+      self.start,
+      self.end,
+      self.timestamp,
+      self.content_hash
+    )
+  }
+}
+
+/// A range delivered to a remote, hashed as it goes
+#[derive(Clone)]
+pub struct Delivery {
+  remote: String,
+  strand: Cid,
+  range: Option<(u64, u64)>,
+  hasher: Sha256,
+}
+
+impl Delivery {
+  pub fn new(remote: &str, strand: Cid) -> Self {
+    Self {
+      remote: remote.to_string(),
+      strand,
+      range: None,
+      hasher: Sha256::new(),
+    }
+  }
+
+  /// The delivery once the remote accepts these tixels, which follow on
+  pub fn with(&self, tixels: &[Twine]) -> Self {
+    let mut next = self.clone();
+    next.add(tixels);
+    next
+  }
+
+  fn add(&mut self, tixels: &[Twine]) {
+    for tixel in tixels {
+      self.hasher.update(tixel.bytes());
+      self.range = match self.range {
+        Some((start, _)) => Some((start, tixel.index())),
+        None => Some((tixel.index(), tixel.index())),
+      };
+    }
+  }
+}
+
+struct Signer {
+  key: Ed25519KeyPair,
+  key_id: String,
+}
+
+impl Signer {
+  fn sign(&self, delivery: Delivery, at: DateTime<Utc>) -> Option<Receipt> {
+    let (start, end) = delivery.range?;
+    let mut receipt = Receipt {
+      remote: delivery.remote,
+      strand: delivery.strand.to_string(),
+      start,
+      end,
+      timestamp: at.to_rfc3339_opts(SecondsFormat::Secs, true),
+      content_hash: format!(
+        "sha-256:{}",
+        hex::encode(delivery.hasher.finalize())
+      ),
+      key_id: self.key_id.clone(),
+      signature: String::new(),
+    };
+    receipt.signature =
+      hex::encode(self.key.sign(receipt.message().as_bytes()));
+    Some(receipt)
+  }
+}
+
+pub struct Receipts {
+  signer: Signer,
+  file: Mutex<File>,
+}
+
+impl Receipts {
+  pub async fn from_env() -> Result<Option<Self>> {
+    let path = match config_value("SYNC_RECEIPTS_PATH")? {
+      Some(path) => path,
+      None => return Ok(None),
+    };
+    let key_path = config_value("SYNC_RECEIPT_KEY_PATH")?.ok_or_else(|| {
+      anyhow!("SYNC_RECEIPT_KEY_PATH is needed to sign sync receipts")
+    })?;
+    let pem = std::fs::read(&key_path)
+      .map_err(|e| anyhow!("Failed to read {}: {}", key_path, e))?;
+    let der = rustls_pemfile::private_key(&mut pem.as_slice())
+      .map_err(|e| anyhow!("Invalid signing key {}: {}", key_path, e))?
+      .ok_or_else(|| anyhow!("No private key in {}", key_path))?;
+    let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der.secret_der())
+      .map_err(|e| anyhow!("{} is not an Ed25519 key: {}", key_path, e))?;
+    let key_id = config_value("SYNC_RECEIPT_KEY_ID")?
+      .unwrap_or_else(|| hex::encode(key.public_key().as_ref()));
+    let file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&path)
+      .await
+      .map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+    log::info!("Writing sync receipts to {} signed by {}", path, key_id);
+    Ok(Some(Self {
+      signer: Signer { key, key_id },
+      file: Mutex::new(file),
+    }))
+  }
+
+  /// Sign and record a receipt for what was delivered, if anything
+  pub async fn record(&self, delivery: Delivery) -> Result<()> {
+    let receipt = match self.signer.sign(delivery, Utc::now()) {
+      Some(receipt) => receipt,
+      None => return Ok(()),
+    };
+    let mut line = serde_json::to_vec(&receipt)?;
+    line.push(b'\n');
+    let mut file = self.file.lock().await;
+    file.write_all(&line).await?;
+    file.flush().await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use ring::rand::SystemRandom;
+  use ring::signature::{UnparsedPublicKey, ED25519};
+
+  #[test]
+  fn test_sign() {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let public_key = key.public_key().as_ref().to_vec();
+    let signer = Signer {
+      key,
+      key_id: "test".to_string(),
+    };
+    let strand = Cid::default();
+    let at = DateTime::parse_from_rfc3339("2025-03-01T12:00:00Z")
+      .unwrap()
+      .with_timezone(&Utc);
+    let empty = Delivery::new("primary", strand);
+    assert!(signer.sign(empty, at).is_none());
+
+    let mut delivery = Delivery::new("primary", strand);
+    delivery.range = Some((100, 199));
+    let receipt = signer.sign(delivery, at).unwrap();
+    assert_eq!(receipt.timestamp, "2025-03-01T12:00:00Z");
+    assert_eq!(
+      receipt.message(),
+      format!(
+        "primary\n{}\n100\n199\n2025-03-01T12:00:00Z\nsha-256:{}",
+        strand,
+        hex::encode(Sha256::digest(b""))
+      )
+    );
+    UnparsedPublicKey::new(&ED25519, public_key)
+      .verify(
+        receipt.message().as_bytes(),
+        &hex::decode(&receipt.signature).unwrap(),
+      )
+      .unwrap();
+  }
+}
@@ -0,0 +1,246 @@
+//! The remote stores data is mirrored to.
+//!
+//! Remotes are listed in the yaml file at `REMOTES_PATH`, or else the single
+//! remote at `REMOTE_STORE_ADDRESS` (with `REMOTE_STORE_API_KEY`) is used, if
+//! set.
+//! Every remote keeps its own sync cursors and circuit breaker (see
+//! [crate::breaker]), so one broken remote doesn't hold up the others.
+//! Remotes are told apart by name in the persisted cursors, so renaming one
+//! starts it over.
+//!
+//! The list is read again on `SIGHUP` (or a `reload` message), so keys can
+//! be rotated and remotes added or removed without a restart (see
+//! [reload]).
+use anyhow::{anyhow, Result};
+use biab_utils::{config_secret, config_value, Secret};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Instant;
+use twine_protocol::prelude::*;
+
+use crate::archive::{ArchiveConfig, ArchiveTarget};
+use crate::breaker::{Breaker, BreakerConfig};
+use crate::cursors::SyncCursors;
+use crate::http_target::HttpTarget;
+use crate::ipfs::{IpfsConfig, IpfsTarget};
+use crate::pushback::{self, Pushback};
+use crate::s3::{S3Config, S3Target};
+use crate::status;
+use crate::target::Target;
+
+/// Expected yaml structure:
+/// ```yaml
+/// remotes:
+///   - name: primary
+///     address: https://beacon.example.com
+///     api_key: secret
+///   - address: https://mirror.example.com
+///   - name: bucket
+///     s3:
+///       endpoint: https://s3.us-east-1.amazonaws.com
+///       bucket: beacon-mirror
+///       format: car
+///   - ipfs:
+///       api: http://kubo:5001
+///   - name: backup
+///     archive:
+///       path: /archive
+///       pulses_per_file: 10000
+/// ```
+#[derive(Debug, Deserialize)]
+struct RemotesConfig {
+  remotes: Vec<RemoteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoteConfig {
+  /// Defaults to the address
+  name: Option<String>,
+  /// A twine HTTP store
+  address: Option<String>,
+  api_key: Option<Secret>,
+  /// Or a file holding it
+  api_key_file: Option<PathBuf>,
+  /// Or an object store bucket
+  s3: Option<S3Config>,
+  /// Or an IPFS node
+  ipfs: Option<IpfsConfig>,
+  /// Or CAR archives on disk
+  archive: Option<ArchiveConfig>,
+}
+
+impl RemoteConfig {
+  fn name(&self) -> String {
+    if let Some(name) = &self.name {
+      return name.clone();
+    }
+    match (&self.address, &self.s3, &self.ipfs, &self.archive) {
+      (Some(address), ..) => address.clone(),
+      (None, Some(s3), ..) => S3Target::default_name(s3),
+      (None, None, Some(ipfs), _) => IpfsTarget::default_name(ipfs),
+      (None, None, None, Some(archive)) => ArchiveTarget::default_name(archive),
+      (None, None, None, None) => String::new(),
+    }
+  }
+}
+
+pub struct Remote {
+  pub name: String,
+  pub target: Box<dyn Target>,
+  /// The next index to send for each strand, as persisted in [SyncCursors]
+  pub cursors: HashMap<Cid, u64>,
+  /// Where the next audit of each strand starts
+  pub audit_positions: HashMap<Cid, u64>,
+  /// Ranges audits found missing, to send again
+  pub repairs: Vec<AbsoluteRange>,
+  /// Tixels sent ahead of the cursor for each strand (see [crate::lanes])
+  pub ahead: HashMap<Cid, (u64, u64)>,
+  /// How many tixels to send at once, less while the remote pushes back
+  pub chunk_size: usize,
+  max_chunk_size: usize,
+  breaker: Breaker,
+}
+
+impl Remote {
+  fn new(
+    config: RemoteConfig,
+    breaker: BreakerConfig,
+    chunk_size: usize,
+  ) -> Result<Self> {
+    let name = config.name();
+    let target: Box<dyn Target> =
+      match (config.address, config.s3, config.ipfs, config.archive) {
+        (Some(address), None, None, None) => {
+          let api_key = Secret::inline_or_file(
+            "api_key",
+            config.api_key,
+            config.api_key_file.as_deref(),
+          )?;
+          let api_key = api_key.as_ref().map(Secret::expose);
+          Box::new(HttpTarget::new(&address, api_key.unwrap_or_default())?)
+        }
+        (None, Some(s3), None, None) => Box::new(S3Target::new(s3)?),
+        (None, None, Some(ipfs), None) => Box::new(IpfsTarget::new(ipfs)?),
+        (None, None, None, Some(archive)) => {
+          Box::new(ArchiveTarget::new(archive))
+        }
+        _ => {
+          return Err(anyhow!(
+            "Remote {} needs exactly one of address, s3, ipfs or archive",
+            name
+          ))
+        }
+      };
+    Ok(Self {
+      breaker: Breaker::new(&name, breaker),
+      name,
+      target,
+      cursors: HashMap::new(),
+      audit_positions: HashMap::new(),
+      repairs: Vec::new(),
+      ahead: HashMap::new(),
+      chunk_size,
+      max_chunk_size: chunk_size,
+    })
+  }
+
+  pub async fn load_cursors(&mut self, cursors: &SyncCursors) -> Result<()> {
+    self.cursors = cursors.load(&self.name).await?;
+    Ok(())
+  }
+
+  /// When this remote should next be synced, if it's backing off
+  pub fn retry_at(&self) -> Option<Instant> {
+    self.breaker.retry_at()
+  }
+
+  /// Whether to sync now, or wait because of recent failures
+  pub fn ready(&mut self) -> bool {
+    self.breaker.ready(&self.name)
+  }
+
+  pub fn succeeded(&mut self) {
+    status::observe_success(&self.name);
+    self.breaker.record_success(&self.name)
+  }
+
+  pub fn failed(&mut self, e: anyhow::Error) {
+    status::observe_error(&self.name, &e);
+    if e.downcast_ref::<Pushback>().is_some() {
+      self.chunk_size =
+        pushback::next_chunk_size(self.chunk_size, self.max_chunk_size, true);
+      log::warn!(
+        "{} is pushing back, sending chunks of {} tixels",
+        self.name,
+        self.chunk_size
+      );
+    }
+    self.breaker.record_failure(&self.name, e)
+  }
+
+  /// Grow the chunk size back after a chunk was accepted
+  pub fn chunk_accepted(&mut self) {
+    self.chunk_size =
+      pushback::next_chunk_size(self.chunk_size, self.max_chunk_size, false);
+  }
+}
+
+pub fn from_env(chunk_size: usize) -> Result<Vec<Remote>> {
+  let configs = match config_value("REMOTES_PATH")? {
+    Some(path) => {
+      let file = std::fs::File::open(&path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+      let config: RemotesConfig =
+        serde_yaml::from_reader(std::io::BufReader::new(file))
+          .map_err(|e| anyhow!("Invalid remotes file {}: {}", path, e))?;
+      config.remotes
+    }
+    None => match config_value("REMOTE_STORE_ADDRESS")? {
+      Some(address) => vec![RemoteConfig {
+        name: None,
+        address: Some(address),
+        api_key: config_secret("REMOTE_STORE_API_KEY")?,
+        api_key_file: None,
+        s3: None,
+        ipfs: None,
+        archive: None,
+      }],
+      // eg: only pulling from upstreams
+      None => vec![],
+    },
+  };
+  let breaker = BreakerConfig::from_env()?;
+  configs
+    .into_iter()
+    .map(|config| {
+      log::info!("Syncing to {}", config.name());
+      Remote::new(config, breaker, chunk_size)
+    })
+    .collect()
+}
+
+/// Read the remotes again, rebuilding each from the new configuration and
+/// its persisted cursors, so nothing learned about its old address (eg: what
+/// was sent ahead, or the chunk size it pushed back to) is kept. The current
+/// remotes are left alone if the new configuration is invalid.
+pub async fn reload(
+  remotes: &mut Vec<Remote>,
+  chunk_size: usize,
+  cursors: &SyncCursors,
+) -> Result<()> {
+  let mut fresh = from_env(chunk_size)?;
+  for remote in fresh.iter_mut() {
+    remote.load_cursors(cursors).await?;
+    if !remotes.iter().any(|r| r.name == remote.name) {
+      log::info!("Added remote {}", remote.name);
+    }
+  }
+  for remote in remotes.iter() {
+    if !fresh.iter().any(|r| r.name == remote.name) {
+      log::info!("Removed remote {}", remote.name);
+    }
+  }
+  *remotes = fresh;
+  Ok(())
+}
@@ -0,0 +1,93 @@
+//! Sync state of each remote, served as JSON at `GET /status` (on
+//! `METRICS_ADDR`, next to the metrics).
+//!
+//! For every remote it has the time of the last successful sync, the last
+//! error and when it happened, and for each strand the last index synced,
+//! the local latest index and how many tixels the remote is behind.
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+use twine_protocol::prelude::*;
+
+#[derive(Debug, Default, Clone, Serialize)]
+struct StrandStatus {
+  /// The last index the remote accepted, if any
+  synced_index: Option<u64>,
+  latest_index: u64,
+  lag: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+struct RemoteStatus {
+  last_success: Option<DateTime<Utc>>,
+  last_error: Option<String>,
+  last_error_at: Option<DateTime<Utc>>,
+  strands: BTreeMap<String, StrandStatus>,
+}
+
+static STATUS: LazyLock<Mutex<BTreeMap<String, RemoteStatus>>> =
+  LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+fn update(remote: &str, f: impl FnOnce(&mut RemoteStatus)) {
+  let mut status = STATUS.lock().expect("status lock");
+  f(status.entry(remote.to_string()).or_default())
+}
+
+/// The status of a strand with `next` the next index to send
+fn strand_status(next: u64, latest_index: u64) -> StrandStatus {
+  StrandStatus {
+    synced_index: next.checked_sub(1),
+    latest_index,
+    lag: (latest_index + 1).saturating_sub(next),
+  }
+}
+
+/// Where the remote is along a strand
+pub fn observe_strand(remote: &str, strand: &Cid, next: u64, latest: u64) {
+  update(remote, |status| {
+    status
+      .strands
+      .insert(strand.to_string(), strand_status(next, latest));
+  })
+}
+
+pub fn observe_success(remote: &str) {
+  update(remote, |status| status.last_success = Some(Utc::now()))
+}
+
+pub fn observe_error(remote: &str, error: &anyhow::Error) {
+  update(remote, |status| {
+    status.last_error = Some(error.to_string());
+    status.last_error_at = Some(Utc::now());
+  })
+}
+
+pub fn render() -> warp::reply::Response {
+  let status = STATUS.lock().expect("status lock").clone();
+  let body = serde_json::to_vec(&serde_json::json!({ "remotes": status }))
+    .unwrap_or_default();
+  warp::reply::Reply::into_response(warp::reply::with_header(
+    body,
+    "content-type",
+    "application/json",
+  ))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_strand_status() {
+    let status = strand_status(0, 9);
+    assert_eq!(status.synced_index, None);
+    assert_eq!(status.lag, 10);
+    let status = strand_status(10, 9);
+    assert_eq!(status.synced_index, Some(9));
+    assert_eq!(status.lag, 0);
+    let status = strand_status(5, 9);
+    assert_eq!(status.synced_index, Some(4));
+    assert_eq!(status.lag, 5);
+  }
+}
@@ -0,0 +1,67 @@
+//! Where remotes keep what is synced to them.
+//!
+//! A twine HTTP store is the usual target (see [crate::http_target]), but
+//! anything that can store strands and tixels can be one, eg: an object
+//! store bucket (see [crate::s3]), an IPFS node (see [crate::ipfs]) or CAR
+//! archives on disk (see [crate::archive]).
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::collections::BTreeSet;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::car::to_car_stream;
+
+/// A CAR file of the blocks
+pub async fn car<I: IntoIterator<Item = AnyTwine>>(
+  blocks: I,
+  root: Cid,
+) -> Vec<u8> {
+  to_car_stream(futures::stream::iter(blocks), vec![root])
+    .collect::<Vec<_>>()
+    .await
+    .concat()
+}
+
+#[async_trait]
+pub trait Target: Send + Sync {
+  /// The next index the target is missing for this strand, asked for when
+  /// nothing is known about it yet
+  async fn next_index(&self, strand: &Strand) -> Result<u64>;
+
+  async fn save_strand(&self, strand: &Strand) -> Result<()>;
+
+  /// Whether tixels have to be sent in order, or can go ahead of the ones
+  /// before them (see [crate::lanes])
+  fn in_order(&self) -> bool {
+    true
+  }
+
+  /// Save consecutive tixels of the strand
+  async fn save_tixels(
+    &self,
+    strand: &Strand,
+    tixels: Vec<Twine>,
+  ) -> Result<()>;
+
+  /// Whether [Target::stored_cid] can be used to verify what was saved
+  fn can_read_back(&self) -> bool {
+    false
+  }
+
+  /// The cid of the tixel the target has at the index, if any
+  async fn stored_cid(
+    &self,
+    _strand: &Strand,
+    _index: u64,
+  ) -> Result<Option<Cid>> {
+    Ok(None)
+  }
+
+  /// The indices of the range the target has, for audits, if it can tell
+  async fn present(
+    &self,
+    _range: AbsoluteRange,
+  ) -> Result<Option<BTreeSet<u64>>> {
+    Ok(None)
+  }
+}
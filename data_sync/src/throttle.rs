@@ -0,0 +1,85 @@
+//! Limits on how fast tixels are sent to remotes.
+//!
+//! Tixels are sent in chunks of `SYNC_CHUNK_SIZE` (default 1000). Backfilling
+//! a long strand can otherwise take all of the host's upload bandwidth, so
+//! `SYNC_CHUNK_DELAY_MS` adds a pause between chunks, and
+//! `SYNC_MAX_BYTES_PER_SEC` caps the rate chunks are sent at. The cap is
+//! shared by all remotes, since they share the uplink: each chunk reserves
+//! its share of time, and waits for it to come up.
+use anyhow::Result;
+use biab_utils::{config_or, config_parse};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{sleep, sleep_until, Instant};
+
+/// When a chunk of `bytes` may be sent, and when the one after it may be,
+/// given when the next chunk was due to go
+fn reserve(
+  now: Instant,
+  next: Instant,
+  bytes: u64,
+  max_bytes_per_sec: u64,
+) -> (Instant, Instant) {
+  let at = next.max(now);
+  let duration =
+    Duration::from_secs_f64(bytes as f64 / max_bytes_per_sec as f64);
+  (at, at + duration)
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+  pub chunk_size: usize,
+  chunk_delay: Duration,
+  max_bytes_per_sec: Option<u64>,
+  next: Mutex<Instant>,
+}
+
+impl Throttle {
+  pub fn from_env() -> Result<Self> {
+    Ok(Self {
+      chunk_size: config_or("SYNC_CHUNK_SIZE", 1000usize)?.max(1),
+      chunk_delay: Duration::from_millis(config_or("SYNC_CHUNK_DELAY_MS", 0)?),
+      max_bytes_per_sec: config_parse::<u64>("SYNC_MAX_BYTES_PER_SEC")?
+        .filter(|max| *max > 0),
+      next: Mutex::new(Instant::now()),
+    })
+  }
+
+  /// Wait until a chunk of `bytes` may be sent, `first` being the first
+  /// chunk of a range
+  pub async fn wait(&self, bytes: u64, first: bool) {
+    if !first && !self.chunk_delay.is_zero() {
+      sleep(self.chunk_delay).await;
+    }
+    let max_bytes_per_sec = match self.max_bytes_per_sec {
+      Some(max) => max,
+      None => return,
+    };
+    let at = {
+      let mut next = self.next.lock().expect("throttle lock");
+      let (at, after) =
+        reserve(Instant::now(), *next, bytes, max_bytes_per_sec);
+      *next = after;
+      at
+    };
+    sleep_until(at).await;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_reserve() {
+    let now = Instant::now();
+    // nothing sent lately, so it goes now
+    let (at, next) = reserve(now, now - Duration::from_secs(5), 2000, 1000);
+    assert_eq!(at, now);
+    assert_eq!(next, now + Duration::from_secs(2));
+    // and the next one waits its turn
+    let (at, next) = reserve(now, next, 500, 1000);
+    assert_eq!(at, now + Duration::from_secs(2));
+    assert_eq!(next, now + Duration::from_millis(2500));
+  }
+}
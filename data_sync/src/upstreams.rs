@@ -0,0 +1,247 @@
+//! Beacons mirrored into the local store.
+//!
+//! The inverse of pushing to remotes: strands of the upstreams listed in the
+//! yaml file at `UPSTREAMS_PATH` are pulled into the local database, so an
+//! organization can run a replica of external beacons (which can then be
+//! served by the portal and pushed on to remotes like local strands).
+//!
+//! Either every strand of an upstream is mirrored, or only the listed ones.
+//! Strands that aren't in the local store yet are pulled from `from_index`
+//! (default 0), after which they follow the upstream's latest tixel. Tixels
+//! before `from_index` are never stored, so the local store answers queries
+//! for them with not found. A strand that can't be started (eg: the upstream
+//! no longer has `from_index`) is skipped and tried again on the next pull,
+//! without counting against the upstream.
+use crate::breaker::{Breaker, BreakerConfig};
+use crate::metrics;
+use anyhow::{anyhow, Result};
+use biab_utils::{config_value, DbStore, Secret};
+use futures::TryStreamExt;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Instant;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_http_store::v2::HttpStore;
+use twine_protocol::twine_lib::resolver::unchecked_base::BaseResolver;
+use twine_sql_store::sqlx::MySqlPool;
+
+/// Expected yaml structure:
+/// ```yaml
+/// upstreams:
+///   - name: example
+///     address: https://beacon.example.com
+///     api_key: secret
+///     strands: [bafyrei...]
+///     from_index: 1000
+/// ```
+#[derive(Debug, Deserialize)]
+struct UpstreamsConfig {
+  upstreams: Vec<UpstreamConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpstreamConfig {
+  /// Defaults to the address
+  name: Option<String>,
+  address: String,
+  api_key: Option<Secret>,
+  /// Or a file holding it
+  api_key_file: Option<PathBuf>,
+  /// Only mirror these strands, or all of them if empty
+  #[serde(default)]
+  strands: Vec<String>,
+  #[serde(default)]
+  from_index: u64,
+}
+
+pub struct Upstream {
+  pub name: String,
+  store: HttpStore,
+  strands: Vec<Cid>,
+  from_index: u64,
+  breaker: Breaker,
+}
+
+impl Upstream {
+  fn new(config: UpstreamConfig, breaker: BreakerConfig) -> Result<Self> {
+    let api_key = Secret::inline_or_file(
+      "api_key",
+      config.api_key,
+      config.api_key_file.as_deref(),
+    )?;
+    let store = biab_utils::open_http_store(
+      &config.address,
+      api_key.as_ref().map(Secret::expose).unwrap_or_default(),
+    )?;
+    let name = config.name.unwrap_or(config.address);
+    let strands = config
+      .strands
+      .iter()
+      .map(|s| {
+        Cid::try_from(s.as_str())
+          .map_err(|e| anyhow!("Invalid strand {} for {}: {}", s, name, e))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    Ok(Self {
+      breaker: Breaker::new(&name, breaker),
+      name,
+      store,
+      strands,
+      from_index: config.from_index,
+    })
+  }
+
+  /// When this upstream should next be pulled, if it's backing off
+  pub fn retry_at(&self) -> Option<Instant> {
+    self.breaker.retry_at()
+  }
+
+  /// Pull whatever is new, unless backing off after failures
+  pub async fn pull(
+    &mut self,
+    store: &DbStore,
+    pool: &MySqlPool,
+    chunk_size: usize,
+  ) {
+    if !self.breaker.ready(&self.name) {
+      log::debug!("Skipping {} after recent failures", self.name);
+      return;
+    }
+    match self.pull_strands(store, pool, chunk_size).await {
+      Ok(()) => self.breaker.record_success(&self.name),
+      Err(e) => self.breaker.record_failure(&self.name, e),
+    }
+  }
+
+  async fn strands(&self) -> Result<Vec<Strand>> {
+    if self.strands.is_empty() {
+      return Ok(self.store.strands().await?.try_collect().await?);
+    }
+    let mut strands = Vec::with_capacity(self.strands.len());
+    for cid in &self.strands {
+      strands.push(self.store.resolve_strand(cid).await?.unpack());
+    }
+    Ok(strands)
+  }
+
+  /// Store the tixel at `from_index`, which the rest of the strand follows
+  async fn save_first_tixel(
+    &self,
+    store: &DbStore,
+    pool: &MySqlPool,
+    strand: &Strand,
+  ) -> Result<()> {
+    let first = self.store.resolve_index(strand, self.from_index).await?;
+    store
+      .save_first_tixel(pool, strand, &first.unpack())
+      .await?;
+    Ok(())
+  }
+
+  async fn pull_strands(
+    &self,
+    store: &DbStore,
+    pool: &MySqlPool,
+    chunk_size: usize,
+  ) -> Result<()> {
+    log::debug!("Pulling from {}...", self.name);
+    for strand in self.strands().await? {
+      let latest = match self.store.resolve_latest(&strand).await {
+        Ok(latest) => latest,
+        Err(ResolutionError::NotFound) => continue,
+        Err(e) => return Err(e.into()),
+      };
+      let starting_index = match store.resolve_latest(&strand).await {
+        Ok(latest) => latest.index() + 1,
+        Err(ResolutionError::NotFound) if self.from_index == 0 => {
+          store.save(strand.clone()).await?;
+          0
+        }
+        Err(ResolutionError::NotFound) => {
+          if latest.index() < self.from_index {
+            log::debug!("Nothing to pull yet for strand: {}", strand.cid());
+            continue;
+          }
+          if let Err(e) = self.save_first_tixel(store, pool, &strand).await {
+            log::error!(
+              "Error starting strand {} from {} at index {}: {}",
+              strand.cid(),
+              self.name,
+              self.from_index,
+              e
+            );
+            continue;
+          }
+          self.from_index + 1
+        }
+        Err(e) => return Err(e.into()),
+      };
+      if latest.index() < starting_index {
+        log::debug!("No new tixels to pull for strand: {}", strand.cid());
+        continue;
+      }
+
+      let range =
+        AbsoluteRange::new(strand.cid(), starting_index, latest.index());
+      log::debug!("Pulling range from {}: {}", self.name, range);
+      let stream = self.store.resolve_range(range).await?;
+      // save them a chunk at a time
+      let mut chunks = stream.try_chunks(chunk_size).map_err(|e| anyhow!(e));
+      while let Some(chunk) = chunks.try_next().await? {
+        let count = chunk.len();
+        log::debug!("Saving chunk of {} tixels from {}", count, self.name);
+        store.save_many(chunk).await?;
+        metrics::observe_pulled(&self.name, count);
+      }
+    }
+    Ok(())
+  }
+}
+
+/// The index of the first tixel of `strand` in `store`, which is above 0 for
+/// strands mirrored from `from_index`. There are no gaps after the first
+/// tixel, so it's found with a binary search up to `latest`.
+pub async fn first_index<R: BaseResolver>(
+  store: &R,
+  strand: &Cid,
+  latest: u64,
+) -> Result<u64, ResolutionError> {
+  if latest == 0 || store.has_index(strand, 0).await? {
+    return Ok(0);
+  }
+  // index `missing` isn't stored and index `found` is
+  let (mut missing, mut found) = (0, latest);
+  while found - missing > 1 {
+    let mid = missing + (found - missing) / 2;
+    if store.has_index(strand, mid).await? {
+      found = mid;
+    } else {
+      missing = mid;
+    }
+  }
+  Ok(found)
+}
+
+pub fn from_env() -> Result<Vec<Upstream>> {
+  let path = match config_value("UPSTREAMS_PATH")? {
+    Some(path) => path,
+    None => return Ok(vec![]),
+  };
+  let file = std::fs::File::open(&path)
+    .map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+  let config: UpstreamsConfig =
+    serde_yaml::from_reader(std::io::BufReader::new(file))
+      .map_err(|e| anyhow!("Invalid upstreams file {}: {}", path, e))?;
+  let breaker = BreakerConfig::from_env()?;
+  config
+    .upstreams
+    .into_iter()
+    .map(|config| {
+      log::info!(
+        "Mirroring {}",
+        config.name.as_deref().unwrap_or(&config.address)
+      );
+      Upstream::new(config, breaker)
+    })
+    .collect()
+}
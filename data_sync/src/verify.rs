@@ -0,0 +1,121 @@
+//! Reading back what was synced to remotes.
+//!
+//! A remote accepting a chunk doesn't mean it stored it, eg: a proxy that
+//! answers for the wrong store, or silent corruption. With `SYNC_VERIFY` set
+//! to `sample` (checking `SYNC_VERIFY_SAMPLE` random tixels per chunk) or
+//! `all`, tixels are resolved back from the remote after each chunk and
+//! their cids compared with the local ones. Mismatches are counted in the
+//! metrics and logged as alerts. Missing tixels are queued to be repaired by
+//! the next sync, but ones that differ are left for an operator to look at.
+//!
+//! Only remotes that can resolve tixels (twine HTTP stores) are verified.
+use crate::metrics;
+use crate::remotes::Remote;
+use anyhow::{anyhow, Result};
+use biab_utils::{config_or, config_value};
+use twine_protocol::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verify {
+  Off,
+  /// Up to this many tixels per chunk
+  Sample(usize),
+  All,
+}
+
+impl Verify {
+  pub fn from_env() -> Result<Self> {
+    match config_value("SYNC_VERIFY")?.as_deref() {
+      None | Some("off") => Ok(Verify::Off),
+      Some("sample") => {
+        Ok(Verify::Sample(config_or("SYNC_VERIFY_SAMPLE", 10)?))
+      }
+      Some("all") => Ok(Verify::All),
+      Some(other) => Err(anyhow!(
+        "Invalid SYNC_VERIFY {:?}: expected off, sample or all",
+        other
+      )),
+    }
+  }
+
+  /// Which of `len` tixels in a chunk to check, in order
+  fn pick(&self, len: usize) -> Vec<usize> {
+    match self {
+      Verify::Off => vec![],
+      Verify::All => (0..len).collect(),
+      Verify::Sample(n) => {
+        let mut picked =
+          rand::seq::index::sample(&mut rand::thread_rng(), len, len.min(*n))
+            .into_vec();
+        picked.sort_unstable();
+        picked
+      }
+    }
+  }
+
+  /// The indices and cids to check of a chunk about to be sent
+  pub fn expected(&self, chunk: &[Twine]) -> Vec<(u64, Cid)> {
+    self
+      .pick(chunk.len())
+      .into_iter()
+      .map(|i| (chunk[i].index(), chunk[i].cid()))
+      .collect()
+  }
+}
+
+/// Compare what the remote has with what was sent
+pub async fn verify(
+  remote: &mut Remote,
+  strand: &Strand,
+  expected: &[(u64, Cid)],
+) -> Result<()> {
+  if !remote.target.can_read_back() {
+    return Ok(());
+  }
+  for (index, cid) in expected {
+    let result = match remote.target.stored_cid(strand, *index).await? {
+      Some(stored) if stored == *cid => "ok",
+      Some(stored) => {
+        log::error!(
+          "ALERT: {} has {} at index {} of strand {}, expected {}",
+          remote.name,
+          stored,
+          index,
+          strand.cid(),
+          cid
+        );
+        "mismatch"
+      }
+      None => {
+        log::error!(
+          "ALERT: {} is missing index {} of strand {} after saving it",
+          remote.name,
+          index,
+          strand.cid()
+        );
+        remote
+          .repairs
+          .push(AbsoluteRange::new(strand.cid(), *index, *index));
+        "missing"
+      }
+    };
+    metrics::observe_verified(&remote.name, result);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_pick() {
+    assert!(Verify::Off.pick(10).is_empty());
+    assert_eq!(Verify::All.pick(3), vec![0, 1, 2]);
+    assert_eq!(Verify::Sample(5).pick(3), vec![0, 1, 2]);
+    let picked = Verify::Sample(4).pick(1000);
+    assert_eq!(picked.len(), 4);
+    assert!(picked.windows(2).all(|w| w[0] < w[1]));
+    assert!(picked.iter().all(|i| *i < 1000));
+  }
+}
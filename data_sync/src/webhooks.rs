@@ -0,0 +1,73 @@
+//! Notifications of remotes catching up.
+//!
+//! When a sync gets a remote up to the local latest tixel of a strand, a
+//! small JSON message is posted to each url in `SYNC_WEBHOOK_URLS` (comma
+//! separated), eg: for cache invalidation or bots:
+//!
+//! ```json
+//! {"remote": "primary", "strand": "bafyrei...", "latest_index": 1234}
+//! ```
+//!
+//! Notifications are sent in the background and failures are only logged,
+//! so a slow or broken webhook doesn't hold up syncing.
+use anyhow::Result;
+use biab_utils::config_list;
+use serde::Serialize;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_http_store::reqwest::Client;
+
+#[derive(Debug, Serialize)]
+struct Synced<'a> {
+  remote: &'a str,
+  strand: String,
+  latest_index: u64,
+}
+
+pub struct Webhooks {
+  urls: Vec<String>,
+  client: Client,
+}
+
+impl Webhooks {
+  pub fn from_env() -> Result<Self> {
+    Ok(Self {
+      urls: config_list("SYNC_WEBHOOK_URLS")?,
+      client: Client::new(),
+    })
+  }
+
+  /// Tell the webhooks the remote has the strand up to `latest_index`
+  pub fn notify(&self, remote: &str, strand: &Cid, latest_index: u64) {
+    if self.urls.is_empty() {
+      return;
+    }
+    let body = match serde_json::to_vec(&Synced {
+      remote,
+      strand: strand.to_string(),
+      latest_index,
+    }) {
+      Ok(body) => body,
+      Err(e) => {
+        log::error!("Failed to encode sync notification: {}", e);
+        return;
+      }
+    };
+    for url in &self.urls {
+      let request = self
+        .client
+        .post(url)
+        .header("content-type", "application/json")
+        .body(body.clone());
+      let url = url.clone();
+      tokio::spawn(async move {
+        let result = request
+          .send()
+          .await
+          .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+          log::warn!("Failed to notify {} of sync: {}", url, e);
+        }
+      });
+    }
+  }
+}
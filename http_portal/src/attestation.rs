@@ -0,0 +1,43 @@
+//! The attestation of the beacon's HSM signing key.
+//!
+//! If `KEY_ATTESTATION_PATH` points at the certificates saved by
+//! `pulse_generator hsm-keygen` (or `hsm-attest`), they're served at
+//! `GET /attestation.pem`, for anyone to check the key pulses are signed
+//! with was generated in, and never left, a YubiHSM2.
+use anyhow::{anyhow, Result};
+use biab_utils::config_value;
+use std::sync::Arc;
+use warp::Filter;
+
+/// The attestation, if configured
+pub fn from_env() -> Result<Option<Arc<String>>> {
+  let Some(path) = config_value("KEY_ATTESTATION_PATH")? else {
+    return Ok(None);
+  };
+  let pem = std::fs::read_to_string(&path)
+    .map_err(|e| anyhow!("Failed to read {}: {}", path, e))?;
+  if !pem.contains("-----BEGIN CERTIFICATE-----") {
+    return Err(anyhow!("No certificates in {}", path));
+  }
+  Ok(Some(Arc::new(pem)))
+}
+
+pub fn routes(
+  attestation: Option<Arc<String>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::get()
+    .and(warp::path!("attestation.pem"))
+    .and_then(move || {
+      let attestation = attestation.clone();
+      async move {
+        match attestation {
+          Some(pem) => Ok(warp::reply::with_header(
+            pem.to_string(),
+            "content-type",
+            "application/x-pem-file",
+          )),
+          None => Err(warp::reject::not_found()),
+        }
+      }
+    })
+}
@@ -0,0 +1,249 @@
+//! API keys for privileged routes.
+//!
+//! Keys are sent as `Authorization: ApiKey <key>`. Only SHA-256 hashes of
+//! the keys are configured, in the yaml file at `API_KEYS_PATH`. Each key has
+//! scopes saying what it may do and optionally a rate limit tier. The
+//! `WRITE_API_KEY` variable is a shortcut for a single key that may write.
+use crate::handlers::HttpError;
+use crate::rate_limit::Limit;
+use anyhow::{anyhow, Result};
+use biab_utils::{config_secret, config_value};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use warp::Filter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+  /// Push strands and tixels to the portal
+  Write,
+  /// Use administrative routes
+  Admin,
+  /// Query ranges longer than anonymous clients may
+  Ranges,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyEntry {
+  name: String,
+  /// Hex encoded SHA-256 of the key
+  sha256: String,
+  #[serde(default)]
+  scopes: HashSet<Scope>,
+  tier: Option<String>,
+}
+
+/// Expected yaml structure:
+/// ```yaml
+/// tiers:
+///   partner:
+///     per_minute: 6000
+///     burst: 1000
+/// keys:
+///   - name: mirror
+///     sha256: 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+///     scopes: [write, ranges]
+///     tier: partner
+/// ```
+#[derive(Debug, Default, Deserialize)]
+struct KeysConfig {
+  #[serde(default)]
+  tiers: HashMap<String, Limit>,
+  #[serde(default)]
+  keys: Vec<KeyEntry>,
+}
+
+/// Who is making a request
+#[derive(Debug)]
+pub struct Principal {
+  pub name: String,
+  scopes: HashSet<Scope>,
+  /// The rate limit tier, if not the default one for keys
+  pub limit: Option<Limit>,
+}
+
+impl Principal {
+  pub fn can(&self, scope: Scope) -> bool {
+    self.scopes.contains(&scope)
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+  by_hash: HashMap<[u8; 32], Arc<Principal>>,
+}
+
+fn hash_key(key: &str) -> [u8; 32] {
+  Sha256::digest(key.as_bytes()).into()
+}
+
+impl ApiKeys {
+  pub fn from_env() -> Result<Self> {
+    let config = match config_value("API_KEYS_PATH")? {
+      Some(path) => {
+        let file = std::fs::File::open(&path)
+          .map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+        serde_yaml::from_reader(std::io::BufReader::new(file))
+          .map_err(|e| anyhow!("Invalid API keys file {}: {}", path, e))?
+      }
+      None => KeysConfig::default(),
+    };
+    let mut keys = Self::from_config(config)?;
+    if let Some(key) = config_secret("WRITE_API_KEY")? {
+      if !key.expose().is_empty() {
+        keys.insert(
+          hash_key(key.expose()),
+          Principal {
+            name: "WRITE_API_KEY".to_string(),
+            scopes: HashSet::from([Scope::Write]),
+            limit: None,
+          },
+        );
+      }
+    }
+    Ok(keys)
+  }
+
+  fn from_config(config: KeysConfig) -> Result<Self> {
+    let mut keys = Self::default();
+    for entry in config.keys {
+      let hash: [u8; 32] = hex::decode(entry.sha256.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Key {} has an invalid sha256", entry.name))?;
+      let limit = entry
+        .tier
+        .map(|tier| {
+          config.tiers.get(&tier).copied().ok_or_else(|| {
+            anyhow!("Key {} has an unknown tier {}", entry.name, tier)
+          })
+        })
+        .transpose()?;
+      keys.insert(
+        hash,
+        Principal {
+          name: entry.name,
+          scopes: entry.scopes,
+          limit,
+        },
+      );
+    }
+    Ok(keys)
+  }
+
+  fn insert(&mut self, hash: [u8; 32], principal: Principal) {
+    self.by_hash.insert(hash, Arc::new(principal));
+  }
+
+  /// Whether any key has the scope, otherwise routes needing it are off
+  pub fn any_can(&self, scope: Scope) -> bool {
+    self.by_hash.values().any(|p| p.can(scope))
+  }
+
+  /// The principal for an `Authorization` header. Requests without a known
+  /// key are anonymous, so routes needing a key refuse them while public
+  /// routes serve them as anyone else.
+  pub fn authenticate(
+    &self,
+    authorization: Option<&str>,
+  ) -> Option<Arc<Principal>> {
+    let key = authorization?.strip_prefix("ApiKey ")?.trim();
+    self.by_hash.get(&hash_key(key)).cloned()
+  }
+}
+
+/// The principal making the request, if any
+pub fn principal(
+  keys: Arc<ApiKeys>,
+) -> impl Filter<Extract = (Option<Arc<Principal>>,), Error = warp::Rejection> + Clone
+{
+  warp::header::optional::<String>("authorization").map(
+    move |authorization: Option<String>| {
+      keys.authenticate(authorization.as_deref())
+    },
+  )
+}
+
+/// Only let through requests with a key that has the scope. If no key has
+/// it, the route is rejected as not found.
+pub fn require(
+  keys: Arc<ApiKeys>,
+  scope: Scope,
+) -> impl Filter<Extract = (Arc<Principal>,), Error = warp::Rejection> + Clone {
+  let enabled = keys.any_can(scope);
+  warp::any()
+    .and_then(move || async move {
+      if enabled {
+        Ok(())
+      } else {
+        Err(warp::reject::not_found())
+      }
+    })
+    .untuple_one()
+    .and(principal(keys))
+    .and_then(move |principal: Option<Arc<Principal>>| async move {
+      match principal {
+        Some(principal) if principal.can(scope) => Ok(principal),
+        Some(_) => Err(warp::reject::custom(HttpError::Forbidden)),
+        None => Err(warp::reject::custom(HttpError::Unauthorized)),
+      }
+    })
+}
+
+/// How many pulses one range request may cover
+#[derive(Debug, Clone, Copy)]
+pub struct RangeLimits {
+  pub max: u64,
+  /// For requests without a key with the [Scope::Ranges] scope
+  pub anonymous: u64,
+}
+
+/// The longest range the requester may query at once
+pub fn max_range(
+  keys: Arc<ApiKeys>,
+  limits: RangeLimits,
+) -> impl Filter<Extract = (u64,), Error = warp::Rejection> + Clone {
+  principal(keys).map(move |principal: Option<Arc<Principal>>| {
+    if principal.is_some_and(|p| p.can(Scope::Ranges)) {
+      limits.max
+    } else {
+      limits.anonymous.min(limits.max)
+    }
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_authenticate() {
+    let config: KeysConfig = serde_yaml::from_str(
+      "
+tiers:
+  partner:
+    per_minute: 60
+    burst: 10
+keys:
+  - name: mirror
+    sha256: 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+    scopes: [write]
+    tier: partner
+",
+    )
+    .unwrap();
+    let keys = ApiKeys::from_config(config).unwrap();
+    assert!(keys.any_can(Scope::Write));
+    assert!(!keys.any_can(Scope::Admin));
+
+    let principal = keys.authenticate(Some("ApiKey test")).unwrap();
+    assert_eq!(principal.name, "mirror");
+    assert!(principal.can(Scope::Write));
+    assert!(principal.limit.is_some());
+    assert!(keys.authenticate(None).is_none());
+    assert!(keys.authenticate(Some("ApiKey nope")).is_none());
+    assert!(keys.authenticate(Some("Bearer test")).is_none());
+  }
+}
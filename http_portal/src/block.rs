@@ -0,0 +1,75 @@
+//! Raw IPLD blocks at `GET /block/<cid>`.
+//!
+//! Like an IPFS gateway's `?format=raw`, the response is the exact DAG-CBOR
+//! bytes of a stored strand or tixel, so generic IPLD tooling can fetch and
+//! verify single blocks without knowing about twine queries.
+use crate::cache::{self, Freshness};
+use crate::handlers::HttpError;
+use crate::proof;
+use crate::PortalStore;
+use std::sync::Arc;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::twine::TwineBlock;
+use warp::http::header::CONTENT_TYPE;
+use warp::Filter;
+
+const DAG_CBOR: u64 = 0x71;
+const CONTENT_TYPE_RAW: &str = "application/vnd.ipld.raw";
+const CONTENT_TYPE_DAG_CBOR: &str = "application/vnd.ipld.dag-cbor";
+
+/// The strand or tixel with this cid
+async fn find(store: &PortalStore, cid: Cid) -> Result<AnyTwine, HttpError> {
+  match store.resolve_strand(cid).await {
+    Ok(strand) => Ok(strand.unpack().into()),
+    Err(ResolutionError::NotFound) => {
+      Ok(proof::find_pulse(store, cid, None).await?.into())
+    }
+    Err(e) => Err(e.into()),
+  }
+}
+
+async fn block(
+  cid: Cid,
+  store: Arc<PortalStore>,
+  accept: Option<String>,
+  if_none_match: Option<String>,
+) -> Result<warp::reply::Response, HttpError> {
+  let etag = format!("\"{}.block\"", cid);
+  if cache::matches_etag(if_none_match.as_deref(), &etag) {
+    return Ok(cache::not_modified(&etag, Freshness::Immutable));
+  }
+  let twine = find(&store, cid).await?;
+  let content_type = if cid.codec() == DAG_CBOR
+    && !accept.is_some_and(|accept| accept.contains(CONTENT_TYPE_RAW))
+  {
+    CONTENT_TYPE_DAG_CBOR
+  } else {
+    CONTENT_TYPE_RAW
+  };
+  let res = warp::reply::with_header(
+    twine.bytes().to_vec(),
+    CONTENT_TYPE,
+    content_type,
+  );
+  Ok(cache::with_cache_headers(
+    warp::reply::Reply::into_response(res),
+    Some(&etag),
+    Freshness::Immutable,
+  ))
+}
+
+/// `/block/<cid>`
+pub fn routes(
+  store: Arc<PortalStore>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::get()
+    .and(warp::path!("block" / Cid))
+    .and(warp::any().map(move || store.clone()))
+    .and(warp::header::optional::<String>("accept"))
+    .and(warp::header::optional::<String>("if-none-match"))
+    .and_then(|cid, store, accept, if_none_match| async move {
+      block(cid, store, accept, if_none_match)
+        .await
+        .map_err(warp::reject::custom)
+    })
+}
@@ -0,0 +1,140 @@
+//! Cache headers for responses.
+//!
+//! Anything addressed by CID (or by a fixed index) can never change, so it
+//! is served as immutable with the CID as its ETag. Responses that change as
+//! new pulses are published stay fresh until the next pulse is due.
+use crate::compression::plain_etag;
+use chrono::Utc;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::resolver::RangeQuery;
+use twine_spec_rng::{RandomnessPayload, RngStrandDetails};
+use warp::http::header::{HeaderValue, CACHE_CONTROL, ETAG};
+use warp::http::StatusCode;
+
+/// Used for strands that aren't randomness strands (or can't be decoded)
+pub const DEFAULT_MAX_AGE: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Freshness {
+  Immutable,
+  MaxAge(u64),
+}
+
+impl Freshness {
+  fn header_value(&self) -> HeaderValue {
+    match self {
+      Freshness::Immutable => {
+        HeaderValue::from_static("public, max-age=31536000, immutable")
+      }
+      Freshness::MaxAge(secs) => {
+        HeaderValue::from_str(&format!("public, max-age={}", secs))
+          .expect("valid header")
+      }
+    }
+  }
+}
+
+fn rng_period(strand: &Strand) -> Option<chrono::TimeDelta> {
+  strand
+    .extract_details::<RngStrandDetails>()
+    .ok()
+    .map(|details| details.period)
+}
+
+/// How many seconds a response containing the latest pulse of a strand
+/// stays fresh. For randomness strands this is the time until the next
+/// pulse is due, otherwise a short default.
+pub fn latest_max_age(twine: &Twine) -> u64 {
+  let period = match rng_period(twine.strand()) {
+    Some(period) => period,
+    None => return DEFAULT_MAX_AGE,
+  };
+  let timestamp = match twine.extract_payload::<RandomnessPayload>() {
+    Ok(payload) => payload.timestamp(),
+    Err(_) => return DEFAULT_MAX_AGE,
+  };
+  let remaining = (timestamp + period - Utc::now()).num_seconds();
+  remaining.clamp(1, period.num_seconds().max(1)) as u64
+}
+
+/// Strand listings are cached for the shortest period of the strands
+pub fn strands_max_age<'a>(strands: impl Iterator<Item = &'a Strand>) -> u64 {
+  strands
+    .filter_map(rng_period)
+    .map(|period| period.num_seconds().max(1) as u64)
+    .min()
+    .unwrap_or(DEFAULT_MAX_AGE)
+}
+
+/// Whether a range query always resolves to the same tixels, ie: it doesn't
+/// depend on the latest index and doesn't reach past it
+pub fn is_fixed_range(range: RangeQuery, latest: u64) -> bool {
+  match range.to_absolute(latest) {
+    Some(abs) => {
+      abs.upper() <= latest && range.to_absolute(latest + 1) == Some(abs)
+    }
+    None => false,
+  }
+}
+
+/// The ETag of a response containing a single twine. JSON and CAR, with or
+/// without the strand, are different representations so they get
+/// different tags.
+pub fn etag(cid: &Cid, as_car: bool, full: bool) -> String {
+  format!(
+    "\"{}{}{}\"",
+    cid,
+    if full { "-full" } else { "" },
+    if as_car { ".car" } else { "" }
+  )
+}
+
+/// Check an If-None-Match header against an ETag, in any encoding
+pub fn matches_etag(if_none_match: Option<&str>, etag: &str) -> bool {
+  if_none_match.is_some_and(|header| {
+    header.split(',').map(str::trim).any(|tag| {
+      tag == "*" || plain_etag(tag.strip_prefix("W/").unwrap_or(tag)) == etag
+    })
+  })
+}
+
+pub fn with_cache_headers(
+  mut res: warp::reply::Response,
+  etag: Option<&str>,
+  freshness: Freshness,
+) -> warp::reply::Response {
+  res
+    .headers_mut()
+    .insert(CACHE_CONTROL, freshness.header_value());
+  if let Some(etag) = etag {
+    res
+      .headers_mut()
+      .insert(ETAG, HeaderValue::from_str(etag).expect("valid header"));
+  }
+  res
+}
+
+pub fn not_modified(etag: &str, freshness: Freshness) -> warp::reply::Response {
+  let mut res = warp::reply::Response::default();
+  *res.status_mut() = StatusCode::NOT_MODIFIED;
+  with_cache_headers(res, Some(etag), freshness)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::compression::{encoded_etag, Encoding};
+
+  #[test]
+  fn test_matches_etag() {
+    let tag = etag(&Cid::default(), false, false);
+    assert!(matches_etag(Some(&tag), &tag));
+    assert!(matches_etag(Some(&format!("\"x\", W/{}", tag)), &tag));
+    assert!(matches_etag(Some("*"), &tag));
+    let encoded = encoded_etag(&tag, Encoding::Gzip);
+    assert!(matches_etag(Some(&format!("W/{}", encoded)), &tag));
+    assert!(!matches_etag(Some("\"x\""), &tag));
+    assert!(!matches_etag(None, &tag));
+    assert_ne!(tag, etag(&Cid::default(), true, false));
+  }
+}
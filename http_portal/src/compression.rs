@@ -0,0 +1,195 @@
+//! Compress responses for clients that accept it.
+//!
+//! Range queries can be megabytes of DAG-JSON, which compresses very well.
+//! Responses are compressed with brotli or gzip, whichever the client
+//! prefers in `Accept-Encoding`, unless they are known to be smaller than
+//! the configured minimum size. Streamed responses (eg: CAR files of long
+//! ranges) have no known size so they are always compressed, as they stream.
+//! Bodies already in memory are compressed in memory too, keeping a known
+//! length (eg: for the digest of signed responses). The ETag of a
+//! response gets the encoding added, so caches don't mix up its encoded and
+//! plain representations.
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+use futures::TryStreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use warp::http::header::{
+  HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+  VARY,
+};
+use warp::http::StatusCode;
+use warp::hyper::body::{to_bytes, Body, HttpBody};
+use warp::reply::Response;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+  Brotli,
+  Gzip,
+}
+
+impl Encoding {
+  fn name(&self) -> &'static str {
+    match self {
+      Encoding::Brotli => "br",
+      Encoding::Gzip => "gzip",
+    }
+  }
+}
+
+/// The ETag of the representation in an encoding
+pub fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+  match etag.strip_suffix('"') {
+    Some(tag) => format!("{}-{}\"", tag, encoding.name()),
+    None => etag.to_string(),
+  }
+}
+
+/// The ETag of the plain representation, given the ETag of any
+pub fn plain_etag(etag: &str) -> String {
+  [Encoding::Brotli, Encoding::Gzip]
+    .iter()
+    .find_map(|encoding| {
+      let tag = etag.strip_suffix(&format!("-{}\"", encoding.name()))?;
+      Some(format!("{}\"", tag))
+    })
+    .unwrap_or_else(|| etag.to_string())
+}
+
+/// Tag the response as the representation in `encoding`
+fn vary_by_encoding(headers: &mut HeaderMap, encoding: Encoding) {
+  let etag = headers.get(ETAG).and_then(|etag| etag.to_str().ok());
+  if let Some(etag) = etag.map(|etag| encoded_etag(etag, encoding)) {
+    headers.insert(ETAG, HeaderValue::from_str(&etag).expect("valid header"));
+  }
+  headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+}
+
+/// Pick the encoding from an `Accept-Encoding` header, preferring brotli
+/// when the client has no preference
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+  let mut best: Option<(Encoding, f32)> = None;
+  for item in accept_encoding?.split(',') {
+    let mut parts = item.split(';');
+    let name = parts.next().unwrap_or_default().trim();
+    let quality = parts
+      .filter_map(|p| p.trim().strip_prefix("q="))
+      .find_map(|q| q.trim().parse::<f32>().ok())
+      .unwrap_or(1.0);
+    let encoding = match name.to_ascii_lowercase().as_str() {
+      "br" => Encoding::Brotli,
+      "gzip" | "x-gzip" => Encoding::Gzip,
+      _ => continue,
+    };
+    if quality <= 0.0 {
+      continue;
+    }
+    let better = match best {
+      None => true,
+      Some((current, q)) => {
+        quality > q
+          || (quality == q
+            && encoding == Encoding::Brotli
+            && current != Encoding::Brotli)
+      }
+    };
+    if better {
+      best = Some((encoding, quality));
+    }
+  }
+  best.map(|(encoding, _)| encoding)
+}
+
+fn is_compressible(res: &Response) -> bool {
+  let content_type = match res.headers().get(CONTENT_TYPE) {
+    Some(value) => value.to_str().unwrap_or_default(),
+    None => return false,
+  };
+  content_type.starts_with("application/json")
+    || content_type.starts_with("application/octet-stream")
+    || content_type.starts_with("application/vnd.ipld.car")
+    || content_type.starts_with("text/")
+}
+
+/// Compress a response body if the client accepts it and it is worth it
+pub async fn compress(
+  mut res: Response,
+  accept_encoding: Option<&str>,
+  min_size: u64,
+) -> Response {
+  let encoding = match negotiate(accept_encoding) {
+    Some(encoding) => encoding,
+    None => return res,
+  };
+  // not modified, so tagged as the representation the client has
+  if res.status() == StatusCode::NOT_MODIFIED {
+    vary_by_encoding(res.headers_mut(), encoding);
+    return res;
+  }
+  if !res.status().is_success()
+    || res.headers().contains_key(CONTENT_ENCODING)
+    || !is_compressible(&res)
+  {
+    return res;
+  }
+  // small responses are sent as they are, but tagged the same as if they
+  // weren't so revalidation gives the same tag either way
+  vary_by_encoding(res.headers_mut(), encoding);
+  let size = res.body().size_hint().exact();
+  if size.is_some_and(|size| size < min_size) {
+    return res;
+  }
+
+  let (mut parts, body) = res.into_parts();
+  let reader =
+    StreamReader::new(TryStreamExt::map_err(body, std::io::Error::other));
+  let body = match encoding {
+    Encoding::Brotli => {
+      Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader)))
+    }
+    Encoding::Gzip => {
+      Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader)))
+    }
+  };
+  parts.headers.remove(CONTENT_LENGTH);
+  let body = match size {
+    Some(_) => match to_bytes(body).await {
+      Ok(bytes) => Body::from(bytes),
+      Err(e) => {
+        log::error!("Failed to compress the response: {}", e);
+        return crate::problem::response(
+          StatusCode::INTERNAL_SERVER_ERROR,
+          "internal",
+          "Internal server error".to_string(),
+        );
+      }
+    },
+    None => body,
+  };
+  parts
+    .headers
+    .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+  Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_negotiate() {
+    assert_eq!(negotiate(None), None);
+    assert_eq!(negotiate(Some("identity")), None);
+    assert_eq!(negotiate(Some("gzip, deflate, br")), Some(Encoding::Brotli));
+    assert_eq!(negotiate(Some("br;q=0.5, gzip")), Some(Encoding::Gzip));
+    assert_eq!(negotiate(Some("br;q=0, gzip;q=0.1")), Some(Encoding::Gzip));
+    assert_eq!(negotiate(Some("gzip;q=0")), None);
+  }
+
+  #[test]
+  fn test_encoded_etag() {
+    let tag = encoded_etag("\"abc.car\"", Encoding::Brotli);
+    assert_eq!(tag, "\"abc.car-br\"");
+    assert_eq!(plain_etag(&tag), "\"abc.car\"");
+    assert_eq!(plain_etag("\"abc-gzip\""), "\"abc\"");
+    assert_eq!(plain_etag("\"abc-full\""), "\"abc-full\"");
+  }
+}
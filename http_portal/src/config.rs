@@ -0,0 +1,82 @@
+//! The portal's settings, read once and checked at startup (see
+//! [biab_utils::config_value] for where they're read from).
+//!
+//! The parts with settings of their own (eg: TLS, CORS, the API keys, the
+//! rate limits) read them when they're created, which is at startup too.
+use anyhow::{anyhow, Result};
+use biab_utils::{config_or, config_value};
+use serde::{Serialize, Serializer};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use twine_protocol::prelude::*;
+
+#[derive(Debug, Serialize)]
+pub struct Config {
+  pub port: u16,
+  pub bind_address: IpAddr,
+  /// eg: for a reverse proxy in the same pod
+  pub unix_socket_path: Option<String>,
+  pub max_range_size: u64,
+  pub anonymous_max_range_size: u64,
+  pub pulse_poll_interval_ms: u64,
+  pub drain_timeout_secs: u64,
+  pub compression_min_size: u64,
+  #[serde(serialize_with = "display")]
+  pub nist_compat_strand: Option<Cid>,
+  #[serde(serialize_with = "display")]
+  pub drand_compat_strand: Option<Cid>,
+}
+
+impl Config {
+  pub fn load() -> Result<Self> {
+    let max_range_size = config_or("MAX_RANGE_SIZE", 1000)?;
+    Ok(Self {
+      port: config_or("PORT", 80)?,
+      bind_address: config_or(
+        "BIND_ADDRESS",
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+      )?,
+      unix_socket_path: config_value("UNIX_SOCKET_PATH")?,
+      max_range_size,
+      anonymous_max_range_size: config_or(
+        "ANONYMOUS_MAX_RANGE_SIZE",
+        max_range_size,
+      )?,
+      pulse_poll_interval_ms: config_or("PULSE_POLL_INTERVAL_MS", 500)?,
+      drain_timeout_secs: config_or("DRAIN_TIMEOUT_SECS", 30)?,
+      compression_min_size: config_or("COMPRESSION_MIN_SIZE", 1024)?,
+      nist_compat_strand: compat_strand("NIST_COMPAT_STRAND")?,
+      drand_compat_strand: compat_strand("DRAND_COMPAT_STRAND")?,
+    })
+  }
+
+  pub fn poll_interval(&self) -> Duration {
+    Duration::from_millis(self.pulse_poll_interval_ms.max(1))
+  }
+
+  pub fn drain_timeout(&self) -> Duration {
+    Duration::from_secs(self.drain_timeout_secs)
+  }
+}
+
+/// The strand served by one of the compatibility APIs, if enabled
+fn compat_strand(var: &str) -> Result<Option<Cid>> {
+  match config_value(var)? {
+    Some(cid) => {
+      let cid = Cid::try_from(cid.as_str())
+        .map_err(|e| anyhow!("Invalid {}: {}", var, e))?;
+      Ok(Some(cid))
+    }
+    None => Ok(None),
+  }
+}
+
+fn display<S: Serializer>(
+  cid: &Option<Cid>,
+  serializer: S,
+) -> Result<S::Ok, S::Error> {
+  match cid {
+    Some(cid) => serializer.collect_str(cid),
+    None => serializer.serialize_none(),
+  }
+}
@@ -0,0 +1,66 @@
+//! Cross origin access for browser based clients.
+//!
+//! By default any origin may read from the portal with GET requests. The
+//! policy is configured with comma separated lists in `CORS_ALLOWED_ORIGINS`
+//! (`*` for any), `CORS_ALLOWED_METHODS` and `CORS_ALLOWED_HEADERS`.
+use anyhow::{anyhow, Result};
+use biab_utils::config_value;
+use warp::http::Method;
+
+const DEFAULT_HEADERS: &str =
+  "accept,accept-encoding,if-none-match,x-request-id";
+/// Headers clients need to see for caching, pagination, pulse lookups, error
+/// reports and signature checks
+const EXPOSED_HEADERS: [&str; 9] = [
+  "content-digest",
+  "etag",
+  "link",
+  "retry-after",
+  "signature",
+  "signature-input",
+  "x-pulse-index",
+  "x-request-id",
+  "x-spool-version",
+];
+
+fn list(var: &str, default: &str) -> Result<Vec<String>> {
+  let list = config_value(var)?.unwrap_or_else(|| default.into());
+  Ok(
+    list
+      .split(',')
+      .map(|s| s.trim().to_string())
+      .filter(|s| !s.is_empty())
+      .collect(),
+  )
+}
+
+pub fn from_env() -> Result<warp::cors::Builder> {
+  let origins = list("CORS_ALLOWED_ORIGINS", "*")?;
+  let methods = list("CORS_ALLOWED_METHODS", "GET")?
+    .into_iter()
+    .map(|m| {
+      Method::from_bytes(m.to_ascii_uppercase().as_bytes())
+        .map_err(|_| anyhow!("Invalid method in CORS_ALLOWED_METHODS: {}", m))
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let headers = list("CORS_ALLOWED_HEADERS", DEFAULT_HEADERS)?;
+
+  let cors = warp::cors()
+    .allow_methods(methods)
+    .allow_headers(headers.iter().map(String::as_str))
+    .expose_headers(EXPOSED_HEADERS);
+  if origins.iter().any(|o| o == "*") {
+    Ok(cors.allow_any_origin())
+  } else {
+    for origin in &origins {
+      // warp panics on origins it can't parse, so check them up front
+      warp::http::HeaderValue::from_str(origin)
+        .ok()
+        .filter(|_| origin.contains("://"))
+        .ok_or_else(|| {
+          anyhow!("Invalid origin in CORS_ALLOWED_ORIGINS: {}", origin)
+        })?;
+    }
+    Ok(cors.allow_origins(origins.iter().map(String::as_str)))
+  }
+}
@@ -0,0 +1,177 @@
+//! A drand HTTP API compatible view of one strand.
+//!
+//! Serves the strand configured in `DRAND_COMPAT_STRAND` under `/drand`
+//! using drand's JSON schema. Rounds start at 1, so round `r` is the pulse at
+//! index `r - 1`. Signatures are the tixel signatures made with the strand
+//! key, not BLS signatures, so they have to be checked as twine data.
+use crate::cache::{self, Freshness};
+use crate::handlers::HttpError;
+use crate::pulses;
+use crate::PortalStore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use twine_protocol::prelude::*;
+use warp::Filter;
+
+#[derive(Debug, Serialize)]
+struct Metadata {
+  #[serde(rename = "beaconID")]
+  beacon_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Info {
+  public_key: String,
+  period: i64,
+  genesis_time: i64,
+  hash: String,
+  #[serde(rename = "groupHash")]
+  group_hash: String,
+  #[serde(rename = "schemeID")]
+  scheme_id: String,
+  metadata: Metadata,
+}
+
+#[derive(Debug, Serialize)]
+struct Beacon {
+  round: u64,
+  randomness: String,
+  signature: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  previous_signature: Option<String>,
+}
+
+fn chain_hash(strand: &Cid) -> String {
+  hex::encode(strand.hash().digest())
+}
+
+fn signature_hex(tixel: &Tixel) -> String {
+  pulses::signature(tixel)
+    .map(hex::encode)
+    .unwrap_or_default()
+}
+
+fn json(value: &impl Serialize, freshness: Freshness) -> warp::reply::Response {
+  let res = warp::reply::Reply::into_response(warp::reply::json(value));
+  cache::with_cache_headers(res, None, freshness)
+}
+
+async fn beacon(
+  store: &PortalStore,
+  twine: &Twine,
+) -> Result<Beacon, HttpError> {
+  if twine.index() == 0 {
+    // the first pulse only commits to the randomness that follows it
+    return Err(HttpError::Resolution(ResolutionError::NotFound));
+  }
+  let prev = store
+    .resolve_index(twine.strand_cid(), twine.index() - 1)
+    .await?
+    .unpack();
+  let output = pulses::output_value_from(twine, &prev)?;
+  Ok(Beacon {
+    round: twine.index() + 1,
+    randomness: hex::encode(Sha256::digest(&output)),
+    signature: signature_hex(twine),
+    previous_signature: Some(signature_hex(&prev)),
+  })
+}
+
+mod handlers {
+  use super::*;
+
+  pub async fn info(
+    strand: Cid,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let first = store.resolve_index(strand, 0).await?.unpack();
+    let period = pulses::period(first.strand())
+      .map(|p| p.num_seconds())
+      .unwrap_or_default();
+    let info = Info {
+      public_key: hex::encode(&first.strand().key().key),
+      period,
+      genesis_time: pulses::timestamp(&first)?.timestamp(),
+      hash: chain_hash(&strand),
+      group_hash: chain_hash(&strand),
+      scheme_id: first.strand().spec_str().to_string(),
+      metadata: Metadata {
+        beacon_id: strand.to_string(),
+      },
+    };
+    Ok(json(&info, Freshness::Immutable))
+  }
+
+  pub async fn latest(
+    strand: Cid,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let twine = store.resolve_latest(strand).await?.unpack();
+    let freshness = Freshness::MaxAge(cache::latest_max_age(&twine));
+    Ok(json(&beacon(&store, &twine).await?, freshness))
+  }
+
+  pub async fn round(
+    strand: Cid,
+    round: u64,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let index = round
+      .checked_sub(1)
+      .ok_or_else(|| HttpError::BadRequest("Rounds start at 1".to_string()))?;
+    let twine = store.resolve_index(strand, index).await?.unpack();
+    Ok(json(&beacon(&store, &twine).await?, Freshness::Immutable))
+  }
+}
+
+fn reject(err: HttpError) -> warp::Rejection {
+  warp::reject::custom(err)
+}
+
+/// The drand compatible routes, under `/drand` and `/drand/<chain hash>`.
+/// Everything is rejected as not found if no strand is configured.
+pub fn routes(
+  strand: Option<Cid>,
+  store: Arc<PortalStore>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let strand = warp::any().and_then(move || async move {
+    strand.ok_or_else(warp::reject::not_found)
+  });
+  // the chain hash is optional, but has to match if it is given
+  let chain = warp::path::param::<String>()
+    .and(strand.clone())
+    .and_then(|hash: String, strand: Cid| async move {
+      if hash == chain_hash(&strand) {
+        Ok(strand)
+      } else {
+        Err(warp::reject::not_found())
+      }
+    })
+    .or(strand)
+    .unify();
+  let base = warp::path("drand").and(chain);
+  let store = warp::any().map(move || store.clone());
+
+  let info = base
+    .clone()
+    .and(warp::path!("info"))
+    .and(store.clone())
+    .and_then(|strand, store| async move {
+      handlers::info(strand, store).await.map_err(reject)
+    });
+  let latest = base
+    .clone()
+    .and(warp::path!("public" / "latest"))
+    .and(store.clone())
+    .and_then(|strand, store| async move {
+      handlers::latest(strand, store).await.map_err(reject)
+    });
+  let round = base.and(warp::path!("public" / u64)).and(store).and_then(
+    |strand, round, store| async move {
+      handlers::round(strand, round, store).await.map_err(reject)
+    },
+  );
+
+  warp::get().and(info.or(latest).or(round))
+}
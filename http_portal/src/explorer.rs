@@ -0,0 +1,19 @@
+//! A small web explorer at `GET /explorer`, built with the `explorer`
+//! feature. It lists the strands with a countdown to their next pulse and
+//! lets users browse and verify pulses using the public API.
+use warp::Filter;
+
+#[cfg(feature = "explorer")]
+const PAGE: Option<&str> = Some(include_str!("../explorer/index.html"));
+#[cfg(not(feature = "explorer"))]
+const PAGE: Option<&str> = None;
+
+/// `/explorer`
+pub fn routes(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::get().and(warp::path!("explorer")).and_then(|| async {
+    PAGE
+      .map(warp::reply::html)
+      .ok_or_else(warp::reject::not_found)
+  })
+}
@@ -0,0 +1,179 @@
+//! Fall back to other stores for data the database doesn't have.
+//!
+//! `FALLBACK_STORE_URLS` is a comma separated list of twine HTTP stores
+//! (eg: another portal) that are asked, in order, whenever the local database
+//! fails or is missing something. `FALLBACK_STORE_API_KEY` is sent to them if
+//! set. Data pushed to the portal is only ever saved locally.
+use anyhow::Result;
+use async_trait::async_trait;
+use biab_utils::{config_list, config_secret, Secret};
+use futures::Stream;
+use std::sync::Arc;
+use twine_protocol::twine_lib::{
+  as_cid::AsCid,
+  errors::{ResolutionError, StoreError},
+  resolver::{
+    unchecked_base::{BaseResolver, TwineStream},
+    AbsoluteRange, MaybeSend, Resolver, ResolverSetSeries,
+  },
+  store::Store,
+  twine::{AnyTwine, Strand, Tixel},
+  Cid,
+};
+
+/// Reads from the primary store and then the fallbacks, writes to the
+/// primary store only
+pub struct FallbackStore<P> {
+  primary: Arc<P>,
+  /// The primary store followed by the fallbacks, if there are any
+  series: Option<ResolverSetSeries<Arc<dyn BaseResolver>>>,
+}
+
+impl<P: BaseResolver + 'static> FallbackStore<P> {
+  pub fn from_env(primary: P) -> Result<Self> {
+    let primary = Arc::new(primary);
+    let urls = config_list("FALLBACK_STORE_URLS")?;
+    if urls.is_empty() {
+      return Ok(Self {
+        primary,
+        series: None,
+      });
+    }
+
+    let api_key = config_secret("FALLBACK_STORE_API_KEY")?;
+    let api_key = api_key.as_ref().map(Secret::expose).unwrap_or_default();
+    let mut series: ResolverSetSeries<Arc<dyn BaseResolver>> =
+      ResolverSetSeries::new(vec![primary.clone() as Arc<dyn BaseResolver>]);
+    for url in urls {
+      log::info!("Falling back to the store at {}", url);
+      series.add(Arc::new(biab_utils::open_http_store(&url, api_key)?));
+    }
+    Ok(Self {
+      primary,
+      series: Some(series),
+    })
+  }
+
+  /// Only read from the primary store
+  pub fn local(primary: P) -> Self {
+    Self {
+      primary: Arc::new(primary),
+      series: None,
+    }
+  }
+
+  /// The local store, eg: to check that the database is up
+  pub fn primary(&self) -> &P {
+    &self.primary
+  }
+
+  fn reader(&self) -> &dyn BaseResolver {
+    match &self.series {
+      Some(series) => series,
+      None => self.primary.as_ref(),
+    }
+  }
+}
+
+#[async_trait]
+impl<P: BaseResolver + 'static> BaseResolver for FallbackStore<P> {
+  async fn has_index(
+    &self,
+    strand: &Cid,
+    index: u64,
+  ) -> Result<bool, ResolutionError> {
+    self.reader().has_index(strand, index).await
+  }
+
+  async fn has_twine(
+    &self,
+    strand: &Cid,
+    cid: &Cid,
+  ) -> Result<bool, ResolutionError> {
+    self.reader().has_twine(strand, cid).await
+  }
+
+  async fn has_strand(&self, cid: &Cid) -> Result<bool, ResolutionError> {
+    self.reader().has_strand(cid).await
+  }
+
+  async fn fetch_latest(&self, strand: &Cid) -> Result<Tixel, ResolutionError> {
+    self.reader().fetch_latest(strand).await
+  }
+
+  async fn fetch_index(
+    &self,
+    strand: &Cid,
+    index: u64,
+  ) -> Result<Tixel, ResolutionError> {
+    self.reader().fetch_index(strand, index).await
+  }
+
+  async fn fetch_tixel(
+    &self,
+    strand: &Cid,
+    tixel: &Cid,
+  ) -> Result<Tixel, ResolutionError> {
+    self.reader().fetch_tixel(strand, tixel).await
+  }
+
+  async fn fetch_strand(
+    &self,
+    strand: &Cid,
+  ) -> Result<Strand, ResolutionError> {
+    self.reader().fetch_strand(strand).await
+  }
+
+  async fn range_stream<'a>(
+    &'a self,
+    range: AbsoluteRange,
+  ) -> Result<TwineStream<'a, Tixel>, ResolutionError> {
+    self.reader().range_stream(range).await
+  }
+
+  async fn fetch_strands<'a>(
+    &'a self,
+  ) -> Result<TwineStream<'a, Strand>, ResolutionError> {
+    self.reader().fetch_strands().await
+  }
+}
+
+impl<P: BaseResolver + 'static> Resolver for FallbackStore<P> {}
+
+#[async_trait]
+impl<P: Store + 'static> Store for FallbackStore<P> {
+  async fn save<T: Into<AnyTwine> + MaybeSend>(
+    &self,
+    twine: T,
+  ) -> Result<(), StoreError> {
+    self.primary.save(twine).await
+  }
+
+  async fn save_many<
+    I: Into<AnyTwine> + MaybeSend,
+    S: Iterator<Item = I> + MaybeSend,
+    T: IntoIterator<Item = I, IntoIter = S> + MaybeSend,
+  >(
+    &self,
+    twines: T,
+  ) -> Result<(), StoreError> {
+    self.primary.save_many(twines).await
+  }
+
+  async fn save_stream<
+    I: Into<AnyTwine> + MaybeSend,
+    T: Stream<Item = I> + MaybeSend + Unpin,
+  >(
+    &self,
+    twines: T,
+  ) -> Result<(), StoreError> {
+    self.primary.save_stream(twines).await
+  }
+
+  async fn delete<C: AsCid + MaybeSend>(
+    &self,
+    cid: C,
+  ) -> Result<(), StoreError> {
+    self.primary.delete(cid).await
+  }
+}
@@ -0,0 +1,182 @@
+//! Liveness and readiness probes.
+//!
+//! `GET /healthz` answers as long as the portal is serving requests.
+//! `GET /readyz` also checks that the database answers and, if
+//! `READINESS_STRAND` is set, that the strand's latest pulse is no older than
+//! `READINESS_MAX_PERIODS` (default 2) periods. Not ready is a `503`.
+use crate::pulses;
+use crate::PortalStore;
+use anyhow::{anyhow, Result};
+use biab_utils::{config_or, config_value, DbStore};
+use chrono::Utc;
+use futures::StreamExt;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use twine_protocol::prelude::*;
+use warp::http::StatusCode;
+use warp::Filter;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Freshness {
+  strand: Cid,
+  max_periods: u32,
+}
+
+impl Freshness {
+  pub fn from_env() -> Result<Option<Self>> {
+    let strand = match config_value("READINESS_STRAND")? {
+      Some(strand) => Cid::try_from(strand.as_str())
+        .map_err(|e| anyhow!("Invalid READINESS_STRAND: {}", e))?,
+      None => return Ok(None),
+    };
+    let max_periods: u32 = config_or("READINESS_MAX_PERIODS", 2)?;
+    Ok(Some(Self {
+      strand,
+      max_periods: max_periods.max(1),
+    }))
+  }
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+  ok: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  latency_ms: Option<u128>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  age_seconds: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  max_age_seconds: Option<i64>,
+}
+
+impl Check {
+  fn failed(error: impl ToString) -> Self {
+    Self {
+      ok: false,
+      error: Some(error.to_string()),
+      latency_ms: None,
+      age_seconds: None,
+      max_age_seconds: None,
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+  status: &'static str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  database: Option<Check>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  latest_pulse: Option<Check>,
+}
+
+async fn check_database(store: &DbStore) -> Check {
+  let start = Instant::now();
+  let res = tokio::time::timeout(CHECK_TIMEOUT, async {
+    // reading one strand is enough to know queries go through
+    match store.strands().await?.next().await {
+      Some(Err(e)) => Err(e),
+      _ => Ok(()),
+    }
+  })
+  .await;
+  match res {
+    Ok(Ok(())) => Check {
+      ok: true,
+      error: None,
+      latency_ms: Some(start.elapsed().as_millis()),
+      age_seconds: None,
+      max_age_seconds: None,
+    },
+    Ok(Err(e)) => Check::failed(e),
+    Err(_) => Check::failed("timed out"),
+  }
+}
+
+async fn check_latest_pulse(
+  store: &PortalStore,
+  freshness: Freshness,
+) -> Check {
+  let latest = match tokio::time::timeout(
+    CHECK_TIMEOUT,
+    store.resolve_latest(freshness.strand),
+  )
+  .await
+  {
+    Ok(Ok(latest)) => latest.unpack(),
+    Ok(Err(e)) => return Check::failed(e),
+    Err(_) => return Check::failed("timed out"),
+  };
+  let period = match pulses::period(latest.strand()) {
+    Some(period) => period,
+    None => return Check::failed("not a randomness strand"),
+  };
+  let timestamp = match pulses::timestamp(&latest) {
+    Ok(timestamp) => timestamp,
+    Err(e) => return Check::failed(e),
+  };
+  let age = (Utc::now() - timestamp).num_seconds();
+  let max_age = period.num_seconds() * freshness.max_periods as i64;
+  Check {
+    ok: age <= max_age,
+    error: (age > max_age).then(|| "latest pulse is overdue".to_string()),
+    latency_ms: None,
+    age_seconds: Some(age),
+    max_age_seconds: Some(max_age),
+  }
+}
+
+async fn ready(
+  store: Arc<PortalStore>,
+  freshness: Option<Freshness>,
+) -> warp::reply::Response {
+  // fallback stores would hide a broken database
+  let database = check_database(store.inner().inner().primary()).await;
+  let latest_pulse = match freshness {
+    Some(freshness) if database.ok => {
+      Some(check_latest_pulse(&store, freshness).await)
+    }
+    _ => None,
+  };
+  let ok = database.ok && latest_pulse.as_ref().map_or(true, |c| c.ok);
+  if !ok {
+    log::warn!("Portal is not ready");
+  }
+  let status = Status {
+    status: if ok { "ready" } else { "not ready" },
+    database: Some(database),
+    latest_pulse,
+  };
+  let code = if ok {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  warp::reply::Reply::into_response(warp::reply::with_status(
+    warp::reply::json(&status),
+    code,
+  ))
+}
+
+/// `/healthz` and `/readyz`
+pub fn routes(
+  store: Arc<PortalStore>,
+  freshness: Option<Freshness>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let healthz = warp::path!("healthz").map(|| {
+    warp::reply::json(&Status {
+      status: "ok",
+      database: None,
+      latest_pulse: None,
+    })
+  });
+  let readyz = warp::path!("readyz")
+    .and(warp::any().map(move || store.clone()))
+    .then(move |store| ready(store, freshness));
+
+  warp::get().and(healthz.or(readyz))
+}
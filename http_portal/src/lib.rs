@@ -0,0 +1,1022 @@
+use anyhow::Result;
+use biab_utils::handle_shutdown_signal;
+use config::Config;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::errors::StoreError;
+use warp::Filter;
+
+mod attestation;
+mod auth;
+mod beacons;
+mod block;
+mod cache;
+mod compression;
+mod config;
+mod cors;
+mod dag_json;
+mod drand;
+mod explorer;
+mod fallback;
+mod health;
+mod metrics;
+mod nist;
+mod object_cache;
+mod pagination;
+mod problem;
+mod proof;
+mod pulses;
+mod push;
+mod randomness;
+mod rate_limit;
+mod request_id;
+mod shaping;
+mod signing;
+mod strand_info;
+mod subscriptions;
+mod tls;
+mod verify;
+
+/// The database (and any fallback stores) limited to the strands of a beacon,
+/// with hot objects cached in memory
+pub type PortalStore = object_cache::CachingStore<
+  beacons::StrandFilter<fallback::FallbackStore<biab_utils::DbStore>>,
+>;
+
+/// What the portal does, for `--help`
+pub const ABOUT: &str = "Serves the beacon's pulses over HTTP";
+
+/// Run the portal
+pub async fn run() -> Result<()> {
+  if biab_utils::help(ABOUT, &[]) {
+    return Ok(());
+  }
+  request_id::init_logger();
+  if let Some(command) = biab_utils::command() {
+    return Err(anyhow::anyhow!("Unknown command {}, see --help", command));
+  }
+  let config = Config::load()?;
+  if biab_utils::print_config(&config, true)? {
+    return Ok(());
+  }
+
+  // Setup graceful shutdown
+  let shutdown = Arc::new(Notify::new());
+  tokio::spawn(handle_shutdown_signal(shutdown.clone()));
+
+  let range_limits = auth::RangeLimits {
+    max: config.max_range_size,
+    anonymous: config.anonymous_max_range_size,
+  };
+  let poll_interval = config.poll_interval();
+  let drain_timeout = config.drain_timeout();
+  let compression_min_size = config.compression_min_size;
+  let unix_socket = config.unix_socket_path.clone();
+  let tls = tls::TlsSettings::from_env()?;
+  if tls.is_some() && unix_socket.is_some() {
+    return Err(anyhow::anyhow!(
+      "TLS can't be used with UNIX_SOCKET_PATH, terminate it at the proxy"
+    ));
+  }
+  let cors = cors::from_env()?;
+  let keys = Arc::new(auth::ApiKeys::from_env()?);
+  let limiter = Arc::new(rate_limit::RateLimiter::from_env()?);
+  let readiness = health::Freshness::from_env()?;
+  let signer = signing::ResponseSigner::from_env()?;
+  let attestation = attestation::from_env()?;
+
+  let db = biab_utils::open_store()
+    .await?
+    .with_observer(metrics::observe_query);
+  let store = Arc::new(object_cache::CachingStore::from_env(
+    beacons::StrandFilter::all(fallback::FallbackStore::from_env(db)?),
+  )?);
+
+  let feed = subscriptions::PulseFeed::new(store.clone());
+  tokio::spawn(feed.clone().run(poll_interval));
+  follow_pulse_events(feed.clone(), shutdown.clone()).await?;
+
+  // other beacons under /beacons/<name>/, each with their own store, feed
+  // and rate limits
+  let mut beacons = warp::any()
+    .and_then(|| async {
+      Err::<warp::reply::Response, _>(warp::reject::not_found())
+    })
+    .boxed();
+  for beacon in
+    beacons::from_env(&limiter, object_cache::capacity_from_env()?).await?
+  {
+    let feed = subscriptions::PulseFeed::new(beacon.store.clone());
+    tokio::spawn(feed.clone().run(poll_interval));
+    let routes = warp::path("beacons")
+      .and(warp::path(beacon.name))
+      .and(filters::api(
+        beacon.store,
+        feed,
+        range_limits,
+        beacon.nist_strand,
+        beacon.drand_strand,
+        keys.clone(),
+        beacon.limiter,
+      ))
+      .map(warp::Reply::into_response);
+    beacons = beacons.or(routes).unify().boxed();
+  }
+
+  // probes aren't rate limited so orchestrators can poll them freely
+  let api = warp::any()
+    .map(std::time::Instant::now)
+    .and(warp::path::full())
+    .and(
+      warp::query::raw()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify(),
+    )
+    .and(warp::method())
+    .and(warp::header::optional::<String>("accept-encoding"))
+    .and(
+      health::routes(store.clone(), readiness)
+        .or(signing::routes(signer.clone()))
+        .or(attestation::routes(attestation))
+        .or(beacons)
+        .or(filters::api(
+          store,
+          feed,
+          range_limits,
+          config.nist_compat_strand,
+          config.drand_compat_strand,
+          keys,
+          limiter,
+        )),
+    )
+    .then(
+      move |started,
+            path: warp::path::FullPath,
+            query: Option<String>,
+            method,
+            accept_encoding: Option<String>,
+            reply| {
+        let signer = signer.clone();
+        async move {
+          let mut res = compression::compress(
+            warp::Reply::into_response(reply),
+            accept_encoding.as_deref(),
+            compression_min_size,
+          )
+          .await;
+          if let Some(signer) = signer {
+            res = signer.sign(path.as_str(), query.as_deref(), res).await;
+          }
+          metrics::observe_request(path.as_str(), &method, started, &res);
+          request_id::with_header(res)
+        }
+      },
+    )
+    .with(cors)
+    .with(warp::log::custom(request_id::access_log))
+    .with(warp::trace(request_id::span));
+
+  // on shutdown new connections are refused while open requests finish
+  let stopping = {
+    let shutdown = shutdown.clone();
+    async move { shutdown.notified().await }
+  };
+  let addr = std::net::SocketAddr::new(config.bind_address, config.port);
+  let serve = async move {
+    match (tls, unix_socket) {
+      (Some(tls), _) => {
+        let incoming = tls.incoming(addr).await?;
+        warp::serve(api)
+          .serve_incoming_with_graceful_shutdown(incoming, stopping)
+          .await;
+      }
+      (None, Some(path)) => {
+        let incoming = unix_socket_incoming(&path)?;
+        warp::serve(api)
+          .serve_incoming_with_graceful_shutdown(incoming, stopping)
+          .await;
+      }
+      (None, None) => {
+        let (_, server) = warp::serve(api)
+          .try_bind_with_graceful_shutdown(addr, stopping)
+          .map_err(|e| {
+            anyhow::anyhow!("Failed to listen on {}: {}", addr, e)
+          })?;
+        server.await;
+      }
+    }
+    anyhow::Ok(())
+  };
+  tokio::pin!(serve);
+
+  tokio::select! {
+    res = &mut serve => return res,
+    _ = shutdown.notified() => {
+      log::info!(
+        "Shutting down, waiting up to {:?} for open requests...",
+        drain_timeout
+      );
+    }
+  };
+  match tokio::time::timeout(drain_timeout, serve).await {
+    Ok(res) => res?,
+    Err(_) => log::warn!("Requests still open after {:?}", drain_timeout),
+  }
+
+  Ok(())
+}
+
+/// Look for new pulses as soon as the generator publishes them, if
+/// subscribed to its events (see [biab_utils::event_bus])
+async fn follow_pulse_events(
+  feed: Arc<subscriptions::PulseFeed>,
+  shutdown: Arc<Notify>,
+) -> Result<()> {
+  let Some(bus) = biab_utils::event_bus(shutdown).await? else {
+    return Ok(());
+  };
+  let mut events = bus.subscribe(&[biab_utils::PULSE_PUBLISHED_TOPIC]).await?;
+  tokio::spawn(async move {
+    while events.recv().await.is_some() {
+      feed.wake();
+    }
+  });
+  Ok(())
+}
+
+/// Connections to a unix socket at `path`
+fn unix_socket_incoming(
+  path: &str,
+) -> Result<tokio_stream::wrappers::UnixListenerStream> {
+  use std::os::unix::fs::FileTypeExt;
+  // left behind by a previous run
+  if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+    std::fs::remove_file(path)?;
+  }
+  let listener = tokio::net::UnixListener::bind(path)
+    .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", path, e))?;
+  log::info!("Listening on {}", path);
+  Ok(tokio_stream::wrappers::UnixListenerStream::new(listener))
+}
+
+mod filters {
+  use super::*;
+  use serde::Deserialize;
+  use std::sync::Arc;
+
+  // GET / -> all strands
+  // POST / -> save a CAR file of strands and tixels (needs WRITE_API_KEY)
+  // POST /:strand -> save a CAR file of tixels of one strand
+  // GET /:query -> parse the AnyQuery and return the result
+  // GET /:query?full -> also include the strand in the result
+  // GET /:query?fields=cid,index -> only some fields of each tixel
+  // GET /:query?omit-payload -> every field but the payload
+  // GET /:query?limit=n&page=n -> a page of a range query
+  // GET /:query?cursor=n -> continue a range query from an index
+  // GET /latest -> the latest tixel of every strand
+  // GET /strand/:cid/latest -> the latest tixel of one strand
+  // GET /strand/:cid/info -> decoded details and pulse range of a strand
+  // GET /strand/:cid/next?after=n -> wait for the pulse after index n
+  // GET /subscribe -> websocket pushing new pulses of subscribed strands
+  // GET /proof?from=:cid&to=:cid -> skip list path between two pulses
+  // GET /randomness/:strand/:index -> the randomness of a pulse
+  // GET /randomness/:strand/time/:seconds -> the randomness at a time
+  // GET /verify/:strand/:start/:end -> verification report for a range
+  // GET /block/:cid -> the raw DAG-CBOR bytes of a strand or tixel
+  // GET /healthz -> liveness probe
+  // GET /readyz -> readiness probe checking the database and latest pulse
+  // GET /metrics -> prometheus metrics (needs an admin key if any exist)
+  // GET /explorer -> web explorer (with the explorer feature)
+  // GET /signing-key -> JWK of the response signing key (if configured)
+  // /beacons/:name/... -> all of the above for a beacon in BEACONS_PATH
+
+  #[derive(Debug, Deserialize)]
+  struct Truthy(Option<String>);
+
+  impl From<Truthy> for bool {
+    fn from(t: Truthy) -> bool {
+      t.0.map_or(false, |s| s.to_ascii_lowercase() != "false")
+    }
+  }
+
+  impl Default for Truthy {
+    fn default() -> Self {
+      Truthy(None)
+    }
+  }
+
+  #[derive(Debug, Deserialize)]
+  struct QueryParams {
+    #[serde(default)]
+    full: Truthy,
+    fields: Option<String>,
+    #[serde(default, rename = "omit-payload")]
+    omit_payload: Truthy,
+    limit: Option<u64>,
+    page: Option<u64>,
+    cursor: Option<u64>,
+  }
+
+  #[derive(Debug, Deserialize)]
+  struct NextParams {
+    #[serde(default)]
+    full: Truthy,
+    after: Option<u64>,
+    timeout: Option<u64>,
+  }
+
+  impl QueryParams {
+    fn shape(self) -> Result<shaping::Shape, handlers::HttpError> {
+      shaping::Shape::new(
+        self.full.into(),
+        self.fields.as_deref(),
+        self.omit_payload.into(),
+      )
+      .map_err(handlers::HttpError::BadQuery)
+    }
+
+    fn page_request(&self) -> pagination::PageRequest {
+      pagination::PageRequest {
+        limit: self.limit,
+        page: self.page,
+        cursor: self.cursor,
+      }
+    }
+  }
+
+  pub fn api(
+    store: Arc<PortalStore>,
+    feed: Arc<subscriptions::PulseFeed>,
+    range_limits: auth::RangeLimits,
+    nist_strand: Option<Cid>,
+    drand_strand: Option<Cid>,
+    keys: Arc<auth::ApiKeys>,
+    limiter: Arc<rate_limit::RateLimiter>,
+  ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+  {
+    rate_limit::filter(limiter, keys.clone())
+      .and(
+        push::routes(keys.clone(), store.clone())
+          .or(list_strands(store.clone()))
+          .or(nist::routes(nist_strand, store.clone()))
+          .or(drand::routes(drand_strand, store.clone()))
+          .or(latest(store.clone()))
+          .or(strand_latest(store.clone()))
+          .or(strand_info::routes(store.clone()))
+          .or(strand_next(feed.clone()))
+          .or(subscribe(feed))
+          .or(proof::routes(store.clone()))
+          .or(randomness::routes(store.clone()))
+          .or(verify::routes(store.clone(), keys.clone(), range_limits))
+          .or(block::routes(store.clone()))
+          .or(metrics::routes(store.clone(), keys.clone()))
+          .or(explorer::routes())
+          .or(query(store, keys, range_limits)),
+      )
+      .recover(problem::recover)
+      .with(warp::reply::with::header("X-Spool-Version", "2"))
+  }
+
+  fn list_strands(
+    store: Arc<PortalStore>,
+  ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+  {
+    warp::path::end()
+      .and(with_store(store))
+      .and(with_check_accept_car())
+      .and_then(|store, as_car| async move {
+        let res = handlers::list_strands(store, as_car).await; // Added parameter `as_car`
+        match res {
+          Ok(reply) => Ok(reply),
+          Err(err) => Err(warp::reject::custom(err)),
+        }
+      })
+  }
+
+  fn latest(
+    store: Arc<PortalStore>,
+  ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+  {
+    warp::path!("latest")
+      .and(with_store(store))
+      .and(with_check_accept_car())
+      .and(warp::query::<QueryParams>())
+      .and_then(|store, as_car: bool, params: QueryParams| async move {
+        let res = handlers::latest(store, as_car, params.full.into()).await;
+        match res {
+          Ok(reply) => Ok(reply),
+          Err(err) => Err(warp::reject::custom(err)),
+        }
+      })
+  }
+
+  fn strand_latest(
+    store: Arc<PortalStore>,
+  ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+  {
+    warp::path!("strand" / Cid / "latest")
+      .and(with_store(store))
+      .and(with_check_accept_car())
+      .and(with_if_none_match())
+      .and(warp::query::<QueryParams>())
+      .and_then(
+        |strand,
+         store,
+         as_car: bool,
+         if_none_match: Option<String>,
+         params: QueryParams| async move {
+          let res = handlers::strand_latest(
+            strand,
+            store,
+            as_car,
+            params.full.into(),
+            if_none_match,
+          )
+          .await;
+          match res {
+            Ok(reply) => Ok(reply),
+            Err(err) => Err(warp::reject::custom(err)),
+          }
+        },
+      )
+  }
+
+  fn strand_next(
+    feed: Arc<subscriptions::PulseFeed>,
+  ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+  {
+    warp::path!("strand" / Cid / "next")
+      .and(warp::any().map(move || feed.clone()))
+      .and(with_check_accept_car())
+      .and(warp::query::<NextParams>())
+      .and_then(
+        |strand, feed, as_car: bool, params: NextParams| async move {
+          let timeout = params
+            .timeout
+            .unwrap_or(handlers::DEFAULT_NEXT_TIMEOUT)
+            .clamp(1, handlers::MAX_NEXT_TIMEOUT);
+          let res = handlers::strand_next(
+            strand,
+            feed,
+            as_car,
+            params.full.into(),
+            params.after,
+            std::time::Duration::from_secs(timeout),
+          )
+          .await;
+          match res {
+            Ok(reply) => Ok(reply),
+            Err(err) => Err(warp::reject::custom(err)),
+          }
+        },
+      )
+  }
+
+  fn subscribe(
+    feed: Arc<subscriptions::PulseFeed>,
+  ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+  {
+    warp::path!("subscribe")
+      .and(warp::ws())
+      .and(warp::any().map(move || feed.clone()))
+      .map(|ws: warp::ws::Ws, feed| {
+        ws.on_upgrade(move |socket| subscriptions::handle_socket(socket, feed))
+      })
+  }
+
+  fn query(
+    store: Arc<PortalStore>,
+    keys: Arc<auth::ApiKeys>,
+    range_limits: auth::RangeLimits,
+  ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+  {
+    warp::path::param()
+      .and_then(|query: String| async move {
+        query.parse::<AnyQuery>().map_err(|e| {
+          // a path not starting with a CID is an unknown route, not a query
+          let strand = query.split(':').next().unwrap_or_default();
+          if Cid::try_from(strand).is_err() {
+            return warp::reject::not_found();
+          }
+          warp::reject::custom(handlers::HttpError::BadQuery(format!(
+            "Invalid query {}: {}",
+            query, e
+          )))
+        })
+      })
+      .and(with_store(store))
+      .and(with_check_accept_car())
+      .and(with_if_none_match())
+      .and(warp::query::<QueryParams>())
+      .and(auth::max_range(keys, range_limits))
+      .and_then(
+        |query,
+         store,
+         as_car: bool,
+         if_none_match: Option<String>,
+         params: QueryParams,
+         max_range: u64| async move {
+          let page = params.page_request();
+          let shape = params.shape().map_err(warp::reject::custom)?;
+          let res = handlers::query(
+            query,
+            store,
+            as_car,
+            shape,
+            if_none_match,
+            page,
+            max_range,
+          )
+          .await;
+          match res {
+            Ok(reply) => Ok(reply),
+            Err(err) => Err(warp::reject::custom(err)),
+          }
+        },
+      )
+  }
+
+  // checks the header for format accept
+  pub fn with_check_accept_car(
+  ) -> impl Filter<Extract = (bool,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("accept").map(|accept: Option<String>| {
+      accept
+        .map(|accept| {
+          accept.contains("application/octet-stream")
+            || accept.contains("application/vnd.ipld.car")
+        })
+        .unwrap_or(false)
+    })
+  }
+
+  fn with_if_none_match(
+  ) -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone
+  {
+    warp::header::optional::<String>("if-none-match")
+  }
+
+  fn with_store(
+    store: Arc<PortalStore>,
+  ) -> impl Filter<Extract = (Arc<PortalStore>,), Error = std::convert::Infallible>
+       + Clone {
+    warp::any().map(move || store.clone())
+  }
+}
+
+mod handlers {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::cache::{self, Freshness};
+  use futures::{Stream, StreamExt, TryStreamExt};
+  use twine_protocol::twine_lib::resolver::{AbsoluteRange, SingleQuery};
+
+  const STREAM_BATCH_SIZE: u64 = 100;
+  /// Seconds to wait for the next pulse
+  pub const DEFAULT_NEXT_TIMEOUT: u64 = 30;
+  pub const MAX_NEXT_TIMEOUT: u64 = 120;
+
+  #[derive(Debug)]
+  pub enum HttpError {
+    Resolution(ResolutionError),
+    Store(StoreError),
+    /// The path isn't a valid twine query
+    BadQuery(String),
+    BadRequest(String),
+    RangeTooLarge(String),
+    Unauthorized,
+    Forbidden,
+    TooManyRequests {
+      retry_after: u64,
+    },
+  }
+  impl From<ResolutionError> for HttpError {
+    fn from(e: ResolutionError) -> Self {
+      HttpError::Resolution(e)
+    }
+  }
+  impl From<StoreError> for HttpError {
+    fn from(e: StoreError) -> Self {
+      HttpError::Store(e)
+    }
+  }
+  impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+      match self {
+        HttpError::Resolution(e) => write!(f, "{}", e),
+        HttpError::Store(e) => write!(f, "{}", e),
+        HttpError::BadQuery(msg) => write!(f, "{}", msg),
+        HttpError::BadRequest(msg) => write!(f, "{}", msg),
+        HttpError::RangeTooLarge(msg) => write!(f, "{}", msg),
+        HttpError::Unauthorized => write!(f, "unauthorized"),
+        HttpError::Forbidden => write!(f, "forbidden"),
+        HttpError::TooManyRequests { retry_after } => {
+          write!(f, "too many requests, retry in {} seconds", retry_after)
+        }
+      }
+    }
+  }
+  impl std::error::Error for HttpError {}
+  impl warp::reject::Reject for HttpError {}
+
+  pub async fn query(
+    q: AnyQuery,
+    store: Arc<PortalStore>,
+    as_car: bool,
+    shape: shaping::Shape,
+    if_none_match: Option<String>,
+    page: pagination::PageRequest,
+    max_range: u64,
+  ) -> Result<impl warp::Reply, HttpError> {
+    log::debug!("Query: {:?}, shape: {:?}", q, shape);
+    let full = shape.with_strand();
+    let mut link = None;
+    let mut etag = None;
+    let freshness;
+    let result = match q {
+      AnyQuery::Strand(strand_cid) => {
+        let strand = store.resolve_strand(&strand_cid).await?;
+        let tag = cache::etag(&strand_cid, as_car, false);
+        if cache::matches_etag(if_none_match.as_deref(), &tag) {
+          return Ok(cache::not_modified(&tag, Freshness::Immutable));
+        }
+        etag = Some(tag);
+        freshness = Freshness::Immutable;
+        models::AnyResult::Strands {
+          items: vec![strand.unpack().clone().into()],
+        }
+      }
+      AnyQuery::One(query) => {
+        let twine = store.resolve(query).await?;
+        freshness = match query {
+          SingleQuery::Stitch(_) => Freshness::Immutable,
+          SingleQuery::Index(_, index) if index >= 0 => Freshness::Immutable,
+          _ => Freshness::MaxAge(cache::latest_max_age(&twine)),
+        };
+        let tag = shape.etag(&twine.cid(), as_car);
+        if cache::matches_etag(if_none_match.as_deref(), &tag) {
+          return Ok(cache::not_modified(&tag, freshness));
+        }
+        etag = Some(tag);
+        let strand = if full {
+          let strand = twine.strand().clone().into();
+          Some(strand)
+        } else {
+          None
+        };
+        models::AnyResult::Tixels {
+          items: vec![(*twine.unpack()).clone().into()],
+          strand,
+        }
+      }
+      AnyQuery::Many(range) => {
+        let latest = store.resolve_latest(range.strand_cid()).await?;
+        freshness = if cache::is_fixed_range(range, latest.index()) {
+          Freshness::Immutable
+        } else {
+          Freshness::MaxAge(cache::latest_max_age(&latest))
+        };
+        let page_range = match range.to_absolute(latest.index()) {
+          Some(range) => {
+            let page = pagination::paginate(range, page, max_range)
+              .map_err(HttpError::BadRequest)?;
+            if let Some(next) = page.next {
+              link = Some(format!(
+                "<./{}?limit={}&cursor={}{}>; rel=\"next\"",
+                range,
+                page.limit,
+                next,
+                shape.query_string()
+              ));
+            }
+            page.range
+          }
+          None => None,
+        };
+        if as_car {
+          // stream large ranges rather than holding them in memory
+          let strand = full.then(|| AnyTwine::from(latest.strand().clone()));
+          let twines = futures::stream::iter(page_range)
+            .flat_map(move |range| stream_range(store.clone(), range))
+            .chain(futures::stream::iter(strand));
+          let res = models::car_response(twines);
+          return Ok(cache::with_cache_headers(
+            with_link(res, link),
+            None,
+            freshness,
+          ));
+        }
+        let tixels: Vec<_> = match page_range {
+          Some(range) => {
+            store.resolve_range(range).await?.try_collect().await?
+          }
+          None => vec![],
+        };
+        let strand = if full {
+          Some(latest.strand().clone().into())
+        } else {
+          None
+        };
+        models::AnyResult::Tixels {
+          items: tixels.into_iter().map(|t| (*t).clone().into()).collect(),
+          strand,
+        }
+      }
+    };
+    // CAR files always hold whole blocks
+    let result = if as_car { result } else { shape.apply(result) };
+    let res = result.to_response(as_car).await;
+    Ok(cache::with_cache_headers(
+      with_link(res, link),
+      etag.as_deref(),
+      freshness,
+    ))
+  }
+
+  fn with_link(
+    mut res: warp::reply::Response,
+    link: Option<String>,
+  ) -> warp::reply::Response {
+    if let Some(link) = link {
+      res.headers_mut().insert(
+        warp::http::header::LINK,
+        warp::http::HeaderValue::from_str(&link).expect("valid header"),
+      );
+    }
+    res
+  }
+
+  /// Fetch a range from the store in batches so only one batch is held in
+  /// memory at a time. Errors end the stream early because the response
+  /// status has already been sent by the time they happen.
+  fn stream_range(
+    store: Arc<PortalStore>,
+    range: AbsoluteRange,
+  ) -> impl Stream<Item = AnyTwine> + Send + 'static {
+    futures::stream::iter(range.batches(STREAM_BATCH_SIZE))
+      .then(move |batch| {
+        let store = store.clone();
+        async move {
+          store
+            .resolve_range(batch)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await
+        }
+      })
+      .take_while(|res| {
+        if let Err(e) = res {
+          log::error!("Failed to stream range: {}", e);
+        }
+        futures::future::ready(res.is_ok())
+      })
+      .flat_map(|res| {
+        let twines = res.unwrap_or_default();
+        futures::stream::iter(
+          twines.into_iter().map(|t| AnyTwine::from((*t).clone())),
+        )
+      })
+  }
+
+  pub async fn strand_latest(
+    strand: Cid,
+    store: Arc<PortalStore>,
+    as_car: bool,
+    full: bool,
+    if_none_match: Option<String>,
+  ) -> Result<impl warp::Reply, HttpError> {
+    let twine = store.resolve_latest(strand).await?;
+    let freshness = Freshness::MaxAge(cache::latest_max_age(&twine));
+    let tag = cache::etag(&twine.cid(), as_car, full);
+    if cache::matches_etag(if_none_match.as_deref(), &tag) {
+      return Ok(cache::not_modified(&tag, freshness));
+    }
+    let strand = if full {
+      Some(twine.strand().clone().into())
+    } else {
+      None
+    };
+    let result = models::AnyResult::Tixels {
+      items: vec![(*twine.unpack()).clone().into()],
+      strand,
+    };
+    let res = result.to_response(as_car).await;
+    Ok(cache::with_cache_headers(res, Some(&tag), freshness))
+  }
+
+  /// Wait for the pulse after `after` (or after the current latest pulse)
+  /// to be published. Responds with 204 if it doesn't arrive in time.
+  pub async fn strand_next(
+    strand: Cid,
+    feed: Arc<subscriptions::PulseFeed>,
+    as_car: bool,
+    full: bool,
+    after: Option<u64>,
+    timeout: std::time::Duration,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let store = feed.store().clone();
+    let strand_twine = store.resolve_strand(&strand).await?.unpack();
+    let wanted = match after {
+      Some(after) => after.checked_add(1).ok_or_else(|| {
+        HttpError::BadRequest(format!("No pulse comes after {}", after))
+      })?,
+      None => match store.resolve_latest(strand).await {
+        Ok(latest) => latest.index() + 1,
+        Err(ResolutionError::NotFound) => 0,
+        Err(e) => return Err(e.into()),
+      },
+    };
+
+    // subscribe before checking the store so the pulse can't be missed
+    let mut receiver = feed.subscribe_from(strand, wanted);
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let mut timed_out = false;
+    let twine = loop {
+      match store.resolve_index(strand, wanted).await {
+        Ok(twine) => break Some(twine.unpack()),
+        Err(ResolutionError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+      }
+      if timed_out {
+        break None;
+      }
+      tokio::select! {
+        _ = &mut deadline => timed_out = true,
+        res = receiver.recv() => {
+          if let Err(tokio::sync::broadcast::error::RecvError::Closed) = res {
+            timed_out = true;
+          }
+        }
+      }
+    };
+
+    let twine = match twine {
+      Some(twine) => twine,
+      None => {
+        let mut res = warp::reply::Response::default();
+        *res.status_mut() = warp::http::StatusCode::NO_CONTENT;
+        res.headers_mut().insert(
+          warp::http::header::CACHE_CONTROL,
+          warp::http::HeaderValue::from_static("no-store"),
+        );
+        return Ok(res);
+      }
+    };
+
+    let tag = cache::etag(&twine.cid(), as_car, full);
+    let result = models::AnyResult::Tixels {
+      items: vec![(*twine).clone().into()],
+      strand: full.then(|| strand_twine.into()),
+    };
+    let res = result.to_response(as_car).await;
+    // the pulse at a given index never changes
+    let freshness = match after {
+      Some(_) => Freshness::Immutable,
+      None => Freshness::MaxAge(cache::latest_max_age(&twine)),
+    };
+    Ok(cache::with_cache_headers(res, Some(&tag), freshness))
+  }
+
+  pub async fn latest(
+    store: Arc<PortalStore>,
+    as_car: bool,
+    full: bool,
+  ) -> Result<impl warp::Reply, HttpError> {
+    let strands: Vec<_> = store.strands().await?.try_collect().await?;
+    let mut latest = Vec::with_capacity(strands.len());
+    for strand in strands {
+      match store.resolve_latest(strand.cid()).await {
+        Ok(twine) => latest.push(twine.unpack()),
+        // strands without any tixels yet
+        Err(ResolutionError::NotFound) => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    let max_age = latest
+      .iter()
+      .map(cache::latest_max_age)
+      .min()
+      .unwrap_or(cache::DEFAULT_MAX_AGE);
+    let strands = if full {
+      latest.iter().map(|t| t.strand().clone().into()).collect()
+    } else {
+      vec![]
+    };
+    let result = models::AnyResult::Latest {
+      items: latest.iter().map(|t| (**t).clone().into()).collect(),
+      strands,
+    };
+    let res = result.to_response(as_car).await;
+    Ok(cache::with_cache_headers(
+      res,
+      None,
+      Freshness::MaxAge(max_age),
+    ))
+  }
+
+  pub async fn list_strands(
+    store: Arc<PortalStore>,
+    as_car: bool,
+  ) -> Result<impl warp::Reply, HttpError> {
+    let strands: Vec<_> = store.strands().await?.try_collect().await?;
+    let max_age = cache::strands_max_age(strands.iter());
+    let result = models::AnyResult::Strands {
+      items: strands.into_iter().map(|s| s.clone().into()).collect(),
+    };
+    let res = result.to_response(as_car).await;
+    Ok(cache::with_cache_headers(
+      res,
+      None,
+      Freshness::MaxAge(max_age),
+    ))
+  }
+}
+
+mod models {
+  use super::*;
+  use futures::{Stream, StreamExt};
+  use serde::{Deserialize, Serialize};
+  use twine_protocol::twine_lib::{car::to_car_stream, twine::Tagged};
+  use warp::http::header::{HeaderValue, CONTENT_TYPE};
+  use warp::reply::Reply;
+
+  // The api can return a json object with an "items" array
+  // which possibly contains a "strand" object containing the owning strand
+  // If it's an error, it returns an object with an "error" key
+  #[derive(Debug, Serialize, Deserialize)]
+  #[serde(untagged)]
+  pub enum AnyResult {
+    Tixels {
+      #[serde(with = "crate::dag_json")]
+      items: Vec<Tagged<Tixel>>,
+      #[serde(with = "crate::dag_json")]
+      #[serde(skip_serializing_if = "Option::is_none")]
+      strand: Option<Tagged<Strand>>,
+    },
+    Latest {
+      #[serde(with = "crate::dag_json")]
+      items: Vec<Tagged<Tixel>>,
+      #[serde(with = "crate::dag_json")]
+      #[serde(skip_serializing_if = "Vec::is_empty")]
+      strands: Vec<Tagged<Strand>>,
+    },
+    Strands {
+      #[serde(with = "crate::dag_json")]
+      items: Vec<Tagged<Strand>>,
+    },
+    /// Only some fields of each tixel, see [crate::shaping]
+    Shaped {
+      items: Vec<serde_json::Map<String, serde_json::Value>>,
+    },
+    Error {
+      error: String,
+    },
+  }
+
+  impl AnyResult {
+    pub async fn to_response(self, as_car: bool) -> warp::reply::Response {
+      if as_car {
+        let items = match self {
+          AnyResult::Tixels { items, strand } => items
+            .into_iter()
+            .map(|t| AnyTwine::from(t.unpack()))
+            .chain(strand.into_iter().map(|s| AnyTwine::from(s.unpack())))
+            .collect::<Vec<_>>(),
+          AnyResult::Latest { items, strands } => items
+            .into_iter()
+            .map(|t| AnyTwine::from(t.unpack()))
+            .chain(strands.into_iter().map(|s| AnyTwine::from(s.unpack())))
+            .collect::<Vec<_>>(),
+          AnyResult::Strands { items } => items
+            .into_iter()
+            .map(|s| AnyTwine::from(s.unpack()))
+            .collect::<Vec<_>>(),
+          _ => return warp::reply::json(&self).into_response(),
+        };
+        car_response(futures::stream::iter(items))
+      } else {
+        warp::reply::json(&self).into_response()
+      }
+    }
+  }
+
+  /// Respond with a CAR file that is encoded as the twines are produced,
+  /// using chunked transfer encoding
+  pub fn car_response<S>(twines: S) -> warp::reply::Response
+  where
+    S: Stream<Item = AnyTwine> + Send + 'static,
+  {
+    let carstream = to_car_stream(twines, vec![Cid::default()])
+      .map(Ok::<_, std::convert::Infallible>);
+    let mut res =
+      warp::reply::Response::new(warp::hyper::Body::wrap_stream(carstream));
+    res.headers_mut().insert(
+      CONTENT_TYPE,
+      HeaderValue::from_static("application/octet-stream"),
+    );
+    res
+  }
+}
@@ -0,0 +1,231 @@
+//! Prometheus metrics at `GET /metrics`.
+//!
+//! Requests are counted and timed per route, along with response sizes,
+//! database query durations, cache hit rates and rate limiting. The age of
+//! the latest pulse of every strand is measured when the metrics are
+//! scraped. If any API key has the `admin` scope, scraping needs one.
+use crate::auth::{self, ApiKeys, Principal, Scope};
+use crate::handlers::HttpError;
+use crate::pulses;
+use crate::PortalStore;
+use biab_utils::{gauge_vec, histogram_vec, int_counter_vec};
+use chrono::Utc;
+use futures::TryStreamExt;
+use prometheus::{exponential_buckets, GaugeVec, HistogramVec, IntCounterVec};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use twine_protocol::prelude::*;
+use warp::http::{Method, StatusCode};
+use warp::hyper::body::HttpBody;
+use warp::Filter;
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "portal_requests_total",
+    "HTTP requests by route, method and status",
+    &["route", "method", "status"],
+  )
+});
+
+static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+  histogram_vec(
+    "portal_request_duration_seconds",
+    "Time to produce a response, by route",
+    &["route"],
+    None,
+  )
+});
+
+static RESPONSE_SIZE: LazyLock<HistogramVec> = LazyLock::new(|| {
+  histogram_vec(
+    "portal_response_size_bytes",
+    "Size of response bodies of known length, by route",
+    &["route"],
+    Some(exponential_buckets(256.0, 4.0, 8).expect("response size buckets")),
+  )
+});
+
+static DB_QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+  histogram_vec(
+    "portal_db_query_duration_seconds",
+    "Time taken by store operations, including retries",
+    &["operation", "result"],
+    Some(exponential_buckets(0.0005, 2.0, 14).expect("query duration buckets")),
+  )
+});
+
+static CACHE_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "portal_cache_requests_total",
+    "Cache lookups by cache and result (hit or miss)",
+    &["cache", "result"],
+  )
+});
+
+static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "portal_rate_limit_requests_total",
+    "Requests checked against rate limits, by result (allowed or limited)",
+    &["result"],
+  )
+});
+
+static LATEST_PULSE_AGE: LazyLock<GaugeVec> = LazyLock::new(|| {
+  gauge_vec(
+    "portal_latest_pulse_age_seconds",
+    "Seconds since the latest pulse of each strand",
+    &["strand"],
+  )
+});
+
+/// A bounded name for the route a path belongs to
+fn route(path: &str) -> &'static str {
+  let path = path.trim_start_matches('/');
+  // beacons served under a prefix share the labels of the default one
+  let path = match path.strip_prefix("beacons/") {
+    Some(rest) => rest.split_once('/').map_or("", |(_, rest)| rest),
+    None => path,
+  };
+  let mut segments = path.split('/');
+  match segments.next().unwrap_or("") {
+    "" => "root",
+    "latest" => "latest",
+    "strand" => match segments.nth(1) {
+      Some("latest") => "strand_latest",
+      Some("next") => "strand_next",
+      Some("info") => "strand_info",
+      _ => "query",
+    },
+    "subscribe" => "subscribe",
+    "proof" => "proof",
+    "randomness" => "randomness",
+    "verify" => "verify",
+    "block" => "block",
+    "beacon" => "nist",
+    "drand" => "drand",
+    "healthz" => "healthz",
+    "readyz" => "readyz",
+    "metrics" => "metrics",
+    "explorer" => "explorer",
+    "signing-key" => "signing_key",
+    _ => "query",
+  }
+}
+
+/// Record a finished request
+pub fn observe_request(
+  path: &str,
+  method: &Method,
+  started: Instant,
+  res: &warp::reply::Response,
+) {
+  let route = route(path);
+  let status = res.status();
+  REQUESTS
+    .with_label_values(&[route, method.as_str(), status.as_str()])
+    .inc();
+  REQUEST_DURATION
+    .with_label_values(&[route])
+    .observe(started.elapsed().as_secs_f64());
+  // streamed (eg: compressed) bodies have no known size
+  if let Some(size) = res.body().size_hint().exact() {
+    RESPONSE_SIZE
+      .with_label_values(&[route])
+      .observe(size as f64);
+  }
+  // conditional requests answered from the client's copy
+  if status == StatusCode::NOT_MODIFIED {
+    observe_cache("etag", true);
+  } else if res.headers().contains_key("etag") {
+    observe_cache("etag", false);
+  }
+}
+
+pub fn observe_cache(cache: &str, hit: bool) {
+  CACHE_REQUESTS
+    .with_label_values(&[cache, if hit { "hit" } else { "miss" }])
+    .inc();
+}
+
+pub fn observe_rate_limit(allowed: bool) {
+  RATE_LIMITED
+    .with_label_values(&[if allowed { "allowed" } else { "limited" }])
+    .inc();
+}
+
+/// Passed to the store with [biab_utils::RetryingStore::with_observer]
+pub fn observe_query(operation: &str, duration: Duration, ok: bool) {
+  DB_QUERY_DURATION
+    .with_label_values(&[operation, if ok { "ok" } else { "error" }])
+    .observe(duration.as_secs_f64());
+}
+
+async fn update_pulse_ages(store: &PortalStore) -> Result<(), ResolutionError> {
+  let strands: Vec<_> = store.strands().await?.try_collect().await?;
+  // forget strands that are gone
+  LATEST_PULSE_AGE.reset();
+  let now = Utc::now();
+  for strand in strands {
+    let latest = match store.resolve_latest(strand.cid()).await {
+      Ok(latest) => latest.unpack(),
+      Err(ResolutionError::NotFound) => continue,
+      Err(e) => return Err(e),
+    };
+    if let Ok(timestamp) = pulses::timestamp(&latest) {
+      let age = (now - timestamp).num_milliseconds() as f64 / 1000.0;
+      LATEST_PULSE_AGE
+        .with_label_values(&[strand.cid().to_string().as_str()])
+        .set(age);
+    }
+  }
+  Ok(())
+}
+
+async fn render(store: Arc<PortalStore>) -> warp::reply::Response {
+  if let Err(e) = update_pulse_ages(&store).await {
+    log::error!("Failed to measure latest pulse ages: {}", e);
+  }
+  biab_utils::metrics_response()
+}
+
+/// `/metrics`
+pub fn routes(
+  store: Arc<PortalStore>,
+  keys: Arc<ApiKeys>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let protected = keys.any_can(Scope::Admin);
+  let auth = auth::principal(keys)
+    .and_then(move |principal: Option<Arc<Principal>>| async move {
+      match principal {
+        _ if !protected => Ok(()),
+        Some(principal) if principal.can(Scope::Admin) => Ok(()),
+        Some(_) => Err(warp::reject::custom(HttpError::Forbidden)),
+        None => Err(warp::reject::custom(HttpError::Unauthorized)),
+      }
+    })
+    .untuple_one();
+
+  warp::get()
+    .and(warp::path!("metrics"))
+    .and(auth)
+    .and(warp::any().map(move || store.clone()))
+    .then(render)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_route() {
+    assert_eq!(route("/"), "root");
+    assert_eq!(route("/latest"), "latest");
+    assert_eq!(route("/strand/bafy/latest"), "strand_latest");
+    assert_eq!(route("/strand/bafy/next"), "strand_next");
+    assert_eq!(route("/beacon/2.0/pulse/last"), "nist");
+    assert_eq!(route("/randomness/bafy/3"), "randomness");
+    assert_eq!(route("/bafy:1:3"), "query");
+    assert_eq!(route("/beacons/test/"), "root");
+    assert_eq!(route("/beacons/test/strand/bafy/info"), "strand_info");
+  }
+}
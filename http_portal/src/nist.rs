@@ -0,0 +1,454 @@
+//! A NIST randomness beacon 2.0 compatible view of one strand.
+//!
+//! Pulses are served in the NIST pulse JSON format so existing NIST beacon
+//! clients can consume the strand configured in `NIST_COMPAT_STRAND`.
+//! Timestamps in paths are unix epoch milliseconds, like the NIST API.
+use crate::cache::{self, Freshness};
+use crate::handlers::HttpError;
+use crate::pulses::{self, ExternalSource, TimeSearch};
+use crate::PortalStore;
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+use std::sync::Arc;
+use twine_protocol::prelude::*;
+use warp::Filter;
+
+const CHAIN_INDEX: u64 = 1;
+/// Status code bit for the first pulse of a chain
+const STATUS_CHAIN_START: u32 = 1;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct External {
+  source_id: String,
+  status_code: u32,
+  value: String,
+}
+
+impl External {
+  /// The first value recorded from another beacon, its source identified by
+  /// the SHA-512 of its uri, or all zeros without one
+  fn from_sources(sources: &[ExternalSource]) -> Self {
+    match sources.first() {
+      Some(source) => Self {
+        source_id: hex::encode_upper(Sha512::digest(source.uri.as_bytes())),
+        status_code: 0,
+        value: hex::encode_upper(&source.value),
+      },
+      None => Self {
+        source_id: "0".repeat(128),
+        status_code: 0,
+        value: "0".repeat(128),
+      },
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListValue {
+  uri: String,
+  #[serde(rename = "type")]
+  kind: &'static str,
+  value: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Pulse {
+  uri: String,
+  version: &'static str,
+  cipher_suite: u32,
+  period: i64,
+  certificate_id: String,
+  chain_index: u64,
+  pulse_index: u64,
+  time_stamp: String,
+  local_random_value: String,
+  external: External,
+  list_values: Vec<ListValue>,
+  precommitment_value: String,
+  status_code: u32,
+  signature_value: String,
+  output_value: String,
+}
+
+#[derive(Debug, Serialize)]
+struct PulseResponse {
+  pulse: Pulse,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SkipListResponse {
+  skip_list: Vec<Pulse>,
+}
+
+fn pulse_uri(index: u64) -> String {
+  format!("/beacon/2.0/chain/{}/pulse/{}", CHAIN_INDEX, index)
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+  time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn from_millis(millis: i64) -> Result<DateTime<Utc>, HttpError> {
+  Utc
+    .timestamp_millis_opt(millis)
+    .single()
+    .ok_or_else(|| HttpError::BadRequest(format!("Invalid time {}", millis)))
+}
+
+/// Output values are 512 bits. The first pulse has none, so it gets zeros.
+async fn output_hex(
+  store: &PortalStore,
+  twine: &Twine,
+) -> Result<String, HttpError> {
+  if twine.index() == 0 {
+    return Ok("0".repeat(128));
+  }
+  Ok(hex::encode_upper(pulses::output_value(store, twine).await?))
+}
+
+/// The pulses a NIST pulse links to: the previous pulse and the first
+/// pulses of the current hour, day, month and year
+async fn list_values(
+  store: &PortalStore,
+  twine: &Twine,
+  time: DateTime<Utc>,
+) -> Result<Vec<(&'static str, Twine)>, HttpError> {
+  let hour = time
+    .with_nanosecond(0)
+    .and_then(|t| t.with_second(0))
+    .and_then(|t| t.with_minute(0))
+    .expect("valid hour");
+  let day = hour.with_hour(0).expect("valid day");
+  let month = day.with_day(1).expect("valid month");
+  let year = month.with_month(1).expect("valid year");
+
+  let strand = twine.strand_cid();
+  let mut values = Vec::with_capacity(5);
+  if twine.index() > 0 {
+    let prev = store.resolve_index(strand, twine.index() - 1).await?;
+    values.push(("previous", prev.unpack()));
+  }
+  let anchors = [
+    ("hour", hour),
+    ("day", day),
+    ("month", month),
+    ("year", year),
+  ];
+  for (kind, start) in anchors {
+    let first =
+      pulses::find_by_time(store, strand, start, TimeSearch::AtOrAfter).await?;
+    values.push((kind, first));
+  }
+  Ok(values)
+}
+
+async fn to_pulse(
+  store: &PortalStore,
+  twine: &Twine,
+) -> Result<Pulse, HttpError> {
+  let payload = pulses::payload(twine)?;
+  let time = payload.timestamp();
+  let period = pulses::period(twine.strand())
+    .map(|p| p.num_milliseconds())
+    .unwrap_or_default();
+
+  let mut list = Vec::new();
+  for (kind, linked) in list_values(store, twine, time).await? {
+    list.push(ListValue {
+      uri: pulse_uri(linked.index()),
+      kind,
+      value: output_hex(store, &linked).await?,
+    });
+  }
+
+  Ok(Pulse {
+    uri: pulse_uri(twine.index()),
+    version: "Version 2.0",
+    cipher_suite: 0,
+    period,
+    certificate_id: hex::encode_upper(twine.strand_cid().hash().digest()),
+    chain_index: CHAIN_INDEX,
+    pulse_index: twine.index(),
+    time_stamp: format_time(time),
+    local_random_value: hex::encode_upper(payload.salt()),
+    external: External::from_sources(&pulses::external_sources(twine)),
+    list_values: list,
+    precommitment_value: hex::encode_upper(payload.pre().digest()),
+    status_code: if twine.index() == 0 {
+      STATUS_CHAIN_START
+    } else {
+      0
+    },
+    signature_value: pulses::signature(twine)
+      .map(hex::encode_upper)
+      .unwrap_or_default(),
+    output_value: output_hex(store, twine).await?,
+  })
+}
+
+async fn pulse_response(
+  store: &PortalStore,
+  twine: Twine,
+  freshness: Freshness,
+) -> Result<warp::reply::Response, HttpError> {
+  let res = PulseResponse {
+    pulse: to_pulse(store, &twine).await?,
+  };
+  Ok(cache::with_cache_headers(
+    warp::reply::Reply::into_response(warp::reply::json(&res)),
+    None,
+    freshness,
+  ))
+}
+
+/// Walk back from `to` to `from` along the list values, taking the
+/// longest jump that doesn't pass `from` each time
+async fn skip_list(
+  store: &PortalStore,
+  from: Twine,
+  to: Twine,
+) -> Result<Vec<Pulse>, HttpError> {
+  if from.index() > to.index() {
+    return Err(HttpError::BadRequest(
+      "The start of a skip list must come before its end".to_string(),
+    ));
+  }
+  let mut path = vec![to];
+  loop {
+    let current = path.last().expect("non empty path").clone();
+    if current.index() == from.index() {
+      break;
+    }
+    let time = pulses::timestamp(&current)?;
+    let next = list_values(store, &current, time)
+      .await?
+      .into_iter()
+      .map(|(_, twine)| twine)
+      .filter(|twine| {
+        twine.index() >= from.index() && twine.index() < current.index()
+      })
+      .min_by_key(|twine| twine.index());
+    match next {
+      Some(next) => path.push(next),
+      // no link lands in the range, so step back one pulse at a time
+      None => {
+        let prev = store
+          .resolve_index(current.strand_cid(), current.index() - 1)
+          .await?;
+        path.push(prev.unpack());
+      }
+    }
+  }
+
+  let mut pulses = Vec::with_capacity(path.len());
+  for twine in path.iter().rev() {
+    pulses.push(to_pulse(store, twine).await?);
+  }
+  Ok(pulses)
+}
+
+mod handlers {
+  use super::*;
+
+  pub async fn last(
+    strand: Cid,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let twine = store.resolve_latest(strand).await?.unpack();
+    let freshness = Freshness::MaxAge(cache::latest_max_age(&twine));
+    pulse_response(&store, twine, freshness).await
+  }
+
+  pub async fn by_index(
+    strand: Cid,
+    index: u64,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let twine = store.resolve_index(strand, index).await?.unpack();
+    pulse_response(&store, twine, Freshness::Immutable).await
+  }
+
+  pub async fn by_time(
+    strand: Cid,
+    millis: i64,
+    search: TimeSearch,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let time = from_millis(millis)?;
+    let twine = pulses::find_by_time(&store, strand, time, search).await?;
+    // the answer is settled once a later pulse is out, until then the
+    // next pulse could still be the one searched for
+    let freshness = match store.resolve_index(strand, twine.index() + 1).await {
+      Ok(_) => Freshness::Immutable,
+      Err(ResolutionError::NotFound) => {
+        Freshness::MaxAge(cache::latest_max_age(&twine))
+      }
+      Err(e) => return Err(e.into()),
+    };
+    pulse_response(&store, twine, freshness).await
+  }
+
+  pub async fn skip_list_by_time(
+    strand: Cid,
+    from: i64,
+    to: i64,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let from = pulses::find_by_time(
+      &store,
+      strand,
+      from_millis(from)?,
+      TimeSearch::AtOrAfter,
+    )
+    .await?;
+    let to = pulses::find_by_time(
+      &store,
+      strand,
+      from_millis(to)?,
+      TimeSearch::AtOrBefore,
+    )
+    .await?;
+    let res = SkipListResponse {
+      skip_list: skip_list(&store, from, to).await?,
+    };
+    Ok(warp::reply::Reply::into_response(warp::reply::json(&res)))
+  }
+
+  pub async fn skip_list_by_index(
+    strand: Cid,
+    from: u64,
+    to: u64,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let from = store.resolve_index(strand, from).await?.unpack();
+    let to = store.resolve_index(strand, to).await?.unpack();
+    let res = SkipListResponse {
+      skip_list: skip_list(&store, from, to).await?,
+    };
+    let res = warp::reply::Reply::into_response(warp::reply::json(&res));
+    Ok(cache::with_cache_headers(res, None, Freshness::Immutable))
+  }
+}
+
+fn reject(err: HttpError) -> warp::Rejection {
+  warp::reject::custom(err)
+}
+
+/// The NIST compatible routes, under `/beacon/2.0`. Everything is rejected
+/// as not found if no strand is configured.
+pub fn routes(
+  strand: Option<Cid>,
+  store: Arc<PortalStore>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let strand = warp::any().and_then(move || async move {
+    strand.ok_or_else(warp::reject::not_found)
+  });
+  let store = warp::any().map(move || store.clone());
+  let base = warp::path!("beacon" / "2.0" / ..).and(strand);
+
+  let last = base
+    .clone()
+    .and(
+      warp::path!("pulse" / "last")
+        .or(warp::path!("chain" / "last" / "pulse" / "last"))
+        .unify(),
+    )
+    .and(store.clone())
+    .and_then(|strand, store| async move {
+      handlers::last(strand, store).await.map_err(reject)
+    });
+
+  let by_index = base
+    .clone()
+    .and(warp::path!("chain" / u64 / "pulse" / u64))
+    .and(store.clone())
+    .and_then(|strand, chain: u64, index: u64, store| async move {
+      if chain != CHAIN_INDEX {
+        return Err(warp::reject::not_found());
+      }
+      handlers::by_index(strand, index, store)
+        .await
+        .map_err(reject)
+    });
+
+  let time = |search: TimeSearch| {
+    move |strand: Cid, millis: i64, store: Arc<PortalStore>| async move {
+      handlers::by_time(strand, millis, search, store)
+        .await
+        .map_err(reject)
+    }
+  };
+  let by_time = base
+    .clone()
+    .and(warp::path!("pulse" / "time" / i64))
+    .and(store.clone())
+    .and_then(time(TimeSearch::AtOrBefore));
+  let previous = base
+    .clone()
+    .and(warp::path!("pulse" / "time" / "previous" / i64))
+    .and(store.clone())
+    .and_then(time(TimeSearch::Before));
+  let next = base
+    .clone()
+    .and(warp::path!("pulse" / "time" / "next" / i64))
+    .and(store.clone())
+    .and_then(time(TimeSearch::After));
+
+  let skip_time = base
+    .clone()
+    .and(warp::path!("skiplist" / "time" / i64 / i64))
+    .and(store.clone())
+    .and_then(|strand, from, to, store| async move {
+      handlers::skip_list_by_time(strand, from, to, store)
+        .await
+        .map_err(reject)
+    });
+  let skip_index = base
+    .and(warp::path!("chain" / u64 / "skiplist" / u64 / u64))
+    .and(store)
+    .and_then(|strand, chain: u64, from, to, store| async move {
+      if chain != CHAIN_INDEX {
+        return Err(warp::reject::not_found());
+      }
+      handlers::skip_list_by_index(strand, from, to, store)
+        .await
+        .map_err(reject)
+    });
+
+  warp::get().and(
+    last
+      .or(by_index)
+      .or(by_time)
+      .or(previous)
+      .or(next)
+      .or(skip_time)
+      .or(skip_index),
+  )
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_external() {
+    let external = External::from_sources(&[]);
+    assert_eq!(external.source_id, "0".repeat(128));
+    assert_eq!(external.value, "0".repeat(128));
+
+    let source = ExternalSource {
+      source: "drand".to_string(),
+      uri: "https://api.drand.sh/public/latest".to_string(),
+      round: 1,
+      value: vec![0xab; 32].into(),
+    };
+    let external = External::from_sources(&[source]);
+    assert_eq!(external.source_id.len(), 128);
+    assert_eq!(external.value, "AB".repeat(32));
+  }
+}
@@ -0,0 +1,259 @@
+//! Keep hot twine objects in memory.
+//!
+//! Strands and tixels never change once saved, so the portal keeps the most
+//! recently used ones (by CID, and tixels also by strand and index) in front
+//! of the database. Up to `OBJECT_CACHE_SIZE` (default 10000) objects are
+//! kept per kind; 0 turns the cache off. Latest tixel lookups always go to
+//! the database, but their result is cached for later lookups by CID or
+//! index.
+use crate::metrics;
+use anyhow::Result;
+use async_trait::async_trait;
+use biab_utils::config_or;
+use futures::Stream;
+use lru::LruCache;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use twine_protocol::twine_lib::{
+  as_cid::AsCid,
+  errors::{ResolutionError, StoreError},
+  resolver::{
+    unchecked_base::{BaseResolver, TwineStream},
+    AbsoluteRange, MaybeSend, Resolver,
+  },
+  store::Store,
+  twine::{AnyTwine, Strand, Tixel},
+  Cid,
+};
+
+struct Lru<K: Hash + Eq, V>(Mutex<LruCache<K, V>>);
+
+impl<K: Hash + Eq, V: Clone> Lru<K, V> {
+  fn new(capacity: NonZeroUsize) -> Self {
+    Self(Mutex::new(LruCache::new(capacity)))
+  }
+
+  fn get(&self, key: &K) -> Option<V> {
+    let value = self.0.lock().expect("cache lock").get(key).cloned();
+    metrics::observe_cache("objects", value.is_some());
+    value
+  }
+
+  fn put(&self, key: K, value: V) {
+    self.0.lock().expect("cache lock").put(key, value);
+  }
+
+  fn clear(&self) {
+    self.0.lock().expect("cache lock").clear();
+  }
+}
+
+struct Caches {
+  strands: Lru<Cid, Strand>,
+  tixels: Lru<Cid, Tixel>,
+  by_index: Lru<(Cid, u64), Tixel>,
+}
+
+/// The configured `OBJECT_CACHE_SIZE`
+pub fn capacity_from_env() -> Result<usize> {
+  config_or("OBJECT_CACHE_SIZE", 10000)
+}
+
+/// Wraps a store and answers repeated lookups from memory
+pub struct CachingStore<R> {
+  inner: R,
+  caches: Option<Caches>,
+}
+
+impl<R> CachingStore<R> {
+  pub fn new(inner: R, capacity: usize) -> Self {
+    let caches = NonZeroUsize::new(capacity).map(|capacity| Caches {
+      strands: Lru::new(capacity),
+      tixels: Lru::new(capacity),
+      by_index: Lru::new(capacity),
+    });
+    Self { inner, caches }
+  }
+
+  pub fn from_env(inner: R) -> Result<Self> {
+    Ok(Self::new(inner, capacity_from_env()?))
+  }
+
+  pub fn inner(&self) -> &R {
+    &self.inner
+  }
+
+  fn remember(&self, tixel: &Tixel) {
+    if let Some(caches) = &self.caches {
+      caches.tixels.put(tixel.cid(), tixel.clone());
+      caches
+        .by_index
+        .put((tixel.strand_cid(), tixel.index()), tixel.clone());
+    }
+  }
+}
+
+#[async_trait]
+impl<R: BaseResolver> BaseResolver for CachingStore<R> {
+  async fn has_index(
+    &self,
+    strand: &Cid,
+    index: u64,
+  ) -> Result<bool, ResolutionError> {
+    if let Some(caches) = &self.caches {
+      if caches.by_index.get(&(*strand, index)).is_some() {
+        return Ok(true);
+      }
+    }
+    self.inner.has_index(strand, index).await
+  }
+
+  async fn has_twine(
+    &self,
+    strand: &Cid,
+    cid: &Cid,
+  ) -> Result<bool, ResolutionError> {
+    if let Some(caches) = &self.caches {
+      if caches
+        .tixels
+        .get(cid)
+        .is_some_and(|tixel| tixel.strand_cid() == *strand)
+      {
+        return Ok(true);
+      }
+    }
+    self.inner.has_twine(strand, cid).await
+  }
+
+  async fn has_strand(&self, cid: &Cid) -> Result<bool, ResolutionError> {
+    if let Some(caches) = &self.caches {
+      if caches.strands.get(cid).is_some() {
+        return Ok(true);
+      }
+    }
+    self.inner.has_strand(cid).await
+  }
+
+  async fn fetch_latest(&self, strand: &Cid) -> Result<Tixel, ResolutionError> {
+    // the latest tixel changes, so it's never answered from the cache
+    let tixel = self.inner.fetch_latest(strand).await?;
+    self.remember(&tixel);
+    Ok(tixel)
+  }
+
+  async fn fetch_index(
+    &self,
+    strand: &Cid,
+    index: u64,
+  ) -> Result<Tixel, ResolutionError> {
+    if let Some(tixel) = self
+      .caches
+      .as_ref()
+      .and_then(|caches| caches.by_index.get(&(*strand, index)))
+    {
+      return Ok(tixel);
+    }
+    let tixel = self.inner.fetch_index(strand, index).await?;
+    self.remember(&tixel);
+    Ok(tixel)
+  }
+
+  async fn fetch_tixel(
+    &self,
+    strand: &Cid,
+    tixel: &Cid,
+  ) -> Result<Tixel, ResolutionError> {
+    if let Some(tixel) = self
+      .caches
+      .as_ref()
+      .and_then(|caches| caches.tixels.get(tixel))
+      .filter(|tixel| tixel.strand_cid() == *strand)
+    {
+      return Ok(tixel);
+    }
+    let tixel = self.inner.fetch_tixel(strand, tixel).await?;
+    self.remember(&tixel);
+    Ok(tixel)
+  }
+
+  async fn fetch_strand(
+    &self,
+    strand: &Cid,
+  ) -> Result<Strand, ResolutionError> {
+    if let Some(strand) = self
+      .caches
+      .as_ref()
+      .and_then(|caches| caches.strands.get(strand))
+    {
+      return Ok(strand);
+    }
+    let strand = self.inner.fetch_strand(strand).await?;
+    if let Some(caches) = &self.caches {
+      caches.strands.put(strand.cid(), strand.clone());
+    }
+    Ok(strand)
+  }
+
+  async fn range_stream<'a>(
+    &'a self,
+    range: AbsoluteRange,
+  ) -> Result<TwineStream<'a, Tixel>, ResolutionError> {
+    // ranges are mostly read once, so they'd only push out hot objects
+    self.inner.range_stream(range).await
+  }
+
+  async fn fetch_strands<'a>(
+    &'a self,
+  ) -> Result<TwineStream<'a, Strand>, ResolutionError> {
+    self.inner.fetch_strands().await
+  }
+}
+
+impl<R: BaseResolver> Resolver for CachingStore<R> {}
+
+#[async_trait]
+impl<R: Store> Store for CachingStore<R> {
+  async fn save<T: Into<AnyTwine> + MaybeSend>(
+    &self,
+    twine: T,
+  ) -> Result<(), StoreError> {
+    self.inner.save(twine).await
+  }
+
+  async fn save_many<
+    I: Into<AnyTwine> + MaybeSend,
+    S: Iterator<Item = I> + MaybeSend,
+    T: IntoIterator<Item = I, IntoIter = S> + MaybeSend,
+  >(
+    &self,
+    twines: T,
+  ) -> Result<(), StoreError> {
+    self.inner.save_many(twines).await
+  }
+
+  async fn save_stream<
+    I: Into<AnyTwine> + MaybeSend,
+    T: Stream<Item = I> + MaybeSend + Unpin,
+  >(
+    &self,
+    twines: T,
+  ) -> Result<(), StoreError> {
+    self.inner.save_stream(twines).await
+  }
+
+  async fn delete<C: AsCid + MaybeSend>(
+    &self,
+    cid: C,
+  ) -> Result<(), StoreError> {
+    let cid = *cid.as_cid();
+    let res = self.inner.delete(cid).await;
+    // deleting a strand takes its tixels with it, so start over
+    if let Some(caches) = &self.caches {
+      caches.strands.clear();
+      caches.tixels.clear();
+      caches.by_index.clear();
+    }
+    res
+  }
+}
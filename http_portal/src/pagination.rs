@@ -0,0 +1,123 @@
+//! Splitting range queries into pages of bounded size
+use twine_protocol::twine_lib::resolver::AbsoluteRange;
+
+/// The requested page of a range query
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PageRequest {
+  /// Number of tixels per page
+  pub limit: Option<u64>,
+  /// Zero-based page number
+  pub page: Option<u64>,
+  /// Index to continue from, as given by a previous response
+  pub cursor: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Page {
+  /// The part of the range on this page (None if past the end)
+  pub range: Option<AbsoluteRange>,
+  pub limit: u64,
+  /// Cursor for the following page, if there is one
+  pub next: Option<u64>,
+}
+
+/// Select the requested page of a range, never returning more than `max`
+/// tixels
+pub fn paginate(
+  range: AbsoluteRange,
+  req: PageRequest,
+  max: u64,
+) -> Result<Page, String> {
+  let max = max.max(1);
+  let limit = req.limit.unwrap_or(max).clamp(1, max);
+  let increasing = range.is_increasing();
+
+  let start = match req.cursor {
+    Some(cursor) => {
+      if cursor < range.lower() || cursor > range.upper() {
+        return Err(format!(
+          "cursor {} is outside of the range {}",
+          cursor, range
+        ));
+      }
+      cursor
+    }
+    None => {
+      let offset = req.page.unwrap_or(0).saturating_mul(limit);
+      if offset >= range.len() {
+        return Ok(Page {
+          range: None,
+          limit,
+          next: None,
+        });
+      }
+      if increasing {
+        range.start + offset
+      } else {
+        range.start - offset
+      }
+    }
+  };
+
+  let end = if increasing {
+    start.saturating_add(limit - 1).min(range.end)
+  } else {
+    start.saturating_sub(limit - 1).max(range.end)
+  };
+  let next = match (end == range.end, increasing) {
+    (true, _) => None,
+    (false, true) => Some(end + 1),
+    (false, false) => Some(end - 1),
+  };
+
+  Ok(Page {
+    range: Some(AbsoluteRange::new(range.strand, start, end)),
+    limit,
+    next,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use twine_protocol::twine_lib::Cid;
+
+  #[test]
+  fn test_paginate() {
+    let cid = Cid::default();
+    let range = AbsoluteRange::new(cid, 0, 9);
+
+    let page = paginate(range, PageRequest::default(), 4).unwrap();
+    assert_eq!(page.range, Some(AbsoluteRange::new(cid, 0, 3)));
+    assert_eq!(page.next, Some(4));
+
+    let req = PageRequest {
+      cursor: Some(8),
+      ..Default::default()
+    };
+    let page = paginate(range, req, 4).unwrap();
+    assert_eq!(page.range, Some(AbsoluteRange::new(cid, 8, 9)));
+    assert_eq!(page.next, None);
+
+    let req = PageRequest {
+      limit: Some(3),
+      page: Some(1),
+      ..Default::default()
+    };
+    let page = paginate(AbsoluteRange::new(cid, 9, 0), req, 4).unwrap();
+    assert_eq!(page.range, Some(AbsoluteRange::new(cid, 6, 4)));
+    assert_eq!(page.next, Some(3));
+
+    let req = PageRequest {
+      page: Some(5),
+      ..Default::default()
+    };
+    assert_eq!(paginate(range, req, 4).unwrap().range, None);
+
+    let req = PageRequest {
+      cursor: Some(10),
+      ..Default::default()
+    };
+    assert!(paginate(range, req, 4).is_err());
+  }
+}
@@ -0,0 +1,182 @@
+//! Errors as RFC 7807 `application/problem+json` responses.
+//!
+//! Every error response carries a machine readable `code` next to the
+//! standard `title`, `status` and `detail` members, eg:
+//!
+//! ```json
+//! {
+//!   "type": "about:blank",
+//!   "title": "Not Found",
+//!   "status": 404,
+//!   "code": "not_found",
+//!   "detail": "Twine not found"
+//! }
+//! ```
+use crate::handlers::HttpError;
+use serde::Serialize;
+use twine_protocol::twine_lib::errors::{ResolutionError, StoreError};
+use warp::http::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use warp::http::StatusCode;
+use warp::reject::Rejection;
+
+const CONTENT_TYPE_PROBLEM: &str = "application/problem+json";
+
+#[derive(Debug, Serialize)]
+struct Problem<'a> {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  title: &'a str,
+  status: u16,
+  code: &'static str,
+  detail: String,
+}
+
+fn resolution_problem(e: &ResolutionError) -> (StatusCode, &'static str) {
+  match e {
+    ResolutionError::NotFound => (StatusCode::NOT_FOUND, "not_found"),
+    // the database or a fallback store couldn't be reached
+    ResolutionError::Fetch(_) => {
+      (StatusCode::SERVICE_UNAVAILABLE, "store_unavailable")
+    }
+    // the parts of the query don't belong together (eg: a tixel of another
+    // strand)
+    ResolutionError::Invalid(_) | ResolutionError::QueryMismatch(_) => {
+      (StatusCode::BAD_REQUEST, "bad_query")
+    }
+    ResolutionError::BadData(_) => {
+      (StatusCode::INTERNAL_SERVER_ERROR, "bad_stored_data")
+    }
+  }
+}
+
+impl HttpError {
+  /// The response status and error code
+  pub fn problem(&self) -> (StatusCode, &'static str) {
+    match self {
+      HttpError::Resolution(e) => resolution_problem(e),
+      HttpError::Store(StoreError::Invalid(_)) => {
+        (StatusCode::BAD_REQUEST, "invalid_twine")
+      }
+      HttpError::Store(StoreError::Fetching(e)) => resolution_problem(e),
+      HttpError::Store(StoreError::Saving(_)) => {
+        (StatusCode::SERVICE_UNAVAILABLE, "store_unavailable")
+      }
+      HttpError::BadQuery(_) => (StatusCode::BAD_REQUEST, "bad_query"),
+      HttpError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+      HttpError::RangeTooLarge(_) => {
+        (StatusCode::BAD_REQUEST, "range_too_large")
+      }
+      HttpError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+      HttpError::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
+      HttpError::TooManyRequests { .. } => {
+        (StatusCode::TOO_MANY_REQUESTS, "too_many_requests")
+      }
+    }
+  }
+}
+
+/// A problem+json response
+pub fn response(
+  status: StatusCode,
+  code: &'static str,
+  detail: String,
+) -> warp::reply::Response {
+  let problem = Problem {
+    kind: "about:blank",
+    title: status.canonical_reason().unwrap_or("Error"),
+    status: status.as_u16(),
+    code,
+    detail,
+  };
+  let mut res = warp::reply::Reply::into_response(warp::reply::with_status(
+    warp::reply::json(&problem),
+    status,
+  ));
+  res
+    .headers_mut()
+    .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_PROBLEM));
+  res
+}
+
+/// Turn any rejection into a problem+json response
+pub async fn recover(
+  err: Rejection,
+) -> Result<warp::reply::Response, std::convert::Infallible> {
+  if let Some(e) = err.find::<HttpError>() {
+    let (status, code) = e.problem();
+    if status.is_server_error() {
+      log::error!("{}", e);
+    }
+    let mut res = response(status, code, e.to_string());
+    if let HttpError::TooManyRequests { retry_after } = e {
+      res.headers_mut().insert(RETRY_AFTER, (*retry_after).into());
+    }
+    return Ok(res);
+  }
+
+  let (status, code, detail) = if err.is_not_found() {
+    (
+      StatusCode::NOT_FOUND,
+      "not_found",
+      "No such route".to_string(),
+    )
+  } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+    (StatusCode::BAD_REQUEST, "bad_query", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+    (StatusCode::BAD_REQUEST, "bad_request", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+    (StatusCode::BAD_REQUEST, "bad_request", e.to_string())
+  } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+    (
+      StatusCode::METHOD_NOT_ALLOWED,
+      "method_not_allowed",
+      e.to_string(),
+    )
+  } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+    (
+      StatusCode::PAYLOAD_TOO_LARGE,
+      "payload_too_large",
+      e.to_string(),
+    )
+  } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+    (
+      StatusCode::UNSUPPORTED_MEDIA_TYPE,
+      "unsupported_media_type",
+      e.to_string(),
+    )
+  } else {
+    log::error!("Unhandled rejection: {:?}", err);
+    (
+      StatusCode::INTERNAL_SERVER_ERROR,
+      "internal",
+      "Internal server error".to_string(),
+    )
+  };
+  Ok(response(status, code, detail))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use twine_protocol::prelude::*;
+  use twine_protocol::twine_lib::resolver::SingleQuery;
+
+  #[test]
+  fn test_problem() {
+    let not_found = HttpError::Resolution(ResolutionError::NotFound);
+    assert_eq!(not_found.problem(), (StatusCode::NOT_FOUND, "not_found"));
+    let unavailable = HttpError::Store(StoreError::Fetching(
+      ResolutionError::Fetch("connection reset".to_string()),
+    ));
+    assert_eq!(
+      unavailable.problem(),
+      (StatusCode::SERVICE_UNAVAILABLE, "store_unavailable")
+    );
+    let bad_query = HttpError::BadQuery("nope".to_string());
+    assert_eq!(bad_query.problem(), (StatusCode::BAD_REQUEST, "bad_query"));
+    let mismatch = HttpError::Resolution(ResolutionError::QueryMismatch(
+      SingleQuery::Index(Cid::default(), 1),
+    ));
+    assert_eq!(mismatch.problem(), (StatusCode::BAD_REQUEST, "bad_query"));
+  }
+}
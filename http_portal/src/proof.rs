@@ -0,0 +1,140 @@
+//! Continuity proofs between two pulses.
+//!
+//! `GET /proof?from=<cid>&to=<cid>` returns the pulses along the skip list
+//! path from `to` back to `from`, together with their strand. Each pulse in
+//! the proof links to the one before it, so checking the signatures and
+//! links of this short list shows that both pulses belong to the same strand
+//! in order, without downloading the range between them.
+use crate::cache::{self, Freshness};
+use crate::handlers::HttpError;
+use crate::models::AnyResult;
+use crate::PortalStore;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::sync::Arc;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::skiplist::SkipList;
+use warp::Filter;
+
+#[derive(Debug, Deserialize)]
+struct ProofParams {
+  from: String,
+  to: String,
+  /// Saves looking through every strand for the pulses
+  strand: Option<String>,
+}
+
+fn parse_cid(name: &str, cid: &str) -> Result<Cid, HttpError> {
+  Cid::try_from(cid)
+    .map_err(|e| HttpError::BadRequest(format!("Invalid {} cid: {}", name, e)))
+}
+
+/// Look a pulse up by its cid alone
+pub async fn find_pulse(
+  store: &PortalStore,
+  cid: Cid,
+  strand: Option<Cid>,
+) -> Result<Twine, HttpError> {
+  let strands: Vec<Cid> = match strand {
+    Some(strand) => vec![strand],
+    None => {
+      store
+        .strands()
+        .await?
+        .filter_map(|s| async move { s.ok().map(|s| s.cid()) })
+        .collect()
+        .await
+    }
+  };
+  for strand in strands {
+    match store.resolve_stitch(strand, cid).await {
+      Ok(twine) => return Ok(twine.unpack()),
+      Err(ResolutionError::NotFound | ResolutionError::QueryMismatch(_)) => {}
+      Err(e) => return Err(e.into()),
+    }
+  }
+  Err(HttpError::Resolution(ResolutionError::NotFound))
+}
+
+/// The pulses from `to` back to `from`, each linking to the next
+async fn skip_path(
+  store: &PortalStore,
+  from: Twine,
+  to: Twine,
+) -> Result<Vec<Twine>, HttpError> {
+  if from.strand_cid() != to.strand_cid() {
+    return Err(HttpError::BadRequest(
+      "The pulses are on different strands".to_string(),
+    ));
+  }
+  if from.index() > to.index() {
+    return Err(HttpError::BadRequest(
+      "The from pulse must come before the to pulse".to_string(),
+    ));
+  }
+  let strand = to.strand_cid();
+  let to_index = to.index();
+  let mut path = vec![to];
+  for index in SkipList::new(from.radix(), to_index, from.index(), false) {
+    path.push(store.resolve_index(strand, index).await?.unpack());
+  }
+  if from.index() != to_index {
+    path.push(from);
+  }
+
+  for pair in path.windows(2) {
+    if !pair[0].back_stitches().includes(pair[1].cid()) {
+      return Err(HttpError::Resolution(ResolutionError::BadData(format!(
+        "pulse {} does not link to pulse {}",
+        pair[0].index(),
+        pair[1].index()
+      ))));
+    }
+  }
+  Ok(path)
+}
+
+async fn proof(
+  params: ProofParams,
+  store: Arc<PortalStore>,
+  as_car: bool,
+) -> Result<warp::reply::Response, HttpError> {
+  let strand = params
+    .strand
+    .as_deref()
+    .map(|s| parse_cid("strand", s))
+    .transpose()?;
+  let from = parse_cid("from", &params.from)?;
+  let to = parse_cid("to", &params.to)?;
+  let from = find_pulse(&store, from, strand).await?;
+  let to = find_pulse(&store, to, strand.or(Some(from.strand_cid()))).await?;
+
+  let strand = to.strand().clone();
+  let path = skip_path(&store, from, to).await?;
+  let result = AnyResult::Tixels {
+    items: path.into_iter().map(|t| (*t).clone().into()).collect(),
+    strand: Some(strand.into()),
+  };
+  // both ends are addressed by cid, so the proof never changes
+  Ok(cache::with_cache_headers(
+    result.to_response(as_car).await,
+    None,
+    Freshness::Immutable,
+  ))
+}
+
+/// The proof route, answering in JSON or as a CAR file
+pub fn routes(
+  store: Arc<PortalStore>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::path!("proof")
+    .and(warp::get())
+    .and(warp::query::<ProofParams>())
+    .and(warp::any().map(move || store.clone()))
+    .and(crate::filters::with_check_accept_car())
+    .and_then(|params, store, as_car| async move {
+      proof(params, store, as_car)
+        .await
+        .map_err(warp::reject::custom)
+    })
+}
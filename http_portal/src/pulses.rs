@@ -0,0 +1,151 @@
+//! Helpers for reading randomness pulses out of the store
+use crate::PortalStore;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::{serde_ipld_dagcbor, Bytes, Ipld};
+use twine_spec_rng::{RandomnessPayload, RngStrandDetails};
+
+/// A value from another beacon (eg: NIST or drand), recorded by the
+/// generator in the pulse payload's `external` field
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExternalSource {
+  pub source: String,
+  pub uri: String,
+  pub round: u64,
+  pub value: Bytes,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExternalSources {
+  #[serde(default)]
+  external: Vec<ExternalSource>,
+}
+
+pub fn payload(tixel: &Tixel) -> Result<RandomnessPayload, ResolutionError> {
+  tixel
+    .extract_payload::<RandomnessPayload>()
+    .map_err(|e| ResolutionError::BadData(e.to_string()))
+}
+
+/// The values from other beacons recorded in a pulse, if any
+pub fn external_sources(tixel: &Tixel) -> Vec<ExternalSource> {
+  tixel
+    .extract_payload::<ExternalSources>()
+    .map(|payload| payload.external)
+    .unwrap_or_default()
+}
+
+pub fn period(strand: &Strand) -> Option<TimeDelta> {
+  strand
+    .extract_details::<RngStrandDetails>()
+    .ok()
+    .map(|details| details.period)
+}
+
+pub fn timestamp(tixel: &Tixel) -> Result<DateTime<Utc>, ResolutionError> {
+  Ok(payload(tixel)?.timestamp())
+}
+
+/// The randomness of a pulse, validated against the pulse before it.
+///
+/// The first pulse of a strand only commits to future randomness, so it has
+/// no output of its own.
+pub async fn output_value(
+  store: &PortalStore,
+  twine: &Twine,
+) -> Result<Vec<u8>, ResolutionError> {
+  if twine.index() == 0 {
+    return Err(ResolutionError::BadData(
+      "the first pulse of a strand has no randomness".to_string(),
+    ));
+  }
+  let prev = store
+    .resolve_index(twine.strand_cid(), twine.index() - 1)
+    .await?
+    .unpack();
+  output_value_from(twine, &prev)
+}
+
+/// Like [output_value] when the previous pulse is already at hand
+pub fn output_value_from(
+  twine: &Twine,
+  prev: &Twine,
+) -> Result<Vec<u8>, ResolutionError> {
+  RandomnessPayload::extract_randomness(twine, prev)
+    .map_err(|e| ResolutionError::BadData(e.to_string()))
+}
+
+/// Check that a pulse reveals the salt its previous pulse committed to
+pub fn validate_randomness(twine: &Twine, prev: &Twine) -> Result<(), String> {
+  payload(twine)
+    .map_err(|e| e.to_string())?
+    .validate_randomness(prev)
+    .map_err(|e| e.to_string())
+}
+
+/// The signature bytes of a tixel
+pub fn signature(tixel: &Tixel) -> Option<Vec<u8>> {
+  let ipld: Ipld = serde_ipld_dagcbor::from_slice(&tixel.bytes()).ok()?;
+  match ipld {
+    Ipld::Map(map) => match map.get("s") {
+      Some(Ipld::Bytes(bytes)) => Some(bytes.clone()),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSearch {
+  /// The last pulse at or before the time
+  AtOrBefore,
+  /// The last pulse strictly before the time
+  Before,
+  /// The first pulse at or after the time
+  AtOrAfter,
+  /// The first pulse strictly after the time
+  After,
+}
+
+/// Find a pulse by its timestamp.
+///
+/// Pulse timestamps always increase along a strand, so this is a binary
+/// search over the indices.
+pub async fn find_by_time(
+  store: &PortalStore,
+  strand: Cid,
+  time: DateTime<Utc>,
+  search: TimeSearch,
+) -> Result<Twine, ResolutionError> {
+  let latest = store.resolve_latest(strand).await?.unpack();
+  // find the first index whose pulse is past the boundary
+  let past = |ts: DateTime<Utc>| match search {
+    TimeSearch::AtOrBefore | TimeSearch::After => ts > time,
+    TimeSearch::Before | TimeSearch::AtOrAfter => ts >= time,
+  };
+  let (mut lo, mut hi) = (0, latest.index() + 1);
+  if !past(timestamp(&latest)?) {
+    lo = hi;
+  }
+  while lo < hi {
+    let mid = lo + (hi - lo) / 2;
+    let twine = store.resolve_index(strand, mid).await?.unpack();
+    if past(timestamp(&twine)?) {
+      hi = mid;
+    } else {
+      lo = mid + 1;
+    }
+  }
+
+  let index = match search {
+    TimeSearch::AtOrBefore | TimeSearch::Before => {
+      lo.checked_sub(1).ok_or(ResolutionError::NotFound)?
+    }
+    TimeSearch::AtOrAfter | TimeSearch::After => lo,
+  };
+  if index > latest.index() {
+    return Err(ResolutionError::NotFound);
+  }
+  Ok(store.resolve_index(strand, index).await?.unpack())
+}
@@ -0,0 +1,148 @@
+//! Write endpoints so the portal can act as a twine HTTP store.
+//!
+//! Other nodes (or a `data_sync` service) push CAR files of strands and
+//! tixels with `POST /` or `POST /<strand cid>`. Requests need an API key
+//! with the `write` scope, sent as `Authorization: ApiKey <key>` like
+//! `data_sync` does. Without such a key configured the endpoints are off.
+use crate::auth::{self, ApiKeys, Scope};
+use crate::handlers::HttpError;
+use crate::PortalStore;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::car::from_car_bytes;
+use twine_protocol::twine_lib::resolver::SingleQuery;
+use twine_protocol::twine_lib::store::Store;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::Filter;
+
+/// Largest CAR file accepted in one request
+const MAX_UPLOAD_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Serialize)]
+struct Saved {
+  strands: usize,
+  tixels: usize,
+}
+
+async fn save(
+  store: &PortalStore,
+  strand_cid: Option<Cid>,
+  body: Bytes,
+) -> Result<Saved, HttpError> {
+  let twines = from_car_bytes(&mut body.as_ref())
+    .map_err(|e| HttpError::BadRequest(format!("Invalid CAR file: {}", e)))?;
+
+  let mut strands = HashMap::new();
+  let mut tixels = vec![];
+  for twine in twines {
+    match twine {
+      AnyTwine::Strand(strand) => {
+        strands.insert(strand.cid(), strand);
+      }
+      AnyTwine::Tixel(tixel) => tixels.push(tixel),
+    }
+  }
+  if let Some(cid) = strand_cid {
+    let other = strands
+      .keys()
+      .copied()
+      .chain(tixels.iter().map(|t| t.strand_cid()))
+      .find(|c| *c != cid);
+    if let Some(other) = other {
+      return Err(HttpError::BadRequest(format!(
+        "Data for strand {} can't be saved to strand {}",
+        other, cid
+      )));
+    }
+  }
+  tixels.sort_by_key(|t| (t.strand_cid(), t.index()));
+
+  // check everything before saving anything
+  let uploaded: HashSet<Cid> = tixels.iter().map(|t| t.cid()).collect();
+  let mut known: HashMap<Cid, Strand> = HashMap::new();
+  for tixel in &tixels {
+    let cid = tixel.strand_cid();
+    if !known.contains_key(&cid) {
+      let strand = match strands.get(&cid) {
+        Some(strand) => strand.clone(),
+        None => match store.resolve_strand(&cid).await {
+          Ok(strand) => strand.unpack(),
+          Err(ResolutionError::NotFound) => {
+            return Err(HttpError::BadRequest(format!(
+              "Strand {} must be saved before its tixels",
+              cid
+            )))
+          }
+          Err(e) => return Err(e.into()),
+        },
+      };
+      known.insert(cid, strand);
+    }
+    known[&cid].verify_tixel(tixel).map_err(|e| {
+      HttpError::BadRequest(format!("Tixel {}: {}", tixel.cid(), e))
+    })?;
+    if let Some(prev) = tixel.previous() {
+      let saved = uploaded.contains(&prev.tixel)
+        || store.has(SingleQuery::Stitch(prev.clone())).await?;
+      if !saved {
+        return Err(HttpError::BadRequest(format!(
+          "Tixel {} follows {} which has not been saved",
+          tixel.cid(),
+          prev.tixel
+        )));
+      }
+    }
+  }
+
+  let saved = Saved {
+    strands: strands.len(),
+    tixels: tixels.len(),
+  };
+  store.save_many(strands.into_values()).await?;
+  store.save_many(tixels).await?;
+  Ok(saved)
+}
+
+fn reject(err: HttpError) -> warp::Rejection {
+  warp::reject::custom(err)
+}
+
+/// `POST /` and `POST /<strand cid>` accepting CAR files
+pub fn routes(
+  keys: Arc<ApiKeys>,
+  store: Arc<PortalStore>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let strand = warp::path::param::<Cid>()
+    .map(Some)
+    .or(warp::any().map(|| None))
+    .unify();
+
+  warp::post()
+    .and(strand)
+    .and(warp::path::end())
+    .and(auth::require(keys, Scope::Write))
+    .and(warp::body::content_length_limit(MAX_UPLOAD_BYTES))
+    .and(warp::body::bytes())
+    .and(warp::any().map(move || store.clone()))
+    .and_then(
+      |strand: Option<Cid>,
+       principal: Arc<auth::Principal>,
+       body: Bytes,
+       store: Arc<PortalStore>| async move {
+        let saved = save(&store, strand, body).await.map_err(reject)?;
+        log::info!(
+          "Saved {} strands and {} tixels pushed by {}",
+          saved.strands,
+          saved.tixels,
+          principal.name
+        );
+        Ok::<_, warp::Rejection>(warp::reply::with_status(
+          warp::reply::json(&saved),
+          StatusCode::CREATED,
+        ))
+      },
+    )
+}
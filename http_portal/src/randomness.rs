@@ -0,0 +1,154 @@
+//! Just the randomness of a pulse, for consumers that don't want to parse
+//! tixels themselves.
+//!
+//! The output is derived with `RandomnessPayload::extract_randomness`, the
+//! same way clients verifying the strand would.
+use crate::cache::{self, Freshness};
+use crate::handlers::HttpError;
+use crate::pulses::{self, TimeSearch};
+use crate::PortalStore;
+use base64::Engine;
+use chrono::{TimeZone, Utc};
+use serde::Deserialize;
+use std::sync::Arc;
+use twine_protocol::prelude::*;
+use warp::http::header::CONTENT_TYPE;
+use warp::Filter;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+  #[default]
+  Hex,
+  Base64,
+  /// The bytes themselves
+  Raw,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Params {
+  #[serde(default)]
+  encoding: Encoding,
+}
+
+fn encode(output: Vec<u8>, encoding: Encoding) -> warp::reply::Response {
+  use warp::reply::Reply;
+  match encoding {
+    Encoding::Hex => hex::encode(output).into_response(),
+    Encoding::Base64 => base64::engine::general_purpose::STANDARD
+      .encode(output)
+      .into_response(),
+    Encoding::Raw => {
+      warp::reply::with_header(output, CONTENT_TYPE, "application/octet-stream")
+        .into_response()
+    }
+  }
+}
+
+async fn respond(
+  store: &PortalStore,
+  twine: Twine,
+  params: Params,
+) -> Result<warp::reply::Response, HttpError> {
+  if twine.index() == 0 {
+    return Err(HttpError::BadRequest(
+      "The first pulse of a strand has no randomness".to_string(),
+    ));
+  }
+  let output = pulses::output_value(store, &twine).await?;
+  // the pulse a time maps to can change until a later one is published
+  let latest = store.resolve_latest(twine.strand_cid()).await?.unpack();
+  let freshness = if twine.index() < latest.index() {
+    Freshness::Immutable
+  } else {
+    Freshness::MaxAge(cache::latest_max_age(&latest))
+  };
+  let res = warp::reply::with_header(
+    encode(output, params.encoding),
+    "X-Pulse-Index",
+    twine.index().to_string(),
+  );
+  Ok(cache::with_cache_headers(
+    warp::reply::Reply::into_response(res),
+    None,
+    freshness,
+  ))
+}
+
+mod handlers {
+  use super::*;
+
+  pub async fn by_index(
+    strand: Cid,
+    index: u64,
+    params: Params,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let twine = store.resolve_index(strand, index).await?.unpack();
+    respond(&store, twine, params).await
+  }
+
+  pub async fn latest(
+    strand: Cid,
+    params: Params,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let twine = store.resolve_latest(strand).await?.unpack();
+    respond(&store, twine, params).await
+  }
+
+  /// The randomness in effect at a unix time, in seconds
+  pub async fn by_time(
+    strand: Cid,
+    seconds: i64,
+    params: Params,
+    store: Arc<PortalStore>,
+  ) -> Result<warp::reply::Response, HttpError> {
+    let time = Utc.timestamp_opt(seconds, 0).single().ok_or_else(|| {
+      HttpError::BadRequest(format!("Invalid time {}", seconds))
+    })?;
+    let twine =
+      pulses::find_by_time(&store, strand, time, TimeSearch::AtOrBefore)
+        .await?;
+    respond(&store, twine, params).await
+  }
+}
+
+fn reject(err: HttpError) -> warp::Rejection {
+  warp::reject::custom(err)
+}
+
+/// Routes under `/randomness/<strand>`
+pub fn routes(
+  store: Arc<PortalStore>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let store = warp::any().map(move || store.clone());
+  let params = warp::query::<Params>();
+
+  let latest = warp::path!("randomness" / Cid / "latest")
+    .and(params.clone())
+    .and(store.clone())
+    .and_then(|strand, params, store| async move {
+      handlers::latest(strand, params, store)
+        .await
+        .map_err(reject)
+    });
+  let by_time = warp::path!("randomness" / Cid / "time" / i64)
+    .and(params.clone())
+    .and(store.clone())
+    .and_then(|strand, seconds, params, store| async move {
+      handlers::by_time(strand, seconds, params, store)
+        .await
+        .map_err(reject)
+    });
+  let by_index = warp::path!("randomness" / Cid / u64)
+    .and(params)
+    .and(store)
+    .and_then(|strand, index, params, store| async move {
+      handlers::by_index(strand, index, params, store)
+        .await
+        .map_err(reject)
+    });
+
+  warp::get().and(latest.or(by_time).or(by_index))
+}
@@ -0,0 +1,267 @@
+//! Per client request rate limits.
+//!
+//! Every client gets a token bucket that refills at `RATE_LIMIT_PER_MINUTE`
+//! requests per minute and holds up to `RATE_LIMIT_BURST` requests. Clients
+//! are told when to retry with a `429 Too Many Requests`. Clients with an
+//! API key are limited by key rather than by address, at the rate of the
+//! key's tier or else `RATE_LIMIT_API_KEY_PER_MINUTE` with a burst of
+//! `RATE_LIMIT_API_KEY_BURST`. Limits of 0 turn limiting off.
+use crate::auth::{self, ApiKeys, Principal};
+use crate::handlers::HttpError;
+use crate::metrics;
+use anyhow::Result;
+use biab_utils::{config_flag, config_or};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use warp::Filter;
+
+/// Idle buckets are forgotten once this many clients are tracked
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "LimitConfig")]
+pub struct Limit {
+  per_minute: u64,
+  burst: u64,
+}
+
+/// A limit as written in yaml, checked before use
+#[derive(Deserialize)]
+struct LimitConfig {
+  per_minute: u64,
+  burst: u64,
+}
+
+impl TryFrom<LimitConfig> for Limit {
+  type Error = String;
+
+  fn try_from(config: LimitConfig) -> Result<Self, Self::Error> {
+    if config.per_minute == 0 {
+      return Err("per_minute must be above 0".to_string());
+    }
+    if config.burst == 0 {
+      return Err("burst must be at least 1".to_string());
+    }
+    Ok(Self {
+      per_minute: config.per_minute,
+      burst: config.burst,
+    })
+  }
+}
+
+impl Limit {
+  fn per_second(&self) -> f64 {
+    self.per_minute as f64 / 60.0
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+  Address(IpAddr),
+  Key(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+  limit: Limit,
+  tokens: f64,
+  updated: Instant,
+}
+
+impl Bucket {
+  fn new(limit: Limit, now: Instant) -> Self {
+    Self {
+      limit,
+      tokens: limit.burst as f64,
+      updated: now,
+    }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.limit.per_second())
+      .min(self.limit.burst as f64);
+    self.updated = now;
+  }
+
+  /// Take a token, or say how many seconds until one is available
+  fn take(&mut self, now: Instant) -> Result<(), u64> {
+    self.refill(now);
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      Ok(())
+    } else {
+      let wait = (1.0 - self.tokens) / self.limit.per_second();
+      Err(wait.ceil().max(1.0) as u64)
+    }
+  }
+
+  fn is_full(&self, now: Instant) -> bool {
+    let elapsed = now.duration_since(self.updated).as_secs_f64();
+    self.tokens + elapsed * self.limit.per_second() >= self.limit.burst as f64
+  }
+}
+
+pub struct RateLimiter {
+  by_address: Option<Limit>,
+  by_key: Option<Limit>,
+  trust_forwarded_for: bool,
+  buckets: Mutex<HashMap<Client, Bucket>>,
+}
+
+fn limit_from_env(
+  var: &str,
+  burst_var: &str,
+  default: u64,
+) -> Result<Option<Limit>> {
+  let per_minute = config_or(var, default)?;
+  let burst = config_or(burst_var, per_minute)?;
+  Ok((per_minute > 0).then_some(Limit {
+    per_minute,
+    burst: burst.max(1),
+  }))
+}
+
+impl RateLimiter {
+  pub fn from_env() -> Result<Self> {
+    Ok(Self {
+      by_address: limit_from_env(
+        "RATE_LIMIT_PER_MINUTE",
+        "RATE_LIMIT_BURST",
+        600,
+      )?,
+      by_key: limit_from_env(
+        "RATE_LIMIT_API_KEY_PER_MINUTE",
+        "RATE_LIMIT_API_KEY_BURST",
+        6000,
+      )?,
+      trust_forwarded_for: config_flag("TRUST_FORWARDED_FOR")?,
+      buckets: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// A limiter with its own budget, using these limits or else the same ones
+  pub fn separate(
+    &self,
+    by_address: Option<Limit>,
+    by_key: Option<Limit>,
+  ) -> Self {
+    Self {
+      by_address: by_address.or(self.by_address),
+      by_key: by_key.or(self.by_key),
+      trust_forwarded_for: self.trust_forwarded_for,
+      buckets: Mutex::new(HashMap::new()),
+    }
+  }
+
+  fn client(
+    &self,
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<&str>,
+    principal: Option<&Principal>,
+  ) -> Option<(Client, Limit)> {
+    if let Some(principal) = principal {
+      let client = Client::Key(principal.name.clone());
+      return principal.limit.or(self.by_key).map(|limit| (client, limit));
+    }
+    let forwarded = forwarded_for
+      .filter(|_| self.trust_forwarded_for)
+      .and_then(|f| f.split(',').next())
+      .and_then(|ip| ip.trim().parse().ok());
+    // without an address (eg: behind the built-in TLS) there's no one to
+    // attribute the request to
+    let address = forwarded.or(remote.map(|r| r.ip()))?;
+    self
+      .by_address
+      .map(|limit| (Client::Address(address), limit))
+  }
+
+  /// Count a request, returning the seconds to wait if over the limit
+  fn check(&self, client: Client, limit: Limit) -> Result<(), u64> {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().expect("buckets lock");
+    if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+      buckets.retain(|_, bucket| !bucket.is_full(now));
+    }
+    let bucket = buckets
+      .entry(client)
+      .or_insert_with(|| Bucket::new(limit, now));
+    // a key may have moved to another tier
+    bucket.limit = limit;
+    let result = bucket.take(now);
+    metrics::observe_rate_limit(result.is_ok());
+    result
+  }
+}
+
+/// Rejects requests over the limit with [HttpError::TooManyRequests]
+pub fn filter(
+  limiter: Arc<RateLimiter>,
+  keys: Arc<ApiKeys>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+  warp::addr::remote()
+    .and(warp::header::optional::<String>("x-forwarded-for"))
+    .and(auth::principal(keys))
+    .and_then(
+      move |remote: Option<SocketAddr>,
+            forwarded_for: Option<String>,
+            principal: Option<Arc<Principal>>| {
+        let limiter = limiter.clone();
+        async move {
+          let client = limiter.client(
+            remote,
+            forwarded_for.as_deref(),
+            principal.as_deref(),
+          );
+          match client {
+            Some((client, limit)) => {
+              limiter.check(client, limit).map_err(|retry_after| {
+                warp::reject::custom(HttpError::TooManyRequests { retry_after })
+              })
+            }
+            None => Ok(()),
+          }
+        }
+      },
+    )
+    .untuple_one()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn test_bucket() {
+    let limit = Limit {
+      per_minute: 60,
+      burst: 2,
+    };
+    let start = Instant::now();
+    let mut bucket = Bucket::new(limit, start);
+    assert_eq!(bucket.take(start), Ok(()));
+    assert_eq!(bucket.take(start), Ok(()));
+    assert_eq!(bucket.take(start), Err(1));
+    let later = start + Duration::from_secs(1);
+    assert_eq!(bucket.take(later), Ok(()));
+    assert!(!bucket.is_full(later));
+    assert!(bucket.is_full(later + Duration::from_secs(2)));
+  }
+
+  #[test]
+  fn test_limit_from_yaml() {
+    let limit: Limit =
+      serde_yaml::from_str("{ per_minute: 60, burst: 10 }").unwrap();
+    assert_eq!(limit.burst, 10);
+    assert!(
+      serde_yaml::from_str::<Limit>("{ per_minute: 0, burst: 10 }").is_err()
+    );
+    assert!(
+      serde_yaml::from_str::<Limit>("{ per_minute: 60, burst: 0 }").is_err()
+    );
+  }
+}
@@ -0,0 +1,155 @@
+//! Request ids and structured access logs.
+//!
+//! Every request gets an id, either the client's `X-Request-Id` (if it looks
+//! sane) or a new uuid. The id is sent back in the `X-Request-Id` header.
+//! Requests are handled in a `request` span carrying the id, the method and
+//! the path, so they're part of every log line written while the request is
+//! handled. Finished requests are logged with their status, latency and
+//! client under the `api` target.
+//!
+//! A layer of the tracing subscriber keeps the id of each request span, so
+//! it can be looked up for the response header.
+use tracing::span::{Attributes, Id};
+use tracing::{field, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+use warp::http::HeaderValue;
+
+pub const HEADER: &str = "x-request-id";
+/// Longest request id accepted from clients
+const MAX_LEN: usize = 128;
+const ACCESS_LOG_TARGET: &str = "api";
+
+/// The id of a request span
+struct RequestId(String);
+
+/// Keeps the ids of the request spans created by [span]
+struct RequestIds;
+
+#[derive(Default)]
+struct RequestIdVisitor(Option<String>);
+
+impl field::Visit for RequestIdVisitor {
+  fn record_str(&mut self, field: &field::Field, value: &str) {
+    if field.name() == "request_id" {
+      self.0 = Some(value.to_string());
+    }
+  }
+
+  fn record_debug(
+    &mut self,
+    field: &field::Field,
+    value: &dyn std::fmt::Debug,
+  ) {
+    if field.name() == "request_id" {
+      self.0 = Some(format!("{:?}", value));
+    }
+  }
+}
+
+impl<S> Layer<S> for RequestIds
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+    let mut visitor = RequestIdVisitor::default();
+    attrs.record(&mut visitor);
+    if let (Some(request_id), Some(span)) = (visitor.0, ctx.span(id)) {
+      span.extensions_mut().insert(RequestId(request_id));
+    }
+  }
+}
+
+/// The id of the request being handled, if any
+pub fn current() -> Option<String> {
+  tracing::Span::current()
+    .with_subscriber(|(id, dispatch)| {
+      let registry = dispatch.downcast_ref::<Registry>()?;
+      registry.span(id)?.scope().find_map(|span| {
+        let extensions = span.extensions();
+        extensions.get::<RequestId>().map(|id| id.0.clone())
+      })
+    })
+    .flatten()
+}
+
+fn is_valid(id: &str) -> bool {
+  !id.is_empty()
+    && id.len() <= MAX_LEN
+    && id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+fn request_span(id: &str, method: &str, path: &str) -> tracing::Span {
+  tracing::info_span!("request", request_id = id, method, path)
+}
+
+/// The span a request is handled in, for [warp::trace]
+pub fn span(info: warp::trace::Info<'_>) -> tracing::Span {
+  let id = info
+    .request_headers()
+    .get(HEADER)
+    .and_then(|id| id.to_str().ok())
+    .filter(|id| is_valid(id))
+    .map(str::to_string)
+    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+  request_span(&id, info.method().as_str(), info.path())
+}
+
+/// Tell the client the id of its request
+pub fn with_header(mut res: warp::reply::Response) -> warp::reply::Response {
+  if let Some(id) = current().and_then(|id| HeaderValue::from_str(&id).ok()) {
+    res.headers_mut().insert(HEADER, id);
+  }
+  res
+}
+
+/// Log a finished request, for [warp::log::custom]
+pub fn access_log(info: warp::log::Info<'_>) {
+  // the request id, method and path are fields of the request span
+  tracing::info!(
+    target: ACCESS_LOG_TARGET,
+    status = info.status().as_u16(),
+    latency_ms = info.elapsed().as_secs_f64() * 1000.0,
+    remote_addr = info.remote_addr().map(field::display),
+    user_agent = info.user_agent(),
+    referer = info.referer(),
+    "{} {} {}",
+    info.method(),
+    info.path(),
+    info.status().as_u16()
+  );
+}
+
+/// Like [biab_utils::init_logger], keeping track of request ids
+pub fn init_logger() {
+  // whatever the LOG_LEVEL, so responses always get their id
+  let request_spans =
+    Targets::new().with_target(module_path!(), tracing::Level::INFO);
+  biab_utils::init_logger_with(RequestIds.with_filter(request_spans));
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use tracing_subscriber::layer::SubscriberExt;
+
+  #[test]
+  fn test_current() {
+    assert!(is_valid("abc-123"));
+    assert!(!is_valid("no spaces"));
+    assert!(!is_valid(""));
+    let subscriber = tracing_subscriber::registry().with(RequestIds);
+    tracing::subscriber::with_default(subscriber, || {
+      assert_eq!(current(), None);
+      let span = request_span("abc-123", "GET", "/latest");
+      let entered = span.enter();
+      assert_eq!(current().as_deref(), Some("abc-123"));
+      drop(entered);
+      assert_eq!(current(), None);
+    });
+  }
+}
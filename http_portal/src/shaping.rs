@@ -0,0 +1,171 @@
+//! What query responses include.
+//!
+//! By default items are whole tixels, and `?full` adds their strand. Clients
+//! that only poll for new pulses can ask for plain JSON items with just some
+//! fields instead, eg: `?fields=index` or `?fields=cid,index,timestamp`, and
+//! `?omit-payload` is short for every field but the payload.
+use crate::cache;
+use crate::models::AnyResult;
+use crate::pulses;
+use serde_json::{Map, Value};
+use std::str::FromStr;
+use twine_protocol::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+  Cid,
+  Strand,
+  Index,
+  Timestamp,
+  Payload,
+}
+
+impl Field {
+  fn name(&self) -> &'static str {
+    match self {
+      Field::Cid => "cid",
+      Field::Strand => "strand",
+      Field::Index => "index",
+      Field::Timestamp => "timestamp",
+      Field::Payload => "payload",
+    }
+  }
+
+  fn value(&self, tixel: &Tixel) -> Value {
+    match self {
+      Field::Cid => tixel.cid().to_string().into(),
+      Field::Strand => tixel.strand_cid().to_string().into(),
+      Field::Index => tixel.index().into(),
+      Field::Timestamp => pulses::timestamp(tixel)
+        .ok()
+        .and_then(|t| serde_json::to_value(t).ok())
+        .unwrap_or(Value::Null),
+      Field::Payload => crate::dag_json::serialize(
+        tixel.payload(),
+        serde_json::value::Serializer,
+      )
+      .unwrap_or(Value::Null),
+    }
+  }
+}
+
+impl FromStr for Field {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "cid" => Ok(Field::Cid),
+      "strand" => Ok(Field::Strand),
+      "index" => Ok(Field::Index),
+      "timestamp" => Ok(Field::Timestamp),
+      "payload" => Ok(Field::Payload),
+      _ => Err(format!(
+        "Unknown field {}, expected cid, strand, index, timestamp or payload",
+        s
+      )),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Shape {
+  /// Include the strand with whole tixels
+  pub full: bool,
+  /// Only these fields of each tixel, as plain JSON
+  pub fields: Option<Vec<Field>>,
+}
+
+impl Shape {
+  pub fn new(
+    full: bool,
+    fields: Option<&str>,
+    omit_payload: bool,
+  ) -> Result<Self, String> {
+    let fields = match fields {
+      Some(fields) => {
+        let fields = fields
+          .split(',')
+          .map(str::trim)
+          .filter(|f| !f.is_empty())
+          .map(Field::from_str)
+          .collect::<Result<Vec<_>, _>>()?;
+        if fields.is_empty() {
+          return Err("No fields given, leave out ?fields for all".to_string());
+        }
+        Some(fields)
+      }
+      None if omit_payload => Some(vec![
+        Field::Cid,
+        Field::Strand,
+        Field::Index,
+        Field::Timestamp,
+      ]),
+      None => None,
+    };
+    Ok(Self { full, fields })
+  }
+
+  /// Whether the strand is sent along with whole tixels
+  pub fn with_strand(&self) -> bool {
+    self.full && self.fields.is_none()
+  }
+
+  /// The ETag of a response for a tixel
+  pub fn etag(&self, cid: &Cid, as_car: bool) -> String {
+    match &self.fields {
+      Some(fields) if !as_car => format!("\"{}-{}\"", cid, self.names(fields)),
+      _ => cache::etag(cid, as_car, self.full),
+    }
+  }
+
+  /// The query string that asks for this shape again, eg: for the next page
+  pub fn query_string(&self) -> String {
+    match &self.fields {
+      Some(fields) => format!("&fields={}", self.names(fields)),
+      None if self.full => "&full".to_string(),
+      None => String::new(),
+    }
+  }
+
+  fn names(&self, fields: &[Field]) -> String {
+    fields.iter().map(Field::name).collect::<Vec<_>>().join(",")
+  }
+
+  /// Trim tixels down to the chosen fields
+  pub fn apply(&self, result: AnyResult) -> AnyResult {
+    match (&self.fields, result) {
+      (Some(fields), AnyResult::Tixels { items, .. }) => AnyResult::Shaped {
+        items: items
+          .into_iter()
+          .map(|tixel| {
+            let tixel = tixel.unpack();
+            fields
+              .iter()
+              .map(|f| (f.name().to_string(), f.value(&tixel)))
+              .collect::<Map<_, _>>()
+          })
+          .collect(),
+      },
+      (_, result) => result,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_shape() {
+    let shape = Shape::new(true, Some("cid, index"), false).unwrap();
+    assert_eq!(shape.fields, Some(vec![Field::Cid, Field::Index]));
+    assert!(!shape.with_strand());
+    assert_eq!(shape.query_string(), "&fields=cid,index");
+    let shape = Shape::new(false, None, true).unwrap();
+    assert!(!shape.fields.unwrap().contains(&Field::Payload));
+    assert!(Shape::new(false, Some("signature"), false).is_err());
+    assert!(Shape::new(false, Some(""), false).is_err());
+    assert!(Shape::new(false, Some(" , "), false).is_err());
+    assert!(Shape::new(true, None, false).unwrap().with_strand());
+  }
+}
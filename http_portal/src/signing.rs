@@ -0,0 +1,235 @@
+//! Signed responses with HTTP Message Signatures (RFC 9421).
+//!
+//! If `RESPONSE_SIGNING_KEY_PATH` points at a PEM encoded Ed25519 private key
+//! (PKCS#8, eg: from `openssl genpkey -algorithm ed25519`), every response
+//! carries a `Content-Digest` (RFC 9530) and a signature over its status,
+//! content type, encoding, digest and the request path and query. Responses
+//! relayed by CDNs or mirrors can then still be attributed to the beacon
+//! operator.
+//!
+//! Only bodies of a known length (ie: already in memory) are hashed for the
+//! digest. Streamed bodies (eg: CAR exports and range responses) are signed
+//! without `Content-Digest`, rather than buffered whole to hash them, so
+//! their signature covers the status, headers and request but not the
+//! content.
+//!
+//! The key id defaults to the hex encoded public key and can be set with
+//! `RESPONSE_SIGNING_KEY_ID`. The public key is served as a JWK at
+//! `GET /signing-key`.
+use crate::problem;
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use biab_utils::config_value;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use warp::http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_TYPE};
+use warp::http::StatusCode;
+use warp::hyper::body::{to_bytes, Body, HttpBody};
+use warp::Filter;
+
+const LABEL: &str = "sig1";
+
+pub struct ResponseSigner {
+  key: Ed25519KeyPair,
+  key_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Jwk<'a> {
+  kty: &'static str,
+  crv: &'static str,
+  alg: &'static str,
+  #[serde(rename = "use")]
+  usage: &'static str,
+  kid: &'a str,
+  x: String,
+}
+
+/// The signature base of RFC 9421 section 2.5
+fn signature_base(components: &[(&str, String)], params: &str) -> String {
+  let mut base = String::new();
+  for (name, value) in components {
+    base.push_str(&format!("{}: {}\n", name, value));
+  }
+  base.push_str(&format!("\"@signature-params\": {}", params));
+  base
+}
+
+fn signature_params(
+  components: &[(&str, String)],
+  created: i64,
+  key_id: &str,
+) -> String {
+  let names: Vec<&str> = components.iter().map(|(name, _)| *name).collect();
+  format!(
+    "({});created={};keyid=\"{}\";alg=\"ed25519\"",
+    names.join(" "),
+    created,
+    key_id
+  )
+}
+
+/// The `@query` of RFC 9421 section 2.2.7, which is `?` without a query
+fn query_component(query: Option<&str>) -> String {
+  format!("?{}", query.unwrap_or_default())
+}
+
+impl ResponseSigner {
+  pub fn from_env() -> Result<Option<Arc<Self>>> {
+    let Some(path) = config_value("RESPONSE_SIGNING_KEY_PATH")? else {
+      return Ok(None);
+    };
+    let pem = std::fs::read(&path)
+      .map_err(|e| anyhow!("Failed to read {}: {}", path, e))?;
+    let der = rustls_pemfile::private_key(&mut pem.as_slice())
+      .map_err(|e| anyhow!("Invalid signing key {}: {}", path, e))?
+      .ok_or_else(|| anyhow!("No private key in {}", path))?;
+    let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der.secret_der())
+      .map_err(|e| anyhow!("{} is not an Ed25519 key: {}", path, e))?;
+    let key_id = config_value("RESPONSE_SIGNING_KEY_ID")?
+      .unwrap_or_else(|| hex::encode(key.public_key().as_ref()));
+    log::info!("Signing responses with key {}", key_id);
+    Ok(Some(Arc::new(Self { key, key_id })))
+  }
+
+  /// Add the signature headers, and the digest for bodies of a known length
+  pub async fn sign(
+    &self,
+    path: &str,
+    query: Option<&str>,
+    res: warp::reply::Response,
+  ) -> warp::reply::Response {
+    // websocket upgrades have no content to sign
+    if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+      return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let (body, digest) = if body.size_hint().exact().is_some() {
+      let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+          log::error!("Failed to read the response to sign: {}", e);
+          return problem::response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Internal server error".to_string(),
+          );
+        }
+      };
+      let digest = format!(
+        "sha-256=:{}:",
+        base64::engine::general_purpose::STANDARD.encode(Sha256::digest(&body))
+      );
+      (Body::from(body), Some(digest))
+    } else {
+      (body, None)
+    };
+
+    let mut components =
+      vec![("\"@status\"", parts.status.as_str().to_string())];
+    for (name, header) in [
+      ("\"content-type\"", CONTENT_TYPE),
+      ("\"content-encoding\"", CONTENT_ENCODING),
+    ] {
+      if let Some(value) = parts.headers.get(header) {
+        components
+          .push((name, String::from_utf8_lossy(value.as_bytes()).into()));
+      }
+    }
+    if let Some(digest) = &digest {
+      components.push(("\"content-digest\"", digest.clone()));
+    }
+    components.push(("\"@path\";req", path.to_string()));
+    components.push(("\"@query\";req", query_component(query)));
+
+    let params = signature_params(
+      &components,
+      chrono::Utc::now().timestamp(),
+      &self.key_id,
+    );
+    let signature = self
+      .key
+      .sign(signature_base(&components, &params).as_bytes());
+    let signature = base64::engine::general_purpose::STANDARD.encode(signature);
+
+    let headers = [
+      ("content-digest", digest),
+      ("signature-input", Some(format!("{}={}", LABEL, params))),
+      ("signature", Some(format!("{}=:{}:", LABEL, signature))),
+    ];
+    for (name, value) in headers {
+      let Some(value) = value else {
+        continue;
+      };
+      if let Ok(value) = HeaderValue::from_str(&value) {
+        parts.headers.insert(name, value);
+      }
+    }
+    warp::reply::Response::from_parts(parts, body)
+  }
+
+  fn jwk(&self) -> Jwk<'_> {
+    Jwk {
+      kty: "OKP",
+      crv: "Ed25519",
+      alg: "EdDSA",
+      usage: "sig",
+      kid: &self.key_id,
+      x: base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .encode(self.key.public_key().as_ref()),
+    }
+  }
+}
+
+/// `/signing-key`, when responses are signed
+pub fn routes(
+  signer: Option<Arc<ResponseSigner>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::get()
+    .and(warp::path!("signing-key"))
+    .and_then(move || {
+      let signer = signer.clone();
+      async move {
+        match signer {
+          Some(signer) => Ok(warp::reply::json(&signer.jwk())),
+          None => Err(warp::reject::not_found()),
+        }
+      }
+    })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_signature_base() {
+    let components = vec![
+      ("\"@status\"", "200".to_string()),
+      ("\"content-type\"", "application/json".to_string()),
+      ("\"@path\";req", "/latest".to_string()),
+    ];
+    let params = signature_params(&components, 1618884473, "test-key");
+    assert_eq!(
+      params,
+      "(\"@status\" \"content-type\" \"@path\";req);created=1618884473;\
+       keyid=\"test-key\";alg=\"ed25519\""
+    );
+    assert_eq!(
+      signature_base(&components, &params),
+      format!(
+        "\"@status\": 200\n\"content-type\": application/json\n\
+         \"@path\";req: /latest\n\"@signature-params\": {}",
+        params
+      )
+    );
+  }
+
+  #[test]
+  fn test_query_component() {
+    assert_eq!(query_component(None), "?");
+    assert_eq!(query_component(Some("full&limit=10")), "?full&limit=10");
+  }
+}
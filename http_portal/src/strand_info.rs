@@ -0,0 +1,96 @@
+//! A readable summary of a strand at `GET /strand/<cid>/info`.
+//!
+//! Clients get the decoded strand details, key algorithm and the range of
+//! published pulses without having to decode DAG-JSON themselves.
+use crate::cache::{self, Freshness};
+use crate::handlers::HttpError;
+use crate::pulses;
+use crate::PortalStore;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use twine_protocol::prelude::*;
+use warp::Filter;
+
+#[derive(Debug, Serialize)]
+struct PulseInfo {
+  index: u64,
+  cid: String,
+  timestamp: Option<DateTime<Utc>>,
+}
+
+impl PulseInfo {
+  fn new(tixel: &Tixel) -> Self {
+    Self {
+      index: tixel.index(),
+      cid: tixel.cid().to_string(),
+      timestamp: pulses::timestamp(tixel).ok(),
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+struct StrandInfo {
+  cid: String,
+  spec: String,
+  subspec: Option<String>,
+  radix: u8,
+  key_algorithm: String,
+  /// Hex encoded DER public key
+  public_key: String,
+  /// Seconds between pulses, for randomness strands
+  period: Option<i64>,
+  expiry: Option<DateTime<Utc>>,
+  first_pulse: Option<PulseInfo>,
+  latest_pulse: Option<PulseInfo>,
+  pulse_count: u64,
+}
+
+async fn info(
+  strand: Cid,
+  store: Arc<PortalStore>,
+) -> Result<warp::reply::Response, HttpError> {
+  let strand = store.resolve_strand(strand).await?.unpack();
+  let latest = match store.resolve_latest(strand.cid()).await {
+    Ok(latest) => Some(latest.unpack()),
+    Err(ResolutionError::NotFound) => None,
+    Err(e) => return Err(e.into()),
+  };
+  let first = match &latest {
+    Some(latest) if latest.index() == 0 => Some(latest.clone()),
+    Some(_) => Some(store.resolve_index(strand.cid(), 0).await?.unpack()),
+    None => None,
+  };
+  let freshness = match &latest {
+    Some(latest) => Freshness::MaxAge(cache::latest_max_age(latest)),
+    None => Freshness::MaxAge(cache::strands_max_age([&strand].into_iter())),
+  };
+
+  let info = StrandInfo {
+    cid: strand.cid().to_string(),
+    spec: strand.spec_str().to_string(),
+    subspec: strand.subspec().map(|s| s.to_string()),
+    radix: strand.radix(),
+    key_algorithm: strand.key().alg.to_string(),
+    public_key: hex::encode(&strand.key().key),
+    period: pulses::period(&strand).map(|p| p.num_seconds()),
+    expiry: strand.expiry(),
+    first_pulse: first.as_deref().map(PulseInfo::new),
+    pulse_count: latest.as_ref().map_or(0, |l| l.index() + 1),
+    latest_pulse: latest.as_deref().map(PulseInfo::new),
+  };
+  let res = warp::reply::Reply::into_response(warp::reply::json(&info));
+  Ok(cache::with_cache_headers(res, None, freshness))
+}
+
+/// `/strand/<cid>/info`
+pub fn routes(
+  store: Arc<PortalStore>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::get()
+    .and(warp::path!("strand" / Cid / "info"))
+    .and(warp::any().map(move || store.clone()))
+    .and_then(|strand, store| async move {
+      info(strand, store).await.map_err(warp::reject::custom)
+    })
+}
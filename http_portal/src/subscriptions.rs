@@ -0,0 +1,296 @@
+//! Push newly published pulses to subscribed clients.
+//!
+//! The [PulseFeed] watches the store for new tixels on strands that have
+//! subscribers and broadcasts them, checking right away when woken (eg: by
+//! the generator publishing a pulse). Clients subscribe over a websocket by
+//! sending `{"subscribe": ["<strand cid>", ...]}` (or `unsubscribe`) and
+//! then receive every new pulse of those strands.
+use crate::models::AnyResult;
+use crate::PortalStore;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamMap;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::resolver::AbsoluteRange;
+use warp::ws::{Message, WebSocket};
+
+/// Pulses buffered per strand before slow subscribers start missing them
+const CHANNEL_CAPACITY: usize = 64;
+/// Most pulses sent at once if the feed falls behind a strand
+const MAX_BACKFILL: u64 = 100;
+const MAX_SUBSCRIPTIONS: usize = 32;
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Channel {
+  sender: broadcast::Sender<Tixel>,
+  last_index: Option<u64>,
+}
+
+pub struct PulseFeed {
+  store: Arc<PortalStore>,
+  channels: Mutex<HashMap<Cid, Channel>>,
+  woken: Notify,
+}
+
+impl PulseFeed {
+  pub fn new(store: Arc<PortalStore>) -> Arc<Self> {
+    Arc::new(Self {
+      store,
+      channels: Mutex::new(HashMap::new()),
+      woken: Notify::new(),
+    })
+  }
+
+  pub fn store(&self) -> &Arc<PortalStore> {
+    &self.store
+  }
+
+  /// Receive the pulses of a strand published from now on
+  pub fn subscribe(&self, strand: Cid) -> broadcast::Receiver<Tixel> {
+    self.subscribe_inner(strand, None)
+  }
+
+  /// Like [PulseFeed::subscribe] but if the strand isn't being watched yet,
+  /// pulses from `next_index` onwards are sent, so nothing published
+  /// before the first poll is missed
+  pub fn subscribe_from(
+    &self,
+    strand: Cid,
+    next_index: u64,
+  ) -> broadcast::Receiver<Tixel> {
+    self.subscribe_inner(strand, next_index.checked_sub(1))
+  }
+
+  fn subscribe_inner(
+    &self,
+    strand: Cid,
+    last_index: Option<u64>,
+  ) -> broadcast::Receiver<Tixel> {
+    let mut channels = self.channels.lock().expect("channels lock");
+    let channel = channels.entry(strand).or_insert_with(|| Channel {
+      sender: broadcast::channel(CHANNEL_CAPACITY).0,
+      last_index: None,
+    });
+    if channel.last_index.is_none() {
+      channel.last_index = last_index;
+    }
+    channel.sender.subscribe()
+  }
+
+  /// Poll the store for new pulses now rather than at the next interval
+  pub fn wake(&self) {
+    self.woken.notify_one();
+  }
+
+  /// Poll the store for new pulses until the task is dropped
+  pub async fn run(self: Arc<Self>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+      tokio::select! {
+        _ = interval.tick() => {}
+        _ = self.woken.notified() => {}
+      }
+      self.poll().await;
+    }
+  }
+
+  async fn poll(&self) {
+    let watched: Vec<(Cid, Option<u64>)> = {
+      let mut channels = self.channels.lock().expect("channels lock");
+      channels.retain(|_, channel| channel.sender.receiver_count() > 0);
+      channels
+        .iter()
+        .map(|(cid, channel)| (*cid, channel.last_index))
+        .collect()
+    };
+
+    for (strand, last_index) in watched {
+      if let Err(e) = self.poll_strand(strand, last_index).await {
+        log::error!("Failed to check strand {} for new pulses: {}", strand, e);
+      }
+    }
+  }
+
+  async fn poll_strand(
+    &self,
+    strand: Cid,
+    last_index: Option<u64>,
+  ) -> Result<(), ResolutionError> {
+    let latest = match self.store.resolve_latest(strand).await {
+      Ok(latest) => latest.unpack(),
+      Err(ResolutionError::NotFound) => return Ok(()),
+      Err(e) => return Err(e),
+    };
+    let latest_index = latest.index();
+    let tixels: Vec<Tixel> = match last_index {
+      Some(last) if latest_index > last => {
+        let start = (last + 1).max(latest_index.saturating_sub(MAX_BACKFILL));
+        let range = AbsoluteRange::new(strand, start, latest_index);
+        let twines: Vec<_> =
+          self.store.resolve_range(range).await?.try_collect().await?;
+        twines.into_iter().map(|t| (*t).clone()).collect()
+      }
+      // first poll after subscribing only records where we are
+      _ => vec![],
+    };
+
+    let mut channels = self.channels.lock().expect("channels lock");
+    if let Some(channel) = channels.get_mut(&strand) {
+      channel.last_index = Some(latest_index);
+      for tixel in tixels {
+        // no receivers left is fine, the channel is removed next poll
+        let _ = channel.sender.send(tixel);
+      }
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ClientMessage {
+  Subscribe(Vec<String>),
+  Unsubscribe(Vec<String>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Ack {
+  Subscribed(Vec<String>),
+  Unsubscribed(Vec<String>),
+}
+
+fn parse_cids(cids: &[String]) -> Result<Vec<Cid>, String> {
+  cids
+    .iter()
+    .map(|s| {
+      Cid::try_from(s.as_str()).map_err(|e| format!("Invalid cid {}: {}", s, e))
+    })
+    .collect()
+}
+
+fn error_message(error: String) -> Message {
+  let json = serde_json::to_string(&AnyResult::Error { error })
+    .expect("serializable error");
+  Message::text(json)
+}
+
+type Subscriptions = StreamMap<Cid, BroadcastStream<Tixel>>;
+
+async fn handle_client_message(
+  feed: &PulseFeed,
+  subscriptions: &mut Subscriptions,
+  msg: &str,
+) -> Result<Message, String> {
+  let msg: ClientMessage =
+    serde_json::from_str(msg).map_err(|e| format!("Invalid message: {}", e))?;
+  match msg {
+    ClientMessage::Subscribe(strands) => {
+      let cids = parse_cids(&strands)?;
+      if subscriptions.len() + cids.len() > MAX_SUBSCRIPTIONS {
+        return Err(format!(
+          "At most {} strands can be subscribed to",
+          MAX_SUBSCRIPTIONS
+        ));
+      }
+      for cid in &cids {
+        feed
+          .store()
+          .resolve_strand(cid)
+          .await
+          .map_err(|e| format!("Strand {}: {}", cid, e))?;
+      }
+      for cid in cids {
+        subscriptions.insert(cid, BroadcastStream::new(feed.subscribe(cid)));
+      }
+      let ack = serde_json::to_string(&Ack::Subscribed(strands))
+        .expect("serializable ack");
+      Ok(Message::text(ack))
+    }
+    ClientMessage::Unsubscribe(strands) => {
+      for cid in parse_cids(&strands)? {
+        subscriptions.remove(&cid);
+      }
+      let ack = serde_json::to_string(&Ack::Unsubscribed(strands))
+        .expect("serializable ack");
+      Ok(Message::text(ack))
+    }
+  }
+}
+
+/// Serve a websocket subscription until the client goes away.
+///
+/// Clients that stop answering pings are disconnected. Clients that can't
+/// keep up with the pulses get an error telling them how many they missed.
+pub async fn handle_socket(socket: WebSocket, feed: Arc<PulseFeed>) {
+  let (mut tx, mut rx) = socket.split();
+  let mut subscriptions = Subscriptions::new();
+  let mut ping = tokio::time::interval(PING_INTERVAL);
+  let mut awaiting_pong = false;
+
+  loop {
+    let outgoing = tokio::select! {
+      msg = rx.next() => match msg {
+        Some(Ok(msg)) => {
+          awaiting_pong = false;
+          if msg.is_close() {
+            break;
+          }
+          match msg.to_str() {
+            Ok(text) => {
+              handle_client_message(&feed, &mut subscriptions, text)
+                .await
+                .unwrap_or_else(error_message)
+            }
+            Err(_) => continue,
+          }
+        }
+        Some(Err(e)) => {
+          log::debug!("Websocket error: {}", e);
+          break;
+        }
+        None => break,
+      },
+      Some((strand, pulse)) = subscriptions.next(), if !subscriptions.is_empty() => {
+        match pulse {
+          Ok(tixel) => {
+            let result = AnyResult::Tixels {
+              items: vec![tixel.into()],
+              strand: None,
+            };
+            Message::text(
+              serde_json::to_string(&result).expect("serializable pulse"),
+            )
+          }
+          Err(BroadcastStreamRecvError::Lagged(n)) => error_message(format!(
+            "Missed {} pulses of strand {} because they were not read fast enough",
+            n, strand
+          )),
+        }
+      },
+      _ = ping.tick() => {
+        if awaiting_pong {
+          log::debug!("Closing unresponsive websocket");
+          break;
+        }
+        awaiting_pong = true;
+        Message::ping(Vec::new())
+      }
+    };
+
+    // waiting for the client here pushes back on the broadcast channels
+    if let Err(e) = tx.send(outgoing).await {
+      log::debug!("Failed to write to websocket: {}", e);
+      break;
+    }
+  }
+
+  let _ = tx.close().await;
+}
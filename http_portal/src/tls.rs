@@ -0,0 +1,305 @@
+//! Terminate TLS in the portal itself.
+//!
+//! Certificates are either read from `TLS_CERT_PATH` and `TLS_KEY_PATH`
+//! (PEM files) or, with the `acme` feature, obtained from Let's Encrypt for
+//! the `ACME_DOMAINS`. Certificate files are re-read every
+//! `TLS_RELOAD_INTERVAL_SECS` (default 300) and on SIGHUP. Only new
+//! handshakes use the reloaded certificate, so open connections are kept.
+use anyhow::{anyhow, Result};
+use biab_utils::{config_or, config_value};
+use futures::Stream;
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Connections that finished their handshake but weren't picked up yet
+const HANDSHAKE_BACKLOG: usize = 128;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum TlsSettings {
+  Files {
+    cert: PathBuf,
+    key: PathBuf,
+    reload_interval: Duration,
+  },
+  #[cfg(feature = "acme")]
+  Acme {
+    domains: Vec<String>,
+    contact: Option<String>,
+    cache_dir: PathBuf,
+    production: bool,
+  },
+}
+
+impl TlsSettings {
+  /// Read the TLS settings. TLS is disabled if nothing is configured.
+  pub fn from_env() -> Result<Option<Self>> {
+    #[cfg(feature = "acme")]
+    if config_value("ACME_DOMAINS")?.is_some() {
+      return Ok(Some(Self::Acme {
+        domains: biab_utils::config_list("ACME_DOMAINS")?,
+        contact: config_value("ACME_CONTACT")?,
+        cache_dir: config_value("ACME_CACHE_DIR")?
+          .unwrap_or_else(|| "/data/acme".into())
+          .into(),
+        production: biab_utils::config_flag("ACME_PRODUCTION")?,
+      }));
+    }
+
+    match (
+      config_value("TLS_CERT_PATH")?,
+      config_value("TLS_KEY_PATH")?,
+    ) {
+      (Some(cert), Some(key)) => {
+        let reload_interval: u64 = config_or("TLS_RELOAD_INTERVAL_SECS", 300)?;
+        Ok(Some(Self::Files {
+          cert: cert.into(),
+          key: key.into(),
+          reload_interval: Duration::from_secs(reload_interval.max(1)),
+        }))
+      }
+      (None, None) => Ok(None),
+      _ => Err(anyhow!(
+        "TLS_CERT_PATH and TLS_KEY_PATH must be set together"
+      )),
+    }
+  }
+
+  /// Accept connections on `addr` and complete their TLS handshakes
+  pub async fn incoming(
+    self,
+    addr: SocketAddr,
+  ) -> Result<impl Stream<Item = std::io::Result<TlsStream<TcpStream>>>> {
+    let handshake = match self {
+      Self::Files {
+        cert,
+        key,
+        reload_interval,
+      } => {
+        let resolver = Arc::new(ReloadingCert::load(cert, key)?);
+        tokio::spawn(resolver.clone().watch(reload_interval));
+        let config = server_config(resolver)?;
+        Handshake::Plain(tokio_rustls::TlsAcceptor::from(config))
+      }
+      #[cfg(feature = "acme")]
+      Self::Acme {
+        domains,
+        contact,
+        cache_dir,
+        production,
+      } => acme::handshake(domains, contact, cache_dir, production)?,
+    };
+
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving https on {}", addr);
+    let (tx, rx) = mpsc::channel(HANDSHAKE_BACKLOG);
+    tokio::spawn(async move {
+      loop {
+        let (tcp, peer) = match listener.accept().await {
+          Ok(conn) => conn,
+          Err(e) => {
+            log::error!("Failed to accept connection: {}", e);
+            continue;
+          }
+        };
+        // handshakes run on their own so slow clients don't hold up others
+        let handshake = handshake.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+          let stream =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake.accept(tcp))
+              .await;
+          match stream {
+            Ok(Ok(Some(stream))) => {
+              let _ = tx.send(Ok(stream)).await;
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+              log::debug!("TLS handshake with {} failed: {}", peer, e)
+            }
+            Err(_) => log::debug!("TLS handshake with {} timed out", peer),
+          }
+        });
+      }
+    });
+    Ok(ReceiverStream::new(rx))
+  }
+}
+
+fn server_config(
+  resolver: Arc<dyn ResolvesServerCert>,
+) -> Result<Arc<ServerConfig>> {
+  let mut config =
+    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+      .with_safe_default_protocol_versions()?
+      .with_no_client_auth()
+      .with_cert_resolver(resolver);
+  config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+  Ok(Arc::new(config))
+}
+
+#[derive(Clone)]
+enum Handshake {
+  Plain(tokio_rustls::TlsAcceptor),
+  #[cfg(feature = "acme")]
+  Acme {
+    config: Arc<ServerConfig>,
+    challenge_config: Arc<ServerConfig>,
+  },
+}
+
+impl Handshake {
+  /// The TLS stream, or nothing if the connection only answered an ACME
+  /// challenge
+  async fn accept(
+    &self,
+    tcp: TcpStream,
+  ) -> std::io::Result<Option<TlsStream<TcpStream>>> {
+    match self {
+      Handshake::Plain(acceptor) => acceptor.accept(tcp).await.map(Some),
+      #[cfg(feature = "acme")]
+      Handshake::Acme {
+        config,
+        challenge_config,
+      } => {
+        let start = tokio_rustls::LazyConfigAcceptor::new(
+          rustls::server::Acceptor::default(),
+          tcp,
+        )
+        .await?;
+        if rustls_acme::is_tls_alpn_challenge(&start.client_hello()) {
+          let mut tls = start.into_stream(challenge_config.clone()).await?;
+          tokio::io::AsyncWriteExt::shutdown(&mut tls).await?;
+          Ok(None)
+        } else {
+          start.into_stream(config.clone()).await.map(Some)
+        }
+      }
+    }
+  }
+}
+
+/// Serves the certificate from files, reloading it when they change
+#[derive(Debug)]
+struct ReloadingCert {
+  cert_path: PathBuf,
+  key_path: PathBuf,
+  current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCert {
+  fn load(cert_path: PathBuf, key_path: PathBuf) -> Result<Self> {
+    let current = RwLock::new(Arc::new(read_cert(&cert_path, &key_path)?));
+    Ok(Self {
+      cert_path,
+      key_path,
+      current,
+    })
+  }
+
+  fn reload(&self) {
+    match read_cert(&self.cert_path, &self.key_path) {
+      Ok(cert) => {
+        let mut current = self.current.write().expect("certificate lock");
+        if current.cert != cert.cert {
+          log::info!("Loaded new TLS certificate");
+        }
+        *current = Arc::new(cert);
+      }
+      // keep serving the old certificate rather than nothing
+      Err(e) => log::error!("Failed to reload TLS certificate: {}", e),
+    }
+  }
+
+  async fn watch(self: Arc<Self>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    let mut hangup =
+      tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .ok();
+    loop {
+      tokio::select! {
+        _ = interval.tick() => {}
+        Some(_) = async { hangup.as_mut()?.recv().await } => {
+          log::info!("Reloading TLS certificate on SIGHUP");
+        }
+      }
+      self.reload();
+    }
+  }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+  fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    Some(self.current.read().expect("certificate lock").clone())
+  }
+}
+
+fn read_cert(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+  let read = |path: &Path| {
+    std::fs::read(path)
+      .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))
+  };
+  let certs = rustls_pemfile::certs(&mut read(cert_path)?.as_slice())
+    .collect::<std::io::Result<Vec<_>>>()
+    .map_err(|e| {
+      anyhow!("Invalid certificate {}: {}", cert_path.display(), e)
+    })?;
+  if certs.is_empty() {
+    return Err(anyhow!("No certificates in {}", cert_path.display()));
+  }
+  let key = rustls_pemfile::private_key(&mut read(key_path)?.as_slice())
+    .map_err(|e| anyhow!("Invalid key {}: {}", key_path.display(), e))?
+    .ok_or_else(|| anyhow!("No private key in {}", key_path.display()))?;
+  let key = ring::sign::any_supported_type(&key)
+    .map_err(|e| anyhow!("Unsupported key {}: {}", key_path.display(), e))?;
+  Ok(CertifiedKey::new(certs, key))
+}
+
+#[cfg(feature = "acme")]
+mod acme {
+  use super::*;
+  use futures::StreamExt;
+  use rustls_acme::caches::DirCache;
+  use rustls_acme::AcmeConfig;
+
+  pub fn handshake(
+    domains: Vec<String>,
+    contact: Option<String>,
+    cache_dir: PathBuf,
+    production: bool,
+  ) -> Result<Handshake> {
+    if domains.is_empty() {
+      return Err(anyhow!("ACME_DOMAINS has no domains"));
+    }
+    let mut state = AcmeConfig::new(domains)
+      .contact(contact.map(|c| format!("mailto:{}", c)))
+      .cache(DirCache::new(cache_dir))
+      .directory_lets_encrypt(production)
+      .state();
+    let challenge_config = state.challenge_rustls_config();
+    let config = server_config(state.resolver())?;
+    // renewals happen in the background and new handshakes pick them up
+    tokio::spawn(async move {
+      while let Some(event) = state.next().await {
+        match event {
+          Ok(event) => log::info!("ACME: {:?}", event),
+          Err(e) => log::error!("ACME error: {:?}", e),
+        }
+      }
+    });
+    Ok(Handshake::Acme {
+      config,
+      challenge_config,
+    })
+  }
+}
@@ -0,0 +1,204 @@
+//! Server-side verification of a range of pulses.
+//!
+//! `GET /verify/<strand>/<start>/<end>` walks the range and checks every
+//! pulse the way a client would: the signature against the strand key, the
+//! link to the previous pulse, and the randomness precommitment made by the
+//! previous pulse. Problems are collected into a report instead of failing
+//! the request, so light clients get the whole picture at once.
+use crate::auth::{self, ApiKeys, RangeLimits};
+use crate::handlers::HttpError;
+use crate::pulses;
+use crate::PortalStore;
+use futures::TryStreamExt;
+use serde::Serialize;
+use std::sync::Arc;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::resolver::AbsoluteRange;
+use warp::Filter;
+
+const BATCH_SIZE: u64 = 100;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum IssueKind {
+  /// The pulse could not be read from the store
+  Missing,
+  Signature,
+  /// The pulse does not link to the one before it
+  Continuity,
+  /// The salt doesn't match the previous pulse's precommitment
+  Randomness,
+}
+
+#[derive(Debug, Serialize)]
+struct Issue {
+  index: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  cid: Option<String>,
+  kind: IssueKind,
+  message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+  strand: String,
+  start: u64,
+  end: u64,
+  checked: u64,
+  valid: bool,
+  issues: Vec<Issue>,
+}
+
+impl Report {
+  fn issue(
+    &mut self,
+    index: u64,
+    cid: Option<Cid>,
+    kind: IssueKind,
+    message: impl ToString,
+  ) {
+    self.issues.push(Issue {
+      index,
+      cid: cid.map(|c| c.to_string()),
+      kind,
+      message: message.to_string(),
+    });
+  }
+}
+
+fn check_pulse(report: &mut Report, twine: &Twine, prev: Option<&Twine>) {
+  let index = twine.index();
+  let cid = Some(twine.cid());
+  if let Err(e) = twine.strand().verify_tixel(twine) {
+    report.issue(index, cid, IssueKind::Signature, e);
+  }
+  // without the previous pulse there is nothing to compare against
+  let prev = match prev {
+    Some(prev) => prev,
+    None => return,
+  };
+  match twine.previous() {
+    Some(stitch) if stitch.tixel == prev.cid() => {}
+    Some(stitch) => report.issue(
+      index,
+      cid,
+      IssueKind::Continuity,
+      format!("links to {} instead of {}", stitch.tixel, prev.cid()),
+    ),
+    None => report.issue(
+      index,
+      cid,
+      IssueKind::Continuity,
+      "has no link to the previous pulse",
+    ),
+  }
+  if let Err(e) = pulses::validate_randomness(twine, prev) {
+    report.issue(index, cid, IssueKind::Randomness, e);
+  }
+}
+
+async fn verify_range(
+  store: &PortalStore,
+  strand: Cid,
+  start: u64,
+  end: u64,
+) -> Result<Report, HttpError> {
+  let mut report = Report {
+    strand: strand.to_string(),
+    start,
+    end,
+    checked: 0,
+    valid: true,
+    issues: vec![],
+  };
+  // the first pulse of the range is checked against the one before it
+  let mut prev = match start.checked_sub(1) {
+    Some(index) => match store.resolve_index(strand, index).await {
+      Ok(twine) => Some(twine.unpack()),
+      Err(ResolutionError::NotFound) => None,
+      Err(e) => return Err(e.into()),
+    },
+    None => None,
+  };
+
+  for batch in AbsoluteRange::new(strand, start, end).batches(BATCH_SIZE) {
+    let twines: Result<Vec<Twine>, _> = match store.resolve_range(batch).await {
+      Ok(stream) => stream.try_collect().await,
+      Err(e) => Err(e),
+    };
+    let twines = match twines {
+      Ok(twines) => twines,
+      Err(e) => {
+        report.issue(
+          batch.lower(),
+          None,
+          IssueKind::Missing,
+          format!("pulses {} could not be read: {}", batch, e),
+        );
+        prev = None;
+        continue;
+      }
+    };
+    for twine in twines {
+      let expected = prev.as_ref().map(|p| p.index() + 1);
+      if expected.is_some_and(|expected| twine.index() != expected) {
+        report.issue(
+          twine.index(),
+          Some(twine.cid()),
+          IssueKind::Missing,
+          format!("expected index {}", expected.unwrap_or_default()),
+        );
+      }
+      check_pulse(&mut report, &twine, prev.as_ref());
+      report.checked += 1;
+      prev = Some(twine);
+    }
+  }
+
+  report.valid = report.issues.is_empty();
+  Ok(report)
+}
+
+async fn verify(
+  strand: Cid,
+  start: u64,
+  end: u64,
+  store: Arc<PortalStore>,
+  max_range: u64,
+) -> Result<warp::reply::Json, HttpError> {
+  if start > end {
+    return Err(HttpError::BadRequest(
+      "The range start must not be after its end".to_string(),
+    ));
+  }
+  if end - start >= max_range {
+    return Err(HttpError::RangeTooLarge(format!(
+      "At most {} pulses can be verified at once",
+      max_range
+    )));
+  }
+  let latest = store.resolve_latest(strand).await?.unpack();
+  if start > latest.index() {
+    return Err(HttpError::Resolution(ResolutionError::NotFound));
+  }
+  let end = end.min(latest.index());
+  let report = verify_range(&store, strand, start, end).await?;
+  Ok(warp::reply::json(&report))
+}
+
+/// The verification route. Ranges are limited like range queries.
+pub fn routes(
+  store: Arc<PortalStore>,
+  keys: Arc<ApiKeys>,
+  limits: RangeLimits,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  warp::path!("verify" / Cid / u64 / u64)
+    .and(warp::get())
+    .and(warp::any().map(move || store.clone()))
+    .and(auth::max_range(keys, limits))
+    .and_then(|strand, start, end, store, max_range| async move {
+      verify(strand, start, end, store, max_range)
+        .await
+        .map_err(warp::reject::custom)
+    })
+}
@@ -0,0 +1,224 @@
+//! External timestamp anchoring of published pulses.
+//!
+//! The sha256 digest of a pulse CID is submitted to an OpenTimestamps
+//! calendar or an RFC 3161 timestamp authority, and the returned proof is
+//! stored in the `Anchors` table next to the tixel. This lets third parties
+//! bound the creation time of a pulse without trusting the beacon's clock.
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use twine_protocol::prelude::Cid;
+use twine_protocol::twine_http_store::reqwest::Client;
+use twine_sql_store::sqlx::MySqlPool;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnchorService {
+  OpenTimestamps(String),
+  Rfc3161(String),
+}
+
+impl AnchorService {
+  fn kind(&self) -> &'static str {
+    match self {
+      AnchorService::OpenTimestamps(_) => "ots",
+      AnchorService::Rfc3161(_) => "rfc3161",
+    }
+  }
+
+  fn url(&self) -> &str {
+    match self {
+      AnchorService::OpenTimestamps(url) => url,
+      AnchorService::Rfc3161(url) => url,
+    }
+  }
+}
+
+/// Parses entries like `ots:https://a.pool.opentimestamps.org`
+/// or `rfc3161:https://freetsa.org/tsr`
+impl FromStr for AnchorService {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.trim().split_once(':') {
+      Some(("ots", url)) => Ok(AnchorService::OpenTimestamps(url.to_string())),
+      Some(("rfc3161", url)) => Ok(AnchorService::Rfc3161(url.to_string())),
+      _ => Err(anyhow::anyhow!(
+        "Invalid anchor service '{}'. Expected ots:<url> or rfc3161:<url>",
+        s
+      )),
+    }
+  }
+}
+
+pub struct Anchorer {
+  services: Vec<AnchorService>,
+  client: Client,
+  pool: MySqlPool,
+}
+
+impl Anchorer {
+  pub fn new(services: Vec<AnchorService>, pool: MySqlPool) -> Self {
+    Self {
+      services,
+      client: Client::new(),
+      pool,
+    }
+  }
+
+  /// Submit the pulse to every configured service and store the proofs.
+  /// Failures are logged, but don't stop the other services.
+  pub async fn anchor(&self, cid: &Cid) {
+    let digest: [u8; 32] = Sha256::digest(cid.to_bytes()).into();
+    for service in &self.services {
+      match self.submit(service, &digest).await {
+        Ok(proof) => {
+          if let Err(e) = self.store_proof(cid, service, &digest, &proof).await
+          {
+            log::error!("Failed to store anchor proof for {}: {}", cid, e);
+          } else {
+            log::info!("Anchored pulse {} with {}", cid, service.url());
+          }
+        }
+        Err(e) => {
+          log::error!("Failed to anchor {} with {}: {}", cid, service.url(), e);
+        }
+      }
+    }
+  }
+
+  async fn submit(
+    &self,
+    service: &AnchorService,
+    digest: &[u8; 32],
+  ) -> Result<Vec<u8>> {
+    let request = match service {
+      AnchorService::OpenTimestamps(url) => self
+        .client
+        .post(format!("{}/digest", url.trim_end_matches('/')))
+        .header("Accept", "application/vnd.opentimestamps.v1")
+        .body(digest.to_vec()),
+      AnchorService::Rfc3161(url) => self
+        .client
+        .post(url)
+        .header("Content-Type", "application/timestamp-query")
+        .body(timestamp_request(digest)),
+    };
+    let response = request.send().await?.error_for_status()?;
+    let proof = response.bytes().await?.to_vec();
+    if let AnchorService::Rfc3161(_) = service {
+      match timestamp_response_status(&proof) {
+        Some(0) | Some(1) => {}
+        status => {
+          return Err(anyhow::anyhow!(
+            "Timestamp authority rejected request (status {:?})",
+            status
+          ))
+        }
+      }
+    }
+    Ok(proof)
+  }
+
+  async fn store_proof(
+    &self,
+    cid: &Cid,
+    service: &AnchorService,
+    digest: &[u8; 32],
+    proof: &[u8],
+  ) -> Result<()> {
+    twine_sql_store::sqlx::query(
+      "INSERT INTO Anchors (tixel, service, kind, digest, proof) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(cid.to_bytes())
+    .bind(service.url())
+    .bind(service.kind())
+    .bind(digest.to_vec())
+    .bind(proof)
+    .execute(&self.pool)
+    .await?;
+    Ok(())
+  }
+}
+
+/// DER encoded RFC 3161 TimeStampReq for a sha256 digest
+fn timestamp_request(digest: &[u8; 32]) -> Vec<u8> {
+  let mut nonce = chrono::Utc::now()
+    .timestamp_nanos_opt()
+    .unwrap_or_default()
+    .to_be_bytes();
+  // keep the nonce a positive, minimally encoded integer
+  nonce[0] = (nonce[0] & 0x7f) | 0x40;
+
+  let mut req = vec![0x30, 0x43];
+  // version
+  req.extend_from_slice(&[0x02, 0x01, 0x01]);
+  // messageImprint { AlgorithmIdentifier { sha256, NULL }, OCTET STRING }
+  req.extend_from_slice(&[0x30, 0x31, 0x30, 0x0d, 0x06, 0x09]);
+  req
+    .extend_from_slice(&[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01]);
+  req.extend_from_slice(&[0x05, 0x00, 0x04, 0x20]);
+  req.extend_from_slice(digest);
+  // nonce
+  req.extend_from_slice(&[0x02, 0x08]);
+  req.extend_from_slice(&nonce);
+  // certReq
+  req.extend_from_slice(&[0x01, 0x01, 0xff]);
+  req
+}
+
+/// Skip a DER tag and length, returning the contents
+fn der_contents(bytes: &[u8], tag: u8) -> Option<&[u8]> {
+  let (&t, rest) = bytes.split_first()?;
+  if t != tag {
+    return None;
+  }
+  let (&len, rest) = rest.split_first()?;
+  if len < 0x80 {
+    return rest.get(..len as usize);
+  }
+  let n = (len & 0x7f) as usize;
+  let len = rest
+    .get(..n)?
+    .iter()
+    .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+  rest.get(n..n + len)
+}
+
+/// Extract the PKIStatus from a DER encoded TimeStampResp
+fn timestamp_response_status(resp: &[u8]) -> Option<u8> {
+  let resp = der_contents(resp, 0x30)?;
+  let status_info = der_contents(resp, 0x30)?;
+  match der_contents(status_info, 0x02)? {
+    [status] => Some(*status),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_services() {
+    assert_eq!(
+      "ots:https://a.pool.opentimestamps.org"
+        .parse::<AnchorService>()
+        .unwrap(),
+      AnchorService::OpenTimestamps(
+        "https://a.pool.opentimestamps.org".to_string()
+      )
+    );
+    assert!("https://freetsa.org".parse::<AnchorService>().is_err());
+  }
+
+  #[test]
+  fn test_timestamp_request_encoding() {
+    let req = timestamp_request(&[0xab; 32]);
+    assert_eq!(req.len(), 0x43 + 2);
+    let contents = der_contents(&req, 0x30).unwrap();
+    assert_eq!(contents.len(), 0x43);
+    // a granted response with no token
+    let resp = [0x30, 0x05, 0x30, 0x03, 0x02, 0x01, 0x00];
+    assert_eq!(timestamp_response_status(&resp), Some(0));
+  }
+}
@@ -0,0 +1,326 @@
+//! The generator's settings, read once and checked at startup (see
+//! [biab_utils::config_value] for where they're read from).
+//!
+//! Helpers with settings of their own (eg: the rng factory deliveries, the
+//! python rng, the peers) read them when they're created, which is at
+//! startup too.
+use crate::anchoring::AnchorService;
+use crate::external_beacons::ExternalBeacon;
+use crate::timing;
+use anyhow::{anyhow, Result};
+use biab_utils::{
+  config_list, config_or, config_parse, config_secret, config_value,
+  required_config_value, Secret,
+};
+use chrono::{Duration, TimeDelta};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+const DEFAULT_PULSE_PERIOD: &str = "1m";
+const DEFAULT_LEAD_TIME_SECONDS: u64 = 10;
+
+#[derive(Debug, Serialize)]
+pub struct Config {
+  pub strand_json_path: String,
+  /// The details of the strand, if it has to be created
+  pub strand_config_path: Option<String>,
+  /// The period of the strand, if it has to be created
+  pub pulse_period: String,
+  pub rng_storage_path: String,
+  /// From `LEAD_TIME_MS`, or else `LEAD_TIME_SECONDS`
+  pub lead_time_ms: u64,
+  /// How many signatures to time at startup, 0 to skip the self-test
+  pub signer_self_test_rounds: u32,
+  pub stitch_config_path: Option<String>,
+  pub signer: SignerConfig,
+  pub cosigners: Option<CosignersConfig>,
+  pub anchor_services: Vec<String>,
+  pub external_beacons: Vec<String>,
+  pub entropy_archive: Option<EntropyArchiveConfig>,
+  pub rng_script: String,
+  pub rng_script_fallback: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignerConfig {
+  /// A key in a PEM file, at `PRIVATE_KEY_PATH`
+  Key { private_key_path: String },
+  /// A key in a YubiHSM2
+  Hsm {
+    address: String,
+    port: u16,
+    auth_key_id: u16,
+    password: Secret,
+    signing_key_id: u16,
+    /// How often to ping the HSM to keep the session open, 0 for never
+    keepalive_secs: u64,
+    /// How many times to try signing, on a new session after each failure
+    sign_attempts: u32,
+  },
+}
+
+/// The operators co-signing each pulse with keys of their own (see
+/// [biab_utils::Cosigners]), from `COSIGNERS_PATH`
+#[derive(Debug, Serialize)]
+pub struct CosignersConfig {
+  /// How many of them must sign each pulse
+  pub threshold: usize,
+  pub signers: Vec<(String, SignerConfig)>,
+}
+
+/// Expected yaml structure, at `COSIGNERS_PATH`:
+/// ```yaml
+/// threshold: 2
+/// signers:
+///   - name: alice
+///     hsm:
+///       address: hsm-alice:12345
+///       auth_key_id: 2
+///       password_file: /run/secrets/alice_hsm_password
+///       signing_key_id: 0x0100
+///   - name: bob
+///     private_key_path: /run/secrets/bob_key.pem
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CosignersFile {
+  threshold: usize,
+  signers: Vec<SignerEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SignerEntry {
+  name: String,
+  private_key_path: Option<String>,
+  hsm: Option<HsmEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HsmEntry {
+  /// `host:port`, the port defaulting to 12345
+  address: String,
+  #[serde(default = "default_auth_key_id")]
+  auth_key_id: u16,
+  password: Option<Secret>,
+  /// Or a file holding it
+  password_file: Option<PathBuf>,
+  signing_key_id: u16,
+}
+
+fn default_auth_key_id() -> u16 {
+  1
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntropyArchiveConfig {
+  pub path: String,
+  pub public_key: String,
+  pub retention_days: Option<i64>,
+}
+
+impl Config {
+  pub fn load() -> Result<Self> {
+    let pulse_period = config_value("PULSE_PERIOD")?
+      .unwrap_or_else(|| DEFAULT_PULSE_PERIOD.to_string());
+    timing::parse_period(&pulse_period)
+      .map_err(|e| anyhow!("Invalid PULSE_PERIOD: {}", e))?;
+    let anchor_services = config_list("ANCHOR_SERVICES")?;
+    for service in &anchor_services {
+      service
+        .parse::<AnchorService>()
+        .map_err(|e| anyhow!("Invalid ANCHOR_SERVICES: {}", e))?;
+    }
+    let external_beacons = config_list("EXTERNAL_BEACONS")?;
+    for beacon in &external_beacons {
+      beacon
+        .parse::<ExternalBeacon>()
+        .map_err(|e| anyhow!("Invalid EXTERNAL_BEACONS: {}", e))?;
+    }
+    Ok(Self {
+      strand_json_path: required_config_value("STRAND_JSON_PATH")?,
+      strand_config_path: config_value("STRAND_CONFIG_PATH")?,
+      pulse_period,
+      rng_storage_path: required_config_value("RNG_STORAGE_PATH")?,
+      lead_time_ms: load_lead_time_ms()?,
+      signer_self_test_rounds: config_or("SIGNER_SELF_TEST_ROUNDS", 3)?,
+      stitch_config_path: config_value("STITCH_CONFIG_PATH")?,
+      signer: SignerConfig::load()?,
+      cosigners: CosignersConfig::load()?,
+      anchor_services,
+      external_beacons,
+      entropy_archive: EntropyArchiveConfig::load()?,
+      rng_script: config_value("RNG_SCRIPT")?
+        .unwrap_or_else(|| "rng.py".to_string()),
+      rng_script_fallback: config_value("RNG_SCRIPT_FALLBACK")?,
+    })
+  }
+
+  pub fn lead_time(&self) -> Duration {
+    Duration::milliseconds(self.lead_time_ms as i64)
+  }
+
+  /// The period of a new strand
+  pub fn pulse_period(&self) -> Result<TimeDelta> {
+    timing::parse_period(&self.pulse_period)
+  }
+}
+
+impl SignerConfig {
+  pub fn load() -> Result<Self> {
+    if let Some(private_key_path) = config_value("PRIVATE_KEY_PATH")? {
+      return Ok(Self::Key { private_key_path });
+    }
+    let hsm_url = config_value("HSM_ADDRESS")?.ok_or_else(|| {
+      anyhow!("No signing key: set PRIVATE_KEY_PATH, or HSM_ADDRESS to sign with a YubiHSM2")
+    })?;
+    let (address, port) = parse_hsm_address(&hsm_url)
+      .map_err(|e| anyhow!("Invalid HSM_ADDRESS {:?}: {}", hsm_url, e))?;
+    // might also be in hex
+    let signing_key_id = required_config_value("HSM_SIGNING_KEY_ID")?;
+    let signing_key_id = parse_u16(&signing_key_id).map_err(|e| {
+      anyhow!("Invalid HSM_SIGNING_KEY_ID {:?}: {}", signing_key_id, e)
+    })?;
+    Ok(Self::Hsm {
+      address,
+      port,
+      auth_key_id: config_or("HSM_AUTH_KEY_ID", 1)?,
+      password: config_secret("HSM_PASSWORD")?.ok_or_else(|| {
+        anyhow!("HSM_PASSWORD is required to sign with a YubiHSM2")
+      })?,
+      signing_key_id,
+      keepalive_secs: config_or("HSM_KEEPALIVE_SECS", 20)?,
+      sign_attempts: config_or("HSM_SIGN_ATTEMPTS", 3)?,
+    })
+  }
+}
+
+impl CosignersConfig {
+  /// The HSMs share the `HSM_KEEPALIVE_SECS` and `HSM_SIGN_ATTEMPTS`
+  /// settings
+  fn load() -> Result<Option<Self>> {
+    let Some(path) = config_value("COSIGNERS_PATH")? else {
+      return Ok(None);
+    };
+    let file = std::fs::File::open(&path)
+      .map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+    let config: CosignersFile =
+      serde_yaml::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| anyhow!("Invalid cosigners file {}: {}", path, e))?;
+    let keepalive_secs = config_or("HSM_KEEPALIVE_SECS", 20)?;
+    let sign_attempts = config_or("HSM_SIGN_ATTEMPTS", 3)?;
+    let signers = config
+      .signers
+      .into_iter()
+      .map(|entry| {
+        let signer = match (entry.private_key_path, entry.hsm) {
+          (Some(private_key_path), None) => {
+            SignerConfig::Key { private_key_path }
+          }
+          (None, Some(hsm)) => {
+            let (address, port) = parse_hsm_address(&hsm.address)?;
+            let password = Secret::inline_or_file(
+              "password",
+              hsm.password,
+              hsm.password_file.as_deref(),
+            )?
+            .ok_or_else(|| anyhow!("No password for the HSM"))?;
+            SignerConfig::Hsm {
+              address,
+              port,
+              auth_key_id: hsm.auth_key_id,
+              password,
+              signing_key_id: hsm.signing_key_id,
+              keepalive_secs,
+              sign_attempts,
+            }
+          }
+          _ => {
+            return Err(anyhow!("Set either private_key_path or hsm"));
+          }
+        };
+        Ok((entry.name, signer))
+      })
+      .enumerate()
+      .map(|(i, signer)| {
+        signer.map_err(|e| anyhow!("Invalid signer {} in {}: {}", i, path, e))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    Ok(Some(Self {
+      threshold: config.threshold,
+      signers,
+    }))
+  }
+}
+
+/// The lead time in milliseconds, `LEAD_TIME_MS` taking precedence over
+/// `LEAD_TIME_SECONDS` (for periods too short for whole seconds)
+fn load_lead_time_ms() -> Result<u64> {
+  let ms = match config_parse::<u64>("LEAD_TIME_MS")? {
+    Some(ms) => Some(ms),
+    None => config_or("LEAD_TIME_SECONDS", DEFAULT_LEAD_TIME_SECONDS)?
+      .checked_mul(1000),
+  };
+  ms.filter(|ms| {
+    i64::try_from(*ms).is_ok_and(|ms| Duration::try_milliseconds(ms).is_some())
+  })
+  .ok_or_else(|| anyhow!("The lead time is too long"))
+}
+
+impl EntropyArchiveConfig {
+  fn load() -> Result<Option<Self>> {
+    let Some(path) = config_value("ENTROPY_ARCHIVE_PATH")? else {
+      return Ok(None);
+    };
+    let public_key =
+      config_value("ENTROPY_ARCHIVE_PUBLIC_KEY")?.ok_or_else(|| {
+        anyhow!("ENTROPY_ARCHIVE_PUBLIC_KEY is required to archive entropy")
+      })?;
+    Ok(Some(Self {
+      path,
+      public_key,
+      retention_days: config_parse("ENTROPY_ARCHIVE_RETENTION_DAYS")?,
+    }))
+  }
+}
+
+/// `host:port`, or `host` on the default port
+fn parse_hsm_address(url: &str) -> Result<(String, u16)> {
+  match url.split_once(':') {
+    Some((address, port)) => Ok((address.to_string(), port.parse()?)),
+    None => Ok((url.to_string(), 12345)),
+  }
+}
+
+fn parse_u16(s: &str) -> Result<u16> {
+  match s.strip_prefix("0x") {
+    Some(hex) => Ok(u16::from_str_radix(hex, 16)?),
+    None => Ok(s.parse()?),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_u16() {
+    assert_eq!(parse_u16("0x0a").unwrap(), 10);
+    assert_eq!(parse_u16("12").unwrap(), 12);
+    assert_eq!(parse_u16("1").unwrap(), 1);
+    assert!(parse_u16("0xzz").is_err());
+  }
+
+  #[test]
+  fn test_parse_hsm_address() {
+    assert_eq!(
+      parse_hsm_address("hsm:1234").unwrap(),
+      ("hsm".to_string(), 1234)
+    );
+    assert_eq!(
+      parse_hsm_address("hsm").unwrap(),
+      ("hsm".to_string(), 12345)
+    );
+    assert!(parse_hsm_address("hsm:port").is_err());
+  }
+}
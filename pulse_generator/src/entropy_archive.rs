@@ -0,0 +1,159 @@
+//! Optional encrypted archive of the raw entropy used for each pulse.
+//!
+//! Records are sealed (anonymous crypto_box) to an operator's X25519 public
+//! key, so the generator can't read back what it has written. Every line of
+//! an archive file is one base64 encoded sealed record. Files are rotated
+//! daily and removed once they are older than the retention period.
+use anyhow::Result;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use crypto_box::{aead::OsRng, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use twine_protocol::prelude::Cid;
+
+const FILE_PREFIX: &str = "entropy-";
+const FILE_SUFFIX: &str = ".sealed";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveRecord {
+  pub index: u64,
+  pub cid: String,
+  pub archived_at: DateTime<Utc>,
+  /// hex encoded randomness
+  pub randomness: String,
+}
+
+pub struct EntropyArchive {
+  dir: PathBuf,
+  public_key: PublicKey,
+  retention: Option<TimeDelta>,
+}
+
+impl EntropyArchive {
+  pub fn new<P: Into<PathBuf>>(dir: P, public_key: [u8; 32]) -> Self {
+    Self {
+      dir: dir.into(),
+      public_key: PublicKey::from(public_key),
+      retention: None,
+    }
+  }
+
+  /// Parse the operator public key from a hex string
+  pub fn from_hex_key<P: Into<PathBuf>>(dir: P, key: &str) -> Result<Self> {
+    let bytes = hex::decode(key.trim())?;
+    let key: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+      anyhow::anyhow!(
+        "Archive public key must be 32 bytes, got {}",
+        bytes.len()
+      )
+    })?;
+    Ok(Self::new(dir, key))
+  }
+
+  pub fn with_retention(mut self, retention: TimeDelta) -> Self {
+    self.retention = Some(retention);
+    self
+  }
+
+  fn file_for(&self, date: NaiveDate) -> PathBuf {
+    self.dir.join(format!(
+      "{}{}{}",
+      FILE_PREFIX,
+      date.format("%Y%m%d"),
+      FILE_SUFFIX
+    ))
+  }
+
+  /// Seal and append the randomness used in a pulse
+  pub fn append(&self, index: u64, cid: &Cid, randomness: &[u8]) -> Result<()> {
+    let now = Utc::now();
+    let record = ArchiveRecord {
+      index,
+      cid: cid.to_string(),
+      archived_at: now,
+      randomness: hex::encode(randomness),
+    };
+    let plaintext = serde_json::to_vec(&record)?;
+    let sealed = self
+      .public_key
+      .seal(&mut OsRng, &plaintext)
+      .map_err(|e| anyhow::anyhow!("Failed to seal archive record: {}", e))?;
+    let line = base64::engine::general_purpose::STANDARD.encode(sealed);
+
+    std::fs::create_dir_all(&self.dir)?;
+    let mut file = std::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(self.file_for(now.date_naive()))?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+
+    self.prune(now)?;
+    Ok(())
+  }
+
+  /// Remove archive files older than the retention period
+  fn prune(&self, now: DateTime<Utc>) -> Result<()> {
+    let retention = match self.retention {
+      Some(retention) => retention,
+      None => return Ok(()),
+    };
+    let cutoff = (now - retention).date_naive();
+    for entry in std::fs::read_dir(&self.dir)? {
+      let path = entry?.path();
+      let date = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_prefix(FILE_PREFIX))
+        .and_then(|n| n.strip_suffix(FILE_SUFFIX))
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok());
+      if let Some(date) = date {
+        if date < cutoff {
+          log::info!("Removing expired entropy archive {}", path.display());
+          std::fs::remove_file(&path)?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crypto_box::SecretKey;
+
+  #[test]
+  fn test_append_and_prune() {
+    let dir = std::env::temp_dir()
+      .join(format!("entropy-archive-test-{}", std::process::id()));
+    let secret = SecretKey::generate(&mut OsRng);
+    let archive = EntropyArchive::new(&dir, secret.public_key().to_bytes())
+      .with_retention(TimeDelta::days(7));
+
+    let stale =
+      archive.file_for((Utc::now() - TimeDelta::days(30)).date_naive());
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&stale, "").unwrap();
+
+    let rand = [7u8; 64];
+    archive.append(3, &Cid::default(), &rand).unwrap();
+
+    assert!(!stale.exists());
+    let contents =
+      std::fs::read_to_string(archive.file_for(Utc::now().date_naive()))
+        .unwrap();
+    let line = contents.lines().next().unwrap();
+    let sealed = base64::engine::general_purpose::STANDARD
+      .decode(line)
+      .unwrap();
+    let record: ArchiveRecord =
+      serde_json::from_slice(&secret.unseal(&sealed).unwrap()).unwrap();
+    assert_eq!(record.index, 3);
+    assert_eq!(record.randomness, hex::encode(rand));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
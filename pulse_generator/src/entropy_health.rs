@@ -0,0 +1,209 @@
+//! Quick statistical health checks on incoming entropy.
+//!
+//! These don't replace validation of the entropy source itself, but they
+//! catch obviously broken output (stuck values, heavy bias) before it is
+//! committed to a pulse. Bytes are treated as 8 bit samples.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Repetition count test cutoff for alpha = 2^-20 and H = 8
+/// (C = 1 + ceil(20 / H), SP 800-90B section 4.4.1)
+const REPETITION_CUTOFF: usize = 4;
+/// Adaptive proportion test window and cutoff for non-binary samples with
+/// H = 8 (SP 800-90B section 4.4.2)
+const PROPORTION_WINDOW: usize = 512;
+const PROPORTION_CUTOFF: usize = 13;
+/// Number of pooled bytes over which the chi-square test is computed
+const CHI_SQUARE_WINDOW: usize = 4096;
+/// Chi-square critical value for 255 degrees of freedom at p ~ 1e-6
+const CHI_SQUARE_CRITICAL: f64 = 377.2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthError {
+  Repetition { value: u8, count: usize },
+  Proportion { value: u8, count: usize },
+  ChiSquare { statistic: f64 },
+}
+
+impl std::fmt::Display for HealthError {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      HealthError::Repetition { value, count } => write!(
+        f,
+        "repetition count test failed: byte 0x{:02x} repeated {} times",
+        value, count
+      ),
+      HealthError::Proportion { value, count } => write!(
+        f,
+        "adaptive proportion test failed: byte 0x{:02x} seen {} times in {} samples",
+        value, count, PROPORTION_WINDOW
+      ),
+      HealthError::ChiSquare { statistic } => write!(
+        f,
+        "chi-square test failed: statistic {:.1} exceeds {:.1}",
+        statistic, CHI_SQUARE_CRITICAL
+      ),
+    }
+  }
+}
+
+impl std::error::Error for HealthError {}
+
+pub fn repetition_count_test(samples: &[u8]) -> Result<(), HealthError> {
+  let mut iter = samples.iter();
+  let mut last = match iter.next() {
+    Some(b) => *b,
+    None => return Ok(()),
+  };
+  let mut count = 1;
+  for &b in iter {
+    if b == last {
+      count += 1;
+      if count >= REPETITION_CUTOFF {
+        return Err(HealthError::Repetition { value: b, count });
+      }
+    } else {
+      last = b;
+      count = 1;
+    }
+  }
+  Ok(())
+}
+
+pub fn adaptive_proportion_test(samples: &[u8]) -> Result<(), HealthError> {
+  for window in samples.chunks(PROPORTION_WINDOW) {
+    let first = window[0];
+    let count = window.iter().filter(|&&b| b == first).count();
+    if count >= PROPORTION_CUTOFF {
+      return Err(HealthError::Proportion {
+        value: first,
+        count,
+      });
+    }
+  }
+  Ok(())
+}
+
+pub fn chi_square_statistic(samples: &[u8]) -> f64 {
+  let mut counts = [0usize; 256];
+  samples.iter().for_each(|&b| counts[b as usize] += 1);
+  let expected = samples.len() as f64 / 256.0;
+  counts
+    .iter()
+    .map(|&c| {
+      let diff = c as f64 - expected;
+      diff * diff / expected
+    })
+    .sum()
+}
+
+/// Keeps a rolling pool of previously accepted entropy so that tests
+/// which need more samples than a single pulse provides can be applied.
+#[derive(Debug, Default)]
+pub struct EntropyGate {
+  pool: Mutex<VecDeque<u8>>,
+}
+
+impl EntropyGate {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Check a candidate sample. If it passes, it is added to the pool.
+  pub fn check(&self, sample: &[u8]) -> Result<(), HealthError> {
+    let mut pool = self.pool.lock().expect("Failed to acquire lock");
+
+    repetition_count_test(sample)?;
+
+    // the proportion test runs over the tail of the pool plus the sample
+    // so that it sees a full window once enough data has been collected
+    let tail = PROPORTION_WINDOW
+      .saturating_sub(sample.len())
+      .min(pool.len());
+    let recent: Vec<u8> = pool
+      .iter()
+      .skip(pool.len() - tail)
+      .chain(sample.iter())
+      .copied()
+      .collect();
+    adaptive_proportion_test(&recent)?;
+
+    if pool.len() + sample.len() >= CHI_SQUARE_WINDOW {
+      let skip = pool.len() + sample.len() - CHI_SQUARE_WINDOW;
+      let window: Vec<u8> = pool
+        .iter()
+        .chain(sample.iter())
+        .skip(skip)
+        .copied()
+        .collect();
+      let statistic = chi_square_statistic(&window);
+      if statistic > CHI_SQUARE_CRITICAL {
+        // the pooled history can no longer be trusted either
+        pool.clear();
+        return Err(HealthError::ChiSquare { statistic });
+      }
+    }
+
+    pool.extend(sample.iter().copied());
+    let excess = pool.len().saturating_sub(CHI_SQUARE_WINDOW);
+    pool.drain(..excess);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // deterministic but well distributed bytes
+  fn sample(seed: u64, len: usize) -> Vec<u8> {
+    let mut x = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..len)
+      .map(|_| {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        (x >> 24) as u8
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_repetition_count() {
+    assert!(repetition_count_test(&sample(1, 64)).is_ok());
+    let mut stuck = sample(1, 64);
+    stuck[10..14].copy_from_slice(&[7, 7, 7, 7]);
+    assert!(matches!(
+      repetition_count_test(&stuck),
+      Err(HealthError::Repetition { value: 7, .. })
+    ));
+  }
+
+  #[test]
+  fn test_adaptive_proportion() {
+    assert!(adaptive_proportion_test(&sample(2, 512)).is_ok());
+    let mut biased = sample(2, 512);
+    for i in (0..512).step_by(32) {
+      biased[i] = biased[0];
+    }
+    assert!(adaptive_proportion_test(&biased).is_err());
+  }
+
+  #[test]
+  fn test_gate_pools_samples() {
+    let gate = EntropyGate::new();
+    for seed in 1..100 {
+      assert!(gate.check(&sample(seed, 64)).is_ok());
+    }
+    assert_eq!(gate.pool.lock().unwrap().len(), CHI_SQUARE_WINDOW);
+
+    let stuck = vec![0u8; 64];
+    assert!(gate.check(&stuck).is_err());
+    assert!(gate.pool.lock().unwrap().iter().any(|&b| b != 0));
+
+    let flat: Vec<u8> = (0..64).map(|i| (i % 4) as u8).collect();
+    let gate = EntropyGate::new();
+    let failed = (0..64).any(|_| gate.check(&flat).is_err());
+    assert!(failed);
+  }
+}
@@ -0,0 +1,156 @@
+//! Fetches the latest values of other public randomness beacons so they
+//! can be recorded in our pulses.
+//!
+//! Including another beacon's output in a pulse means our pulse could not
+//! have been computed before that value was published, which chains the
+//! unpredictability guarantees of both beacons.
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use twine_protocol::twine_http_store::reqwest::Client;
+use twine_protocol::twine_lib::Bytes;
+
+const NIST_DEFAULT_URL: &str = "https://beacon.nist.gov/beacon/2.0";
+const DRAND_DEFAULT_URL: &str = "https://api.drand.sh";
+const FETCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// A value from an external beacon, as recorded in the pulse payload
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExternalSource {
+  pub source: String,
+  pub uri: String,
+  pub round: u64,
+  pub value: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExternalBeacon {
+  Nist(String),
+  Drand(String),
+}
+
+/// Parses entries like `nist`, `drand` or `drand:https://api.drand.sh`
+impl FromStr for ExternalBeacon {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let (kind, url) = match s.trim().split_once(':') {
+      Some((kind, url)) => (kind, Some(url.to_string())),
+      None => (s.trim(), None),
+    };
+    match kind {
+      "nist" => Ok(ExternalBeacon::Nist(
+        url.unwrap_or(NIST_DEFAULT_URL.to_string()),
+      )),
+      "drand" => Ok(ExternalBeacon::Drand(
+        url.unwrap_or(DRAND_DEFAULT_URL.to_string()),
+      )),
+      _ => Err(anyhow::anyhow!(
+        "Invalid external beacon '{}'. Expected nist[:<url>] or drand[:<url>]",
+        s
+      )),
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NistPulse {
+  pulse_index: u64,
+  output_value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NistResponse {
+  pulse: NistPulse,
+}
+
+#[derive(Debug, Deserialize)]
+struct DrandResponse {
+  round: u64,
+  randomness: String,
+}
+
+pub struct ExternalBeacons {
+  beacons: Vec<ExternalBeacon>,
+  client: Client,
+}
+
+impl ExternalBeacons {
+  pub fn new(beacons: Vec<ExternalBeacon>) -> Result<Self> {
+    let client = Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    Ok(Self { beacons, client })
+  }
+
+  /// Fetch the latest value of every configured beacon. Beacons that can't
+  /// be reached are logged and left out.
+  pub async fn fetch_all(&self) -> Vec<ExternalSource> {
+    let results = futures::future::join_all(
+      self.beacons.iter().map(|beacon| self.fetch(beacon)),
+    )
+    .await;
+    results
+      .into_iter()
+      .zip(self.beacons.iter())
+      .filter_map(|(res, beacon)| match res {
+        Ok(source) => Some(source),
+        Err(e) => {
+          log::error!("Failed to fetch external beacon {:?}: {}", beacon, e);
+          None
+        }
+      })
+      .collect()
+  }
+
+  async fn fetch(&self, beacon: &ExternalBeacon) -> Result<ExternalSource> {
+    match beacon {
+      ExternalBeacon::Nist(url) => {
+        let uri = format!("{}/pulse/last", url.trim_end_matches('/'));
+        let body = self.get(&uri).await?;
+        let res: NistResponse = serde_json::from_slice(&body)?;
+        Ok(ExternalSource {
+          source: "nist".to_string(),
+          uri,
+          round: res.pulse.pulse_index,
+          value: hex::decode(res.pulse.output_value)?.into(),
+        })
+      }
+      ExternalBeacon::Drand(url) => {
+        let uri = format!("{}/public/latest", url.trim_end_matches('/'));
+        let body = self.get(&uri).await?;
+        let res: DrandResponse = serde_json::from_slice(&body)?;
+        Ok(ExternalSource {
+          source: "drand".to_string(),
+          uri,
+          round: res.round,
+          value: hex::decode(res.randomness)?.into(),
+        })
+      }
+    }
+  }
+
+  async fn get(&self, uri: &str) -> Result<Vec<u8>> {
+    let res = self.client.get(uri).send().await?.error_for_status()?;
+    Ok(res.bytes().await?.to_vec())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_beacons() {
+    assert_eq!(
+      "nist".parse::<ExternalBeacon>().unwrap(),
+      ExternalBeacon::Nist(NIST_DEFAULT_URL.to_string())
+    );
+    assert_eq!(
+      "drand:https://drand.example.com"
+        .parse::<ExternalBeacon>()
+        .unwrap(),
+      ExternalBeacon::Drand("https://drand.example.com".to_string())
+    );
+    assert!("random.org".parse::<ExternalBeacon>().is_err());
+  }
+}
@@ -0,0 +1,346 @@
+//! Randomness delivered by the rng factory.
+//!
+//! With `RNG_FACTORY=true` the generator listens on `LISTEN_ADDR` (default
+//! `0.0.0.0:5555`) for randomness deliveries and uses the latest one to
+//! assemble each pulse instead of running `RNG_SCRIPT`. A delivery is only
+//! used once, and not if it's older than [MAX_AGE]. After every pulse, the
+//! pulse period is advertised to the rng factory at `RNG_FACTORY_ADDR` so
+//! its deliveries can keep pace, and each time randomness is taken that's
+//! acknowledged, so it can hold back deliveries while they aren't taken.
+//! These notifications go over one connection kept open to the rng factory
+//! (see [MessengerClient]).
+//!
+//! With `RNG_FACTORY=request` it asks the rng factory at `RNG_FACTORY_ADDR`
+//! (default `rng_factory:5555`) for fresh randomness when it's needed
+//! instead, and only accepts the delivery answering that request.
+//!
+//! The id of each delivery used is logged, to find it in the rng factory's
+//! archive, and its attestation (where its randomness came from) is logged
+//! with its SHA-256 digest, and with `RECORD_ATTESTATION=true` the digest is
+//! recorded in the pulse too.
+//!
+//! With a delivery key configured (see [DeliveryAuth]), deliveries that
+//! aren't authenticated with it, weren't issued in the last [MAX_AGE] or
+//! were issued before one already accepted (ie: replayed) are rejected.
+use anyhow::{anyhow, Result};
+use biab_utils::{config_flag, config_value, Peer, CONSUMED_COMMAND};
+use biab_utils::{ClientHandle, MessengerClient};
+use biab_utils::{Consumed, DeliveryAuth, EntropyRequest, Message, Messenger};
+use biab_utils::{RandomnessDelivery, RANDOMNESS_COMMAND};
+use biab_utils::{StrandPeriod, NEED_ENTROPY_COMMAND, STRAND_PERIOD_COMMAND};
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How old a delivery can be and still go into a pulse
+const MAX_AGE: Duration = Duration::from_secs(30);
+/// How long the rng factory has to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many notifications for the rng factory are kept while it can't be
+/// reached
+const NOTIFICATIONS: usize = 8;
+/// How far ahead of ours the rng factory's clock can be
+const CLOCK_SKEW: Duration = Duration::from_secs(5);
+
+type Latest = Arc<Mutex<Option<(Instant, RandomnessDelivery)>>>;
+
+enum Mode {
+  /// Deliveries are pushed, and the latest is kept
+  Push(Latest, ClientHandle),
+  /// Deliveries are requested from the rng factory
+  Request(Peer),
+}
+
+pub struct Deliveries {
+  mode: Mode,
+  verifier: Option<Arc<Verifier>>,
+  /// Whether attestation digests go into pulses
+  record_attestation: bool,
+}
+
+/// Checks deliveries are authentic, fresh and not replayed
+struct Verifier {
+  auth: DeliveryAuth,
+  last_issued: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl Verifier {
+  fn new(auth: DeliveryAuth) -> Self {
+    Self {
+      auth,
+      last_issued: Mutex::new(None),
+    }
+  }
+
+  fn verify(
+    &self,
+    delivery: &RandomnessDelivery,
+    now: DateTime<Utc>,
+  ) -> Result<()> {
+    self.auth.verify(delivery)?;
+    let issued_at = delivery
+      .issued_at
+      .ok_or_else(|| anyhow!("The delivery has no issue time"))?;
+    let age = (now - issued_at).to_std().unwrap_or(Duration::ZERO);
+    if issued_at - now > chrono::Duration::from_std(CLOCK_SKEW)? {
+      return Err(anyhow!("The delivery was issued in the future"));
+    }
+    if age > MAX_AGE {
+      return Err(anyhow!("The delivery was issued {}s ago", age.as_secs()));
+    }
+    let mut last_issued =
+      self.last_issued.lock().expect("Failed to acquire lock");
+    if last_issued.is_some_and(|last| issued_at <= last) {
+      return Err(anyhow!("The delivery was replayed"));
+    }
+    *last_issued = Some(issued_at);
+    Ok(())
+  }
+}
+
+/// The randomness of a delivery received `age` ago, if it's usable. Bytes
+/// past the 64 a pulse takes are ignored.
+fn check(delivery: RandomnessDelivery, age: Duration) -> Result<[u8; 64]> {
+  if age > MAX_AGE {
+    return Err(anyhow!(
+      "The latest delivery from {} is {}s old",
+      delivery.source,
+      age.as_secs()
+    ));
+  }
+  delivery
+    .bytes
+    .get(..64)
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or_else(|| {
+      anyhow!(
+        "Expected 64 bytes of randomness, {} delivered {}",
+        delivery.source,
+        delivery.bytes.len()
+      )
+    })
+}
+
+/// The delivery in a response, if it answers the request
+fn answer(request: &Message, response: Message) -> Result<RandomnessDelivery> {
+  if response.command != RANDOMNESS_COMMAND {
+    return Err(anyhow!("Unexpected {} response", response.command));
+  }
+  let delivery: RandomnessDelivery = response
+    .extract_payload()?
+    .ok_or_else(|| anyhow!("Received a delivery without randomness"))?;
+  if delivery.request_id != Some(request.id) {
+    return Err(anyhow!(
+      "The delivery doesn't answer request {}",
+      request.id
+    ));
+  }
+  Ok(delivery)
+}
+
+async fn request(peer: &Peer) -> Result<RandomnessDelivery> {
+  let mut stream = peer.connect().await?;
+  let messenger = Messenger::new();
+  let request =
+    messenger.delivery(NEED_ENTROPY_COMMAND, &EntropyRequest { bytes: 64 });
+  messenger.send(&mut stream, &request).await?;
+  let response =
+    tokio::time::timeout(REQUEST_TIMEOUT, messenger.receive(&mut stream))
+      .await
+      .map_err(|_| anyhow!("The rng factory didn't answer in time"))?
+      .ok_or_else(|| anyhow!("The rng factory didn't answer"))?;
+  answer(&request, response)
+}
+
+impl Deliveries {
+  pub fn from_env(shutdown: Arc<Notify>) -> Result<Option<Self>> {
+    let verifier = DeliveryAuth::verifier_from_env()?
+      .map(|auth| Arc::new(Verifier::new(auth)));
+    let mode = match config_value("RNG_FACTORY")?.as_deref() {
+      Some("request") => Mode::Request(rng_factory()?),
+      // anything else turns it on or off
+      Some(_) if config_flag("RNG_FACTORY")? => {
+        let latest = listen(shutdown.clone(), verifier.clone())?;
+        let client = MessengerClient::new(rng_factory()?, NOTIFICATIONS);
+        Mode::Push(latest, client.spawn(shutdown))
+      }
+      _ => return Ok(None),
+    };
+    let record_attestation = config_flag("RECORD_ATTESTATION")?;
+    Ok(Some(Self {
+      mode,
+      verifier,
+      record_attestation,
+    }))
+  }
+
+  /// Take the latest delivery's randomness, or request some, along with
+  /// the digest of its attestation if it's to be recorded
+  pub async fn take(&self) -> Result<([u8; 64], Option<Vec<u8>>)> {
+    let (delivery, age) = match &self.mode {
+      Mode::Push(latest, rng_factory) => {
+        let latest = latest.lock().expect("Failed to acquire lock").take();
+        let id = latest.as_ref().and_then(|(_, delivery)| delivery.id);
+        rng_factory.push_delivery(CONSUMED_COMMAND, &Consumed { id });
+        match latest {
+          Some((received, delivery)) => (delivery, received.elapsed()),
+          None => return Err(anyhow!("No randomness has been delivered")),
+        }
+      }
+      Mode::Request(peer) => {
+        let delivery = request(peer).await?;
+        if let Some(verifier) = &self.verifier {
+          verifier.verify(&delivery, Utc::now())?;
+        }
+        (delivery, Duration::ZERO)
+      }
+    };
+    if let Some(id) = delivery.id {
+      log::info!("Using randomness delivery {}", id);
+    }
+    if let Some(conditioning) = &delivery.conditioning {
+      log::info!(
+        "Using randomness from {} conditioned with {}",
+        delivery.sources.join(", "),
+        conditioning.function
+      );
+    }
+    let attestation = delivery.attestation.as_ref().map(|attestation| {
+      let digest = attestation.digest();
+      log::info!(
+        "Randomness attestation {}: {}",
+        hex::encode(&digest),
+        attestation.to_json()
+      );
+      digest
+    });
+    let rand = check(delivery, age)?;
+    Ok((rand, attestation.filter(|_| self.record_attestation)))
+  }
+}
+
+impl Deliveries {
+  /// Let the rng factory know the pulse period, so it can keep pace
+  pub fn advertise(&self, period: chrono::Duration) {
+    if let Mode::Push(_, rng_factory) = &self.mode {
+      let millis = period.num_milliseconds().max(0) as u64;
+      let period = StrandPeriod { millis };
+      rng_factory.push_delivery(STRAND_PERIOD_COMMAND, &period);
+    }
+  }
+}
+
+fn rng_factory() -> Result<Peer> {
+  Peer::from_env("RNG_FACTORY_ADDR", "rng_factory:5555")
+}
+
+/// Keep the latest (verified) delivery pushed to `LISTEN_ADDR`
+fn listen(
+  shutdown: Arc<Notify>,
+  verifier: Option<Arc<Verifier>>,
+) -> Result<Latest> {
+  let latest = Latest::default();
+  let mut messages =
+    biab_utils::start_tcp_server(biab_utils::listen_address()?, shutdown);
+  let received = latest.clone();
+  tokio::spawn(async move {
+    while let Some(message) = messages.recv().await {
+      if message.command != RANDOMNESS_COMMAND {
+        continue;
+      }
+      match message.extract_payload::<RandomnessDelivery>() {
+        Ok(Some(delivery)) => {
+          let verified = verifier
+            .as_ref()
+            .map_or(Ok(()), |verifier| verifier.verify(&delivery, Utc::now()));
+          if let Err(e) = verified {
+            log::error!("ALERT: rejected randomness delivery: {}", e);
+            continue;
+          }
+          log::debug!(
+            "Received randomness from {} via {}",
+            delivery.source,
+            message.peer.as_deref().unwrap_or("an unauthenticated peer")
+          );
+          *received.lock().expect("Failed to acquire lock") =
+            Some((Instant::now(), delivery));
+        }
+        Ok(None) => log::warn!("Received a delivery without randomness"),
+        Err(e) => log::error!("Invalid randomness delivery: {}", e),
+      }
+    }
+  });
+  Ok(latest)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn delivery(len: usize) -> RandomnessDelivery {
+    RandomnessDelivery {
+      id: None,
+      bytes: vec![7; len],
+      source: "os".to_string(),
+      sources: vec!["os".to_string()],
+      conditioning: None,
+      request_id: None,
+      issued_at: None,
+      attestation: None,
+      authentication: None,
+    }
+  }
+
+  #[test]
+  fn test_check() {
+    assert_eq!(check(delivery(64), Duration::ZERO).unwrap(), [7; 64]);
+    assert!(check(delivery(64), Duration::from_secs(31)).is_err());
+    assert!(check(delivery(32), Duration::ZERO).is_err());
+    assert_eq!(check(delivery(128), Duration::ZERO).unwrap(), [7; 64]);
+  }
+
+  #[test]
+  fn test_answer() {
+    let messenger = Messenger::new();
+    let request =
+      messenger.delivery(NEED_ENTROPY_COMMAND, &EntropyRequest { bytes: 64 });
+    let mut answered = delivery(64);
+    answered.request_id = Some(request.id);
+    let response = messenger.delivery(RANDOMNESS_COMMAND, &answered);
+    assert_eq!(answer(&request, response).unwrap().bytes, vec![7; 64]);
+
+    let other = messenger.delivery(NEED_ENTROPY_COMMAND, &());
+    let response = messenger.delivery(RANDOMNESS_COMMAND, &answered);
+    assert!(answer(&other, response).is_err());
+    let unrequested = messenger.delivery(RANDOMNESS_COMMAND, &delivery(64));
+    assert!(answer(&request, unrequested).is_err());
+  }
+
+  #[test]
+  fn test_verify() {
+    let auth = DeliveryAuth::hmac(&[1; 32]).unwrap();
+    let verifier = Verifier::new(DeliveryAuth::hmac(&[1; 32]).unwrap());
+    let now = Utc::now();
+    let issued = |at: DateTime<Utc>| {
+      let mut delivery = delivery(64);
+      delivery.issued_at = Some(at);
+      auth.authenticate(&mut delivery).unwrap();
+      delivery
+    };
+
+    let first = issued(now - chrono::Duration::seconds(2));
+    assert!(verifier.verify(&first, now).is_ok());
+    // replayed
+    assert!(verifier.verify(&first, now).is_err());
+    assert!(verifier.verify(&issued(now), now).is_ok());
+    let stale = issued(now - chrono::Duration::seconds(60));
+    assert!(verifier.verify(&stale, now).is_err());
+    let future = issued(now + chrono::Duration::seconds(60));
+    assert!(verifier.verify(&future, now).is_err());
+    // not authenticated
+    let mut forged = issued(now + chrono::Duration::seconds(1));
+    forged.authentication = None;
+    assert!(verifier.verify(&forged, now).is_err());
+  }
+}
@@ -0,0 +1,77 @@
+//! The YubiHSM2 the generator signs with, and the commands provisioning its
+//! key:
+//!
+//! - `pulse_generator hsm-keygen` generates the signing key at
+//!   `HSM_SIGNING_KEY_ID` (a `HSM_KEY_ALGORITHM` key, default `rsa2048`, in
+//!   the `HSM_KEY_DOMAINS`, default `1`) and saves its attestation
+//! - `pulse_generator hsm-attest` saves the attestation of the key
+//!
+//! The attestation is saved at `HSM_ATTESTATION_PATH` (default
+//! `/data/key-attestation.pem`), where the portal can serve it (see
+//! `KEY_ATTESTATION_PATH`).
+use crate::config::SignerConfig;
+use anyhow::{anyhow, Result};
+use biab_utils::{config_value, KeyAttestation, Secret};
+use std::path::PathBuf;
+use yubihsm::{connector::Connector, Client, Credentials};
+
+/// Open a client with a new session
+pub fn open_client(
+  address: &str,
+  port: u16,
+  auth_key_id: u16,
+  password: &Secret,
+) -> Result<Client> {
+  let connector = Connector::http(&yubihsm::HttpConfig {
+    addr: address.to_string(),
+    port,
+    timeout_ms: 6000,
+  });
+  let creds =
+    Credentials::from_password(auth_key_id, password.expose().as_bytes());
+  Ok(Client::open(connector, creds, true)?)
+}
+
+/// Run `hsm-keygen` (generating the key first) or `hsm-attest`
+pub fn provision(generate: bool) -> Result<()> {
+  let SignerConfig::Hsm {
+    address,
+    port,
+    auth_key_id,
+    password,
+    signing_key_id,
+    ..
+  } = SignerConfig::load()?
+  else {
+    return Err(anyhow!(
+      "PRIVATE_KEY_PATH is set, unset it to provision a key in the HSM"
+    ));
+  };
+  let path = PathBuf::from(
+    config_value("HSM_ATTESTATION_PATH")?
+      .unwrap_or_else(|| "/data/key-attestation.pem".to_string()),
+  );
+  let client = open_client(&address, port, auth_key_id, &password)?;
+  if generate {
+    let algorithm = biab_utils::parse_key_algorithm(
+      &config_value("HSM_KEY_ALGORITHM")?
+        .unwrap_or_else(|| "rsa2048".to_string()),
+    )?;
+    let domains = biab_utils::parse_domains(
+      &config_value("HSM_KEY_DOMAINS")?.unwrap_or_else(|| "1".to_string()),
+    )?;
+    biab_utils::generate_signing_key(
+      &client,
+      signing_key_id,
+      algorithm,
+      domains,
+    )?;
+  }
+  KeyAttestation::fetch(&client, signing_key_id)?.save(&path)?;
+  log::info!(
+    "Saved the attestation of key {:#06x} to {}",
+    signing_key_id,
+    path.display()
+  );
+  Ok(())
+}
@@ -0,0 +1,720 @@
+use anyhow::Result;
+use biab_utils::{handle_shutdown_signal, init_logger};
+use chrono::TimeDelta;
+use std::sync::Arc;
+use tokio::{process::Command, sync::Notify};
+use tracing::Instrument;
+use twine_protocol::{
+  prelude::*,
+  twine_lib::{crypto::PublicKey, twine::CrossStitches},
+};
+mod pulse_assembler;
+use pulse_assembler::*;
+mod anchoring;
+mod cid_str;
+mod config;
+use config::{Config, SignerConfig};
+mod entropy_archive;
+mod entropy_health;
+use entropy_health::EntropyGate;
+mod external_beacons;
+mod factory;
+mod hsm;
+mod metrics;
+mod python;
+// mod payload;
+mod stitch_config;
+mod subspec;
+mod timing;
+
+/// Long lived helpers shared by the scheduled jobs
+struct JobContext {
+  gate: EntropyGate,
+  anchorer: Option<Arc<anchoring::Anchorer>>,
+  external_beacons: Option<external_beacons::ExternalBeacons>,
+  config: Config,
+  data_sync: biab_utils::ClientHandle,
+  events: Option<Arc<dyn biab_utils::EventBus>>,
+  factory: Option<factory::Deliveries>,
+  python: Option<python::PythonRng>,
+}
+
+enum EitherSigner {
+  Hsm(biab_utils::HsmSigner),
+  Ring(twine_protocol::twine_builder::RingSigner),
+}
+
+impl twine_protocol::twine_builder::Signer for EitherSigner {
+  type Key = PublicKey;
+
+  fn sign<T: AsRef<[u8]>>(
+    &self,
+    data: T,
+  ) -> std::result::Result<
+    twine_protocol::twine_lib::crypto::Signature,
+    SigningError,
+  > {
+    let _data = data.as_ref();
+    match self {
+      EitherSigner::Hsm(signer) => signer.sign(_data),
+      EitherSigner::Ring(signer) => signer.sign(_data),
+    }
+  }
+
+  fn public_key(&self) -> Self::Key {
+    match self {
+      EitherSigner::Hsm(signer) => signer.public_key(),
+      EitherSigner::Ring(signer) => signer.public_key(),
+    }
+  }
+}
+
+/// For the strand to be created with the signer the pulses are then signed
+/// with, rather than another one opening its own HSM session
+impl twine_protocol::twine_builder::Signer for &EitherSigner {
+  type Key = PublicKey;
+
+  fn sign<T: AsRef<[u8]>>(
+    &self,
+    data: T,
+  ) -> std::result::Result<
+    twine_protocol::twine_lib::crypto::Signature,
+    SigningError,
+  > {
+    EitherSigner::sign(self, data)
+  }
+
+  fn public_key(&self) -> Self::Key {
+    EitherSigner::public_key(self)
+  }
+}
+
+/// How many sync notifications are kept while the sync service can't be
+/// reached
+const SYNC_NOTIFICATIONS: usize = 4;
+
+/// What the generator does, for `--help`
+pub const ABOUT: &str = "Assembles, signs and publishes the beacon's pulses";
+
+/// The generator's commands, for `--help`
+pub const COMMANDS: &[(&str, &str)] = &[
+  (
+    "hsm-keygen",
+    "generate the signing key in the HSM and attest it",
+  ),
+  ("hsm-attest", "save the attestation of the signing key"),
+  (
+    "cosign-policy",
+    "print the cosigners' keys and threshold, to verify pulses with",
+  ),
+];
+
+/// Run the generator, or one of its commands
+pub async fn run() -> Result<()> {
+  if biab_utils::help(ABOUT, COMMANDS) {
+    return Ok(());
+  }
+  init_logger();
+  match biab_utils::command() {
+    Some("hsm-keygen") => return hsm::provision(true),
+    Some("hsm-attest") => return hsm::provision(false),
+    Some("cosign-policy") => return print_cosign_policy(),
+    Some(command) => {
+      return Err(anyhow::anyhow!("Unknown command {}, see --help", command))
+    }
+    None => {}
+  }
+  let config = Config::load()?;
+  if biab_utils::print_config(&config, true)? {
+    return Ok(());
+  }
+
+  // Setup graceful shutdown
+  let shutdown = Arc::new(Notify::new());
+  tokio::spawn(handle_shutdown_signal(shutdown.clone()));
+  biab_utils::start_metrics_server(shutdown.clone())?;
+
+  let signer = get_signer(&config.signer)?;
+  check_signer(&signer, &config)?;
+
+  // let store = twine_protocol::twine_lib::store::MemoryStore::new();
+  let strand = retrieve_or_create_strand(&signer, &config).await?;
+
+  let store = biab_utils::open_store().await?;
+  let mut assembler = PulseAssembler::new(signer, strand, store)?
+    .with_rng_path(config.rng_storage_path.clone());
+  if let Some(archive) = get_entropy_archive(&config)? {
+    assembler = assembler.with_entropy_archive(archive);
+  }
+  if let Some(cosigners) = get_cosigners(&config)? {
+    assembler = assembler.with_cosigners(cosigners);
+  }
+
+  assembler.init().await?;
+
+  timing::validate_period(assembler.period())?;
+  if config.lead_time() >= assembler.period() {
+    return Err(anyhow::anyhow!(
+      "The lead time ({}ms) must be shorter than the pulse period ({}ms)",
+      config.lead_time_ms,
+      assembler.period().num_milliseconds()
+    ));
+  }
+
+  let context = JobContext {
+    gate: EntropyGate::new(),
+    anchorer: get_anchorer(&config).await?.map(Arc::new),
+    external_beacons: get_external_beacons(&config)?,
+    data_sync: biab_utils::MessengerClient::new(
+      biab_utils::Peer::data_sync()?,
+      SYNC_NOTIFICATIONS,
+    )
+    .spawn(shutdown.clone()),
+    events: biab_utils::event_bus(shutdown.clone()).await?,
+    factory: factory::Deliveries::from_env(shutdown.clone())?,
+    python: python::PythonRng::from_env()?,
+    config,
+  };
+
+  start_scheduler(assembler, context, shutdown).await
+}
+
+async fn get_anchorer(config: &Config) -> Result<Option<anchoring::Anchorer>> {
+  if config.anchor_services.is_empty() {
+    return Ok(None);
+  }
+  let services = config
+    .anchor_services
+    .iter()
+    .map(|s| s.parse())
+    .collect::<Result<Vec<anchoring::AnchorService>>>()?;
+  let pool = biab_utils::open_pool().await?;
+  Ok(Some(anchoring::Anchorer::new(services, pool)))
+}
+
+fn get_external_beacons(
+  config: &Config,
+) -> Result<Option<external_beacons::ExternalBeacons>> {
+  if config.external_beacons.is_empty() {
+    return Ok(None);
+  }
+  let beacons = config
+    .external_beacons
+    .iter()
+    .map(|s| s.parse())
+    .collect::<Result<Vec<external_beacons::ExternalBeacon>>>()?;
+  Ok(Some(external_beacons::ExternalBeacons::new(beacons)?))
+}
+
+fn get_entropy_archive(
+  config: &Config,
+) -> Result<Option<entropy_archive::EntropyArchive>> {
+  let Some(settings) = &config.entropy_archive else {
+    return Ok(None);
+  };
+  let mut archive = entropy_archive::EntropyArchive::from_hex_key(
+    &settings.path,
+    &settings.public_key,
+  )?;
+  if let Some(days) = settings.retention_days {
+    archive = archive.with_retention(TimeDelta::days(days));
+  }
+  Ok(Some(archive))
+}
+
+/// Refuse to start with a signer that doesn't sign with its key, with a key
+/// twine_lib can't verify signatures of, or too slowly to sign pulses within
+/// the lead time
+fn check_signer(signer: &EitherSigner, config: &Config) -> Result<()> {
+  if config.signer_self_test_rounds == 0 {
+    return Ok(());
+  }
+  let result = biab_utils::self_test(signer, config.signer_self_test_rounds)?;
+  log::info!(
+    "Signer self-test: {:?} per signature, {:?} at most",
+    result.mean_latency,
+    result.max_latency
+  );
+  if !result.verified {
+    return Err(anyhow::anyhow!(
+      "twine_lib can't verify signatures of the signer's key, nor could \
+       anyone verify the pulses"
+    ));
+  }
+  let lead_time = config.lead_time().to_std()?;
+  if result.max_latency >= lead_time {
+    return Err(anyhow::anyhow!(
+      "Signing took {:?}, longer than the lead time ({}ms)",
+      result.max_latency,
+      config.lead_time_ms
+    ));
+  }
+  if result.max_latency * 2 >= lead_time {
+    log::warn!(
+      "ALERT: Signing took {:?}, over half of the lead time ({}ms), pulses may be late",
+      result.max_latency,
+      config.lead_time_ms
+    );
+  }
+  Ok(())
+}
+
+fn get_hsm_signer(
+  address: &str,
+  port: u16,
+  auth_key_id: u16,
+  password: &biab_utils::Secret,
+  signing_key_id: u16,
+) -> Result<biab_utils::HsmSigner> {
+  let address = address.to_string();
+  let password = password.clone();
+  // also used to open a new session when the one open is lost
+  let connect =
+    move || hsm::open_client(&address, port, auth_key_id, &password);
+  let signer =
+    biab_utils::HsmSigner::try_new(Box::new(connect), signing_key_id)?;
+  Ok(signer)
+}
+
+fn get_ring_signer(
+  key_path: &str,
+) -> Result<twine_protocol::twine_builder::RingSigner> {
+  let pem = std::fs::read_to_string(key_path)?;
+  let signer = twine_protocol::twine_builder::RingSigner::from_pem(pem)?;
+  Ok(signer)
+}
+
+fn get_signer(config: &SignerConfig) -> Result<EitherSigner> {
+  match config {
+    SignerConfig::Key { private_key_path } => {
+      Ok(EitherSigner::Ring(get_ring_signer(private_key_path)?))
+    }
+    SignerConfig::Hsm {
+      address,
+      port,
+      auth_key_id,
+      password,
+      signing_key_id,
+      keepalive_secs,
+      sign_attempts,
+    } => {
+      let signer = get_hsm_signer(
+        address,
+        *port,
+        *auth_key_id,
+        password,
+        *signing_key_id,
+      )?
+      .with_sign_attempts(*sign_attempts);
+      if *keepalive_secs > 0 {
+        signer.keep_alive(std::time::Duration::from_secs(*keepalive_secs));
+      }
+      Ok(EitherSigner::Hsm(signer))
+    }
+  }
+}
+
+/// The operators' signers, each self-tested like the strand's
+fn get_cosigners(
+  config: &Config,
+) -> Result<Option<biab_utils::Cosigners<EitherSigner>>> {
+  let Some(cosigners) = &config.cosigners else {
+    return Ok(None);
+  };
+  let signers = cosigners
+    .signers
+    .iter()
+    .map(|(name, signer_config)| {
+      let signer = get_signer(signer_config)
+        .and_then(|signer| check_signer(&signer, config).map(|_| signer))
+        .map_err(|e| anyhow::anyhow!("Cosigner {}: {}", name, e))?;
+      Ok((name.clone(), signer))
+    })
+    .collect::<Result<Vec<_>>>()?;
+  Ok(Some(biab_utils::Cosigners::try_new(
+    signers,
+    cosigners.threshold,
+  )?))
+}
+
+/// Print the policy the strand's pulses are checked against by the audit and
+/// `verify`
+fn print_cosign_policy() -> Result<()> {
+  let config = Config::load()?;
+  let cosigners = get_cosigners(&config)?.ok_or_else(|| {
+    anyhow::anyhow!("COSIGNERS_PATH is required to print the cosign policy")
+  })?;
+  let json = std::fs::read_to_string(&config.strand_json_path)?;
+  let strand = Strand::from_tagged_dag_json(json)?;
+  let policy = cosigners.policy(&strand.cid());
+  print!("{}", serde_yaml::to_string(&policy)?);
+  Ok(())
+}
+
+async fn create_strand<S: Signer<Key = PublicKey>>(
+  signer: S,
+  config: &Config,
+) -> Result<Strand> {
+  #[derive(Debug, serde::Serialize, serde::Deserialize)]
+  struct StrandDetails {
+    #[serde(flatten)]
+    rng_details: twine_spec_rng::RngStrandDetails,
+    #[serde(flatten)]
+    custom_details: Ipld,
+  }
+
+  #[derive(Debug, serde::Deserialize)]
+  struct StrandConfig {
+    details: Ipld,
+  }
+
+  let builder = TwineBuilder::new(signer);
+  let cfg_path = config.strand_config_path.as_deref().ok_or_else(|| {
+    anyhow::anyhow!("STRAND_CONFIG_PATH is required to create the strand")
+  })?;
+  let cfg = std::fs::read_to_string(cfg_path)?;
+  let cfg: StrandConfig =
+    twine_protocol::twine_lib::serde_ipld_dagjson::from_slice(cfg.as_bytes())?;
+
+  let period = config.pulse_period()?;
+  let details = StrandDetails {
+    rng_details: twine_spec_rng::RngStrandDetails { period },
+    custom_details: cfg.details,
+  };
+
+  log::info!("Creating new strand with details: {:?}", details);
+  let strand = builder
+    .build_strand()
+    .subspec(twine_spec_rng::subspec_string())
+    .details(details)
+    .done()?;
+
+  let strand_path = &config.strand_json_path;
+  let json = strand.tagged_dag_json_pretty();
+  biab_utils::write_atomic(strand_path, json).await?;
+  log::info!("Strand created and saved to {}", strand_path);
+
+  Ok(strand)
+}
+
+async fn retrieve_or_create_strand<S: Signer<Key = PublicKey>>(
+  signer: S,
+  config: &Config,
+) -> Result<Strand> {
+  let strand_path = &config.strand_json_path;
+  match std::fs::metadata(strand_path) {
+    Ok(_) => {
+      let json = std::fs::read_to_string(strand_path)?;
+      let strand = Strand::from_tagged_dag_json(json)?;
+      Ok(strand)
+    }
+    Err(e) => match e.kind() {
+      std::io::ErrorKind::NotFound => create_strand(signer, config).await,
+      _ => Err(e.into()),
+    },
+  }
+}
+
+async fn start_scheduler(
+  assembler: PulseAssembler<
+    impl Store + Resolver + 'static,
+    impl Signer<Key = PublicKey> + Send + Sync + 'static,
+  >,
+  context: JobContext,
+  shutdown: Arc<Notify>,
+) -> Result<()> {
+  let worker = tokio::spawn(async move {
+    loop {
+      // found by its strand and index in the other services' spans too
+      let index = assembler.next_index().await;
+      let stage = if assembler.needs_assembly().await {
+        "assemble"
+      } else {
+        "publish"
+      };
+      let span = tracing::info_span!(
+        "pulse",
+        strand = %assembler.strand_cid(),
+        index,
+        stage
+      );
+      tokio::select! {
+        _ = shutdown.notified() => {
+          log::info!("Stopping tasks...");
+          break;
+        }
+        res = advance(&assembler, &context).instrument(span) => {
+          if let Err(e) = res {
+            log::error!("Error advancing: {}", e);
+            break;
+          }
+        }
+      }
+    }
+  });
+
+  worker.await?;
+  Ok(())
+}
+
+async fn advance(
+  assembler: &PulseAssembler<
+    impl Store + Resolver + 'static,
+    impl Signer<Key = PublicKey> + 'static,
+  >,
+  context: &JobContext,
+) -> Result<()> {
+  let lead_time = context.config.lead_time();
+
+  if assembler.needs_assembly().await {
+    // refresh stitches within the time window
+    let time_limit = assembler
+      .next_state_in(lead_time + TimeDelta::seconds(1))
+      .await;
+
+    let prev_cross_stitches = assembler.previous_cross_stitches().await;
+    let next_cross_stitches = match tokio::time::timeout(
+      time_limit,
+      refresh_stitches(
+        context.config.stitch_config_path.as_deref(),
+        prev_cross_stitches.clone(),
+      ),
+    )
+    .await
+    {
+      Ok(res) => match res {
+        Ok(cross_stitches) => cross_stitches,
+        Err(e) => {
+          log::error!("Failed to refresh stitches. {}", e);
+          prev_cross_stitches
+        }
+      },
+      Err(_) => {
+        log::error!("Timed out refreshing stitches");
+        prev_cross_stitches
+      }
+    };
+
+    let sleep_time = assembler.next_state_in(lead_time).await;
+    log::debug!("Sleeping for {:?}", sleep_time);
+    tokio::time::sleep(sleep_time).await;
+    let started = std::time::Instant::now();
+    let res = assemble_job(assembler, context, next_cross_stitches).await;
+    metrics::observe_assembled(started.elapsed(), res.is_ok());
+    res?;
+  } else if assembler.needs_publish().await {
+    let sleep_time = assembler.next_state_in(lead_time).await;
+    log::debug!("Sleeping for {:?}", sleep_time);
+    tokio::time::sleep(sleep_time).await;
+    publish_job(assembler, context).await?;
+  } else {
+    unreachable!();
+  }
+  Ok(())
+}
+
+#[tracing::instrument(name = "stitch_refresh", skip_all)]
+async fn refresh_stitches(
+  path: Option<&str>,
+  mut xstitches: CrossStitches,
+) -> Result<CrossStitches> {
+  let path =
+    path.ok_or_else(|| anyhow::anyhow!("STITCH_CONFIG_PATH is not set"))?;
+  let stitch_config = stitch_config::StitchConfig::load(path)?;
+  let stitch_resolver = stitch_config.get_resolver();
+  let strands_to_entwine = stitch_config.strands();
+
+  xstitches
+    .stitches()
+    .iter()
+    .filter(|s| !strands_to_entwine.contains(&s.strand))
+    .for_each(|s| {
+      log::info!("Will not refresh stitch to external strand {}", s.strand);
+    });
+
+  for cid in strands_to_entwine {
+    match xstitches
+      .clone()
+      .add_or_refresh(cid, &stitch_resolver)
+      .await
+    {
+      Ok(updated) => {
+        if xstitches.strand_is_stitched(cid) {
+          log::info!("Refreshed stitch to external strand {}", cid);
+        } else {
+          log::info!("Added new stitch to external strand {}", cid);
+        }
+        xstitches = updated;
+      }
+      Err(e) => {
+        log::error!("Error adding stitch to external strand {}: {}", cid, e);
+      }
+    }
+  }
+
+  Ok(xstitches)
+}
+
+async fn assemble_job(
+  assembler: &PulseAssembler<
+    impl Store + Resolver + 'static,
+    impl Signer<Key = PublicKey> + 'static,
+  >,
+  context: &JobContext,
+  next_cross_stitches: CrossStitches,
+) -> Result<()> {
+  let (rand, external_sources) =
+    tokio::join!(fetch_randomness(context), fetch_external_sources(context));
+  let (rand, attestation) = rand?;
+  match assembler
+    .prepare_next(&rand, next_cross_stitches, external_sources, attestation)
+    .await
+  {
+    Ok(_) => {
+      log::info!(
+        "Pulse {} prepared and ready for release",
+        assembler.prepared().await.expect("prepared pulse").index()
+      );
+      Ok(())
+    }
+    Err(e) => {
+      log::error!("Failed to prepare pulse: {:?}", e);
+      Err(e)
+    }
+  }
+}
+
+async fn publish_job(
+  assembler: &PulseAssembler<
+    impl Store + Resolver + 'static,
+    impl Signer<Key = PublicKey> + 'static,
+  >,
+  context: &JobContext,
+) -> Result<()> {
+  match assembler.publish().await {
+    Ok(latest) => {
+      log::info!("Pulse ({}) published: {}", latest.index(), latest.tixel());
+      metrics::observe_published(Some(&latest));
+
+      if let Some(anchorer) = &context.anchorer {
+        let anchorer = anchorer.clone();
+        let cid = latest.cid();
+        tokio::spawn(
+          async move { anchorer.anchor(&cid).await }.in_current_span(),
+        );
+      }
+
+      if let Some(factory) = &context.factory {
+        factory.advertise(assembler.period());
+      }
+
+      if let Some(events) = &context.events {
+        let published = biab_utils::PulsePublished {
+          strand: latest.strand_cid().to_string(),
+          index: latest.index(),
+          cid: latest.cid().to_string(),
+        };
+        let topic = biab_utils::PULSE_PUBLISHED_TOPIC;
+        biab_utils::publish_event(events.as_ref(), topic, &published).await;
+      }
+
+      // send a tcp message to the syncher
+      context.data_sync.push_text("sync");
+    }
+    Err(e) => {
+      log::error!("Failed to publish pulse: {:?}", e);
+      metrics::observe_published(None);
+      return Err(e);
+    }
+  }
+  Ok(())
+}
+
+async fn fetch_external_sources(
+  context: &JobContext,
+) -> Vec<external_beacons::ExternalSource> {
+  match &context.external_beacons {
+    Some(beacons) => beacons.fetch_all().await,
+    None => vec![],
+  }
+}
+
+/// Fresh randomness, and the digest of its attestation to record if any
+async fn fetch_randomness(
+  context: &JobContext,
+) -> Result<([u8; 64], Option<Vec<u8>>)> {
+  log::info!("Fetching fresh randomness...");
+  let gate = &context.gate;
+  let primary = match &context.factory {
+    Some(factory) => factory.take().await.and_then(|(rand, attestation)| {
+      gate.check(&rand)?;
+      Ok((rand, attestation))
+    }),
+    None => match &context.python {
+      Some(python) => python
+        .call()
+        .await
+        .and_then(|output| checked_randomness(output, gate))
+        .map(|rand| (rand, None)),
+      None => fetch_checked_randomness(&context.config.rng_script, gate)
+        .await
+        .map(|rand| (rand, None)),
+    },
+  };
+  let err = match primary {
+    Ok(rand) => return Ok(rand),
+    Err(e) => e,
+  };
+  log::error!("ALERT: primary entropy source rejected: {}", err);
+
+  let fallback = match &context.config.rng_script_fallback {
+    Some(fallback) => fallback,
+    None => {
+      return Err(anyhow::anyhow!(
+        "Refusing to assemble with suspect entropy and no RNG_SCRIPT_FALLBACK configured"
+      ))
+    }
+  };
+  log::warn!("Falling back to secondary entropy source...");
+  match fetch_checked_randomness(fallback, gate).await {
+    Ok(rand) => Ok((rand, None)),
+    Err(e) => {
+      log::error!("ALERT: secondary entropy source rejected: {}", e);
+      Err(anyhow::anyhow!("Refusing to assemble with suspect entropy"))
+    }
+  }
+}
+
+async fn fetch_checked_randomness(
+  command: &str,
+  gate: &EntropyGate,
+) -> Result<[u8; 64]> {
+  let output = run_python_script(command).await?;
+  checked_randomness(output, gate)
+}
+
+/// The 64 bytes of randomness output, if they pass the health tests
+fn checked_randomness(output: Vec<u8>, gate: &EntropyGate) -> Result<[u8; 64]> {
+  let rand: [u8; 64] = output.as_slice().try_into().map_err(|_| {
+    anyhow::anyhow!("Expected 64 bytes of randomness, got {}", output.len())
+  })?;
+  gate.check(&rand)?;
+  Ok(rand)
+}
+
+async fn run_python_script(command: &str) -> Result<Vec<u8>> {
+  let parts: Vec<&str> = command.split_whitespace().collect();
+  let mut cmd = Command::new(parts[0]);
+  for part in &parts[1..] {
+    cmd.arg(part);
+  }
+  let output = cmd.output().await?;
+  if !output.status.success() {
+    return Err(anyhow::anyhow!(
+      "Failed to run python script: {}",
+      String::from_utf8_lossy(&output.stderr)
+    ));
+  }
+
+  Ok(output.stdout)
+}
@@ -0,0 +1,54 @@
+//! Prometheus metrics at `GET /metrics` on `METRICS_ADDR`, if set.
+//!
+//! Pulses are counted as they're assembled and published, by result, along
+//! with the time taken to assemble them (including fetching randomness).
+//! The index of the latest pulse published on each strand is a gauge.
+use biab_utils::{histogram_vec, int_counter_vec, int_gauge_vec};
+use prometheus::{HistogramVec, IntCounterVec, IntGaugeVec};
+use std::sync::LazyLock;
+use std::time::Duration;
+use twine_protocol::prelude::*;
+
+static PULSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+  int_counter_vec(
+    "generator_pulses_total",
+    "Pulses assembled or published, by stage and result (ok or error)",
+    &["stage", "result"],
+  )
+});
+
+static ASSEMBLY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+  histogram_vec(
+    "generator_assembly_duration_seconds",
+    "Time taken to assemble a pulse, including fetching its randomness",
+    &[],
+    None,
+  )
+});
+
+static LATEST_INDEX: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+  int_gauge_vec(
+    "generator_latest_pulse_index",
+    "Index of the latest pulse published, by strand",
+    &["strand"],
+  )
+});
+
+pub fn observe_assembled(duration: Duration, ok: bool) {
+  PULSES
+    .with_label_values(&["assemble", if ok { "ok" } else { "error" }])
+    .inc();
+  ASSEMBLY_DURATION
+    .with_label_values(&[])
+    .observe(duration.as_secs_f64());
+}
+
+pub fn observe_published(latest: Option<&Twine>) {
+  let result = if latest.is_some() { "ok" } else { "error" };
+  PULSES.with_label_values(&["publish", result]).inc();
+  if let Some(latest) = latest {
+    LATEST_INDEX
+      .with_label_values(&[latest.strand_cid().to_string().as_str()])
+      .set(latest.index() as i64);
+  }
+}
@@ -1,14 +1,84 @@
 use anyhow::Result;
+use biab_utils::{cosigned_message, Cosignature, Cosigners};
 use chrono::Duration;
+use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, sync::Arc};
 use tokio::sync::Mutex;
 use twine_protocol::{
   prelude::*,
-  twine_lib::{crypto::PublicKey, twine::CrossStitches},
+  twine_lib::{
+    crypto::PublicKey, ipld_core::serde::to_ipld, twine::CrossStitches, Bytes,
+  },
 };
 
 use twine_spec_rng::{PayloadBuilder, RandomnessPayload, RngStrandDetails};
 
+use crate::entropy_archive::EntropyArchive;
+use crate::external_beacons::ExternalSource;
+
+/// The rng payload along with values from other beacons, the digest of the
+/// randomness' attestation and the operators' co-signatures. This is only
+/// used when any of them is present, so that pulses of strands which don't
+/// use them keep the plain rng payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtendedPayload {
+  #[serde(flatten)]
+  pub rng: RandomnessPayload,
+  pub external: Vec<ExternalSource>,
+  /// SHA-256 of the rng factory's attestation of the randomness
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub attestation: Option<Bytes>,
+  /// See [biab_utils::Cosigners]
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub cosignatures: Vec<Cosignature>,
+}
+
+/// What the pulse records besides the rng payload
+struct Extras<'a, G> {
+  external_sources: Vec<ExternalSource>,
+  attestation: Option<Vec<u8>>,
+  cosigners: Option<&'a Cosigners<G>>,
+}
+
+impl<G: Signer<Key = PublicKey>> Extras<'_, G> {
+  /// Whether the plain rng payload will do
+  fn is_empty(&self) -> bool {
+    self.external_sources.is_empty()
+      && self.attestation.is_none()
+      && self.cosigners.is_none()
+  }
+
+  /// The extended payload of the pulse at `index` of `strand`, and whether
+  /// the cosigners signed it
+  fn payload(
+    self,
+    rng: RandomnessPayload,
+    strand: &Cid,
+    index: u64,
+  ) -> (ExtendedPayload, Result<()>) {
+    let mut payload = ExtendedPayload {
+      rng,
+      external: self.external_sources,
+      attestation: self.attestation.map(Bytes::from),
+      cosignatures: vec![],
+    };
+    let Some(cosigners) = self.cosigners else {
+      return (payload, Ok(()));
+    };
+    let cosignatures = to_ipld(&payload)
+      .map_err(anyhow::Error::from)
+      .and_then(|ipld| cosigned_message(strand, index, &ipld))
+      .and_then(|message| cosigners.cosign(&message));
+    match cosignatures {
+      Ok(cosignatures) => {
+        payload.cosignatures = cosignatures;
+        (payload, Ok(()))
+      }
+      Err(e) => (payload, Err(e)),
+    }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub enum AssemblyState {
   BeginStrand(Duration),
@@ -74,28 +144,47 @@ impl AssemblyState {
 }
 
 pub struct PulseAssembler<S: Store + Resolver, G: Signer<Key = PublicKey>> {
-  builder: TwineBuilder<2, G>,
+  builder: Arc<TwineBuilder<2, G>>,
   strand: Strand,
   period: Duration,
   store: S,
   rng_path: String,
+  archive: Option<EntropyArchive>,
+  cosigners: Option<Arc<Cosigners<G>>>,
   state: Arc<Mutex<Option<AssemblyState>>>,
 }
 
 impl<S: Store + Resolver, G: Signer<Key = PublicKey>> PulseAssembler<S, G> {
-  pub fn new(signer: G, strand: Strand, store: S) -> Self {
+  pub fn new(signer: G, strand: Strand, store: S) -> Result<Self> {
+    crate::subspec::check_strand(&strand)?;
     let period = strand
       .extract_details::<RngStrandDetails>()
-      .expect("strand details")
+      .map_err(|e| {
+        anyhow::anyhow!(
+          "Strand {} details can not be read as rng details: {}",
+          strand.cid(),
+          e
+        )
+      })?
       .period;
-    Self {
-      builder: TwineBuilder::new(signer),
+    Ok(Self {
+      builder: Arc::new(TwineBuilder::new(signer)),
       strand,
       store,
       rng_path: "./randomness".to_string(),
+      archive: None,
+      cosigners: None,
       state: Arc::new(Mutex::new(None)),
       period,
-    }
+    })
+  }
+
+  pub fn period(&self) -> Duration {
+    self.period
+  }
+
+  pub fn strand_cid(&self) -> Cid {
+    self.strand.cid()
   }
 
   pub fn with_rng_path(mut self, rng_path: String) -> Self {
@@ -103,6 +192,16 @@ impl<S: Store + Resolver, G: Signer<Key = PublicKey>> PulseAssembler<S, G> {
     self
   }
 
+  pub fn with_entropy_archive(mut self, archive: EntropyArchive) -> Self {
+    self.archive = Some(archive);
+    self
+  }
+
+  pub fn with_cosigners(mut self, cosigners: Cosigners<G>) -> Self {
+    self.cosigners = Some(Arc::new(cosigners));
+    self
+  }
+
   pub async fn init<'a>(&'a self) -> Result<&'a Self> {
     self.load_state().await?;
     Ok(self)
@@ -182,9 +281,10 @@ impl<S: Store + Resolver, G: Signer<Key = PublicKey>> PulseAssembler<S, G> {
     Ok(rng.try_into().expect("RNG length"))
   }
 
-  fn save_rng(&self, rng: &[u8; 64]) -> Result<()> {
-    std::fs::write(self.rng_file(), rng)?;
-    Ok(())
+  /// The next pulse can't be made without it, so it's never left half
+  /// written
+  async fn save_rng(&self, rng: &[u8; 64]) -> Result<()> {
+    biab_utils::write_atomic(self.rng_file(), rng.to_vec()).await
   }
 
   pub async fn prepared(&self) -> Option<Twine> {
@@ -194,6 +294,15 @@ impl<S: Store + Resolver, G: Signer<Key = PublicKey>> PulseAssembler<S, G> {
     }
   }
 
+  /// The index of the pulse being assembled or released next
+  pub async fn next_index(&self) -> u64 {
+    match self.state().await {
+      AssemblyState::BeginStrand(_) => 0,
+      AssemblyState::Prepared { prepared, .. } => prepared.index(),
+      AssemblyState::Released { latest, .. } => latest.index() + 1,
+    }
+  }
+
   pub async fn next_state_in(
     &self,
     lead_time: Duration,
@@ -231,32 +340,36 @@ impl<S: Store + Resolver, G: Signer<Key = PublicKey>> PulseAssembler<S, G> {
     &self,
     next_randomness: &[u8; 64],
     cross_stitches: CrossStitches,
-  ) -> Result<()> {
+    external_sources: Vec<ExternalSource>,
+    attestation: Option<Vec<u8>>,
+  ) -> Result<()>
+  where
+    G: Send + Sync + 'static,
+  {
     if !self.needs_assembly().await {
       return Err(anyhow::anyhow!("Called prepare when it wasn't needed"));
     }
 
-    let next = match self.state().await {
-      AssemblyState::BeginStrand(_) => {
-        // start the strand
-        self.store.save(self.strand.clone()).await?;
-        let pb = PayloadBuilder::new(vec![0; 64], next_randomness.to_vec());
-        self
-          .builder
-          .build_first(self.strand.clone())
-          .cross_stitches(cross_stitches)
-          .build_payload_then_done(pb.builder())?
-      }
-      AssemblyState::Released { latest, rand } => {
-        let pb = PayloadBuilder::new(rand.to_vec(), next_randomness.to_vec());
-        self
-          .builder
-          .build_next(&latest)
-          .cross_stitches(cross_stitches)
-          .build_payload_then_done(pb.builder())?
-      }
-      _ => unreachable!(),
-    };
+    let state = self.state().await;
+    if let AssemblyState::BeginStrand(_) = state {
+      // start the strand
+      self.store.save(self.strand.clone()).await?;
+    }
+    // signing can block for a while (eg: an HSM opening a new session), so
+    // it's kept off the async workers
+    let builder = self.builder.clone();
+    let cosigners = self.cosigners.clone();
+    let strand = self.strand.clone();
+    let rand = *next_randomness;
+    let next = tokio::task::spawn_blocking(move || {
+      let extras = Extras {
+        external_sources,
+        attestation,
+        cosigners: cosigners.as_deref(),
+      };
+      build_pulse(&builder, strand, state, &rand, cross_stitches, extras)
+    })
+    .await??;
 
     self
       .set_state(AssemblyState::Prepared {
@@ -271,7 +384,17 @@ impl<S: Store + Resolver, G: Signer<Key = PublicKey>> PulseAssembler<S, G> {
   pub async fn publish(&self) -> Result<Twine> {
     if let AssemblyState::Prepared { prepared, rand } = self.state().await {
       self.store.save(prepared.clone()).await?;
-      self.save_rng(&rand)?;
+      self.save_rng(&rand).await?;
+      if let Some(archive) = &self.archive {
+        if let Err(e) = archive.append(prepared.index(), &prepared.cid(), &rand)
+        {
+          log::error!(
+            "Failed to archive entropy for pulse {}: {}",
+            prepared.index(),
+            e
+          );
+        }
+      }
       self
         .set_state(AssemblyState::Released {
           latest: prepared.clone(),
@@ -284,3 +407,60 @@ impl<S: Store + Resolver, G: Signer<Key = PublicKey>> PulseAssembler<S, G> {
     }
   }
 }
+
+/// The pulse after the one of `state`, signed with the builder's signer and
+/// co-signed by the cosigners
+fn build_pulse<G: Signer<Key = PublicKey>>(
+  builder: &TwineBuilder<2, G>,
+  strand: Strand,
+  state: AssemblyState,
+  next_randomness: &[u8; 64],
+  cross_stitches: CrossStitches,
+  extras: Extras<G>,
+) -> Result<Twine> {
+  let strand_cid = strand.cid();
+  let policy = extras.cosigners.map(|c| c.policy(&strand_cid));
+  // the builder can't be given our errors, so the cosigners' is kept for
+  // after the pulse is built
+  let mut cosigned = Ok(());
+  let next = match state {
+    AssemblyState::BeginStrand(_) => {
+      let pb = PayloadBuilder::new(vec![0; 64], next_randomness.to_vec());
+      let builder = builder.build_first(strand).cross_stitches(cross_stitches);
+      if extras.is_empty() {
+        builder.build_payload_then_done(pb.builder())?
+      } else {
+        let build_rng = pb.builder();
+        builder.build_payload_then_done(|strand, prev| {
+          let rng = build_rng(strand, prev)?;
+          let (payload, signed) = extras.payload(rng, &strand_cid, 0);
+          cosigned = signed;
+          Ok(payload)
+        })?
+      }
+    }
+    AssemblyState::Released { latest, rand } => {
+      let index = latest.index() + 1;
+      let pb = PayloadBuilder::new(rand.to_vec(), next_randomness.to_vec());
+      let builder = builder.build_next(&latest).cross_stitches(cross_stitches);
+      if extras.is_empty() {
+        builder.build_payload_then_done(pb.builder())?
+      } else {
+        let build_rng = pb.builder();
+        builder.build_payload_then_done(|strand, prev| {
+          let rng = build_rng(strand, prev)?;
+          let (payload, signed) = extras.payload(rng, &strand_cid, index);
+          cosigned = signed;
+          Ok(payload)
+        })?
+      }
+    }
+    _ => unreachable!(),
+  };
+  cosigned?;
+  // as the audit and verify will check it
+  if let Some(policy) = policy {
+    policy.check(&next)?;
+  }
+  Ok(next)
+}
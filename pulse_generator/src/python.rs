@@ -0,0 +1,187 @@
+//! Randomness from a Python function, built with the `python` feature.
+//!
+//! Rather than starting `RNG_SCRIPT` for every pulse, the generator can load
+//! a Python script or module once and call a function in it whenever it
+//! needs randomness. `RNG_PYTHON` is the path of the script (ending in `.py`)
+//! or the name of the module, optionally followed by `:` and the function
+//! (default `get_randomness`), which takes no arguments and returns 64 bytes.
+//!
+//! An exception fails the call with its traceback, and so does a call that
+//! doesn't return within `RNG_PYTHON_TIMEOUT_SECS` (default 10). Until that
+//! call does return, further calls fail straight away rather than pile up.
+use anyhow::{anyhow, Result};
+use biab_utils::{config_or, config_value};
+use std::time::Duration;
+
+const DEFAULT_FUNCTION: &str = "get_randomness";
+
+/// The script or module, and the function in it
+fn parse(spec: &str) -> (&str, &str) {
+  match spec.rsplit_once(':') {
+    Some((target, function))
+      if !target.is_empty()
+        && !function.is_empty()
+        && !function.contains(['/', '\\']) =>
+    {
+      (target, function)
+    }
+    _ => (spec, DEFAULT_FUNCTION),
+  }
+}
+
+#[cfg(feature = "python")]
+pub struct PythonRng {
+  function: std::sync::Arc<pyo3::Py<pyo3::PyAny>>,
+  /// Whether a call hasn't returned yet
+  busy: std::sync::Arc<std::sync::atomic::AtomicBool>,
+  timeout: Duration,
+  name: String,
+}
+
+#[cfg(not(feature = "python"))]
+pub enum PythonRng {}
+
+impl PythonRng {
+  /// The function at `RNG_PYTHON`, loaded, if configured
+  pub fn from_env() -> Result<Option<Self>> {
+    let spec = match config_value("RNG_PYTHON")? {
+      Some(spec) if !spec.trim().is_empty() => spec,
+      _ => return Ok(None),
+    };
+    let timeout = config_or("RNG_PYTHON_TIMEOUT_SECS", 10u64)?;
+    let (target, function) = parse(spec.trim());
+    Self::load(target, function, Duration::from_secs(timeout.max(1))).map(Some)
+  }
+
+  #[cfg(not(feature = "python"))]
+  fn load(_target: &str, _function: &str, _timeout: Duration) -> Result<Self> {
+    Err(anyhow!(
+      "RNG_PYTHON needs the generator built with the python feature"
+    ))
+  }
+
+  #[cfg(not(feature = "python"))]
+  pub async fn call(&self) -> Result<Vec<u8>> {
+    match *self {}
+  }
+}
+
+#[cfg(feature = "python")]
+mod embedded {
+  use super::PythonRng;
+  use anyhow::{anyhow, Result};
+  use pyo3::prelude::*;
+  use pyo3::types::{PyBytes, PyBytesMethods, PyTracebackMethods};
+  use std::ffi::CString;
+  use std::path::Path;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  /// A Python exception, with its traceback
+  fn error(py: Python<'_>, e: PyErr) -> anyhow::Error {
+    let traceback = e
+      .traceback(py)
+      .and_then(|traceback| traceback.format().ok())
+      .unwrap_or_default();
+    anyhow!("{}{}", traceback, e)
+  }
+
+  fn import<'py>(
+    py: Python<'py>,
+    target: &str,
+  ) -> Result<Bound<'py, PyModule>> {
+    if !target.ends_with(".py") {
+      return py.import(target).map_err(|e| error(py, e));
+    }
+    let code = std::fs::read_to_string(target)
+      .map_err(|e| anyhow!("Failed to read {}: {}", target, e))?;
+    let name = Path::new(target)
+      .file_stem()
+      .map(|stem| stem.to_string_lossy().into_owned())
+      .unwrap_or_default();
+    PyModule::from_code(
+      py,
+      &CString::new(code)?,
+      &CString::new(target)?,
+      &CString::new(name)?,
+    )
+    .map_err(|e| error(py, e))
+  }
+
+  impl PythonRng {
+    pub(super) fn load(
+      target: &str,
+      function: &str,
+      timeout: Duration,
+    ) -> Result<Self> {
+      let name = format!("{}:{}", target, function);
+      let loaded = Python::with_gil(|py| -> Result<Py<PyAny>> {
+        let function = import(py, target)?
+          .getattr(function)
+          .map_err(|e| error(py, e))?;
+        if !function.is_callable() {
+          return Err(anyhow!("{} isn't callable", name));
+        }
+        Ok(function.unbind())
+      })?;
+      log::info!("Calling {} for randomness", name);
+      Ok(Self {
+        function: Arc::new(loaded),
+        busy: Arc::new(AtomicBool::new(false)),
+        timeout,
+        name,
+      })
+    }
+
+    /// Call the function for randomness
+    pub async fn call(&self) -> Result<Vec<u8>> {
+      if self.busy.swap(true, Ordering::SeqCst) {
+        return Err(anyhow!(
+          "{} hasn't returned from its last call",
+          self.name
+        ));
+      }
+      let function = self.function.clone();
+      let busy = self.busy.clone();
+      let name = self.name.clone();
+      let call = tokio::task::spawn_blocking(move || {
+        let output = Python::with_gil(|py| -> Result<Vec<u8>> {
+          let returned = function.call0(py).map_err(|e| error(py, e))?;
+          let bytes = returned
+            .bind(py)
+            .downcast::<PyBytes>()
+            .map_err(|_| anyhow!("{} didn't return bytes", name))?
+            .as_bytes()
+            .to_vec();
+          Ok(bytes)
+        });
+        busy.store(false, Ordering::SeqCst);
+        output
+      });
+      tokio::time::timeout(self.timeout, call)
+        .await
+        .map_err(|_| {
+          anyhow!(
+            "{} didn't return within {}s",
+            self.name,
+            self.timeout.as_secs()
+          )
+        })?
+        .map_err(|e| anyhow!("Failed to call {}: {}", self.name, e))?
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(parse("rng.py"), ("rng.py", DEFAULT_FUNCTION));
+    assert_eq!(parse("rng.py:random"), ("rng.py", "random"));
+    assert_eq!(parse("beacon.rng:fetch"), ("beacon.rng", "fetch"));
+    assert_eq!(parse("C:\\rng.py"), ("C:\\rng.py", DEFAULT_FUNCTION));
+  }
+}
@@ -0,0 +1,104 @@
+//! Checks that a loaded strand can be continued by the compiled rng subspec.
+//!
+//! A strand's specification can't change once it is created. Pulses can
+//! still be added with a newer `twine_spec_rng` as long as it is semver
+//! compatible with the version the strand was created with. Anything else
+//! (a different subspec, a new major version, or an older library) requires
+//! starting a successor strand.
+use anyhow::Result;
+use twine_protocol::prelude::*;
+use twine_protocol::twine_lib::semver::{Version, VersionReq};
+use twine_protocol::twine_lib::specification::Subspec;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Compatibility {
+  Same,
+  Upgrade { from: Version, to: Version },
+  Incompatible(String),
+}
+
+pub fn compatibility(strand: &Subspec, compiled: &Subspec) -> Compatibility {
+  if strand.prefix() != compiled.prefix() {
+    return Compatibility::Incompatible(format!(
+      "strand uses subspec {} but this generator only supports {}",
+      strand, compiled
+    ));
+  }
+  let (from, to) = (strand.semver(), compiled.semver());
+  if from == to {
+    return Compatibility::Same;
+  }
+  let req = VersionReq::parse(&format!("^{}", from)).expect("valid semver");
+  if req.matches(&to) {
+    Compatibility::Upgrade { from, to }
+  } else {
+    Compatibility::Incompatible(format!(
+      "strand uses {} which is not compatible with the supported {}",
+      strand, compiled
+    ))
+  }
+}
+
+/// Make sure the strand can be continued, logging any subspec upgrade
+pub fn check_strand(strand: &Strand) -> Result<()> {
+  let compiled = Subspec::from_string(twine_spec_rng::subspec_string())?;
+  let subspec = strand.subspec().ok_or_else(|| {
+    anyhow::anyhow!(
+      "Strand {} has no subspec. Expected {}",
+      strand.cid(),
+      compiled
+    )
+  })?;
+
+  match compatibility(&subspec, &compiled) {
+    Compatibility::Same => Ok(()),
+    Compatibility::Upgrade { from, to } => {
+      log::info!(
+        "Continuing strand created with {} v{} using v{}",
+        compiled.prefix(),
+        from,
+        to
+      );
+      Ok(())
+    }
+    Compatibility::Incompatible(reason) => Err(anyhow::anyhow!(
+      "Cannot continue strand {}: {}. Start a successor strand by moving the existing strand file (STRAND_JSON_PATH) aside and restarting.",
+      strand.cid(),
+      reason
+    )),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn subspec(s: &str) -> Subspec {
+    Subspec::from_string(s).unwrap()
+  }
+
+  #[test]
+  fn test_compatibility() {
+    let compiled = subspec("twine-rng/2.1.0");
+    assert_eq!(
+      compatibility(&subspec("twine-rng/2.1.0"), &compiled),
+      Compatibility::Same
+    );
+    assert!(matches!(
+      compatibility(&subspec("twine-rng/2.0.0"), &compiled),
+      Compatibility::Upgrade { .. }
+    ));
+    assert!(matches!(
+      compatibility(&subspec("twine-rng/1.0.0"), &compiled),
+      Compatibility::Incompatible(_)
+    ));
+    assert!(matches!(
+      compatibility(&subspec("twine-rng/2.2.0"), &compiled),
+      Compatibility::Incompatible(_)
+    ));
+    assert!(matches!(
+      compatibility(&subspec("other/2.1.0"), &compiled),
+      Compatibility::Incompatible(_)
+    ));
+  }
+}
@@ -1,8 +1,50 @@
-use chrono::{DateTime, TimeDelta, Utc};
+use anyhow::Result;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+
+/// Parse a pulse period like `1s`, `30s`, `5m` or `1h`.
+///
+/// Periods must be at least one second and a whole number of milliseconds.
+pub fn parse_period(s: &str) -> Result<TimeDelta> {
+  let s = s.trim();
+  let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+  let (value, unit) = s.split_at(split);
+  let value: i64 = value
+    .parse()
+    .map_err(|_| anyhow::anyhow!("Invalid period '{}'", s))?;
+  let period = match unit {
+    "ms" => TimeDelta::try_milliseconds(value),
+    "" | "s" => TimeDelta::try_seconds(value),
+    "m" => TimeDelta::try_minutes(value),
+    "h" => TimeDelta::try_hours(value),
+    _ => return Err(anyhow::anyhow!("Invalid period unit in '{}'", s)),
+  }
+  .ok_or_else(|| anyhow::anyhow!("Period '{}' is too long", s))?;
+  validate_period(period)?;
+  Ok(period)
+}
+
+pub fn validate_period(period: TimeDelta) -> Result<()> {
+  if period < TimeDelta::seconds(1) {
+    return Err(anyhow::anyhow!(
+      "Pulse period must be at least 1 second, got {}ms",
+      period.num_milliseconds()
+    ));
+  }
+  if period.subsec_nanos() % 1_000_000 != 0 {
+    return Err(anyhow::anyhow!(
+      "Pulse period must be a whole number of milliseconds"
+    ));
+  }
+  Ok(())
+}
+
+/// Payload timestamps only carry millisecond precision
+pub fn truncate_millis(time: DateTime<Utc>) -> DateTime<Utc> {
+  time.duration_trunc(TimeDelta::milliseconds(1)).unwrap()
+}
 
 pub fn next_truncated_time(period: TimeDelta) -> DateTime<Utc> {
   let now = Utc::now();
-  use chrono::DurationRound;
   now.duration_trunc(period).unwrap() + period
 }
 
@@ -11,6 +53,7 @@ pub fn next_pulse_timestamp(
   period: TimeDelta,
 ) -> DateTime<Utc> {
   let now = Utc::now();
+  let prev_time = truncate_millis(prev_time);
   if now - prev_time < period {
     prev_time + period
   } else {
@@ -42,4 +85,29 @@ mod test {
     let next = next_pulse_timestamp(prev_time, period);
     assert_eq!(next, ts + period);
   }
+
+  #[test]
+  fn test_short_periods() {
+    let period = TimeDelta::seconds(1);
+    let next = next_truncated_time(period);
+    assert_eq!(next.timestamp_subsec_nanos(), 0);
+    assert!(next - Utc::now() <= period);
+
+    let prev_time = Utc::now() + TimeDelta::nanoseconds(1_234_567);
+    let next = next_pulse_timestamp(prev_time, period);
+    assert_eq!(next, truncate_millis(prev_time) + period);
+  }
+
+  #[test]
+  fn test_parse_period() {
+    assert_eq!(parse_period("1s").unwrap(), TimeDelta::seconds(1));
+    assert_eq!(parse_period("5m").unwrap(), TimeDelta::minutes(5));
+    assert_eq!(
+      parse_period("1500ms").unwrap(),
+      TimeDelta::milliseconds(1500)
+    );
+    assert!(parse_period("500ms").is_err());
+    assert!(parse_period("1d").is_err());
+    assert!(parse_period("99999999999999h").is_err());
+  }
 }
@@ -2,7 +2,7 @@ import os
 import sys
 import hashlib
 
-def main():
+def get_randomness():
     # get randomness from 3 sources
     randomness1 = os.urandom(64)
     randomness2 = os.urandom(64)  # additional source
@@ -12,9 +12,11 @@ def main():
     combined_randomness = randomness1 + randomness2 + randomness3
 
     # hash the combined randomness to ensure it's consistent in size
-    hashed_randomness = hashlib.sha3_512(combined_randomness).digest()
+    return hashlib.sha3_512(combined_randomness).digest()
+
+def main():
     # output hashed randomness
-    sys.stdout.buffer.write(hashed_randomness)
+    sys.stdout.buffer.write(get_randomness())
     sys.stdout.buffer.flush()
 
 if __name__ == "__main__":
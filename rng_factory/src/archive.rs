@@ -0,0 +1,252 @@
+//! Optional encrypted archive of every delivery.
+//!
+//! With `DELIVERY_ARCHIVE_PATH` set, the randomness of each delivery is
+//! archived with its id, the request it answered and when it was issued, so
+//! after an incident the randomness of a pulse can be traced back to the
+//! delivery it came from (the generator logs the id of each delivery it
+//! uses). Like the generator's entropy archive, records are sealed
+//! (anonymous crypto_box) to the operator's X25519 public key in
+//! `DELIVERY_ARCHIVE_PUBLIC_KEY`, one base64 encoded record per line.
+//!
+//! A new file is started every day, and whenever the current one reaches
+//! `DELIVERY_ARCHIVE_FILE_BYTES` (default 16 MiB). Files started more than
+//! `DELIVERY_ARCHIVE_RETENTION_DAYS` ago are removed, as are the oldest
+//! files once all of them add up to more than `DELIVERY_ARCHIVE_MAX_BYTES`,
+//! if set.
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use biab_utils::{config_parse, config_value, RandomnessDelivery};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use crypto_box::{aead::OsRng, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+const FILE_PREFIX: &str = "deliveries-";
+const FILE_SUFFIX: &str = ".sealed";
+/// When a file was started, in its name
+const STARTED_FORMAT: &str = "%Y%m%dT%H%M%S%.6f";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveRecord {
+  pub id: Option<uuid::Uuid>,
+  pub request_id: Option<uuid::Uuid>,
+  pub issued_at: Option<DateTime<Utc>>,
+  pub archived_at: DateTime<Utc>,
+  pub sources: Vec<String>,
+  /// hex encoded randomness
+  pub randomness: String,
+}
+
+/// An archive file, and when it was started
+struct ArchiveFile {
+  path: PathBuf,
+  started: NaiveDateTime,
+  len: u64,
+}
+
+pub struct Archive {
+  dir: PathBuf,
+  public_key: PublicKey,
+  file_bytes: u64,
+  retention: Option<TimeDelta>,
+  max_bytes: Option<u64>,
+}
+
+impl Archive {
+  pub fn new<P: Into<PathBuf>>(dir: P, public_key: [u8; 32]) -> Self {
+    Self {
+      dir: dir.into(),
+      public_key: PublicKey::from(public_key),
+      file_bytes: 16 << 20,
+      retention: None,
+      max_bytes: None,
+    }
+  }
+
+  pub fn from_env() -> Result<Option<Self>> {
+    let dir = match config_value("DELIVERY_ARCHIVE_PATH")? {
+      Some(dir) => dir,
+      None => return Ok(None),
+    };
+    let key = config_value("DELIVERY_ARCHIVE_PUBLIC_KEY")?;
+    let key: [u8; 32] = key
+      .and_then(|key| hex::decode(key.trim()).ok())
+      .and_then(|key| key.try_into().ok())
+      .ok_or_else(|| {
+        anyhow!("DELIVERY_ARCHIVE_PUBLIC_KEY must be a hex X25519 key")
+      })?;
+    let mut archive = Self::new(dir, key);
+    if let Some(file_bytes) =
+      config_parse::<u64>("DELIVERY_ARCHIVE_FILE_BYTES")?
+    {
+      archive.file_bytes = file_bytes.max(1);
+    }
+    if let Some(days) = config_parse::<i64>("DELIVERY_ARCHIVE_RETENTION_DAYS")?
+    {
+      archive.retention = Some(TimeDelta::days(days));
+    }
+    archive.max_bytes = config_parse("DELIVERY_ARCHIVE_MAX_BYTES")?;
+    log::info!("Archiving deliveries in {}", archive.dir.display());
+    Ok(Some(archive))
+  }
+
+  /// The archive files, oldest first
+  fn files(&self) -> Result<Vec<ArchiveFile>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(&self.dir)? {
+      let entry = entry?;
+      let started = entry
+        .file_name()
+        .to_str()
+        .and_then(|name| name.strip_prefix(FILE_PREFIX))
+        .and_then(|name| name.strip_suffix(FILE_SUFFIX))
+        .and_then(|started| {
+          NaiveDateTime::parse_from_str(started, STARTED_FORMAT).ok()
+        });
+      if let Some(started) = started {
+        files.push(ArchiveFile {
+          path: entry.path(),
+          started,
+          len: entry.metadata()?.len(),
+        });
+      }
+    }
+    files.sort_by_key(|file| file.started);
+    Ok(files)
+  }
+
+  /// The file to append to at `now`, started afresh each day or once the
+  /// current one is full
+  fn current(&self, now: DateTime<Utc>) -> Result<PathBuf> {
+    let now = now.naive_utc();
+    match self.files()?.pop() {
+      Some(file)
+        if file.started.date() == now.date() && file.len < self.file_bytes =>
+      {
+        Ok(file.path)
+      }
+      _ => Ok(self.dir.join(format!(
+        "{}{}{}",
+        FILE_PREFIX,
+        now.format(STARTED_FORMAT),
+        FILE_SUFFIX
+      ))),
+    }
+  }
+
+  /// Seal and append a delivery
+  pub fn append(&self, delivery: &RandomnessDelivery) -> Result<()> {
+    self.append_at(delivery, Utc::now())
+  }
+
+  fn append_at(
+    &self,
+    delivery: &RandomnessDelivery,
+    now: DateTime<Utc>,
+  ) -> Result<()> {
+    let record = ArchiveRecord {
+      id: delivery.id,
+      request_id: delivery.request_id,
+      issued_at: delivery.issued_at,
+      archived_at: now,
+      sources: delivery.sources.clone(),
+      randomness: hex::encode(&delivery.bytes),
+    };
+    let plaintext = serde_json::to_vec(&record)?;
+    let sealed = self
+      .public_key
+      .seal(&mut OsRng, &plaintext)
+      .map_err(|e| anyhow!("Failed to seal archive record: {}", e))?;
+    let line = base64::engine::general_purpose::STANDARD.encode(sealed);
+
+    std::fs::create_dir_all(&self.dir)?;
+    let mut file = std::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(self.current(now)?)?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+
+    self.prune(now)
+  }
+
+  /// Remove the files that are too old, then the oldest while there are too
+  /// many bytes, always keeping the current file
+  fn prune(&self, now: DateTime<Utc>) -> Result<()> {
+    let mut files = self.files()?;
+    files.pop();
+    let mut total: u64 = files.iter().map(|file| file.len).sum();
+    for file in files {
+      let expired = self
+        .retention
+        .is_some_and(|retention| file.started < (now - retention).naive_utc());
+      let over = self.max_bytes.is_some_and(|max| total > max);
+      if !expired && !over {
+        continue;
+      }
+      log::info!("Removing delivery archive {}", file.path.display());
+      std::fs::remove_file(&file.path)?;
+      total -= file.len;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crypto_box::SecretKey;
+
+  #[test]
+  fn test_append_and_rotate() {
+    let dir = std::env::temp_dir()
+      .join(format!("delivery-archive-test-{}", std::process::id()));
+    let secret = SecretKey::generate(&mut OsRng);
+    let mut archive = Archive::new(&dir, secret.public_key().to_bytes());
+    archive.retention = Some(TimeDelta::days(7));
+    let delivery = RandomnessDelivery {
+      id: Some(uuid::Uuid::new_v4()),
+      bytes: vec![7; 64],
+      source: "os".to_string(),
+      sources: vec!["os".to_string()],
+      conditioning: None,
+      request_id: None,
+      issued_at: None,
+      attestation: None,
+      authentication: None,
+    };
+
+    let now = Utc::now();
+    archive
+      .append_at(&delivery, now - TimeDelta::days(30))
+      .unwrap();
+    archive.append_at(&delivery, now).unwrap();
+    // the old file expired
+    assert_eq!(archive.files().unwrap().len(), 1);
+    archive.append_at(&delivery, now).unwrap();
+    assert_eq!(archive.files().unwrap().len(), 1);
+
+    // full files are rotated, and the oldest removed once over the limit
+    archive.file_bytes = 1;
+    let second = TimeDelta::seconds(1);
+    archive.append_at(&delivery, now + second).unwrap();
+    let files = archive.files().unwrap();
+    assert_eq!(files.len(), 2);
+    archive.max_bytes = Some(files[1].len);
+    archive.append_at(&delivery, now + second * 2).unwrap();
+    assert_eq!(archive.files().unwrap().len(), 2);
+
+    let file = archive.files().unwrap().pop().unwrap();
+    let contents = std::fs::read_to_string(file.path).unwrap();
+    let sealed = base64::engine::general_purpose::STANDARD
+      .decode(contents.lines().next().unwrap())
+      .unwrap();
+    let record: ArchiveRecord =
+      serde_json::from_slice(&secret.unseal(&sealed).unwrap()).unwrap();
+    assert_eq!(record.id, delivery.id);
+    assert_eq!(record.randomness, hex::encode(&delivery.bytes));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
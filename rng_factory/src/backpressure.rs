@@ -0,0 +1,129 @@
+//! Holding back deliveries the generator isn't taking.
+//!
+//! In push mode, the generator acknowledges each time it takes randomness
+//! for a pulse (see [biab_utils::Consumed]). Once it has, and once it has
+//! advertised its pulse period, it's taken to be paused or down when it
+//! hasn't acknowledged for `DELIVERY_MAX_MISSED_PULSES` periods (default 3),
+//! and `DELIVERY_BACKPRESSURE` decides what happens:
+//!
+//! - `pause` (default): no more randomness is read or delivered until the
+//!   generator acknowledges again, so hardware entropy isn't spent on
+//!   deliveries nobody uses. Buffered deliveries are still sent, so it has
+//!   something to take (and acknowledge) when it's back.
+//! - `discard-oldest`: deliveries carry on, each replacing the last, and the
+//!   oldest are dropped from the buffer while the generator can't be reached
+//!   (see [crate::link])
+//!
+//! A generator that never acknowledges (eg: an older one) is never held
+//! back.
+use anyhow::{anyhow, Result};
+use biab_utils::{config_or, config_value};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Policy {
+  Pause,
+  DiscardOldest,
+}
+
+pub struct Backpressure {
+  policy: Policy,
+  max_missed: u32,
+  /// When the generator last acknowledged, if it does
+  acknowledged: Option<Instant>,
+  /// Whether the generator is taken to be paused or down
+  backed_up: bool,
+}
+
+impl Backpressure {
+  fn new(policy: Policy, max_missed: u32) -> Self {
+    Self {
+      policy,
+      max_missed: max_missed.max(1),
+      acknowledged: None,
+      backed_up: false,
+    }
+  }
+
+  pub fn from_env() -> Result<Self> {
+    let policy = match config_value("DELIVERY_BACKPRESSURE")?.as_deref() {
+      None | Some("pause") => Policy::Pause,
+      Some("discard-oldest") => Policy::DiscardOldest,
+      Some(other) => {
+        return Err(anyhow!("Invalid DELIVERY_BACKPRESSURE: {}", other))
+      }
+    };
+    let max_missed = config_or("DELIVERY_MAX_MISSED_PULSES", 3)?;
+    Ok(Self::new(policy, max_missed))
+  }
+
+  /// Whether to make the next delivery, with pulses every `period` if the
+  /// generator advertised it
+  pub fn ready(&mut self, period: Option<Duration>, now: Instant) -> bool {
+    let backed_up = match (self.acknowledged, period) {
+      (Some(acknowledged), Some(period)) => {
+        now.duration_since(acknowledged) > period * self.max_missed
+      }
+      _ => false,
+    };
+    if backed_up && !self.backed_up {
+      match self.policy {
+        Policy::Pause => log::warn!(
+          "The generator hasn't taken randomness for {} pulses, pausing \
+           deliveries until it does",
+          self.max_missed
+        ),
+        Policy::DiscardOldest => log::warn!(
+          "The generator hasn't taken randomness for {} pulses",
+          self.max_missed
+        ),
+      }
+    }
+    self.backed_up = backed_up;
+    !self.paused()
+  }
+
+  /// Note the generator took randomness
+  pub fn consumed(&mut self, now: Instant) {
+    if self.backed_up {
+      log::info!("The generator is taking randomness again");
+    }
+    self.acknowledged = Some(now);
+    self.backed_up = false;
+  }
+
+  /// Whether deliveries are held back
+  pub fn paused(&self) -> bool {
+    self.backed_up && self.policy == Policy::Pause
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_backpressure() {
+    let start = Instant::now();
+    let period = Some(Duration::from_secs(10));
+    let later = |secs| start + Duration::from_secs(secs);
+    let mut pause = Backpressure::new(Policy::Pause, 3);
+    let mut discard = Backpressure::new(Policy::DiscardOldest, 3);
+    for backpressure in [&mut pause, &mut discard] {
+      // not held back before the generator acknowledges
+      assert!(backpressure.ready(period, later(60)));
+      backpressure.consumed(later(60));
+      assert!(backpressure.ready(period, later(90)));
+      // nor without knowing its period
+      assert!(backpressure.ready(None, later(120)));
+    }
+    assert!(!pause.ready(period, later(91)));
+    assert!(pause.paused());
+    assert!(discard.ready(period, later(91)));
+    assert!(!discard.paused());
+
+    pause.consumed(later(95));
+    assert!(pause.ready(period, later(96)));
+    assert!(!pause.paused());
+  }
+}
@@ -0,0 +1,98 @@
+//! How often, and how much, randomness is delivered.
+//!
+//! Deliveries are `DELIVERY_BYTES` (default 64, what a pulse takes) and come
+//! every `DELIVERY_INTERVAL_SECS` (default 5) until the generator advertises
+//! its pulse period (see [StrandPeriod]), which it sends to `LISTEN_ADDR`
+//! after every pulse. From then on, deliveries come twice a period, so one
+//! is always ready for the next pulse, but at least every [MAX_INTERVAL] so
+//! the latest is never too old for the generator to use.
+use anyhow::{anyhow, Result};
+use biab_utils::config_or;
+use biab_utils::StrandPeriod;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The fewest bytes a pulse takes
+pub const PULSE_BYTES: usize = 64;
+/// The most bytes a delivery can have
+pub const MAX_DELIVERY_BYTES: usize = 1024;
+/// The longest time between deliveries, under the generator's 30s limit
+const MAX_INTERVAL: Duration = Duration::from_secs(25);
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many bytes each delivery has
+pub fn delivery_bytes() -> Result<usize> {
+  let bytes = config_or("DELIVERY_BYTES", PULSE_BYTES)?;
+  if !(PULSE_BYTES..=MAX_DELIVERY_BYTES).contains(&bytes) {
+    return Err(anyhow!(
+      "DELIVERY_BYTES must be from {} to {}",
+      PULSE_BYTES,
+      MAX_DELIVERY_BYTES
+    ));
+  }
+  Ok(bytes)
+}
+
+/// The time between deliveries
+fn interval(configured: Duration, period: Option<Duration>) -> Duration {
+  match period {
+    Some(period) => (period / 2).clamp(MIN_INTERVAL, MAX_INTERVAL),
+    None => configured,
+  }
+}
+
+#[derive(Clone)]
+pub struct Cadence {
+  configured: Duration,
+  /// The pulse period the generator advertised, if it has
+  period: Arc<Mutex<Option<Duration>>>,
+}
+
+impl Cadence {
+  pub fn from_env() -> Result<Self> {
+    let secs = config_or("DELIVERY_INTERVAL_SECS", 5)?;
+    Ok(Self {
+      configured: Duration::from_secs(secs).max(MIN_INTERVAL),
+      period: Arc::new(Mutex::new(None)),
+    })
+  }
+
+  /// The pulse period, if the generator advertised it
+  pub fn period(&self) -> Option<Duration> {
+    *self.period.lock().expect("Failed to acquire lock")
+  }
+
+  pub fn interval(&self) -> Duration {
+    interval(self.configured, self.period())
+  }
+
+  /// Follow the pulse period the generator advertised
+  pub fn follow(&self, advertised: StrandPeriod) {
+    let advertised = Duration::from_millis(advertised.millis);
+    let mut period = self.period.lock().expect("Failed to acquire lock");
+    if *period != Some(advertised) {
+      log::info!(
+        "The pulse period is {}s, delivering every {}s",
+        advertised.as_secs_f64(),
+        interval(self.configured, Some(advertised)).as_secs_f64()
+      );
+      *period = Some(advertised);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_interval() {
+    let configured = Duration::from_secs(5);
+    assert_eq!(interval(configured, None), configured);
+    let secs = |s| Some(Duration::from_secs(s));
+    assert_eq!(interval(configured, secs(10)), Duration::from_secs(5));
+    assert_eq!(interval(configured, secs(20)), Duration::from_secs(10));
+    assert_eq!(interval(configured, secs(60)), MAX_INTERVAL);
+    assert_eq!(interval(configured, secs(1)), MIN_INTERVAL);
+  }
+}
@@ -0,0 +1,343 @@
+//! Combining several entropy sources, with failover.
+//!
+//! Instead of a single source, the YAML file at `ENTROPY_SOURCE_PATH` can
+//! list several:
+//!
+//! ```yaml
+//! combine: xor # or kdf
+//! sources:
+//!   - driver: hwrng
+//!     weight: 2
+//!   - driver: http
+//!     url: https://qrng.example.com/api/bytes
+//!   - driver: os
+//!     priority: 1
+//! ```
+//!
+//! Each read combines the healthy sources of the best (lowest) priority, each
+//! contributing `weight` (default 1) times the bytes asked for, either XORed
+//! together or through SHAKE256 (`kdf`). Sources that fail to read or fail
+//! their health tests are left out, and when none of a priority are left the
+//! sources of the next priority are used instead, until the better ones
+//! recover.
+use crate::source::{Source, SourceConfig};
+use anyhow::{anyhow, Result};
+use biab_utils::{config_value, SourceAttestation, SourceStatus};
+use serde::Deserialize;
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::Shake256;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Combine {
+  #[default]
+  Xor,
+  Kdf,
+}
+
+fn default_weight() -> usize {
+  1
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct WeightedConfig {
+  #[serde(flatten)]
+  source: SourceConfig,
+  /// How many times the bytes asked for the source contributes
+  #[serde(default = "default_weight")]
+  weight: usize,
+  /// Lower is preferred
+  #[serde(default)]
+  priority: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Config {
+  #[serde(default)]
+  combine: Combine,
+  sources: Vec<WeightedConfig>,
+}
+
+impl Config {
+  fn single(source: SourceConfig) -> Self {
+    Self {
+      combine: Combine::default(),
+      sources: vec![WeightedConfig {
+        source,
+        weight: default_weight(),
+        priority: 0,
+      }],
+    }
+  }
+
+  /// Either a list of sources or a single one
+  fn from_yaml(value: serde_yaml::Value) -> Result<Self> {
+    if value.get("sources").is_some() {
+      Ok(serde_yaml::from_value(value)?)
+    } else {
+      Ok(Self::single(serde_yaml::from_value(value)?))
+    }
+  }
+}
+
+struct Weighted {
+  source: Source,
+  weight: usize,
+  priority: u32,
+}
+
+/// Raw entropy and the sources it came from
+pub struct Reading {
+  pub bytes: Vec<u8>,
+  pub sources: Vec<String>,
+}
+
+pub struct Sources {
+  sources: Vec<Weighted>,
+  combine: Combine,
+  /// The priority last read from, to notice failing over
+  active: Mutex<Option<u32>>,
+}
+
+/// XOR `bytes` down to `len` bytes
+fn fold(bytes: &[u8], len: usize, into: &mut [u8]) {
+  for chunk in bytes.chunks(len) {
+    for (byte, other) in into.iter_mut().zip(chunk) {
+      *byte ^= other;
+    }
+  }
+}
+
+fn combine(
+  combine: Combine,
+  contributions: &[(String, Vec<u8>)],
+  len: usize,
+) -> Vec<u8> {
+  let mut output = vec![0; len];
+  match combine {
+    Combine::Xor => {
+      for (_, bytes) in contributions {
+        fold(bytes, len, &mut output);
+      }
+    }
+    Combine::Kdf => {
+      let mut shake = Shake256::default();
+      for (name, bytes) in contributions {
+        // length prefixed, so contributions can't run into each other
+        shake.update(&(name.len() as u64).to_be_bytes());
+        shake.update(name.as_bytes());
+        shake.update(&(bytes.len() as u64).to_be_bytes());
+        shake.update(bytes);
+      }
+      shake.finalize_xof().read(&mut output);
+    }
+  }
+  output
+}
+
+impl Sources {
+  fn open(config: Config, min_entropy: f64) -> Result<Self> {
+    if config.sources.is_empty() {
+      return Err(anyhow!("No entropy sources are configured"));
+    }
+    let sources = config
+      .sources
+      .into_iter()
+      .map(|weighted| {
+        if weighted.weight == 0 {
+          return Err(anyhow!(
+            "The weight of entropy source {} must be at least 1",
+            weighted.source.name()
+          ));
+        }
+        Ok(Weighted {
+          source: weighted.source.open(min_entropy)?,
+          weight: weighted.weight,
+          priority: weighted.priority,
+        })
+      })
+      .collect::<Result<_>>()?;
+    Ok(Self {
+      sources,
+      combine: config.combine,
+      active: Mutex::new(None),
+    })
+  }
+
+  /// The configured sources, eg: `xor(hwrng:/dev/hwrng, os)`
+  pub fn name(&self) -> String {
+    if let [single] = self.sources.as_slice() {
+      return single.source.name.clone();
+    }
+    let names: Vec<&str> = self
+      .sources
+      .iter()
+      .map(|weighted| weighted.source.name.as_str())
+      .collect();
+    format!("{:?}({})", self.combine, names.join(", ")).to_lowercase()
+  }
+
+  /// What's known about each source, and if it's one of `contributed`
+  pub fn attestation(&self, contributed: &[String]) -> Vec<SourceAttestation> {
+    self
+      .sources
+      .iter()
+      .map(|weighted| {
+        let source = &weighted.source;
+        source.attestation(contributed.contains(&source.name))
+      })
+      .collect()
+  }
+
+  /// How each source is doing
+  pub fn status(&self) -> Vec<SourceStatus> {
+    let sources = self.sources.iter();
+    sources.map(|weighted| weighted.source.status()).collect()
+  }
+
+  /// Run the startup tests of every source, leaving out those that fail
+  pub async fn startup(&mut self, chunk: usize) -> Result<()> {
+    let mut passed = Vec::with_capacity(self.sources.len());
+    for weighted in self.sources.drain(..) {
+      match weighted.source.startup(chunk).await {
+        Ok(()) => passed.push(weighted),
+        Err(e) => log::error!(
+          "ALERT: startup tests of entropy source {} failed: {}",
+          weighted.source.name,
+          e
+        ),
+      }
+    }
+    if passed.is_empty() {
+      return Err(anyhow!("No entropy source passed its startup tests"));
+    }
+    self.sources = passed;
+    Ok(())
+  }
+
+  /// Combined raw entropy from the best healthy sources
+  pub async fn read(&self, len: usize) -> Result<Reading> {
+    let mut priorities: Vec<u32> = self
+      .sources
+      .iter()
+      .map(|weighted| weighted.priority)
+      .collect();
+    priorities.sort_unstable();
+    priorities.dedup();
+    for priority in priorities {
+      let mut contributions = Vec::new();
+      let tier = self.sources.iter().filter(|s| s.priority == priority);
+      for weighted in tier {
+        let source = &weighted.source;
+        match source.read(len * weighted.weight).await {
+          Ok(bytes) => contributions.push((source.name.clone(), bytes)),
+          Err(e) => {
+            log::warn!("Leaving out entropy source {}: {}", source.name, e)
+          }
+        }
+      }
+      if contributions.is_empty() {
+        continue;
+      }
+      self.fail_over(priority);
+      return Ok(Reading {
+        bytes: combine(self.combine, &contributions, len),
+        sources: contributions.into_iter().map(|(name, _)| name).collect(),
+      });
+    }
+    Err(anyhow!("None of the entropy sources could be read"))
+  }
+
+  fn fail_over(&self, priority: u32) {
+    let mut active = self.active.lock().expect("Failed to acquire lock");
+    match *active {
+      Some(previous) if previous < priority => log::error!(
+        "ALERT: failed over from entropy sources of priority {} to {}",
+        previous,
+        priority
+      ),
+      Some(previous) if previous > priority => {
+        log::info!("Entropy sources of priority {} recovered", priority)
+      }
+      _ => {}
+    }
+    *active = Some(priority);
+  }
+}
+
+/// The configured sources, with `min_entropy` bits per byte
+pub fn from_env(min_entropy: f64) -> Result<Sources> {
+  let config = match (
+    config_value("ENTROPY_SOURCE_PATH")?,
+    config_value("ENTROPY_SOURCE")?,
+  ) {
+    (Some(path), _) => {
+      let file = std::fs::File::open(&path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+      let invalid =
+        |e: anyhow::Error| anyhow!("Invalid entropy source in {}: {}", path, e);
+      let value = serde_yaml::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| invalid(e.into()))?;
+      Config::from_yaml(value).map_err(invalid)?
+    }
+    (_, Some(value)) => Config::single(SourceConfig::parse(value.trim())?),
+    _ => Config::single(SourceConfig::Os),
+  };
+  Sources::open(config, min_entropy)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_config() {
+    let config = Config::from_yaml(
+      serde_yaml::from_str(
+        "combine: kdf\nsources:\n  - driver: os\n    priority: 1\n  - \
+         driver: hwrng\n    weight: 2",
+      )
+      .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(config.combine, Combine::Kdf);
+    assert_eq!(
+      config.sources,
+      vec![
+        WeightedConfig {
+          source: SourceConfig::Os,
+          weight: 1,
+          priority: 1,
+        },
+        WeightedConfig {
+          source: SourceConfig::Hwrng {
+            path: "/dev/hwrng".to_string()
+          },
+          weight: 2,
+          priority: 0,
+        },
+      ]
+    );
+
+    let single =
+      Config::from_yaml(serde_yaml::from_str("driver: os").unwrap()).unwrap();
+    assert_eq!(single, Config::single(SourceConfig::Os));
+  }
+
+  #[test]
+  fn test_combine() {
+    let contributions = vec![
+      ("a".to_string(), vec![0b1100, 0b1010, 0b0001, 0b0000]),
+      ("b".to_string(), vec![0b1010, 0b0110]),
+    ];
+    // weighted contributions are folded in
+    assert_eq!(
+      combine(Combine::Xor, &contributions, 2),
+      vec![0b1100 ^ 0b0001 ^ 0b1010, 0b1010 ^ 0b0110]
+    );
+    let kdf = combine(Combine::Kdf, &contributions, 64);
+    assert_eq!(kdf.len(), 64);
+    assert_ne!(kdf, combine(Combine::Kdf, &contributions[..1], 64));
+  }
+}
@@ -0,0 +1,226 @@
+//! Conditioning raw entropy into full-entropy deliveries.
+//!
+//! Raw source output may have less than 8 bits of entropy per byte, so it
+//! isn't delivered as is. `ENTROPY_CONDITIONING` picks how it's conditioned:
+//!
+//! - `sha3` (default): each 64 bytes delivered are the SHA3-512 of enough
+//!   raw bytes for 512 + 64 bits of min-entropy (SP 800-90B section
+//!   3.1.5.1.2), so the output has full entropy
+//! - `hmac-drbg`: an HMAC-DRBG with SHA-512 (SP 800-90A section 10.1.2),
+//!   seeded with as many raw bytes and reseeded from the source every
+//!   `ENTROPY_RESEED_INTERVAL` times it's drawn from (default 1, ie: every
+//!   64 bytes the pool is filled with)
+//!
+//! How a delivery was conditioned is recorded in it (see [Conditioning]),
+//! along with the sources that went into it (or into the DRBG's last seed).
+use crate::combine::{Reading, Sources};
+use anyhow::{anyhow, Result};
+use biab_utils::{config_or, config_value, Conditioning};
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use sha3::{Digest, Sha3_512};
+
+/// Bits of output per delivery
+const OUTPUT_BITS: f64 = 512.0;
+
+/// How many raw bytes hold enough entropy for full-entropy output
+fn input_bytes(min_entropy: f64) -> usize {
+  ((OUTPUT_BITS + 64.0) / min_entropy).ceil() as usize
+}
+
+/// An HMAC-DRBG with SHA-512, without personalization or additional input
+struct HmacDrbg {
+  key: [u8; 64],
+  value: [u8; 64],
+  reseed_counter: u64,
+}
+
+impl HmacDrbg {
+  fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 64] {
+    let mut mac =
+      Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes any key size");
+    for part in parts {
+      mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+  }
+
+  fn new(entropy: &[u8], nonce: &[u8]) -> Self {
+    let mut drbg = Self {
+      key: [0; 64],
+      value: [1; 64],
+      reseed_counter: 1,
+    };
+    drbg.update(&[entropy, nonce]);
+    drbg
+  }
+
+  fn update(&mut self, data: &[&[u8]]) {
+    let provided = data.iter().any(|part| !part.is_empty());
+    for round in [0u8, 1] {
+      if round == 1 && !provided {
+        break;
+      }
+      let round = [round];
+      let mut parts: Vec<&[u8]> = vec![&self.value, &round];
+      parts.extend_from_slice(data);
+      self.key = Self::hmac(&self.key, &parts);
+      self.value = Self::hmac(&self.key, &[&self.value]);
+    }
+  }
+
+  fn reseed(&mut self, entropy: &[u8]) {
+    self.update(&[entropy]);
+    self.reseed_counter = 1;
+  }
+
+  fn generate(&mut self, len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(len);
+    while output.len() < len {
+      self.value = Self::hmac(&self.key, &[&self.value]);
+      output.extend_from_slice(&self.value);
+    }
+    output.truncate(len);
+    self.update(&[]);
+    self.reseed_counter += 1;
+    output
+  }
+}
+
+enum Method {
+  Sha3,
+  HmacDrbg {
+    drbg: Option<HmacDrbg>,
+    reseed_interval: u64,
+    /// The sources of the last seed
+    seeded_by: Vec<String>,
+  },
+}
+
+pub struct Conditioner {
+  method: Method,
+  input_bytes: usize,
+}
+
+impl Conditioner {
+  pub fn from_env(min_entropy: f64) -> Result<Self> {
+    let method = match config_value("ENTROPY_CONDITIONING")?.as_deref() {
+      None | Some("sha3") => Method::Sha3,
+      Some("hmac-drbg") => Method::HmacDrbg {
+        drbg: None,
+        seeded_by: Vec::new(),
+        reseed_interval: config_or("ENTROPY_RESEED_INTERVAL", 1)?.max(1),
+      },
+      Some(other) => {
+        return Err(anyhow!("Invalid ENTROPY_CONDITIONING: {}", other))
+      }
+    };
+    Ok(Self {
+      method,
+      input_bytes: input_bytes(min_entropy),
+    })
+  }
+
+  /// How deliveries are conditioned
+  pub fn conditioning(&self) -> Conditioning {
+    let (function, reseed_interval) = match &self.method {
+      Method::Sha3 => ("sha3-512", None),
+      Method::HmacDrbg {
+        reseed_interval, ..
+      } => ("hmac-drbg-sha512", Some(*reseed_interval)),
+    };
+    Conditioning {
+      function: function.to_string(),
+      input_bytes: self.input_bytes,
+      reseed_interval,
+    }
+  }
+
+  /// Conditioned output from fresh raw entropy of the sources
+  pub async fn condition(
+    &mut self,
+    sources: &Sources,
+    len: usize,
+  ) -> Result<Reading> {
+    match &mut self.method {
+      Method::Sha3 => {
+        // a hash of fresh raw entropy for every 64 bytes
+        let mut bytes = Vec::with_capacity(len);
+        let mut contributed: Vec<String> = Vec::new();
+        while bytes.len() < len {
+          let raw = sources.read(self.input_bytes).await?;
+          bytes.extend_from_slice(&Sha3_512::digest(&raw.bytes));
+          for source in raw.sources {
+            if !contributed.contains(&source) {
+              contributed.push(source);
+            }
+          }
+        }
+        bytes.truncate(len);
+        Ok(Reading {
+          bytes,
+          sources: contributed,
+        })
+      }
+      Method::HmacDrbg {
+        drbg,
+        reseed_interval,
+        seeded_by,
+      } => {
+        match drbg {
+          Some(drbg) if drbg.reseed_counter > *reseed_interval => {
+            let entropy = sources.read(self.input_bytes).await?;
+            drbg.reseed(&entropy.bytes);
+            *seeded_by = entropy.sources;
+          }
+          Some(_) => {}
+          None => {
+            let entropy = sources.read(self.input_bytes).await?;
+            let nonce = sources.read(self.input_bytes / 2).await?;
+            *drbg = Some(HmacDrbg::new(&entropy.bytes, &nonce.bytes));
+            *seeded_by = entropy.sources;
+          }
+        }
+        Ok(Reading {
+          bytes: drbg.as_mut().expect("instantiated").generate(len),
+          sources: seeded_by.clone(),
+        })
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_input_bytes() {
+    assert_eq!(input_bytes(8.0), 72);
+    assert_eq!(input_bytes(4.0), 144);
+    assert_eq!(input_bytes(0.5), 1152);
+  }
+
+  #[test]
+  fn test_hmac_drbg() {
+    let mut drbg = HmacDrbg::new(&[1; 72], &[2; 36]);
+    let first = drbg.generate(64);
+    let second = drbg.generate(64);
+    assert_eq!(first.len(), 64);
+    // as computed by a reference implementation of SP 800-90A
+    assert_eq!(hex::encode(&first[..8]), "0c5b3a2c77f71b0a");
+    assert_eq!(hex::encode(&second[..8]), "17c3eb3a0edb0bb8");
+    assert_ne!(first, second);
+    assert_eq!(drbg.reseed_counter, 3);
+
+    // deterministic for the same seed
+    let mut same = HmacDrbg::new(&[1; 72], &[2; 36]);
+    assert_eq!(same.generate(64), first);
+    // and reseeding changes what follows
+    let mut reseeded = HmacDrbg::new(&[1; 72], &[2; 36]);
+    reseeded.generate(64);
+    reseeded.reseed(&[3; 72]);
+    assert_eq!(reseeded.reseed_counter, 1);
+    assert_ne!(reseeded.generate(64), second);
+  }
+}
@@ -0,0 +1,46 @@
+//! The rng factory's settings, read once and checked at startup (see
+//! [biab_utils::config_value] for where they're read from).
+//!
+//! The sources, the pool, the conditioning and the other parts of the
+//! factory read their own settings when they're created, at startup too.
+use crate::{cadence, health};
+use anyhow::{anyhow, Result};
+use biab_utils::config_value;
+use serde::Serialize;
+
+/// Whether randomness is pushed to the generator or waits to be requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+  Push,
+  Request,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Config {
+  pub delivery_mode: DeliveryMode,
+  /// The bytes in each delivery
+  pub delivery_bytes: usize,
+  /// The assessed min-entropy per byte of the sources
+  pub min_entropy: f64,
+}
+
+impl Config {
+  pub fn load() -> Result<Self> {
+    let delivery_mode = match config_value("DELIVERY_MODE")?.as_deref() {
+      None | Some("push") => DeliveryMode::Push,
+      Some("request") => DeliveryMode::Request,
+      Some(other) => {
+        return Err(anyhow!(
+          "Invalid DELIVERY_MODE {:?}: expected push or request",
+          other
+        ))
+      }
+    };
+    Ok(Self {
+      delivery_mode,
+      delivery_bytes: cadence::delivery_bytes()?,
+      min_entropy: health::min_entropy_from_env()?,
+    })
+  }
+}
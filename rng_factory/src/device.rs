@@ -0,0 +1,146 @@
+//! Hardware generators read as character devices.
+//!
+//! Kernel drivers expose generators at eg: `/dev/hwrng`, and USB TRNGs like
+//! the TrueRNG or OneRNG show up as serial devices (eg: `/dev/ttyACM0`).
+//! Serial devices are put in raw mode (with `stty`) so no bytes are
+//! translated, and some need a command before they send anything, which is
+//! written to them first.
+//!
+//! What the kernel knows about the device (the current hardware generator,
+//! or a USB TRNG's product, serial number and firmware version) is read
+//! from sysfs when it's opened, for attesting deliveries.
+use crate::source::EntropySource;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Identifying details of a device, where available
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceInfo {
+  pub device: Option<String>,
+  pub serial: Option<String>,
+  pub firmware: Option<String>,
+}
+
+fn sysfs_value(dir: &Path, name: &str) -> Option<String> {
+  let value = std::fs::read_to_string(dir.join(name)).ok()?;
+  Some(value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// The details of a USB device from its sysfs directory
+fn usb_info(dir: &Path) -> DeviceInfo {
+  let names: Vec<String> = ["manufacturer", "product"]
+    .iter()
+    .filter_map(|name| sysfs_value(dir, name))
+    .collect();
+  DeviceInfo {
+    device: Some(names.join(" ")).filter(|device| !device.is_empty()),
+    serial: sysfs_value(dir, "serial"),
+    firmware: sysfs_value(dir, "bcdDevice"),
+  }
+}
+
+/// What the kernel knows about the device at `path`
+fn device_info(path: &str) -> DeviceInfo {
+  // eg: /dev/serial/by-id/... links to /dev/ttyACM0
+  let name = std::fs::canonicalize(path)
+    .unwrap_or_else(|_| PathBuf::from(path))
+    .file_name()
+    .map(|name| name.to_string_lossy().to_string());
+  match name.as_deref() {
+    Some("hwrng") => DeviceInfo {
+      device: sysfs_value(
+        Path::new("/sys/class/misc/hw_random"),
+        "rng_current",
+      ),
+      ..Default::default()
+    },
+    // the tty's device is the usb interface, in the usb device's directory
+    Some(name) => {
+      std::fs::canonicalize(format!("/sys/class/tty/{}/device", name))
+        .ok()
+        .and_then(|interface| interface.parent().map(usb_info))
+        .unwrap_or_default()
+    }
+    None => DeviceInfo::default(),
+  }
+}
+
+pub struct Device {
+  path: String,
+  init: Option<String>,
+  info: DeviceInfo,
+}
+
+impl Device {
+  pub fn new(path: String, init: Option<String>) -> Self {
+    let info = device_info(&path);
+    Self { path, init, info }
+  }
+
+  pub fn serial(path: String, init: Option<String>) -> Self {
+    let raw = std::process::Command::new("stty")
+      .args(["-F", &path, "raw", "-echo"])
+      .status();
+    match raw {
+      Ok(status) if status.success() => {}
+      Ok(status) => {
+        log::warn!("Failed to put {} in raw mode: stty {}", path, status)
+      }
+      Err(e) => log::warn!("Failed to put {} in raw mode: {}", path, e),
+    }
+    Self::new(path, init)
+  }
+}
+
+#[async_trait]
+impl EntropySource for Device {
+  async fn read(&self, len: usize) -> Result<Vec<u8>> {
+    let mut device = OpenOptions::new()
+      .read(true)
+      .write(self.init.is_some())
+      .open(&self.path)
+      .await
+      .map_err(|e| anyhow!("Failed to open {}: {}", self.path, e))?;
+    if let Some(init) = &self.init {
+      device.write_all(init.as_bytes()).await?;
+      device.flush().await?;
+    }
+    let mut bytes = vec![0; len];
+    device
+      .read_exact(&mut bytes)
+      .await
+      .map_err(|e| anyhow!("Failed to read {}: {}", self.path, e))?;
+    Ok(bytes)
+  }
+
+  fn info(&self) -> DeviceInfo {
+    self.info.clone()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_usb_info() {
+    let dir = std::env::temp_dir().join("rng_factory_test_usb_info");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("manufacturer"), "ubld.it\n").unwrap();
+    std::fs::write(dir.join("product"), "TrueRNG\n").unwrap();
+    std::fs::write(dir.join("bcdDevice"), "0100\n").unwrap();
+    let info = usb_info(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+      info,
+      DeviceInfo {
+        device: Some("ubld.it TrueRNG".to_string()),
+        serial: None,
+        firmware: Some("0100".to_string()),
+      }
+    );
+  }
+}
@@ -0,0 +1,190 @@
+//! Continuous health tests of raw source output (NIST SP 800-90B 4.4).
+//!
+//! Every byte read from a source is a sample for the Repetition Count Test
+//! and the Adaptive Proportion Test, which carry on across reads, so a
+//! source that gets stuck or heavily biased is caught even if each read on
+//! its own looks fine. Reads that fail a test aren't delivered. The cutoffs
+//! follow from the min-entropy per byte the source is assessed to have,
+//! `ENTROPY_MIN_ENTROPY` (default 8, ie: full entropy), with a false alarm
+//! rate of 2^-20. Before the first delivery, [STARTUP_SAMPLES] samples are
+//! tested (section 4.3).
+use anyhow::Result;
+use biab_utils::config_or;
+
+/// False positive probability of each test, as a power of 2
+const ALPHA_EXPONENT: i32 = 20;
+/// Adaptive proportion test window for non-binary samples
+const PROPORTION_WINDOW: usize = 512;
+/// How many samples are tested at startup
+pub const STARTUP_SAMPLES: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthError {
+  Repetition { value: u8, count: usize },
+  Proportion { value: u8, count: usize },
+}
+
+impl std::fmt::Display for HealthError {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      HealthError::Repetition { value, count } => write!(
+        f,
+        "repetition count test failed: byte 0x{:02x} repeated {} times",
+        value, count
+      ),
+      HealthError::Proportion { value, count } => write!(
+        f,
+        "adaptive proportion test failed: byte 0x{:02x} seen {} times in {} \
+         samples",
+        value, count, PROPORTION_WINDOW
+      ),
+    }
+  }
+}
+
+impl std::error::Error for HealthError {}
+
+/// The assessed min-entropy per byte of the source
+pub fn min_entropy_from_env() -> Result<f64> {
+  let min_entropy = config_or("ENTROPY_MIN_ENTROPY", 8.0)?;
+  if !(min_entropy > 0.0 && min_entropy <= 8.0) {
+    return Err(anyhow::anyhow!(
+      "ENTROPY_MIN_ENTROPY must be more than 0 and at most 8"
+    ));
+  }
+  Ok(min_entropy)
+}
+
+/// C = 1 + ceil(alpha exponent / H) (section 4.4.1)
+fn repetition_cutoff(min_entropy: f64) -> usize {
+  1 + (ALPHA_EXPONENT as f64 / min_entropy).ceil() as usize
+}
+
+/// C = 1 + CRITBINOM(W, 2^-H, 1 - alpha) (section 4.4.2), the smallest
+/// count of a value in a window that is less likely than alpha
+fn proportion_cutoff(min_entropy: f64) -> usize {
+  let p = 2f64.powf(-min_entropy);
+  let alpha = 2f64.powi(-ALPHA_EXPONENT);
+  let n = PROPORTION_WINDOW;
+  // the binomial probabilities, in log space to avoid underflow
+  let mut log_pmf = n as f64 * (1.0 - p).ln();
+  let mut cdf = 0.0;
+  for k in 0..=n {
+    if k > 0 {
+      log_pmf += ((n - k + 1) as f64 / k as f64).ln() + (p / (1.0 - p)).ln();
+    }
+    cdf += log_pmf.exp();
+    if cdf >= 1.0 - alpha {
+      return (1 + k).min(n);
+    }
+  }
+  n
+}
+
+/// The state of the tests on a source, and how often they failed
+#[derive(Debug)]
+pub struct Health {
+  repetition_cutoff: usize,
+  proportion_cutoff: usize,
+  /// The last sample and how many times in a row it was seen
+  run: Option<(u8, usize)>,
+  /// The first sample of the window, times it was seen and window size
+  window: Option<(u8, usize, usize)>,
+  pub samples: u64,
+  pub repetition_failures: u64,
+  pub proportion_failures: u64,
+}
+
+impl Health {
+  pub fn new(min_entropy: f64) -> Self {
+    Self {
+      repetition_cutoff: repetition_cutoff(min_entropy),
+      proportion_cutoff: proportion_cutoff(min_entropy),
+      run: None,
+      window: None,
+      samples: 0,
+      repetition_failures: 0,
+      proportion_failures: 0,
+    }
+  }
+
+  fn sample(&mut self, value: u8) -> Result<(), HealthError> {
+    self.samples += 1;
+    let run = match self.run {
+      Some((last, count)) if last == value => count + 1,
+      _ => 1,
+    };
+    self.run = Some((value, run));
+    if run >= self.repetition_cutoff {
+      self.repetition_failures += 1;
+      self.run = None;
+      return Err(HealthError::Repetition { value, count: run });
+    }
+
+    self.window = match self.window {
+      None => Some((value, 1, 1)),
+      Some((first, seen, size)) => {
+        let seen = seen + (first == value) as usize;
+        if seen >= self.proportion_cutoff {
+          self.proportion_failures += 1;
+          self.window = None;
+          return Err(HealthError::Proportion {
+            value: first,
+            count: seen,
+          });
+        }
+        (size + 1 < PROPORTION_WINDOW).then_some((first, seen, size + 1))
+      }
+    };
+    Ok(())
+  }
+
+  /// Run the tests over freshly read samples
+  pub fn check(&mut self, samples: &[u8]) -> Result<(), HealthError> {
+    for value in samples {
+      self.sample(*value)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_cutoffs() {
+    // the examples of SP 800-90B sections 4.4.1 and 4.4.2
+    assert_eq!(repetition_cutoff(8.0), 4);
+    assert_eq!(repetition_cutoff(2.0), 11);
+    assert_eq!(proportion_cutoff(8.0), 13);
+    assert_eq!(proportion_cutoff(4.0), 62);
+    assert_eq!(proportion_cutoff(2.0), 177);
+    assert_eq!(proportion_cutoff(1.0), 311);
+  }
+
+  #[test]
+  fn test_check() {
+    let mut health = Health::new(8.0);
+    let counting: Vec<u8> = (0..=255).cycle().take(4096).collect();
+    assert!(health.check(&counting).is_ok());
+    // a run carries over between reads
+    assert!(health.check(&[9, 9]).is_ok());
+    assert_eq!(
+      health.check(&[9, 9]),
+      Err(HealthError::Repetition { value: 9, count: 4 })
+    );
+    assert_eq!(health.repetition_failures, 1);
+
+    let mut health = Health::new(8.0);
+    let biased: Vec<u8> = (0..64).flat_map(|i| [7, i as u8 + 8]).collect();
+    assert_eq!(
+      health.check(&biased),
+      Err(HealthError::Proportion {
+        value: 7,
+        count: 13
+      })
+    );
+    assert_eq!(health.proportion_failures, 1);
+  }
+}
@@ -0,0 +1,278 @@
+//! The rng factory reads randomness from entropy sources (see [source] and
+//! [combine]), keeps it conditioned in a pool (see [pool]) and delivers it
+//! to the generator, which uses the latest delivery when it assembles a
+//! pulse.
+//!
+//! With `DELIVERY_MODE=request` it delivers nothing until asked instead,
+//! answering each `need-entropy` request from the generator (see
+//! [requests]). Deliveries can be archived (see [archive]), and see
+//! [status] for how it's doing.
+use anyhow::{anyhow, Result};
+use biab_utils::{handle_shutdown_signal, init_logger};
+use biab_utils::{heartbeat_interval, RandomnessDelivery};
+use biab_utils::{Attestation, DeliveryAuth, LinkStatus, Peer};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, Notify};
+use uuid::Uuid;
+
+mod archive;
+mod backpressure;
+mod cadence;
+mod combine;
+mod conditioning;
+mod config;
+mod device;
+mod health;
+mod link;
+mod metrics;
+mod pool;
+mod qrng;
+mod requests;
+mod source;
+mod status;
+mod subprocess;
+use archive::Archive;
+use backpressure::Backpressure;
+use cadence::{Cadence, PULSE_BYTES};
+use combine::Sources;
+use conditioning::Conditioner;
+use config::{Config, DeliveryMode};
+use link::Link;
+use pool::Pool;
+
+/// The sources, how their output is conditioned and the pool it waits in
+struct Factory {
+  sources: Sources,
+  conditioner: Conditioner,
+  pool: Pool,
+  auth: Option<DeliveryAuth>,
+  archive: Option<Archive>,
+  min_entropy: f64,
+  /// When randomness last reached the generator
+  last_delivery: Option<DateTime<Utc>>,
+  /// The connection to the generator, when pushing deliveries
+  generator: Option<LinkStatus>,
+  backpressure: Backpressure,
+}
+
+impl Factory {
+  /// Fill the pool so it has `len` bytes and is above its low watermark
+  async fn fill(&mut self, len: usize) -> Result<()> {
+    self.pool.expire(Instant::now());
+    let mut shortfall = self.pool.shortfall(len);
+    while shortfall > 0 {
+      let chunk = shortfall.min(PULSE_BYTES);
+      let reading = self.conditioner.condition(&self.sources, chunk).await?;
+      self.pool.put(reading, Instant::now(), Utc::now());
+      shortfall -= chunk;
+    }
+    Ok(())
+  }
+
+  /// Top up the pool after a delivery, if it's low
+  async fn top_up(&mut self) {
+    if let Err(e) = self.fill(0).await {
+      log::error!("Failed to fill the pool: {}", e);
+    }
+    metrics::observe_pool(self.pool.level());
+  }
+
+  /// A delivery of `len` bytes from the head of the pool
+  async fn delivery(&mut self, len: usize) -> Result<RandomnessDelivery> {
+    self.pool.expire(Instant::now());
+    if self.pool.level() < len {
+      log::warn!("The pool is low, filling it before delivering");
+      self.pool.starved();
+      self.fill(len).await?;
+    }
+    let (conditioned, collected_at) = self
+      .pool
+      .take(len)
+      .ok_or_else(|| anyhow!("The pool has no randomness"))?;
+    let attestation = Attestation {
+      min_entropy: self.min_entropy,
+      sources: self.sources.attestation(&conditioned.sources),
+    };
+    Ok(RandomnessDelivery {
+      id: Some(Uuid::new_v4()),
+      bytes: conditioned.bytes,
+      source: self.sources.name(),
+      sources: conditioned.sources,
+      conditioning: Some(self.conditioner.conditioning()),
+      request_id: None,
+      issued_at: Some(collected_at),
+      attestation: Some(attestation),
+      authentication: None,
+    })
+  }
+
+  /// Authenticate and archive a delivery that's ready to go, if configured
+  /// to
+  fn issue(
+    &self,
+    mut delivery: RandomnessDelivery,
+  ) -> Result<RandomnessDelivery> {
+    if let Some(auth) = &self.auth {
+      auth.authenticate(&mut delivery)?;
+    }
+    if let Some(archive) = &self.archive {
+      if let Err(e) = archive.append(&delivery) {
+        log::error!("ALERT: failed to archive a delivery: {}", e);
+      }
+    }
+    metrics::observe_delivery(delivery.bytes.len());
+    Ok(delivery)
+  }
+}
+
+/// What the rng factory does, for `--help`
+pub const ABOUT: &str =
+  "Reads randomness from entropy sources and delivers it to the generator";
+
+/// The rng factory's commands, for `--help`
+pub const COMMANDS: &[(&str, &str)] = &[(
+  "healthcheck",
+  "print the status of the rng factory running alongside, failing unless it's healthy",
+)];
+
+/// Run the rng factory, or one of its commands
+pub async fn run() -> Result<()> {
+  if biab_utils::help(ABOUT, COMMANDS) {
+    return Ok(());
+  }
+  init_logger();
+  match biab_utils::command() {
+    Some("healthcheck") => return status::healthcheck().await,
+    Some(command) => {
+      return Err(anyhow!("Unknown command {}, see --help", command))
+    }
+    None => {}
+  }
+  let config = Config::load()?;
+  if biab_utils::print_config(&config, false)? {
+    return Ok(());
+  }
+
+  let shutdown = Arc::new(Notify::new());
+  tokio::spawn(handle_shutdown_signal(shutdown.clone()));
+  biab_utils::start_metrics_server(shutdown.clone())?;
+
+  let min_entropy = config.min_entropy;
+  let mut sources = combine::from_env(min_entropy)?;
+  let conditioner = Conditioner::from_env(min_entropy)?;
+  sources.startup(PULSE_BYTES).await?;
+  let mut factory = Factory {
+    sources,
+    conditioner,
+    pool: Pool::from_env()?,
+    auth: DeliveryAuth::signer_from_env()?,
+    archive: Archive::from_env()?,
+    min_entropy,
+    last_delivery: None,
+    generator: None,
+    backpressure: Backpressure::from_env()?,
+  };
+  factory.fill(0).await?;
+  let factory = Arc::new(Mutex::new(factory));
+  let cadence = Cadence::from_env()?;
+  let requested = config.delivery_mode == DeliveryMode::Request;
+  let server = requests::serve(
+    factory.clone(),
+    cadence.clone(),
+    requested,
+    shutdown.clone(),
+  );
+  if requested {
+    return server.await;
+  }
+  let bytes = config.delivery_bytes;
+  tokio::try_join!(server, push(factory, cadence, bytes, shutdown))?;
+  Ok(())
+}
+
+/// Deliver randomness to the generator at the pace of its pulses
+async fn push(
+  factory: Arc<Mutex<Factory>>,
+  cadence: Cadence,
+  bytes: usize,
+  shutdown: Arc<Notify>,
+) -> Result<()> {
+  let generator = Peer::generator()?;
+  log::info!(
+    "Delivering {} bytes of randomness from {} to {} every {}s",
+    bytes,
+    factory.lock().await.sources.name(),
+    generator.address(),
+    cadence.interval().as_secs_f64()
+  );
+
+  let mut link = Link::from_env(generator)?;
+  let mut heartbeats = tokio::time::interval(heartbeat_interval());
+  let mut next = tokio::time::Instant::now() + cadence.interval();
+  loop {
+    tokio::select! {
+      _ = shutdown.notified() => {
+        log::info!("Stopping...");
+        break;
+      }
+      _ = heartbeats.tick() => {
+        if let Err(e) = link.heartbeat().await {
+          log::warn!("Lost the connection to the generator: {}", e);
+          factory.lock().await.generator = Some(link.status());
+        }
+      }
+      _ = tokio::time::sleep_until(next) => {
+        next = tokio::time::Instant::now() + cadence.interval();
+        let ready = factory
+          .lock()
+          .await
+          .backpressure
+          .ready(cadence.period(), Instant::now());
+        // the factory is unlocked while sending, so requests and status
+        // checks aren't held up by a slow generator
+        let sent = if ready {
+          match take_delivery(&factory, bytes).await {
+            Ok(delivery) => {
+              link.push(delivery);
+              Some(link.flush().await)
+            }
+            Err(e) => Some(Err(e)),
+          }
+        } else {
+          if let Err(e) = link.flush().await {
+            log::debug!("Failed to send buffered deliveries: {}", e);
+          }
+          None
+        };
+        let mut factory = factory.lock().await;
+        match sent {
+          // sent, rather than waiting to reconnect
+          Some(Ok(())) if link.status().buffered == 0 => {
+            factory.last_delivery = Some(Utc::now());
+          }
+          Some(Ok(())) | None => {}
+          Some(Err(e)) => log::error!("Failed to deliver randomness: {}", e),
+        }
+        if ready {
+          factory.top_up().await;
+        }
+        factory.generator = Some(link.status());
+      }
+    }
+  }
+  link.close().await;
+  Ok(())
+}
+
+/// The next delivery, authenticated and archived, locking the factory only
+/// while it's taken
+async fn take_delivery(
+  factory: &Mutex<Factory>,
+  bytes: usize,
+) -> Result<RandomnessDelivery> {
+  let mut factory = factory.lock().await;
+  let delivery = factory.delivery(bytes).await?;
+  factory.issue(delivery)
+}
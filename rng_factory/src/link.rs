@@ -0,0 +1,96 @@
+//! The connection to the generator.
+//!
+//! Deliveries are sent in order over a [MessengerClient], so the connection
+//! is opened again when it drops (eg: the generator restarted) and each
+//! delivery is kept until the generator acknowledged it. While the generator
+//! can't be reached, deliveries wait in a buffer of `DELIVERY_BUFFER`
+//! (default 6, ie: 30 seconds' worth). When it's full the oldest are
+//! dropped, as the generator wouldn't use them anyway. On shutdown, what's
+//! buffered gets one last chance to be sent.
+use anyhow::Result;
+use biab_utils::config_or;
+use biab_utils::{LinkStatus, MessengerClient, Peer};
+use biab_utils::{RandomnessDelivery, RANDOMNESS_COMMAND};
+use std::time::Duration;
+
+/// How long sending what's buffered can take on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Link {
+  client: MessengerClient,
+}
+
+impl Link {
+  pub fn new(generator: Peer, capacity: usize) -> Self {
+    Self {
+      client: MessengerClient::new(generator, capacity),
+    }
+  }
+
+  pub fn from_env(generator: Peer) -> Result<Self> {
+    let capacity = config_or("DELIVERY_BUFFER", 6)?;
+    Ok(Self::new(generator, capacity))
+  }
+
+  /// Queue a delivery, dropping the oldest if the buffer is full
+  pub fn push(&mut self, delivery: RandomnessDelivery) {
+    self.client.push_delivery(RANDOMNESS_COMMAND, &delivery);
+  }
+
+  /// Send what's buffered, unless still waiting to connect again
+  pub async fn flush(&mut self) -> Result<()> {
+    self.client.flush().await
+  }
+
+  /// Keep the connection alive while nothing is sent (see
+  /// [MessengerClient::heartbeat])
+  pub async fn heartbeat(&mut self) -> Result<()> {
+    self.client.heartbeat().await
+  }
+
+  pub fn status(&self) -> LinkStatus {
+    self.client.status()
+  }
+
+  /// Send what's buffered one last time, and drop the rest
+  pub async fn close(self) {
+    self.client.close(SHUTDOWN_TIMEOUT).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn delivery(byte: u8) -> RandomnessDelivery {
+    RandomnessDelivery {
+      id: None,
+      bytes: vec![byte; 64],
+      source: "os".to_string(),
+      sources: vec!["os".to_string()],
+      conditioning: None,
+      request_id: None,
+      issued_at: None,
+      attestation: None,
+      authentication: None,
+    }
+  }
+
+  #[test]
+  fn test_push() {
+    let mut link = Link::new(Peer::generator().unwrap(), 2);
+    for byte in 0..4 {
+      link.push(delivery(byte));
+    }
+    let buffered: Vec<u8> = link
+      .client
+      .queued()
+      .map(|message| {
+        let delivery: RandomnessDelivery =
+          message.extract_payload().unwrap().unwrap();
+        delivery.bytes[0]
+      })
+      .collect();
+    assert_eq!(buffered, vec![2, 3]);
+  }
+}
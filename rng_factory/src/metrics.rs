@@ -0,0 +1,37 @@
+//! Prometheus metrics at `GET /metrics` on `METRICS_ADDR`, if set.
+//!
+//! Deliveries of randomness are counted along with their bytes, and the
+//! bytes waiting in the pool are a gauge, measured after each top up.
+use biab_utils::{int_counter, int_gauge};
+use prometheus::{IntCounter, IntGauge};
+use std::sync::LazyLock;
+
+static DELIVERIES: LazyLock<IntCounter> = LazyLock::new(|| {
+  int_counter(
+    "rng_factory_deliveries_total",
+    "Deliveries of randomness issued",
+  )
+});
+
+static DELIVERED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+  int_counter(
+    "rng_factory_delivered_bytes_total",
+    "Bytes of randomness issued in deliveries",
+  )
+});
+
+static POOL_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+  int_gauge(
+    "rng_factory_pool_bytes",
+    "Conditioned bytes waiting in the pool",
+  )
+});
+
+pub fn observe_delivery(bytes: usize) {
+  DELIVERIES.inc();
+  DELIVERED_BYTES.inc_by(bytes as u64);
+}
+
+pub fn observe_pool(level: usize) {
+  POOL_BYTES.set(level as i64);
+}
@@ -0,0 +1,225 @@
+//! A pool of conditioned randomness, ready to be delivered.
+//!
+//! Rather than reading from the sources while a delivery waits, conditioned
+//! randomness is kept in a pool of up to `POOL_BYTES` (default 1024, ie: 16
+//! pulses' worth). Deliveries are served from the head of the pool, oldest
+//! first, and once it's down to `POOL_LOW_WATERMARK` bytes (default 256) it's
+//! topped up again. Randomness that stays in the pool for more than
+//! `POOL_MAX_AGE_SECS` (default 20, at most the generator's 30) is
+//! discarded, so deliveries are always fresh. Deliveries are stamped with
+//! when their oldest bytes were collected, rather than when they're sent.
+//!
+//! How full the pool is and how old its randomness is are answered to a
+//! `pool-status` message (see [PoolStatus]), along with how often a delivery
+//! had to wait for the pool to be filled, ie: whether the sources keep up.
+use crate::cadence::PULSE_BYTES;
+use crate::combine::Reading;
+use anyhow::{anyhow, Result};
+use biab_utils::config_or;
+use biab_utils::PoolStatus;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The oldest randomness the generator accepts, in seconds
+const MAX_AGE_SECS: u64 = 30;
+
+/// Conditioned randomness, and when it was put in the pool
+struct Chunk {
+  bytes: Vec<u8>,
+  sources: Vec<String>,
+  filled_at: Instant,
+  collected_at: DateTime<Utc>,
+}
+
+pub struct Pool {
+  chunks: VecDeque<Chunk>,
+  capacity: usize,
+  low_watermark: usize,
+  max_age: Duration,
+  /// Bytes discarded for being too old
+  expired: u64,
+  /// Deliveries that waited for the pool to be filled
+  starved: u64,
+  /// The collection time of the last randomness taken
+  last_collected: Option<DateTime<Utc>>,
+}
+
+impl Pool {
+  pub fn new(capacity: usize, low_watermark: usize, max_age: Duration) -> Self {
+    Self {
+      chunks: VecDeque::new(),
+      capacity,
+      low_watermark,
+      max_age,
+      expired: 0,
+      starved: 0,
+      last_collected: None,
+    }
+  }
+
+  pub fn from_env() -> Result<Self> {
+    let capacity = config_or("POOL_BYTES", 1024)?;
+    let low_watermark = config_or("POOL_LOW_WATERMARK", 256)?;
+    let max_age = config_or("POOL_MAX_AGE_SECS", 20)?;
+    if capacity < PULSE_BYTES {
+      return Err(anyhow!("POOL_BYTES must be at least {}", PULSE_BYTES));
+    }
+    if low_watermark >= capacity {
+      return Err(anyhow!("POOL_LOW_WATERMARK must be under POOL_BYTES"));
+    }
+    if max_age > MAX_AGE_SECS {
+      return Err(anyhow!(
+        "POOL_MAX_AGE_SECS must be at most {}, the generator refuses older \
+         randomness",
+        MAX_AGE_SECS
+      ));
+    }
+    Ok(Self::new(
+      capacity,
+      low_watermark,
+      Duration::from_secs(max_age.max(1)),
+    ))
+  }
+
+  /// The bytes in the pool
+  pub fn level(&self) -> usize {
+    self.chunks.iter().map(|chunk| chunk.bytes.len()).sum()
+  }
+
+  /// Discard what's been in the pool too long
+  pub fn expire(&mut self, now: Instant) {
+    while let Some(chunk) = self.chunks.front() {
+      if now.duration_since(chunk.filled_at) <= self.max_age {
+        break;
+      }
+      self.expired += chunk.bytes.len() as u64;
+      self.chunks.pop_front();
+    }
+  }
+
+  /// How many bytes to add so a delivery of `len` can be served and the pool
+  /// stays above its low watermark, if any are needed
+  pub fn shortfall(&self, len: usize) -> usize {
+    let level = self.level();
+    if level >= len && level > self.low_watermark {
+      return 0;
+    }
+    self.capacity.max(len) - level
+  }
+
+  /// Add randomness collected at `collected_at`
+  pub fn put(
+    &mut self,
+    reading: Reading,
+    now: Instant,
+    collected_at: DateTime<Utc>,
+  ) {
+    self.chunks.push_back(Chunk {
+      bytes: reading.bytes,
+      sources: reading.sources,
+      filled_at: now,
+      collected_at,
+    });
+  }
+
+  /// Note that a delivery waited for the pool to be filled
+  pub fn starved(&mut self) {
+    self.starved += 1;
+  }
+
+  /// Take `len` bytes from the head of the pool, if it has them, along with
+  /// when the oldest of them were collected. Takes from the same chunk are
+  /// a microsecond apart, so every delivery has a later time than the last.
+  pub fn take(&mut self, len: usize) -> Option<(Reading, DateTime<Utc>)> {
+    if self.level() < len {
+      return None;
+    }
+    let mut collected_at = self.chunks.front()?.collected_at;
+    if let Some(last) = self.last_collected.filter(|l| collected_at <= *l) {
+      collected_at = last + TimeDelta::microseconds(1);
+    }
+    self.last_collected = Some(collected_at);
+    let mut bytes = Vec::with_capacity(len);
+    let mut sources: Vec<String> = Vec::new();
+    while bytes.len() < len {
+      let chunk = self.chunks.front_mut().expect("enough bytes");
+      let wanted = (len - bytes.len()).min(chunk.bytes.len());
+      bytes.extend(chunk.bytes.drain(..wanted));
+      for source in &chunk.sources {
+        if !sources.contains(source) {
+          sources.push(source.clone());
+        }
+      }
+      if chunk.bytes.is_empty() {
+        self.chunks.pop_front();
+      }
+    }
+    Some((Reading { bytes, sources }, collected_at))
+  }
+
+  pub fn status(&self, now: Instant) -> PoolStatus {
+    let age = |chunk: &Chunk| now.duration_since(chunk.filled_at).as_secs_f64();
+    PoolStatus {
+      level: self.level(),
+      capacity: self.capacity,
+      low_watermark: self.low_watermark,
+      oldest_secs: self.chunks.front().map(age),
+      newest_secs: self.chunks.back().map(age),
+      expired_bytes: self.expired,
+      starved_deliveries: self.starved,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn reading(byte: u8, source: &str) -> Reading {
+    Reading {
+      bytes: vec![byte; 64],
+      sources: vec![source.to_string()],
+    }
+  }
+
+  #[test]
+  fn test_pool() {
+    let start = Instant::now();
+    let at = Utc::now();
+    let secs = |s| Duration::from_secs(s);
+    let time = |s| at + TimeDelta::seconds(s);
+    let mut pool = Pool::new(256, 64, secs(60));
+    assert_eq!(pool.shortfall(64), 256);
+    pool.put(reading(1, "a"), start, at);
+    pool.put(reading(2, "b"), start + secs(30), time(30));
+    pool.put(reading(3, "a"), start + secs(40), time(40));
+    assert_eq!(pool.level(), 192);
+    assert_eq!(pool.shortfall(64), 0);
+    assert_eq!(pool.shortfall(256), 64);
+
+    // served from the head, across chunks
+    let (taken, collected_at) = pool.take(96).unwrap();
+    assert_eq!(&taken.bytes[..64], &[1; 64]);
+    assert_eq!(&taken.bytes[64..], &[2; 32]);
+    assert_eq!(taken.sources, vec!["a", "b"]);
+    // stamped with the oldest bytes
+    assert_eq!(collected_at, at);
+    assert!(pool.take(128).is_none());
+
+    pool.expire(start + secs(95));
+    assert_eq!(pool.level(), 64);
+    let status = pool.status(start + secs(95));
+    assert_eq!(status.expired_bytes, 32);
+    assert_eq!(status.oldest_secs, Some(55.0));
+    // at the low watermark, so it's topped up
+    assert_eq!(pool.shortfall(64), 192);
+
+    // taking from a chunk again is still stamped later
+    let (_, collected_at) = pool.take(32).unwrap();
+    assert_eq!(collected_at, time(40));
+    let (_, collected_at) = pool.take(32).unwrap();
+    let later = time(40) + TimeDelta::microseconds(1);
+    assert_eq!(collected_at, later);
+  }
+}
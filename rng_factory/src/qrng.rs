@@ -0,0 +1,111 @@
+//! Randomness from an HTTP quantum RNG service.
+//!
+//! The url is fetched with a `GET` for every read and the body decoded as
+//! raw bytes, hex or base64, depending on the service.
+use crate::source::EntropySource;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use twine_protocol::twine_http_store::reqwest::header::{
+  HeaderMap, HeaderName, HeaderValue,
+};
+use twine_protocol::twine_http_store::reqwest::Client;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+  #[default]
+  Raw,
+  Hex,
+  Base64,
+}
+
+fn decode(body: &[u8], format: Format) -> Result<Vec<u8>> {
+  let text = || String::from_utf8_lossy(body).trim().to_string();
+  Ok(match format {
+    Format::Raw => body.to_vec(),
+    Format::Hex => hex::decode(text())?,
+    Format::Base64 => {
+      base64::engine::general_purpose::STANDARD.decode(text())?
+    }
+  })
+}
+
+pub struct Qrng {
+  url: String,
+  format: Format,
+  client: Client,
+}
+
+impl Qrng {
+  pub fn new(
+    url: String,
+    format: Format,
+    headers: BTreeMap<String, String>,
+    timeout_secs: u64,
+  ) -> Result<Self> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+      header_map.insert(
+        HeaderName::from_bytes(name.as_bytes())?,
+        HeaderValue::from_str(&value)?,
+      );
+    }
+    let client = Client::builder()
+      .default_headers(header_map)
+      .timeout(Duration::from_secs(timeout_secs))
+      .build()?;
+    Ok(Self {
+      url,
+      format,
+      client,
+    })
+  }
+}
+
+impl Qrng {
+  async fn fetch(&self) -> Result<Vec<u8>> {
+    let body = self
+      .client
+      .get(&self.url)
+      .send()
+      .await?
+      .error_for_status()?
+      .bytes()
+      .await?;
+    decode(&body, self.format)
+  }
+}
+
+#[async_trait]
+impl EntropySource for Qrng {
+  async fn read(&self, len: usize) -> Result<Vec<u8>> {
+    // ask again until there's enough
+    let mut bytes = Vec::with_capacity(len);
+    while bytes.len() < len {
+      let fetched = self.fetch().await?;
+      if fetched.is_empty() {
+        return Err(anyhow!("{} returned nothing", self.url));
+      }
+      bytes.extend_from_slice(&fetched);
+    }
+    bytes.truncate(len);
+    Ok(bytes)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_decode() {
+    assert_eq!(decode(b"\x00\xff", Format::Raw).unwrap(), vec![0, 255]);
+    assert_eq!(decode(b"00ff\n", Format::Hex).unwrap(), vec![0, 255]);
+    assert_eq!(decode(b"AP8=", Format::Base64).unwrap(), vec![0, 255]);
+    assert!(decode(b"zz", Format::Hex).is_err());
+  }
+}
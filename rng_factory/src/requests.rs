@@ -0,0 +1,214 @@
+//! Messages to the rng factory, on `LISTEN_ADDR` (default `0.0.0.0:5555`),
+//! with TLS if configured (see [biab_utils::Listener]).
+//!
+//! - `strand-period`: the generator's pulse period, to pace deliveries by
+//!   (see [crate::cadence])
+//! - `randomness-consumed`: the generator took randomness (see
+//!   [crate::backpressure])
+//! - `pool-status`: answered with how full and fresh the pool is (see
+//!   [crate::pool])
+//! - `rng-status`: answered with how the rng factory is doing (see
+//!   [crate::status])
+//! - `need-entropy`: with `DELIVERY_MODE=request`, the rng factory delivers
+//!   nothing on a timer, and instead answers each of these requests from
+//!   the generator with randomness from the pool, tagged with the id of the
+//!   request it answers
+use crate::cadence::{Cadence, MAX_DELIVERY_BYTES};
+use crate::Factory;
+use anyhow::{anyhow, Result};
+use biab_utils::{listen_address, EntropyRequest, Message, Messenger};
+use biab_utils::{Incoming, Listener, MessageStream, OpenConnection};
+use biab_utils::{CONSUMED_COMMAND, STRAND_PERIOD_COMMAND};
+use biab_utils::{NEED_ENTROPY_COMMAND, RANDOMNESS_COMMAND};
+use biab_utils::{POOL_STATUS_COMMAND, RNG_STATUS_COMMAND};
+use chrono::Utc;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, Notify};
+
+/// Handle messages until shut down, answering requests for randomness if
+/// `requested`
+pub async fn serve(
+  factory: Arc<Mutex<Factory>>,
+  cadence: Cadence,
+  requested: bool,
+  shutdown: Arc<Notify>,
+) -> Result<()> {
+  let address = listen_address()?;
+  let listener = Listener::bind(&address).await?;
+  if requested {
+    log::info!(
+      "Serving randomness from {} on {}",
+      factory.lock().await.sources.name(),
+      address
+    );
+  } else {
+    log::info!("Listening on {}", address);
+  }
+
+  // shared by every connection (clones share what was received lately), so
+  // a message sent again over a new connection is still taken for a repeat
+  let messenger = Messenger::new();
+  loop {
+    tokio::select! {
+      _ = shutdown.notified() => {
+        log::info!("Stopping...");
+        break;
+      }
+      accepted = listener.accept() => match accepted {
+        Ok((incoming, peer)) => {
+          let client = Client {
+            factory: factory.clone(),
+            cadence: cadence.clone(),
+            requested,
+            messenger: messenger.clone(),
+            shutdown: shutdown.clone(),
+          };
+          tokio::spawn(client.handle(incoming, peer));
+        }
+        Err(e) => log::error!("Failed to accept connection: {}", e),
+      }
+    }
+  }
+  Ok(())
+}
+
+struct Client {
+  factory: Arc<Mutex<Factory>>,
+  cadence: Cadence,
+  requested: bool,
+  messenger: Messenger,
+  shutdown: Arc<Notify>,
+}
+
+impl Client {
+  async fn handle(self, incoming: Incoming, peer: SocketAddr) {
+    // listen for shutdown from now on, so it isn't missed between messages
+    let stopping = self.shutdown.notified();
+    tokio::pin!(stopping);
+    stopping.as_mut().enable();
+
+    let mut stream = match incoming.stream().await {
+      Ok(stream) => stream,
+      Err(e) => {
+        log::warn!("[{}] {}", peer, e);
+        return;
+      }
+    };
+    let _connection = OpenConnection::new(peer);
+    loop {
+      let message = tokio::select! {
+        _ = &mut stopping => {
+          log::debug!("[{}] Closing, shutting down", peer);
+          break;
+        }
+        message = self.messenger.receive_live(&mut stream) => match message {
+          Some(message) => message,
+          // closed, silent for too long or sent something that isn't a message
+          None => break,
+        }
+      };
+      let handled = match message.command.as_str() {
+        STRAND_PERIOD_COMMAND => self.follow(&message),
+        CONSUMED_COMMAND => {
+          self
+            .factory
+            .lock()
+            .await
+            .backpressure
+            .consumed(Instant::now());
+          Ok(())
+        }
+        POOL_STATUS_COMMAND => self.pool_status(&mut stream, &message).await,
+        RNG_STATUS_COMMAND => self.status(&mut stream, &message).await,
+        NEED_ENTROPY_COMMAND if self.requested => {
+          self.answer(&mut stream, &message).await
+        }
+        command => {
+          log::warn!("[{}] Ignoring {} message", peer, command);
+          Ok(())
+        }
+      };
+      // requests are confirmed by their answer, other messages acknowledged
+      // if the sender asked
+      let answered = match message.command.as_str() {
+        POOL_STATUS_COMMAND | RNG_STATUS_COMMAND => true,
+        NEED_ENTROPY_COMMAND => self.requested,
+        _ => false,
+      };
+      if !answered {
+        let acknowledged =
+          self.messenger.acknowledge(&mut stream, &message, &handled);
+        if let Err(e) = acknowledged.await {
+          log::warn!("[{}] Failed to acknowledge {}: {}", peer, message.id, e);
+          break;
+        }
+      }
+      if let Err(e) = handled {
+        // closing the connection tells the requester
+        log::error!("[{}] Failed to handle {}: {}", peer, message.id, e);
+        break;
+      }
+    }
+  }
+
+  fn follow(&self, message: &Message) -> Result<()> {
+    let period = message
+      .extract_payload()?
+      .ok_or_else(|| anyhow!("The message has no period"))?;
+    self.cadence.follow(period);
+    Ok(())
+  }
+
+  async fn status(
+    &self,
+    stream: &mut MessageStream,
+    request: &Message,
+  ) -> Result<()> {
+    let status = self.factory.lock().await.status();
+    let answer = self.messenger.answer(request, RNG_STATUS_COMMAND, &status);
+    self.messenger.send(stream, answer).await?;
+    Ok(())
+  }
+
+  async fn pool_status(
+    &self,
+    stream: &mut MessageStream,
+    request: &Message,
+  ) -> Result<()> {
+    let status = self.factory.lock().await.pool.status(Instant::now());
+    let answer = self.messenger.answer(request, POOL_STATUS_COMMAND, &status);
+    self.messenger.send(stream, answer).await?;
+    Ok(())
+  }
+
+  async fn answer(
+    &self,
+    stream: &mut MessageStream,
+    request: &Message,
+  ) -> Result<()> {
+    let EntropyRequest { bytes } = request
+      .extract_payload()?
+      .ok_or_else(|| anyhow!("The request has no payload"))?;
+    if bytes == 0 || bytes > MAX_DELIVERY_BYTES {
+      return Err(anyhow!("Can't deliver {} bytes", bytes));
+    }
+    let mut factory = self.factory.lock().await;
+    let mut delivery = factory.delivery(bytes).await?;
+    delivery.request_id = Some(request.id);
+    let delivery = factory.issue(delivery)?;
+    let answer = self
+      .messenger
+      .answer(request, RANDOMNESS_COMMAND, &delivery);
+    self.messenger.send(stream, answer).await?;
+    log::debug!(
+      "Delivered randomness from {} for {}",
+      delivery.sources.join(", "),
+      request.id
+    );
+    factory.last_delivery = Some(Utc::now());
+    factory.top_up().await;
+    Ok(())
+  }
+}
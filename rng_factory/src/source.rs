@@ -0,0 +1,307 @@
+//! Where the randomness comes from.
+//!
+//! A source is picked with `ENTROPY_SOURCE`, either a short form:
+//!
+//! - `os`: the operating system's generator (`getrandom`)
+//! - `hwrng` or `hwrng:<path>`: a hardware generator device (default
+//!   `/dev/hwrng`)
+//! - `serial:<path>`: a USB serial TRNG (eg: TrueRNG, OneRNG)
+//! - `subprocess:<command>`: the stdout of a command
+//! - `http:<url>`: an HTTP quantum RNG service returning raw bytes
+//!
+//! or, for more parameters, a YAML file at `ENTROPY_SOURCE_PATH`:
+//!
+//! ```yaml
+//! driver: serial
+//! path: /dev/ttyACM0
+//! # OneRNG needs telling to start
+//! init: "cmd0\ncmdO\n"
+//! ```
+//!
+//! which can also list several sources (see [crate::combine]). See
+//! [SourceConfig] for the drivers and their parameters. Without either, the
+//! `os` source is used.
+use crate::device::{Device, DeviceInfo};
+use crate::health::{Health, STARTUP_SAMPLES};
+use crate::qrng::{Format, Qrng};
+use crate::subprocess::Subprocess;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use biab_utils::{SourceAttestation, SourceHealth, SourceStatus};
+use rand::RngCore;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[async_trait]
+pub trait EntropySource: Send + Sync {
+  /// Read exactly `len` bytes of raw entropy
+  async fn read(&self, len: usize) -> Result<Vec<u8>>;
+
+  /// Identifying details of the hardware, if any
+  fn info(&self) -> DeviceInfo {
+    DeviceInfo::default()
+  }
+}
+
+/// How reading a source has gone
+#[derive(Debug, Default)]
+pub struct Reads {
+  /// Reads run through the health tests, and those that failed
+  pub tested: u64,
+  pub failed: u64,
+  /// Why the last read failed, if it did
+  pub last_error: Option<String>,
+}
+
+pub struct Source {
+  pub name: String,
+  pub driver: Box<dyn EntropySource>,
+  pub health: Mutex<Health>,
+  pub reads: Mutex<Reads>,
+}
+
+impl Source {
+  /// Read `len` bytes that passed the health tests
+  pub async fn read(&self, len: usize) -> Result<Vec<u8>> {
+    let read = self.tested_read(len).await;
+    let mut reads = self.reads.lock().expect("Failed to acquire lock");
+    reads.last_error = read.as_ref().err().map(|e| e.to_string());
+    read
+  }
+
+  async fn tested_read(&self, len: usize) -> Result<Vec<u8>> {
+    let bytes = self.driver.read(len).await?;
+    let mut health = self.health.lock().expect("Failed to acquire lock");
+    let tested = health.check(&bytes);
+    let mut reads = self.reads.lock().expect("Failed to acquire lock");
+    reads.tested += 1;
+    if let Err(e) = tested {
+      reads.failed += 1;
+      log::error!(
+        "ALERT: entropy source {} failed health tests: {} ({} repetition and \
+         {} proportion failures in {} samples)",
+        self.name,
+        e,
+        health.repetition_failures,
+        health.proportion_failures,
+        health.samples
+      );
+      return Err(e.into());
+    }
+    Ok(bytes)
+  }
+
+  /// How the source is doing
+  pub fn status(&self) -> SourceStatus {
+    let health = self.health.lock().expect("Failed to acquire lock");
+    let reads = self.reads.lock().expect("Failed to acquire lock");
+    SourceStatus {
+      name: self.name.clone(),
+      healthy: reads.last_error.is_none(),
+      health: SourceHealth {
+        samples: health.samples,
+        repetition_failures: health.repetition_failures,
+        proportion_failures: health.proportion_failures,
+      },
+      pass_rate: (reads.tested > 0)
+        .then(|| (reads.tested - reads.failed) as f64 / reads.tested as f64),
+      last_error: reads.last_error.clone(),
+    }
+  }
+
+  /// What's known about the source and its health so far
+  pub fn attestation(&self, contributed: bool) -> SourceAttestation {
+    let info = self.driver.info();
+    let health = self.health.lock().expect("Failed to acquire lock");
+    SourceAttestation {
+      name: self.name.clone(),
+      device: info.device,
+      serial: info.serial,
+      firmware: info.firmware,
+      health: SourceHealth {
+        samples: health.samples,
+        repetition_failures: health.repetition_failures,
+        proportion_failures: health.proportion_failures,
+      },
+      contributed,
+    }
+  }
+
+  /// Test [STARTUP_SAMPLES] samples before the first delivery, `chunk` bytes
+  /// at a time as some sources only give that many at once
+  pub async fn startup(&self, chunk: usize) -> Result<()> {
+    for _ in 0..STARTUP_SAMPLES / chunk {
+      self.read(chunk).await?;
+    }
+    Ok(())
+  }
+}
+
+fn default_hwrng() -> String {
+  "/dev/hwrng".to_string()
+}
+
+fn default_timeout() -> u64 {
+  10
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "driver", rename_all = "lowercase")]
+pub enum SourceConfig {
+  Os,
+  Hwrng {
+    #[serde(default = "default_hwrng")]
+    path: String,
+  },
+  Serial {
+    path: String,
+    /// Written to the device before reading, eg: to start a OneRNG
+    #[serde(default)]
+    init: Option<String>,
+  },
+  Subprocess {
+    command: String,
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+  },
+  Http {
+    url: String,
+    /// How the response body encodes the bytes
+    #[serde(default)]
+    format: Format,
+    /// Extra request headers, eg: an api key
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+  },
+}
+
+impl SourceConfig {
+  /// Parse the short form of `ENTROPY_SOURCE`
+  pub fn parse(value: &str) -> Result<Self> {
+    let (driver, param) = match value.split_once(':') {
+      Some((driver, param)) => (driver, Some(param.to_string())),
+      None => (value, None),
+    };
+    let config = match (driver, param) {
+      ("os", None) => SourceConfig::Os,
+      ("hwrng", path) => SourceConfig::Hwrng {
+        path: path.unwrap_or_else(default_hwrng),
+      },
+      ("serial", Some(path)) => SourceConfig::Serial { path, init: None },
+      ("subprocess", Some(command)) => SourceConfig::Subprocess {
+        command,
+        timeout_secs: default_timeout(),
+      },
+      ("http", Some(url)) => SourceConfig::Http {
+        url,
+        format: Format::default(),
+        headers: BTreeMap::new(),
+        timeout_secs: default_timeout(),
+      },
+      _ => return Err(anyhow!("Invalid entropy source: {}", value)),
+    };
+    Ok(config)
+  }
+
+  pub fn name(&self) -> String {
+    match self {
+      SourceConfig::Os => "os".to_string(),
+      SourceConfig::Hwrng { path } => format!("hwrng:{}", path),
+      SourceConfig::Serial { path, .. } => format!("serial:{}", path),
+      SourceConfig::Subprocess { command, .. } => {
+        format!("subprocess:{}", command)
+      }
+      SourceConfig::Http { url, .. } => format!("http:{}", url),
+    }
+  }
+
+  pub fn open(self, min_entropy: f64) -> Result<Source> {
+    let name = self.name();
+    let driver: Box<dyn EntropySource> = match self {
+      SourceConfig::Os => Box::new(Os),
+      SourceConfig::Hwrng { path } => Box::new(Device::new(path, None)),
+      SourceConfig::Serial { path, init } => {
+        Box::new(Device::serial(path, init))
+      }
+      SourceConfig::Subprocess {
+        command,
+        timeout_secs,
+      } => Box::new(Subprocess::new(&command, timeout_secs)?),
+      SourceConfig::Http {
+        url,
+        format,
+        headers,
+        timeout_secs,
+      } => Box::new(Qrng::new(url, format, headers, timeout_secs)?),
+    };
+    Ok(Source {
+      name,
+      driver,
+      health: Mutex::new(Health::new(min_entropy)),
+      reads: Mutex::new(Reads::default()),
+    })
+  }
+}
+
+/// The operating system's generator
+struct Os;
+
+#[async_trait]
+impl EntropySource for Os {
+  async fn read(&self, len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    rand::rngs::OsRng.try_fill_bytes(&mut bytes)?;
+    Ok(bytes)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(SourceConfig::parse("os").unwrap(), SourceConfig::Os);
+    assert_eq!(
+      SourceConfig::parse("hwrng").unwrap().name(),
+      "hwrng:/dev/hwrng"
+    );
+    assert_eq!(
+      SourceConfig::parse("serial:/dev/ttyACM0").unwrap(),
+      SourceConfig::Serial {
+        path: "/dev/ttyACM0".to_string(),
+        init: None
+      }
+    );
+    assert_eq!(
+      SourceConfig::parse("subprocess:python3 rng.py").unwrap(),
+      SourceConfig::Subprocess {
+        command: "python3 rng.py".to_string(),
+        timeout_secs: 10
+      }
+    );
+    assert_eq!(
+      SourceConfig::parse("http:https://qrng.example.com/bytes")
+        .unwrap()
+        .name(),
+      "http:https://qrng.example.com/bytes"
+    );
+    assert!(SourceConfig::parse("serial").is_err());
+    assert!(SourceConfig::parse("dice").is_err());
+
+    let config: SourceConfig = serde_yaml::from_str(
+      "driver: serial\npath: /dev/ttyACM0\ninit: \"cmdO\\n\"",
+    )
+    .unwrap();
+    assert_eq!(
+      config,
+      SourceConfig::Serial {
+        path: "/dev/ttyACM0".to_string(),
+        init: Some("cmdO\n".to_string())
+      }
+    );
+  }
+}
@@ -0,0 +1,81 @@
+//! How the rng factory is doing, for operators and container healthchecks.
+//!
+//! A `rng-status` message to `LISTEN_ADDR` is answered with (see
+//! [RngStatus]) whether each source's last read succeeded, its health test
+//! counts and the share of its reads that passed them, when randomness last
+//! reached the generator, the connection to the generator when pushing
+//! deliveries, and the pool. The rng factory is healthy as long as one of
+//! its sources can be read.
+//!
+//! `rng_factory healthcheck` asks the rng factory running alongside it for
+//! its status, prints it as JSON and fails unless it's healthy, eg: for a
+//! docker compose `healthcheck`. When its listener requires TLS, the
+//! healthcheck connects like the generator would, with `RNG_FACTORY_TLS_CA`
+//! (see [biab_utils::ClientTls]) and `RNG_FACTORY_TLS_SERVER_NAME` unless
+//! the certificate is for `127.0.0.1`.
+use crate::Factory;
+use anyhow::{anyhow, Result};
+use biab_utils::RNG_STATUS_COMMAND;
+use biab_utils::{listen_address, Messenger, Peer, RngStatus};
+use std::time::{Duration, Instant};
+
+/// How long the rng factory has to answer a healthcheck
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Factory {
+  pub fn status(&self) -> RngStatus {
+    let sources = self.sources.status();
+    RngStatus {
+      healthy: sources.iter().any(|source| source.healthy),
+      sources,
+      last_delivery: self.last_delivery,
+      generator: self.generator.clone(),
+      paused: self.backpressure.paused(),
+      pool: self.pool.status(Instant::now()),
+    }
+  }
+}
+
+/// Where the rng factory listening on `address` is reached from its own
+/// container
+fn local_address(address: &str) -> String {
+  match address.rsplit_once(':') {
+    Some(("0.0.0.0" | "[::]" | "", port)) => format!("127.0.0.1:{}", port),
+    _ => address.to_string(),
+  }
+}
+
+/// Print the status of the rng factory, failing unless it's healthy
+pub async fn healthcheck() -> Result<()> {
+  let address = local_address(&listen_address()?);
+  let rng_factory = Peer::from_env("RNG_FACTORY_ADDR", &address)?;
+  let mut stream = tokio::time::timeout(TIMEOUT, rng_factory.connect())
+    .await
+    .map_err(|_| anyhow!("Timed out connecting to {}", address))??;
+  let messenger = Messenger::new();
+  messenger.send_text(&mut stream, RNG_STATUS_COMMAND).await?;
+  let status: RngStatus =
+    tokio::time::timeout(TIMEOUT, messenger.receive(&mut stream))
+      .await
+      .map_err(|_| anyhow!("The rng factory didn't answer in time"))?
+      .ok_or_else(|| anyhow!("The rng factory didn't answer"))?
+      .extract_payload()?
+      .ok_or_else(|| anyhow!("The rng factory answered without a status"))?;
+  println!("{}", serde_json::to_string_pretty(&status)?);
+  if !status.healthy {
+    return Err(anyhow!("None of the entropy sources can be read"));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_local_address() {
+    assert_eq!(local_address("0.0.0.0:5555"), "127.0.0.1:5555");
+    assert_eq!(local_address("[::]:5555"), "127.0.0.1:5555");
+    assert_eq!(local_address("10.0.0.2:5555"), "10.0.0.2:5555");
+  }
+}
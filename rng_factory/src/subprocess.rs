@@ -0,0 +1,63 @@
+//! Randomness from the stdout of a command, eg: the python example script.
+use crate::source::EntropySource;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::process::Command;
+
+pub struct Subprocess {
+  program: String,
+  args: Vec<String>,
+  timeout: Duration,
+}
+
+impl Subprocess {
+  pub fn new(command: &str, timeout_secs: u64) -> Result<Self> {
+    let mut parts = command.split_whitespace().map(str::to_string);
+    let program = parts
+      .next()
+      .ok_or_else(|| anyhow!("The entropy source command is empty"))?;
+    Ok(Self {
+      program,
+      args: parts.collect(),
+      timeout: Duration::from_secs(timeout_secs),
+    })
+  }
+}
+
+impl Subprocess {
+  async fn run(&self) -> Result<Vec<u8>> {
+    let output = Command::new(&self.program)
+      .args(&self.args)
+      .kill_on_drop(true)
+      .output();
+    let output = tokio::time::timeout(self.timeout, output)
+      .await
+      .map_err(|_| anyhow!("{} timed out", self.program))??;
+    if !output.status.success() {
+      return Err(anyhow!(
+        "{} failed: {}",
+        self.program,
+        String::from_utf8_lossy(&output.stderr)
+      ));
+    }
+    Ok(output.stdout)
+  }
+}
+
+#[async_trait]
+impl EntropySource for Subprocess {
+  async fn read(&self, len: usize) -> Result<Vec<u8>> {
+    // run it again until there's enough
+    let mut bytes = Vec::with_capacity(len);
+    while bytes.len() < len {
+      let output = self.run().await?;
+      if output.is_empty() {
+        return Err(anyhow!("{} returned nothing", self.program));
+      }
+      bytes.extend_from_slice(&output);
+    }
+    bytes.truncate(len);
+    Ok(bytes)
+  }
+}
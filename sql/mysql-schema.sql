@@ -21,3 +21,30 @@ CREATE TABLE IF NOT EXISTS Tixels (
 );
 
 CREATE INDEX idx_tixels_cid ON Tixels (cid);
+
+-- Proofs from external timestamping services (OpenTimestamps, RFC 3161)
+CREATE TABLE IF NOT EXISTS Anchors (
+  id BIGINT UNSIGNED PRIMARY KEY NOT NULL AUTO_INCREMENT,
+  tixel VARBINARY(82) NOT NULL,
+  service VARCHAR(255) NOT NULL,
+  kind VARCHAR(16) NOT NULL,
+  -- sha256 of the tixel cid bytes, as submitted to the service
+  digest VARBINARY(32) NOT NULL,
+  proof BLOB NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  FOREIGN KEY (tixel) REFERENCES Tixels(cid) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_anchors_tixel ON Anchors (tixel);
+
+-- How far each strand has been synced to each remote store by data_sync
+CREATE TABLE IF NOT EXISTS SyncCursors (
+  remote VARCHAR(255) NOT NULL,
+  strand VARBINARY(82) NOT NULL,
+  -- The next tixel index to send
+  next_index BIGINT UNSIGNED NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+
+  PRIMARY KEY (remote, strand)
+);